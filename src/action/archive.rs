@@ -0,0 +1,89 @@
+use std::{path::Path, process::Command};
+
+use serde_json::{Map, Value};
+
+/// the extensions of the conda package archives
+pub(super) const PACKAGE_EXTS: [&str; 2] = [".tar.bz2", ".conda"];
+
+/// the members of the `info/` dir of the package, e.g. `info/index.json`, listed without
+/// unpacking the package
+pub(super) fn info_members(package: &Path) -> anyhow::Result<Vec<String>> {
+    let output = info_tar(package, &["-t"], "the info")?;
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .filter(|member| member.starts_with("info/") && !member.ends_with('/'))
+        .map(String::from)
+        .collect())
+}
+
+/// a member of the `info/` dir of the package read in memory, the package isn't unpacked
+pub(super) fn read_info_member(package: &Path, member: &str) -> anyhow::Result<Vec<u8>> {
+    info_tar(package, &["-xO", member], member)
+}
+
+/// read `info/index.json` of a package
+pub(super) fn read_index_json(package: &Path) -> anyhow::Result<Map<String, Value>> {
+    let contents = read_info_member(package, "info/index.json")?;
+    match serde_json::from_slice(&contents) {
+        Ok(Value::Object(index)) => Ok(index),
+        Ok(_) => Err(invalid(package, "info/index.json", "not an object")),
+        Err(e) => Err(invalid(package, "info/index.json", &e.to_string())),
+    }
+}
+
+/// run `tar` with the args on the tar of the `info/` dir of the package, a `.tar.bz2` package
+/// is that tar itself, a `.conda` package zips it as `info-*.tar.zst` and needs `unzip` and
+/// `zstd`
+fn info_tar(package: &Path, tar_args: &[&str], what: &str) -> anyhow::Result<Vec<u8>> {
+    let script = if package.to_string_lossy().ends_with(".conda") {
+        "package=\"$1\"; shift; unzip -p \"$package\" 'info-*.tar.zst' | tar --zstd -f - \"$@\""
+    } else {
+        "package=\"$1\"; shift; tar -jf \"$package\" \"$@\""
+    };
+    let output = Command::new("sh")
+        .args(["-c", script, "sh"])
+        .arg(package)
+        .args(tar_args)
+        .output()?;
+    if !output.status.success() {
+        return Err(invalid(
+            package,
+            what,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+fn invalid(package: &Path, what: &str, reason: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "fail to read {} of package '{}': {}",
+        what,
+        package.display(),
+        reason
+    )
+}
+
+#[test]
+fn test_read_info_member() -> anyhow::Result<()> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/inspect");
+    for (package, name) in [
+        ("demo-1.0.0-h1a2b3c4_0.tar.bz2", "demo"),
+        ("demo-cli-2.1.0-pyhd8ed1ab_0.conda", "demo-cli"),
+    ] {
+        let package = fixtures.join(package);
+        let members = info_members(&package)?;
+        assert!(members.contains(&"info/paths.json".to_string()));
+        assert!(members
+            .iter()
+            .all(|member| member.starts_with("info/") && !member.ends_with('/')));
+        assert_eq!(read_index_json(&package)?["name"], name);
+    }
+
+    let package = fixtures.join("demo-cli-2.1.0-pyhd8ed1ab_0.conda");
+    let error = read_info_member(&package, "info/recipe/meta.yaml").unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("fail to read info/recipe/meta.yaml of package"));
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::{hash_map::DefaultHasher, hash_map::Entry, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+use serde::Deserialize;
+
+/// collects the clobbered paths from conda output, e.g.
+///
+/// ```text
+/// ClobberWarning: This transaction has incompatible packages due to a shared path.
+///   packages: conda-forge/linux-64::libfoo-1.0-0, defaults/linux-64::libbar-2.0-0
+///   path: 'lib/libfoo.so'
+/// ```
+#[derive(Debug, Default)]
+pub(super) struct ClobberDetector {
+    in_clobber: bool,
+    paths: Vec<String>,
+}
+
+impl ClobberDetector {
+    pub fn feed(&mut self, line: &str) {
+        if line.contains("ClobberWarning") || line.contains("ClobberError") {
+            self.in_clobber = true;
+            return;
+        }
+        if !self.in_clobber {
+            return;
+        }
+        // the details of a clobber are indented
+        if !line.starts_with(char::is_whitespace) {
+            self.in_clobber = false;
+            return;
+        }
+        if let Some(path) = line
+            .trim()
+            .strip_prefix("path: '")
+            .and_then(|p| p.strip_suffix('\''))
+        {
+            if !self.paths.iter().any(|p| p == path) {
+                self.paths.push(path.to_string());
+            }
+        }
+    }
+
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+}
+
+#[test]
+fn test_detect_clobber() {
+    let output = r#"
+Preparing transaction: ...working... done
+Verifying transaction: ...working...
+
+ClobberWarning: This transaction has incompatible packages due to a shared path.
+  packages: conda-forge/linux-64::libfoo-1.0-0, defaults/linux-64::libbar-2.0-0
+  path: 'lib/libfoo.so'
+
+
+ClobberError: This transaction has incompatible packages due to a shared path.
+  packages: conda-forge/linux-64::libfoo-1.0-0, defaults/linux-64::libbaz-2.0-0
+  path: 'include/foo.h'
+
+ClobberWarning: This transaction has incompatible packages due to a shared path.
+  packages: conda-forge/linux-64::libfoo-1.0-0, defaults/linux-64::libbar-2.0-0
+  path: 'lib/libfoo.so'
+
+done
+Executing transaction: ...working... done
+  path: 'not/a/clobber'
+"#;
+    let mut detector = ClobberDetector::default();
+    for line in output.lines() {
+        detector.feed(line);
+    }
+    assert_eq!(detector.paths(), ["lib/libfoo.so", "include/foo.h"]);
+}
+
+/// paths many packages write into on purpose
+const SHARED_PATHS: &[&str] = &["conda-meta/", "__pycache__/"];
+
+/// a path which more than one package of the target env provides
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Clobber {
+    pub path: String,
+    /// `name-version-build` of the packages
+    pub owners: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PathsJson {
+    paths: Vec<PathsEntry>,
+}
+
+#[derive(Deserialize)]
+struct PathsEntry {
+    _path: String,
+}
+
+/// the paths of an extracted package by its `info/paths.json`
+pub(super) fn load_paths(pkg_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(pkg_dir.join("info").join("paths.json"))?;
+    let paths: PathsJson = serde_json::from_str(&contents)?;
+    Ok(paths.paths.into_iter().map(|p| p._path).collect())
+}
+
+/// the paths provided by more than one package, only the hashes of the paths are kept
+/// unless they clobber
+pub(super) fn find_clobbers<I, P>(packages: I) -> Vec<Clobber>
+where
+    I: IntoIterator<Item = (String, P)>,
+    P: IntoIterator<Item = String>,
+{
+    let hash = |path: &str| {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        hasher.finish()
+    };
+    let mut names = vec![];
+    let mut owners = HashMap::<u64, usize>::new();
+    let mut clobbers = BTreeMap::<String, Vec<usize>>::new();
+    for (owner, (name, paths)) in packages.into_iter().enumerate() {
+        names.push(name);
+        for path in paths {
+            if SHARED_PATHS.iter().any(|shared| path.contains(shared)) {
+                continue;
+            }
+            match owners.entry(hash(&path)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(owner);
+                }
+                Entry::Occupied(entry) => {
+                    let first = *entry.get();
+                    let owners = clobbers.entry(path).or_insert_with(|| vec![first]);
+                    if !owners.contains(&owner) {
+                        owners.push(owner);
+                    }
+                }
+            }
+        }
+    }
+    clobbers
+        .into_iter()
+        .filter(|(_, owners)| owners.len() > 1)
+        .map(|(path, owners)| Clobber {
+            path,
+            owners: owners.into_iter().map(|i| names[i].clone()).collect(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_find_clobbers() -> anyhow::Result<()> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/clobber");
+    let packages = ["libfoo-1.0-0", "libbar-2.0-0", "tool-1.0-0"]
+        .into_iter()
+        .map(|name| Ok((name.to_string(), load_paths(&fixtures.join(name))?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert_eq!(
+        find_clobbers(packages),
+        [
+            Clobber {
+                path: "bin/tool".into(),
+                owners: vec!["libbar-2.0-0".into(), "tool-1.0-0".into()],
+            },
+            Clobber {
+                path: "lib/libfoo.so".into(),
+                owners: vec!["libfoo-1.0-0".into(), "libbar-2.0-0".into()],
+            },
+        ]
+    );
+
+    // a package listing a path twice doesn't clobber itself
+    let packages = [("a-1-0".to_string(), vec!["x".to_string(), "x".to_string()])];
+    assert!(find_clobbers(packages).is_empty());
+    Ok(())
+}
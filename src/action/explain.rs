@@ -0,0 +1,319 @@
+use std::{collections::HashMap, fmt::Display};
+
+use serde::Serialize;
+
+use crate::{
+    recipe::{
+        find_channel_alias, ChangedField, ChannelAlias, FilteredLine, Package, PackageKey,
+        PackageKind, Recipe, RecipeDiff, Update,
+    },
+    selector::{Selector, SelectorTarget},
+};
+
+/// what the plan does to a package
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Install,
+    Update,
+    Delete,
+    /// the change is left out of the plan
+    Skip,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = match self {
+            Operation::Install => "+",
+            Operation::Update => "*",
+            Operation::Delete => "-",
+            Operation::Skip => "~",
+        };
+        write!(f, "{}", sign)
+    }
+}
+
+/// a link of the reason chain of an operation, from the recipe to the plan
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reason {
+    /// the 1-based line of the package in the recipe
+    RecipeLine { line: usize },
+    /// the package is in the env but not in the recipe
+    NotInRecipe,
+    /// every package is installed again since the env is created from scratch
+    Recreate,
+    /// the package isn't in the env yet
+    Missing,
+    /// the fields which differ between the env and the recipe
+    Changed { fields: Vec<ChangedField> },
+    /// the channel is compared by the name `--channel-alias` maps it to
+    ChannelAlias { suffix: String, channel: String },
+    /// deselected by the operator in `--interactive`
+    Deselected,
+    /// the `# [...]` selector of the recipe line is false on the machine, e.g. `__cuda 12.2`
+    Selector { selector: String, machine: String },
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::RecipeLine { line } => write!(f, "recipe line {}", line),
+            Reason::NotInRecipe => write!(f, "not in the recipe"),
+            Reason::Recreate => write!(f, "the env is recreated"),
+            Reason::Missing => write!(f, "missing from the env"),
+            Reason::Changed { fields } => write!(
+                f,
+                "changed {}",
+                fields
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Reason::ChannelAlias { suffix, channel } => {
+                write!(f, "channel alias {}={}", suffix, channel)
+            }
+            Reason::Deselected => write!(f, "deselected by operator"),
+            Reason::Selector { selector, machine } => {
+                write!(f, "selector {} is false on {}", selector, machine)
+            }
+        }
+    }
+}
+
+/// why an operation is in the plan, printed by `install --explain`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Explanation {
+    pub operation: Operation,
+    /// the target package of an update
+    pub package: Package,
+    pub reasons: Vec<Reason>,
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.operation,
+            self.package,
+            self.reasons
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" → ")
+        )
+    }
+}
+
+/// where the changes of a diff come from
+pub struct Provenance<'a> {
+    lines: HashMap<PackageKey, usize>,
+    aliases: &'a [ChannelAlias],
+    /// the diff is against an empty env
+    recreate: bool,
+}
+
+impl<'a> Provenance<'a> {
+    /// `contents` is the recipe the diff was made against
+    pub fn new(contents: &str, aliases: &'a [ChannelAlias], recreate: bool) -> Self {
+        Self {
+            lines: Recipe::package_lines(contents),
+            aliases,
+            recreate,
+        }
+    }
+
+    /// one per operation of the applied diff, then one per deselected change
+    pub fn explain(&self, diff: &RecipeDiff, deselected: &RecipeDiff) -> Vec<Explanation> {
+        let mut explanations = self.explain_diff(diff);
+        explanations.extend(self.explain_diff(deselected).into_iter().map(|mut e| {
+            e.operation = Operation::Skip;
+            e.reasons.push(Reason::Deselected);
+            e
+        }));
+        explanations
+    }
+
+    /// one per recipe line dropped by its selector
+    pub fn explain_filtered(
+        &self,
+        filtered: &[FilteredLine],
+        target: &SelectorTarget,
+    ) -> Vec<Explanation> {
+        filtered
+            .iter()
+            .map(|f| Explanation {
+                operation: Operation::Skip,
+                package: f.package.clone(),
+                reasons: vec![
+                    Reason::RecipeLine { line: f.line },
+                    Reason::Selector {
+                        selector: f.selector.to_string(),
+                        machine: evaluated_on(&f.selector, target),
+                    },
+                ],
+            })
+            .collect()
+    }
+
+    fn explain_diff(&self, diff: &RecipeDiff) -> Vec<Explanation> {
+        let adds = diff.adds.iter().map(|pkg| Explanation {
+            operation: Operation::Install,
+            package: pkg.clone(),
+            reasons: [self.recipe_reasons(pkg, &[]), vec![self.add_reason()]].concat(),
+        });
+        let updates = diff.updates.iter().map(|update| Explanation {
+            operation: Operation::Update,
+            package: update.to.clone(),
+            reasons: self.update_reasons(update),
+        });
+        let deletes = diff.deletes.iter().map(|pkg| Explanation {
+            operation: Operation::Delete,
+            package: pkg.clone(),
+            reasons: vec![Reason::NotInRecipe],
+        });
+        adds.chain(updates).chain(deletes).collect()
+    }
+
+    fn add_reason(&self) -> Reason {
+        if self.recreate {
+            Reason::Recreate
+        } else {
+            Reason::Missing
+        }
+    }
+
+    fn update_reasons(&self, update: &Update) -> Vec<Reason> {
+        let mut reasons = self.recipe_reasons(&update.to, &[&update.from]);
+        reasons.push(Reason::Changed {
+            fields: update.changes.clone(),
+        });
+        reasons
+    }
+
+    /// the recipe line of the package, and the alias which maps its channel or the channel of
+    /// one of `others`
+    fn recipe_reasons(&self, pkg: &Package, others: &[&Package]) -> Vec<Reason> {
+        let line = self
+            .lines
+            .get(&pkg.key())
+            .map(|&line| Reason::RecipeLine { line });
+        let alias = [pkg]
+            .into_iter()
+            .chain(others.iter().copied())
+            .find_map(|p| match &p.kind {
+                PackageKind::Conda { channel, .. } => find_channel_alias(channel, self.aliases),
+                PackageKind::PyPi { .. } => None,
+            })
+            .map(|alias| Reason::ChannelAlias {
+                suffix: alias.suffix.clone(),
+                channel: alias.channel.clone(),
+            });
+        line.into_iter().chain(alias).collect()
+    }
+}
+
+/// what the selector was evaluated against
+fn evaluated_on(selector: &Selector, target: &SelectorTarget) -> String {
+    match selector {
+        Selector::Is(_) | Selector::Not(_) => target.platform.clone().unwrap_or_default(),
+        Selector::Virtual { name, .. } => {
+            match target.virtual_packages.as_ref().and_then(|p| p.get(name)) {
+                Some(version) => format!("{} {}", name, version),
+                None => format!("a machine without {}", name),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_explain_plan() {
+    let aliases = vec!["mirrors/cf=conda-forge".parse::<ChannelAlias>().unwrap()];
+    let old_recipe: Recipe = r#"
+numpy                     1.18.1           py37h7241aed_0    https://mirror.corp/mirrors/cf
+zlib                      1.2.12               h4dc903c_2
+yarl                      1.7.2                    pypi_0    pypi
+aiohttp                   3.8.1                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let contents = r#"# Name                    Version                   Build  Channel
+numpy                     1.18.2           py37h7241aed_0    conda-forge
+libcxx                    12.0.0               h2f01273_0
+yarl                      1.7.3                    pypi_0    pypi
+"#;
+    let new_recipe: Recipe = contents.try_into().unwrap();
+    let diff = old_recipe.diff_with(
+        new_recipe.clone(),
+        &crate::recipe::DiffOptions {
+            channel_aliases: aliases.clone(),
+            ..Default::default()
+        },
+    );
+    let mut selection = crate::recipe::DiffSelection::default();
+    selection.deselect(crate::recipe::ChangeGroup::Updates, 1);
+    let (selected, deselected) = diff.select(&selection);
+
+    let explanations = Provenance::new(contents, &aliases, false).explain(&selected, &deselected);
+    assert_eq!(
+        explanations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        [
+            "+ libcxx=12.0.0=h2f01273_0: recipe line 3 → missing from the env",
+            "* numpy=1.18.2=py37h7241aed_0: recipe line 2 → channel alias mirrors/cf=conda-forge → changed version",
+            "- zlib=1.2.12=h4dc903c_2: not in the recipe",
+            "- aiohttp==3.8.1: not in the recipe",
+            "~ yarl==1.7.3: recipe line 4 → changed version → deselected by operator",
+        ]
+    );
+    assert_eq!(
+        serde_json::to_value(&explanations[1]).unwrap()["reasons"],
+        serde_json::json!([
+            {"kind": "recipe_line", "line": 2},
+            {"kind": "channel_alias", "suffix": "mirrors/cf", "channel": "conda-forge"},
+            {"kind": "changed", "fields": ["version"]},
+        ])
+    );
+
+    let explanations = Provenance::new(contents, &[], true)
+        .explain(&Recipe::default().diff(new_recipe), &RecipeDiff::default());
+    assert_eq!(
+        explanations[0].reasons,
+        [Reason::RecipeLine { line: 3 }, Reason::Recreate]
+    );
+}
+
+#[test]
+fn test_explain_filtered_lines() {
+    use crate::selector::VirtualPackages;
+
+    let contents = r#"
+cudatoolkit               11.8.0              h37601d7_11    conda-forge  # [__cuda]
+libgcc-ng                 12.2.0              h65d4601_19    conda-forge  # [__glibc>=2.28]
+pywin32                   305                      pypi_0    pypi  # [win-64]
+"#;
+    let target = SelectorTarget {
+        platform: Some("linux-64".to_string()),
+        virtual_packages: Some(VirtualPackages::new([(
+            "__glibc".to_string(),
+            "2.17".to_string(),
+        )])),
+    };
+    let (_, filtered) = Recipe::for_target(contents, &target).unwrap();
+    let explanations = Provenance::new(contents, &[], false).explain_filtered(&filtered, &target);
+    assert_eq!(
+        explanations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        [
+            "~ cudatoolkit=11.8.0=h37601d7_11: recipe line 2 → selector [__cuda] is false on a machine without __cuda",
+            "~ libgcc-ng=12.2.0=h65d4601_19: recipe line 3 → selector [__glibc>=2.28] is false on __glibc 2.17",
+            "~ pywin32==305: recipe line 4 → selector [win-64] is false on linux-64",
+        ]
+    );
+}
@@ -0,0 +1,116 @@
+use std::{ffi::OsStr, process::Stdio};
+
+use super::{
+    conda_info,
+    marker::target_prefix,
+    priority::{command, program_command, Executable},
+    sandbox::Sandbox,
+    EnvTarget,
+};
+use crate::{error::Error, human_println, output::machine_mode};
+
+/// run the hooks in order inside the env, their output is streamed to ours
+///
+/// with `sandbox` they run under `bwrap`, or unsandboxed with a warning when it's missing
+pub async fn run_post_install_hooks(
+    target: &EnvTarget,
+    hooks: &[String],
+    sandbox: Option<&Sandbox>,
+) -> anyhow::Result<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let sandbox = match sandbox {
+        Some(_) if !Sandbox::is_available() => {
+            human_println!(
+                "warning: `bwrap` isn't available, the post install hooks run unsandboxed"
+            );
+            None
+        }
+        Some(sandbox) => {
+            let prefix = target_prefix(target)
+                .await?
+                .ok_or_else(|| Error::EnvNotFound(Some(target.to_string())))?;
+            Some((sandbox, prefix, conda_info().await?.pkgs_dirs))
+        }
+        None => None,
+    };
+    for hook in hooks {
+        human_println!("running post install hook '{}'...", hook);
+        let args = post_install_hook_args(target, hook);
+        let mut hook_command = match &sandbox {
+            Some((sandbox, prefix, pkgs_dirs)) => {
+                let conda = Executable::Conda.program().to_string_lossy().into_owned();
+                let argv = sandbox.wrap(prefix, pkgs_dirs, &[vec![conda], args].concat());
+                let mut hook_command = program_command(OsStr::new(&argv[0]));
+                hook_command.args(&argv[1..]);
+                hook_command
+            }
+            None => {
+                let mut hook_command = command(Executable::Conda);
+                hook_command.args(args);
+                hook_command
+            }
+        };
+        let status = hook_command
+            .stdout(if machine_mode() {
+                Stdio::from(std::io::stderr())
+            } else {
+                Stdio::inherit()
+            })
+            .stderr(Stdio::inherit())
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(Error::HookFailed {
+                hook: hook.clone(),
+                code: status.code(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+fn post_install_hook_args(target: &EnvTarget, hook: &str) -> Vec<String> {
+    let mut args = ["run", "--no-capture-output"].map(String::from).to_vec();
+    args.extend(target.args());
+    args.extend(["sh", "-c", hook].map(String::from));
+    args
+}
+
+#[test]
+fn test_post_install_hook_args() {
+    assert_eq!(
+        post_install_hook_args(
+            &"demo".into(),
+            "python -m compileall -q . && chmod -R g+w ."
+        ),
+        [
+            "run",
+            "--no-capture-output",
+            "-n",
+            "demo",
+            "sh",
+            "-c",
+            "python -m compileall -q . && chmod -R g+w .",
+        ]
+    );
+}
+
+#[test]
+fn test_post_install_hook_args_at_prefix() {
+    let target = EnvTarget::Prefix(std::path::PathBuf::from("/nfs/envs/demo"));
+    assert_eq!(
+        post_install_hook_args(&target, "make"),
+        [
+            "run",
+            "--no-capture-output",
+            "-p",
+            "/nfs/envs/demo",
+            "sh",
+            "-c",
+            "make"
+        ]
+    );
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+
+use super::{priority::Executable, run_conda, solver::parse_conda_version, CondaInfo};
+
+static INFO_CACHE: AtomicBool = AtomicBool::new(true);
+static CONDA_VERSION: OnceCell<Option<String>> = OnceCell::const_new();
+
+/// whether `conda info` is served from the cache of the previous runs, on by default
+pub fn set_info_cache(enabled: bool) {
+    INFO_CACHE.store(enabled, Ordering::Relaxed);
+}
+
+/// where a `CondaInfo` comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InfoSource {
+    /// the cache written by a previous run of the same conda binary
+    Cached,
+    /// `conda info --json` just ran
+    Fresh,
+}
+
+impl std::fmt::Display for InfoSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InfoSource::Cached => write!(f, "cached"),
+            InfoSource::Fresh => write!(f, "fresh"),
+        }
+    }
+}
+
+/// what a cached `CondaInfo` was read with, the cache is stale once it differs
+///
+/// an upgrade of conda replaces the binary, an edited `.condarc` or `CONDA_*` variable changes
+/// the dirs, and a `$HOME` on NFS is shared by hosts of other virtual packages, so the key is
+/// read from the file system and the environment alone and a cache hit runs no conda at all
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct InfoCacheKey {
+    conda_bin: PathBuf,
+    modified: SystemTime,
+    size: u64,
+    /// the mtime of every condarc conda may read, `None` if it doesn't exist
+    condarcs: Vec<(PathBuf, Option<SystemTime>)>,
+    conda_vars: BTreeMap<String, String>,
+    host: Option<String>,
+}
+
+/// the variables of an activated env, which don't change `conda info`
+const ACTIVATION_VARS: [&str; 4] = [
+    "CONDA_PREFIX",
+    "CONDA_SHLVL",
+    "CONDA_DEFAULT_ENV",
+    "CONDA_PROMPT_MODIFIER",
+];
+
+impl InfoCacheKey {
+    fn new(conda_bin: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(conda_bin).ok()?;
+        Some(Self {
+            conda_bin: conda_bin.to_path_buf(),
+            modified: metadata.modified().ok()?,
+            size: metadata.len(),
+            condarcs: modified_times(condarc_paths(conda_bin)),
+            conda_vars: std::env::vars()
+                .filter(|(name, _)| {
+                    name.starts_with("CONDA_")
+                        && !ACTIVATION_VARS
+                            .iter()
+                            .any(|var| name == var || name.starts_with(&format!("{}_", var)))
+                })
+                .collect(),
+            host: hostname(),
+        })
+    }
+
+    /// the key of the conda binary in use, `None` if it isn't found
+    fn current() -> Option<Self> {
+        Self::new(&find_program(Executable::Conda.program())?)
+    }
+}
+
+/// the condarc files conda reads, in the order of its docs, a `condarc.d` dir is left out
+fn condarc_paths(conda_bin: &Path) -> Vec<PathBuf> {
+    let mut paths = [
+        "/etc/conda/.condarc",
+        "/etc/conda/condarc",
+        "/var/lib/conda/.condarc",
+        "/var/lib/conda/condarc",
+    ]
+    .map(PathBuf::from)
+    .to_vec();
+    // the root prefix of `<root>/bin/conda` or `<root>/condabin/conda`
+    if let Some(root) = conda_bin.parent().and_then(Path::parent) {
+        paths.extend([root.join(".condarc"), root.join("condarc")]);
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+    if let Some(config) = config {
+        paths.extend([config.join("conda/.condarc"), config.join("conda/condarc")]);
+    }
+    if let Some(home) = home {
+        paths.extend([
+            home.join(".conda/.condarc"),
+            home.join(".conda/condarc"),
+            home.join(".condarc"),
+        ]);
+    }
+    paths.extend(std::env::var_os("CONDARC").map(PathBuf::from));
+    paths
+}
+
+/// the envs `conda create` and `conda remove` touch, a new env changes the mtime of its envs
+/// dir and conda records it in `~/.conda/environments.txt`
+fn envs_paths(envs_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths = envs_dirs.to_vec();
+    paths.extend(
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(".conda/environments.txt")),
+    );
+    paths
+}
+
+fn modified_times(paths: Vec<PathBuf>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .filter(|name| !name.is_empty())
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedInfo {
+    key: InfoCacheKey,
+    /// the mtimes of `envs_paths` when the info was read, `envs` is stale once they differ
+    envs: Vec<(PathBuf, Option<SystemTime>)>,
+    /// the version of the conda which wrote the info
+    conda_version: Option<String>,
+    info: CondaInfo,
+}
+
+/// the program itself if it's a path, otherwise the first match in `PATH`
+fn find_program(program: &OsStr) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// `$XDG_STATE_HOME/conda-cage`, or `~/.local/state/conda-cage`
+fn state_dir() -> PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("conda-cage")
+}
+
+/// `conda info --json`, from the cache when neither its key nor the envs have changed since it
+/// was written
+pub(super) async fn cached_conda_info() -> anyhow::Result<(CondaInfo, InfoSource)> {
+    let key = if INFO_CACHE.load(Ordering::Relaxed) {
+        InfoCacheKey::current()
+    } else {
+        None
+    };
+    match key {
+        Some(key) => {
+            load_or_refresh(&state_dir().join("conda-info.json"), &key, || async {
+                let (conda_version, _) = probe_conda_version().await;
+                Ok((fresh_info().await?, conda_version))
+            })
+            .await
+        }
+        None => Ok((fresh_info().await?, InfoSource::Fresh)),
+    }
+}
+
+/// the version of conda, `None` if `conda --version` fails, it's probed once per process or read
+/// from the info cache when that's fresh, the bool tells whether conda ran for it
+pub(super) async fn probe_conda_version() -> (Option<String>, bool) {
+    let mut ran = false;
+    let version = CONDA_VERSION
+        .get_or_init(|| async {
+            if let Some(version) = cached_conda_version() {
+                return version;
+            }
+            ran = true;
+            run_conda(["--version"])
+                .await
+                .ok()
+                .and_then(|output| parse_conda_version(&output))
+        })
+        .await;
+    (version.clone(), ran)
+}
+
+/// the conda version of the info cache if its key matches, the envs don't matter
+fn cached_conda_version() -> Option<Option<String>> {
+    if !INFO_CACHE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let key = InfoCacheKey::current()?;
+    let contents = std::fs::read(state_dir().join("conda-info.json")).ok()?;
+    let cached = serde_json::from_slice::<CachedInfo>(&contents).ok()?;
+    (cached.key == key).then_some(cached.conda_version)
+}
+
+pub(super) async fn fresh_info() -> anyhow::Result<CondaInfo> {
+    Ok(serde_json::from_str(&run_conda(["info", "--json"]).await?)?)
+}
+
+/// the cached info if its key matches, otherwise `refresh` the info and the conda version and
+/// cache them, a cache which can't be read or written is only skipped
+async fn load_or_refresh<F, Fut>(
+    path: &Path,
+    key: &InfoCacheKey,
+    refresh: F,
+) -> anyhow::Result<(CondaInfo, InfoSource)>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = anyhow::Result<(CondaInfo, Option<String>)>>,
+{
+    let cached = std::fs::read(path)
+        .ok()
+        .and_then(|contents| serde_json::from_slice::<CachedInfo>(&contents).ok());
+    if let Some(cached) = cached {
+        if &cached.key == key && cached.envs == modified_times(envs_paths(&cached.info.envs_dirs)) {
+            return Ok((cached.info, InfoSource::Cached));
+        }
+    }
+    let (info, conda_version) = refresh().await?;
+    let cached = CachedInfo {
+        key: key.clone(),
+        envs: modified_times(envs_paths(&info.envs_dirs)),
+        conda_version,
+        info,
+    };
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Ok(contents) = serde_json::to_vec(&cached) {
+        let _ = std::fs::write(path, contents);
+    }
+    Ok((cached.info, InfoSource::Fresh))
+}
+
+#[tokio::test]
+async fn test_info_cache() -> anyhow::Result<()> {
+    use std::sync::atomic::AtomicUsize;
+
+    let dir = std::env::temp_dir().join("conda-cage-test-info-cache");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let conda_bin = dir.join("conda");
+    std::fs::write(&conda_bin, "#!/bin/sh\n")?;
+    let cache = dir.join("state/conda-info.json");
+
+    let runs = AtomicUsize::new(0);
+    let envs_dir = dir.join("envs");
+    std::fs::create_dir_all(&envs_dir)?;
+    let refresh = |pkgs_dir: &'static str| {
+        let runs = &runs;
+        let envs_dir = envs_dir.clone();
+        move || async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            let info = CondaInfo {
+                envs: vec![],
+                envs_dirs: vec![envs_dir],
+                pkgs_dirs: vec![PathBuf::from(pkgs_dir)],
+                virtual_pkgs: vec![],
+            };
+            Ok((info, Some("23.7.4".to_string())))
+        }
+    };
+    let key = || InfoCacheKey::new(&conda_bin).unwrap();
+
+    let (info, source) = load_or_refresh(&cache, &key(), refresh("/opt/pkgs")).await?;
+    assert_eq!(
+        (source, runs.load(Ordering::SeqCst)),
+        (InfoSource::Fresh, 1)
+    );
+    assert_eq!(info.pkgs_dirs, [PathBuf::from("/opt/pkgs")]);
+    let cached: CachedInfo = serde_json::from_slice(&std::fs::read(&cache)?)?;
+    assert_eq!(cached.conda_version.as_deref(), Some("23.7.4"));
+
+    // a matching key is served without running conda
+    let (info, source) = load_or_refresh(&cache, &key(), refresh("/new/pkgs")).await?;
+    assert_eq!(
+        (source, runs.load(Ordering::SeqCst)),
+        (InfoSource::Cached, 1)
+    );
+    assert_eq!(info.pkgs_dirs, [PathBuf::from("/opt/pkgs")]);
+
+    // conda is upgraded in place
+    let modified = std::fs::metadata(&conda_bin)?.modified()? + std::time::Duration::from_secs(60);
+    std::fs::File::options()
+        .write(true)
+        .open(&conda_bin)?
+        .set_modified(modified)?;
+    let (info, source) = load_or_refresh(&cache, &key(), refresh("/new/pkgs")).await?;
+    assert_eq!(
+        (source, runs.load(Ordering::SeqCst)),
+        (InfoSource::Fresh, 2)
+    );
+    assert_eq!(info.pkgs_dirs, [PathBuf::from("/new/pkgs")]);
+
+    // another conda binary reports another info
+    std::fs::write(&conda_bin, "#!/bin/sh\n# 24.1.0\n")?;
+    let (_, source) = load_or_refresh(&cache, &key(), refresh("/new/pkgs")).await?;
+    assert_eq!(
+        (source, runs.load(Ordering::SeqCst)),
+        (InfoSource::Fresh, 3)
+    );
+
+    // a new env is missing from the cached envs
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    std::fs::create_dir(envs_dir.join("demo"))?;
+    let (_, source) = load_or_refresh(&cache, &key(), refresh("/new/pkgs")).await?;
+    assert_eq!(
+        (source, runs.load(Ordering::SeqCst)),
+        (InfoSource::Fresh, 4)
+    );
+    let (_, source) = load_or_refresh(&cache, &key(), refresh("/new/pkgs")).await?;
+    assert_eq!(source, InfoSource::Cached);
+
+    // a corrupt cache is refreshed
+    std::fs::write(&cache, "{")?;
+    let (_, source) = load_or_refresh(&cache, &key(), refresh("/new/pkgs")).await?;
+    assert_eq!(source, InfoSource::Fresh);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_info_cache_key() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("conda-cage-test-info-cache-key");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("bin"))?;
+    let conda_bin = dir.join("bin/conda");
+    std::fs::write(&conda_bin, "#!/bin/sh\n")?;
+    let key = InfoCacheKey::new(&conda_bin).unwrap();
+    assert!(key.conda_vars.keys().all(|name| name != "CONDA_SHLVL"));
+    assert_eq!(InfoCacheKey::new(&conda_bin).as_ref(), Some(&key));
+
+    // a condarc is added to the root prefix of conda
+    std::fs::write(dir.join(".condarc"), "envs_dirs: [/nfs/envs]\n")?;
+    assert_ne!(InfoCacheKey::new(&conda_bin), Some(key));
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_find_program() {
+    assert_eq!(find_program(OsStr::new("/nonexistent/conda")), None);
+    let sh = find_program(OsStr::new("sh")).unwrap();
+    assert!(sh.is_absolute() && sh.ends_with("sh"));
+}
@@ -0,0 +1,339 @@
+use std::{fmt::Display, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::archive::{info_members, read_index_json, read_info_member, PACKAGE_EXTS};
+use crate::output::human::{format_bytes, format_count};
+
+/// the scripts conda runs around linking a package, shipped as `bin/.<name>-<script>.sh` or
+/// `Scripts/.<name>-<script>.bat`
+const LINK_SCRIPTS: [&str; 3] = ["pre-link", "post-link", "pre-unlink"];
+
+/// the metadata of a package archive, read without unpacking it or touching the pkgs cache
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackageInspection {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub subdir: Option<String>,
+    /// e.g. `python` for a noarch python package
+    pub noarch: Option<String>,
+    pub depends: Vec<String>,
+    pub license: Option<String>,
+    /// `about.summary` of `info/recipe/meta.yaml`, if the package ships its recipe
+    pub summary: Option<String>,
+    pub file_count: usize,
+    /// the bytes of the files once installed, the files of old packages have no size
+    pub total_size: u64,
+    /// the files conda rewrites the build prefix of on install
+    pub prefix_placeholders: usize,
+    /// e.g. `post-link`
+    pub scripts: Vec<String>,
+    /// the console scripts of a noarch python package, e.g. `demo = demo.cli:main`
+    pub entry_points: Vec<String>,
+    /// only with `--files`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<PackageFile>>,
+}
+
+/// a file of the payload of a package
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackageFile {
+    /// relative to the prefix, e.g. `lib/libz.so`
+    pub path: String,
+    pub size: Option<u64>,
+    pub prefix_placeholder: bool,
+}
+
+/// `info/paths.json`
+#[derive(Deserialize)]
+struct PathsJson {
+    paths: Vec<PathsEntry>,
+}
+
+#[derive(Deserialize)]
+struct PathsEntry {
+    #[serde(rename = "_path")]
+    path: String,
+    size_in_bytes: Option<u64>,
+    prefix_placeholder: Option<String>,
+}
+
+/// `info/link.json`, only noarch packages have it
+#[derive(Deserialize)]
+struct LinkJson {
+    noarch: Option<LinkNoarch>,
+}
+
+#[derive(Deserialize)]
+struct LinkNoarch {
+    #[serde(default)]
+    entry_points: Vec<String>,
+}
+
+/// read the metadata of a `.tar.bz2` or `.conda` package, only `info/index.json`,
+/// `info/paths.json`, `info/link.json` and `info/recipe/meta.yaml` are extracted in memory
+pub fn inspect(package: &Path, with_files: bool) -> anyhow::Result<PackageInspection> {
+    let file_name = package.file_name().unwrap_or_default().to_string_lossy();
+    if !PACKAGE_EXTS.iter().any(|ext| file_name.ends_with(ext)) {
+        anyhow::bail!(
+            "'{}' isn't a package, expected a .tar.bz2 or .conda file",
+            package.display()
+        );
+    }
+    let members = info_members(package)?;
+    let has = |member: &str| members.iter().any(|m| m == member);
+    let index = read_index_json(package)?;
+    let string = |key: &str| index.get(key).and_then(Value::as_str).map(String::from);
+
+    let files = if has("info/paths.json") {
+        let paths: PathsJson =
+            serde_json::from_slice(&read_info_member(package, "info/paths.json")?)?;
+        paths
+            .paths
+            .into_iter()
+            .map(|entry| PackageFile {
+                path: entry.path,
+                size: entry.size_in_bytes,
+                prefix_placeholder: entry.prefix_placeholder.is_some(),
+            })
+            .collect()
+    } else if has("info/files") {
+        // packages older than `paths.json` only list the files
+        String::from_utf8_lossy(&read_info_member(package, "info/files")?)
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|path| PackageFile {
+                path: path.to_string(),
+                size: None,
+                prefix_placeholder: false,
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    let entry_points = if has("info/link.json") {
+        let link: LinkJson = serde_json::from_slice(&read_info_member(package, "info/link.json")?)?;
+        link.noarch.map(|n| n.entry_points).unwrap_or_default()
+    } else {
+        vec![]
+    };
+    let meta_yaml = if has("info/recipe/meta.yaml") {
+        Some(
+            String::from_utf8_lossy(&read_info_member(package, "info/recipe/meta.yaml")?)
+                .into_owned(),
+        )
+    } else {
+        None
+    };
+
+    let name = string("name").unwrap_or_default();
+    Ok(PackageInspection {
+        version: string("version").unwrap_or_default(),
+        build: string("build").unwrap_or_default(),
+        subdir: string("subdir"),
+        noarch: string("noarch"),
+        depends: index
+            .get("depends")
+            .and_then(Value::as_array)
+            .map(|depends| {
+                depends
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        license: string("license").or_else(|| {
+            meta_yaml
+                .as_deref()
+                .and_then(|meta| about_field(meta, "license"))
+        }),
+        summary: meta_yaml
+            .as_deref()
+            .and_then(|meta| about_field(meta, "summary")),
+        file_count: files.len(),
+        total_size: files.iter().filter_map(|file| file.size).sum(),
+        prefix_placeholders: files.iter().filter(|file| file.prefix_placeholder).count(),
+        scripts: LINK_SCRIPTS
+            .iter()
+            .filter(|script| {
+                files.iter().any(|file| {
+                    file.path == format!("bin/.{}-{}.sh", name, script)
+                        || file.path == format!("Scripts/.{}-{}.bat", name, script)
+                })
+            })
+            .map(|script| script.to_string())
+            .collect(),
+        entry_points,
+        files: with_files.then_some(files),
+        name,
+    })
+}
+
+/// a scalar of the top level `about:` section of a rendered meta.yaml, e.g. `summary`, the
+/// nested sections aren't looked into
+fn about_field(meta_yaml: &str, key: &str) -> Option<String> {
+    let mut in_about = false;
+    let mut indent = None;
+    for line in meta_yaml.lines() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let line_indent = line.len() - line.trim_start().len();
+        if line_indent == 0 {
+            in_about = line.trim_end() == "about:";
+            indent = None;
+            continue;
+        }
+        if !in_about || *indent.get_or_insert(line_indent) != line_indent {
+            continue;
+        }
+        if let Some(value) = line
+            .trim()
+            .strip_prefix(key)
+            .and_then(|v| v.strip_prefix(':'))
+        {
+            let value = value.trim().trim_matches(|c| c == '\'' || c == '"');
+            return (!value.is_empty()).then(|| value.to_string());
+        }
+    }
+    None
+}
+
+impl Display for PackageInspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.name, self.version, self.build)?;
+        if let Some(subdir) = &self.subdir {
+            write!(f, " ({})", subdir)?;
+        }
+        writeln!(f)?;
+        for (title, value) in [
+            ("summary", &self.summary),
+            ("license", &self.license),
+            ("noarch", &self.noarch),
+        ] {
+            if let Some(value) = value {
+                writeln!(f, "{}: {}", title, value)?;
+            }
+        }
+        writeln!(
+            f,
+            "files: {} ({})",
+            format_count(self.file_count),
+            format_bytes(self.total_size)
+        )?;
+        writeln!(
+            f,
+            "prefix placeholders: {}",
+            format_count(self.prefix_placeholders)
+        )?;
+        if !self.scripts.is_empty() {
+            writeln!(f, "scripts: {}", self.scripts.join(", "))?;
+        }
+        for (title, items) in [
+            ("depends", &self.depends),
+            ("entry points", &self.entry_points),
+        ] {
+            if !items.is_empty() {
+                writeln!(f, "{}:", title)?;
+                for item in items {
+                    writeln!(f, "  {}", item)?;
+                }
+            }
+        }
+        if let Some(files) = &self.files {
+            writeln!(f, "paths:")?;
+            for file in files {
+                write!(f, "  {}", file.path)?;
+                if let Some(size) = file.size {
+                    write!(f, " ({})", format_bytes(size))?;
+                }
+                if file.prefix_placeholder {
+                    write!(f, " [prefix]")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_inspect_tar_bz2() -> anyhow::Result<()> {
+    let package = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures/inspect/demo-1.0.0-h1a2b3c4_0.tar.bz2");
+    let inspection = inspect(&package, false)?;
+    assert_eq!(
+        (
+            inspection.name.as_str(),
+            inspection.version.as_str(),
+            inspection.build.as_str()
+        ),
+        ("demo", "1.0.0", "h1a2b3c4_0")
+    );
+    assert_eq!(inspection.subdir.as_deref(), Some("linux-64"));
+    assert_eq!(
+        inspection.depends,
+        ["libgcc-ng >=12", "zlib >=1.2.13,<2.0a0"]
+    );
+    assert_eq!(inspection.license.as_deref(), Some("MIT"));
+    assert_eq!(
+        inspection.summary.as_deref(),
+        Some("A demo package for conda-cage inspect")
+    );
+    assert_eq!(inspection.file_count, 3);
+    assert_eq!(inspection.total_size, 116);
+    assert_eq!(inspection.prefix_placeholders, 1);
+    assert_eq!(inspection.scripts, ["post-link"]);
+    assert!(inspection.files.is_none());
+    assert_eq!(
+        inspection.to_string(),
+        "demo 1.0.0 h1a2b3c4_0 (linux-64)\nsummary: A demo package for conda-cage inspect\nlicense: MIT\nfiles: 3 (116 B)\nprefix placeholders: 1\nscripts: post-link\ndepends:\n  libgcc-ng >=12\n  zlib >=1.2.13,<2.0a0\n"
+    );
+
+    let files = inspect(&package, true)?.files.unwrap();
+    assert_eq!(
+        files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>(),
+        ["bin/.demo-post-link.sh", "bin/demo", "lib/libdemo.so"]
+    );
+    assert!(files[1].prefix_placeholder);
+    Ok(())
+}
+
+#[test]
+fn test_inspect_conda() -> anyhow::Result<()> {
+    let package = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures/inspect/demo-cli-2.1.0-pyhd8ed1ab_0.conda");
+    let inspection = inspect(&package, true)?;
+    assert_eq!(inspection.name, "demo-cli");
+    assert_eq!(inspection.noarch.as_deref(), Some("python"));
+    assert_eq!(inspection.license.as_deref(), Some("BSD-3-Clause"));
+    // it ships no recipe
+    assert_eq!(inspection.summary, None);
+    assert_eq!(inspection.entry_points, ["demo-cli = demo_cli:main"]);
+    assert!(inspection.scripts.is_empty());
+    let json = serde_json::to_value(&inspection)?;
+    assert_eq!(
+        json["files"][0]["path"],
+        "site-packages/demo_cli/__init__.py"
+    );
+
+    assert!(inspect(Path::new("README.md"), false)
+        .unwrap_err()
+        .to_string()
+        .contains("isn't a package"));
+    Ok(())
+}
+
+#[test]
+fn test_about_field() {
+    let meta = "package:\n  name: demo\nabout:\n  home: https://example.com\n  summary: \"Demo\"\n  description:\n    summary: nested\nextra:\n  summary: other\n";
+    assert_eq!(about_field(meta, "summary").as_deref(), Some("Demo"));
+    assert_eq!(
+        about_field(meta, "home").as_deref(),
+        Some("https://example.com")
+    );
+    assert_eq!(about_field(meta, "license"), None);
+}
@@ -1,112 +1,885 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    future::Future,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     select, signal, spawn,
     sync::mpsc,
 };
 
-use super::{run_conda, spawn_conda, try_get_env_recipe};
-use crate::recipe::{Package, Recipe, RecipeDiff};
+use super::{
+    clobber::{find_clobbers, load_paths, Clobber, ClobberDetector},
+    command_in,
+    explain::{Explanation, Provenance},
+    info_cache::probe_conda_version,
+    link::{LinkEvent, LinkTracker},
+    load_conda_info,
+    local_channel::local_channel_url,
+    lock::EnvLock,
+    marker::{check_managed, mark_managed, target_prefix},
+    priority::Executable,
+    removal::remove_env,
+    run_conda, run_conda_with_timeout, run_in,
+    sandbox::{EnvSandbox, Sandbox},
+    solver::{solver_args, Solver},
+    space::precheck_space,
+    spawn_command,
+    tools::{check_tool, parse_pip_version, BlockedTool},
+    transcript, try_get_target_recipe, virtual_packages, EnvSnapshot, EnvTarget, InfoSource,
+};
+use crate::{
+    error::Error,
+    human_println,
+    output::{
+        human::{format_count, format_duration},
+        progress::{default_sink, ProgressSink},
+        review::{confirm, review_diff},
+    },
+    recipe::{
+        ChannelAlias, DiffOptions, FilteredLine, Package, PackageKind, ParseOptions, Recipe,
+        RecipeDiff,
+    },
+    report::{
+        EnvReport, FailedAttempt, PhaseReport, SkippedChange, Status, WarningKind,
+        DESELECTED_BY_OPERATOR,
+    },
+    selector::{current_platform, uses_virtual_packages, SelectorTarget},
+};
 
-pub async fn install(
+#[derive(Debug, Default, Clone)]
+pub struct InstallOptions {
+    /// remove the local env first
+    pub force_reinstall: bool,
+    pub show_diff: bool,
+    pub show_unchanged: bool,
+    pub pip_extra_index_urls: Vec<String>,
+    pub strict_channel_priority: bool,
+    /// directories of packages which are used as channels prior to the recipe channels
+    pub local_channels: Vec<PathBuf>,
+    /// how to handle an existing env, `None` means updating it in place
+    pub on_conflict: Option<OnConflict>,
+    /// skip the pypi pkgs instead of failing when pip can't be installed
+    pub keep_going: bool,
+    /// fail when conda reports clobbered paths
+    pub strict: bool,
+    /// allow removing python or pip while pypi packages still remain
+    pub allow_remove_python: bool,
+    /// the solver passed to conda, `None` means conda's own default
+    pub solver: Option<Solver>,
+    /// where the progress goes, `None` means `default_sink()`
+    pub progress: Option<Arc<dyn ProgressSink>>,
+    /// kill a single `pip install` which runs longer, and retry it later
+    pub pip_package_timeout: Option<Duration>,
+    /// the failed pip installs after which the remaining pypi pkgs are given up, `None` means
+    /// `DEFAULT_PIP_MAX_FAILURES`
+    pub pip_max_failures: Option<usize>,
+    /// don't reinstall the packages which only moved to another channel
+    pub ignore_channel_changes: bool,
+    /// extra mappings from channel urls to channel names, see `canonical_channel`
+    pub channel_aliases: Vec<ChannelAlias>,
+    /// let the operator deselect changes of the diff, see `review_diff`
+    pub interactive: bool,
+    /// converge an existing env which isn't marked as managed by conda-cage
+    pub adopt: bool,
+    /// look for the paths provided by more than one package before installing, by the
+    /// packages in the pkgs cache
+    pub preflight_check: bool,
+    /// print the conda commands of the install instead of running them
+    pub dry_run: bool,
+    /// the releases of conda and pip refused or warned about, besides the builtin ones
+    pub blocked_tools: Vec<BlockedTool>,
+    /// the subdir the selectors of the recipe are evaluated against, `None` means the
+    /// platform of this machine
+    pub platform: Option<String>,
+    /// print why each package is in the plan, see `Provenance`
+    pub explain: bool,
+    /// whether to restore the previous packages of the env when the install fails after
+    /// touching it
+    pub rollback: Rollback,
+    /// the error kinds after which a failed install is retried once with `force_reinstall`,
+    /// before it's rolled back, e.g. `corrupt_meta`
+    pub auto_force_on: Vec<String>,
+    /// run the conda commands which change the env under `bwrap`, so the post-link scripts of
+    /// the packages only write the env and the pkgs dirs
+    pub sandbox: Option<Sandbox>,
+    /// see `ParseOptions::allow_conda_pypi_pairs`
+    pub allow_conda_pypi_pairs: bool,
+}
+
+/// failed pip installs are retried after the other pypi pkgs until this many failures in total
+pub const DEFAULT_PIP_MAX_FAILURES: usize = 50;
+
+/// how to handle the env which already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnConflict {
+    /// fail if the existing env doesn't match the recipe
+    Error,
+    /// leave the existing env untouched
+    Skip,
+    /// remove the existing env and install it again
+    Overwrite,
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => Err(format!(
+                "invalid value '{}', expected one of: error, skip, overwrite",
+                s
+            )),
+        }
+    }
+}
+
+/// what to do with an env which fails midway, see `rollback`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Rollback {
+    /// leave the env as it is
+    #[default]
+    Never,
+    /// ask the operator, the env is left as it is without a terminal
+    Ask,
+    /// restore its previous packages
+    Always,
+}
+
+#[derive(Debug, PartialEq)]
+enum EnvAction {
+    Create,
+    Update,
+    Skip,
+}
+
+fn resolve_conflict(
     env_name: &str,
+    env_exists: bool,
+    up_to_date: bool,
+    on_conflict: Option<OnConflict>,
+) -> anyhow::Result<EnvAction> {
+    Ok(match (env_exists, on_conflict) {
+        (false, _) | (true, Some(OnConflict::Overwrite)) => EnvAction::Create,
+        (true, _) if up_to_date => EnvAction::Skip,
+        (true, Some(OnConflict::Skip)) => EnvAction::Skip,
+        (true, Some(OnConflict::Error)) => {
+            return Err(anyhow::anyhow!(
+                "env '{}' already exists and doesn't match the recipe",
+                env_name
+            ))
+        }
+        (true, None) => EnvAction::Update,
+    })
+}
+
+#[test]
+fn test_resolve_conflict() {
+    use EnvAction::*;
+
+    // default
+    assert_eq!(
+        resolve_conflict("demo", false, false, None).unwrap(),
+        Create
+    );
+    assert_eq!(resolve_conflict("demo", true, false, None).unwrap(), Update);
+    assert_eq!(resolve_conflict("demo", true, true, None).unwrap(), Skip);
+
+    // error
+    let on_conflict = Some(OnConflict::Error);
+    assert_eq!(
+        resolve_conflict("demo", false, false, on_conflict).unwrap(),
+        Create
+    );
+    assert_eq!(
+        resolve_conflict("demo", true, true, on_conflict).unwrap(),
+        Skip
+    );
+    assert!(resolve_conflict("demo", true, false, on_conflict).is_err());
+
+    // skip
+    let on_conflict = Some(OnConflict::Skip);
+    assert_eq!(
+        resolve_conflict("demo", false, false, on_conflict).unwrap(),
+        Create
+    );
+    assert_eq!(
+        resolve_conflict("demo", true, true, on_conflict).unwrap(),
+        Skip
+    );
+    assert_eq!(
+        resolve_conflict("demo", true, false, on_conflict).unwrap(),
+        Skip
+    );
+
+    // overwrite
+    let on_conflict = Some(OnConflict::Overwrite);
+    assert_eq!(
+        resolve_conflict("demo", false, false, on_conflict).unwrap(),
+        Create
+    );
+    assert_eq!(
+        resolve_conflict("demo", true, true, on_conflict).unwrap(),
+        Create
+    );
+    assert_eq!(
+        resolve_conflict("demo", true, false, on_conflict).unwrap(),
+        Create
+    );
+}
+
+/// install the recipe in the `conda list` format, its selectors are evaluated for
+/// `options.platform` and the virtual packages of this machine
+///
+/// a failure of one of `options.auto_force_on` is retried once with `force_reinstall` first,
+/// then by `options.rollback`, an env which fails after it was touched is restored to its
+/// previous packages, see `rollback`
+pub async fn install(
+    target: &EnvTarget,
     new_recipe: &str,
-    force_reinstall: bool,
-    show_diff: bool,
+    options: &InstallOptions,
+    report: &mut EnvReport,
+) -> anyhow::Result<()> {
+    let platform = options.platform.as_deref().or(current_platform());
+    let parse_options = ParseOptions {
+        allow_conda_pypi_pairs: options.allow_conda_pypi_pairs,
+    };
+    let parsed = ParsedRecipe::parse(new_recipe, platform, &parse_options).await?;
+    install_parsed(target, &parsed, options, report).await
+}
+
+/// install a recipe which is already parsed, e.g. one built by the caller, see `install`
+pub async fn install_recipe(
+    target: &EnvTarget,
+    new_recipe: Recipe,
+    options: &InstallOptions,
+    report: &mut EnvReport,
+) -> anyhow::Result<()> {
+    let platform = options.platform.as_deref().or(current_platform());
+    install_parsed(
+        target,
+        &ParsedRecipe::from_recipe(new_recipe, platform),
+        options,
+        report,
+    )
+    .await
+}
+
+/// parse a recipe in the `conda list` format the way `install` does, for the platform of this
+/// machine and, only when a selector needs them, its virtual packages
+pub async fn parse_recipe(contents: &str, options: &ParseOptions) -> anyhow::Result<Recipe> {
+    Ok(ParsedRecipe::parse(contents, current_platform(), options)
+        .await?
+        .recipe)
+}
+
+/// a recipe with the contents it's parsed from, which `--explain` refers to by line
+struct ParsedRecipe {
+    recipe: Recipe,
+    contents: String,
+    /// what the selectors of the contents were evaluated against
+    selectors: SelectorTarget,
+    filtered: Vec<FilteredLine>,
+}
+
+impl ParsedRecipe {
+    async fn parse(
+        contents: &str,
+        platform: Option<&str>,
+        options: &ParseOptions,
+    ) -> anyhow::Result<Self> {
+        let selectors = SelectorTarget {
+            platform: platform.map(ToString::to_string),
+            virtual_packages: if uses_virtual_packages(contents) {
+                Some(virtual_packages().await?)
+            } else {
+                None
+            },
+        };
+        let (recipe, filtered) =
+            Recipe::parse_with(contents, &selectors, options).map_err(|e| anyhow::anyhow!(e))?;
+        Ok(Self {
+            recipe,
+            contents: contents.to_string(),
+            selectors,
+            filtered,
+        })
+    }
+
+    /// the lines are the ones of the rendered recipe
+    fn from_recipe(recipe: Recipe, platform: Option<&str>) -> Self {
+        Self {
+            contents: recipe.to_string(),
+            recipe,
+            selectors: SelectorTarget {
+                platform: platform.map(ToString::to_string),
+                virtual_packages: None,
+            },
+            filtered: vec![],
+        }
+    }
+}
+
+#[test]
+fn test_parsed_recipe_from_recipe() {
+    let recipe: Recipe = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+django                    3.2.14                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let parsed = ParsedRecipe::from_recipe(recipe.clone(), Some("linux-64"));
+    assert_eq!(Recipe::try_from(parsed.contents.as_str()).unwrap(), recipe);
+    // the explanations refer to the lines of the rendered recipe
+    let line = Recipe::package_lines(&parsed.contents)[&recipe["numpy"].key()];
+    assert!(parsed
+        .contents
+        .lines()
+        .nth(line - 1)
+        .unwrap()
+        .starts_with("numpy "));
+    assert!(parsed.filtered.is_empty());
+}
+
+async fn install_parsed(
+    target: &EnvTarget,
+    new_recipe: &ParsedRecipe,
+    options: &InstallOptions,
+    report: &mut EnvReport,
 ) -> anyhow::Result<()> {
-    let old_recipe = try_get_env_recipe(env_name).await?;
-    let (old_recipe, need_create_env) = if old_recipe.is_none() || force_reinstall {
-        ("".try_into().unwrap(), true)
+    let mut original = None;
+    let mut result = apply_recipe(target, new_recipe, options, report, &mut original).await;
+    let mut touched = report.touched_env();
+    if let Err(err) = &result {
+        let kind = err
+            .downcast_ref::<Error>()
+            .map(Error::kind)
+            .unwrap_or("other");
+        if should_auto_force(kind, &options.auto_force_on, options.force_reinstall) {
+            human_println!(
+                "install of env '{}' failed with {}, retrying from scratch with `--force`",
+                target,
+                kind
+            );
+            let attempt = FailedAttempt::new(err, kind.to_string(), report);
+            *report = EnvReport::new(&report.env_name);
+            report.failed_attempts.push(attempt);
+            let forced = InstallOptions {
+                force_reinstall: true,
+                ..options.clone()
+            };
+            // the packages from before the first attempt are the ones to roll back to
+            let mut retried_original = None;
+            result = apply_recipe(target, new_recipe, &forced, report, &mut retried_original).await;
+            original = original.or(retried_original);
+            touched |= report.touched_env();
+        }
+    }
+    match (result, original) {
+        (Err(err), Some(original)) if touched => match options.rollback {
+            Rollback::Never => Err(err),
+            Rollback::Always => rollback(target, &original, options, report, err).await,
+            Rollback::Ask => {
+                human_println!("{:#}", err);
+                // no terminal to ask is a no
+                let restore = confirm(&format!(
+                    "install of env '{}' failed midway, restore its previous packages?",
+                    target
+                ))
+                .unwrap_or(false);
+                if restore {
+                    rollback(target, &original, options, report, err).await
+                } else {
+                    Err(err.context(format!(
+                        "env '{}' isn't rolled back, pass `--rollback-on-failure` to restore its previous packages",
+                        target
+                    )))
+                }
+            }
+        },
+        (result, _) => result,
+    }
+}
+
+/// recreate the env from the packages it had before the failed install, the error of the
+/// install is returned either way
+async fn rollback(
+    target: &EnvTarget,
+    original: &Recipe,
+    options: &InstallOptions,
+    report: &mut EnvReport,
+    err: anyhow::Error,
+) -> anyhow::Result<()> {
+    human_println!(
+        "install of env '{}' failed midway, restoring its previous packages...",
+        target
+    );
+    let rollback_options = InstallOptions {
+        force_reinstall: true,
+        show_diff: false,
+        show_unchanged: false,
+        on_conflict: None,
+        interactive: false,
+        explain: false,
+        dry_run: false,
+        // the env was checked by the failed install
+        adopt: true,
+        preflight_check: false,
+        platform: None,
+        // a failed rollback isn't rolled back again
+        rollback: Rollback::Never,
+        ..options.clone()
+    };
+    let mut rollback_report = EnvReport::new(&report.env_name);
+    let restored = apply_recipe(
+        target,
+        &ParsedRecipe::from_recipe(original.clone(), None),
+        &rollback_options,
+        &mut rollback_report,
+        &mut None,
+    )
+    .await;
+    report.commands += rollback_report.commands;
+    match restored {
+        Ok(()) => {
+            report.rolled_back = true;
+            Err(err.context(format!(
+                "env '{}' is rolled back to its previous packages",
+                target
+            )))
+        }
+        Err(rollback_err) => Err(err.context(format!(
+            "env '{}' is left broken, fail to roll it back: {}",
+            target, rollback_err
+        ))),
+    }
+}
+
+/// `original` is set to the packages of the env right before it's touched, if it existed
+async fn apply_recipe(
+    target: &EnvTarget,
+    parsed: &ParsedRecipe,
+    options: &InstallOptions,
+    report: &mut EnvReport,
+    original: &mut Option<Recipe>,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let env_name = target.to_string();
+    let env_name = env_name.as_str();
+    let local_channels = options
+        .local_channels
+        .iter()
+        .map(|dir| local_channel_url(dir))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let ParsedRecipe {
+        recipe: new_recipe,
+        contents,
+        selectors,
+        filtered,
+    } = parsed;
+    let new_recipe = new_recipe.clone();
+    let platform_filtered = filtered.len();
+    report.platform_filtered = platform_filtered;
+    let progress = options.progress.clone().unwrap_or_else(default_sink);
+    progress.start("[0/3]", "reading current environment...", 0);
+    // nothing is touched before the diff is applied, so ctrl c stops the scan right away
+    let (_lock, snapshot, (conda_version, probed)) = cancellable(async {
+        // readers of the env wait until the install is done
+        let lock = EnvLock::exclusive(&target.lock_key()).await?;
+        // the snapshot reuses the version probed once per process
+        let conda_version = probe_conda_version().await;
+        let snapshot = try_get_target_recipe(target, false).await?;
+        Ok((lock, snapshot, conda_version))
+    })
+    .await?;
+    progress.finish("read current environment");
+    // `conda list`, and `conda --version` unless it's known already
+    report.commands += if probed { 2 } else { 1 };
+    if let Some(version) = &conda_version {
+        for message in check_tool("conda", version, &options.blocked_tools)? {
+            human_println!("warning: {}", message);
+            report.warn(WarningKind::BlockedTool, message);
+        }
+    }
+    report.tools.conda = conda_version.clone();
+    let executable = options.solver.map(Solver::executable).unwrap_or_default();
+    let solver_args = match options.solver {
+        Some(Solver::Mamba) => vec![],
+        Some(solver) => {
+            match conda_version
+                .as_deref()
+                .and_then(|v| solver_args(solver, v))
+            {
+                Some(args) => args,
+                None => {
+                    let message = format!(
+                        "conda {} doesn't support `--solver`, use the default solver",
+                        conda_version.as_deref().unwrap_or("of unknown version")
+                    );
+                    human_println!("warning: {}", message);
+                    report.warn(WarningKind::UnsupportedSolver, message);
+                    vec![]
+                }
+            }
+        }
+        None => vec![],
+    };
+    let old_recipe = snapshot.map(|s| s.into_recipe());
+    let on_conflict = if options.force_reinstall {
+        Some(OnConflict::Overwrite)
     } else {
-        (old_recipe.unwrap(), false)
+        options.on_conflict
     };
-    let new_recipe: Recipe = Recipe::try_from(new_recipe).map_err(|e| anyhow::anyhow!(e))?;
-    let channels = new_recipe.channels.clone();
-    let diff = old_recipe.diff(new_recipe);
-    if show_diff {
-        println!("{:#}", diff);
+    // the unchanged packages are counted as already satisfied, but only shown on request
+    let diff_options = DiffOptions {
+        with_unchanged: true,
+        ignore_channel_changes: options.ignore_channel_changes,
+        channel_aliases: options.channel_aliases.clone(),
+    };
+    let up_to_date = old_recipe
+        .as_ref()
+        .map(|r| {
+            r.clone()
+                .diff_with(new_recipe.clone(), &diff_options)
+                .is_empty()
+        })
+        .unwrap_or_default();
+    // a skipped env is never touched
+    let skipping = on_conflict == Some(OnConflict::Skip);
+    if let Some(old_recipe) = old_recipe.as_ref().filter(|_| !skipping) {
+        report.commands += 1;
+        if let Some(prefix) = cancellable(target_prefix(target)).await? {
+            let deletes = old_recipe
+                .clone()
+                .diff_with(new_recipe.clone(), &diff_options)
+                .deletes
+                .len();
+            check_managed(
+                env_name,
+                &prefix,
+                options.adopt,
+                options.force_reinstall,
+                deletes,
+            )?;
+            if up_to_date && options.adopt && !options.dry_run {
+                mark_managed(&prefix)?;
+            }
+        }
     }
+    let env_exists = old_recipe.is_some();
+    let snapshot_recipe = old_recipe.clone();
+    let (old_recipe, need_create_env) =
+        match resolve_conflict(env_name, old_recipe.is_some(), up_to_date, on_conflict)? {
+            EnvAction::Create => (Recipe::default(), true),
+            EnvAction::Update => (old_recipe.unwrap(), false),
+            EnvAction::Skip => {
+                if up_to_date {
+                    human_println!("env '{}' is up to date", env_name);
+                } else {
+                    human_println!("env '{}' already exists, skip installing it", env_name);
+                }
+                report.status = Status::Skipped;
+                return Ok(());
+            }
+        };
+    let channels = [local_channels, new_recipe.channels.clone()].concat();
+    let target_recipe = new_recipe.clone();
+    let mut diff = old_recipe.clone().diff_with(new_recipe, &diff_options);
+    let shown_diff = |diff: &RecipeDiff| RecipeDiff {
+        same: if options.show_unchanged {
+            diff.same.clone()
+        } else {
+            vec![]
+        },
+        ..diff.clone()
+    };
+    let mut deselected = RecipeDiff::default();
+    if options.interactive {
+        let (selected, dropped) = diff.select(&review_diff(&diff)?);
+        report.skipped = SkippedChange::from_diff(&dropped, DESELECTED_BY_OPERATOR);
+        diff = selected;
+        deselected = dropped;
+    }
+    if !options.allow_remove_python {
+        check_python_removal(&diff, &target_recipe)?;
+    }
+    if options.show_diff {
+        human_println!("{:#}", shown_diff(&diff));
+        if platform_filtered > 0 {
+            human_println!(
+                "{} recipe lines are filtered out for {}",
+                format_count(platform_filtered),
+                selectors.platform.as_deref().unwrap_or_default()
+            );
+        }
+    }
+    let explanations = if options.explain {
+        let provenance = Provenance::new(contents, &options.channel_aliases, need_create_env);
+        let mut explanations = provenance.explain(&diff, &deselected);
+        explanations.extend(provenance.explain_filtered(filtered, selectors));
+        explanations
+    } else {
+        vec![]
+    };
+    if !options.dry_run {
+        for explanation in &explanations {
+            human_println!("{}", explanation);
+        }
+    }
+    if options.preflight_check {
+        let (info, source) = load_conda_info().await?;
+        if source == InfoSource::Fresh {
+            report.commands += 1;
+        }
+        let (clobbers, uncached) = preflight_clobbers(&old_recipe, &diff, &info.pkgs_dirs);
+        if uncached > 0 {
+            human_println!(
+                "{} packages aren't in the pkgs cache, their paths aren't checked",
+                format_count(uncached)
+            );
+        }
+        if !clobbers.is_empty() {
+            let paths = clobbers
+                .iter()
+                .map(|c| format!("{} ({})", c.path, c.owners.join(", ")))
+                .collect::<Vec<_>>();
+            report.clobbered_paths = clobbers.into_iter().map(|c| c.path).collect();
+            if options.strict {
+                return Err(Error::Clobbered(paths).into());
+            }
+            let message = format!(
+                "{} paths will be clobbered by other packages:\n  {}",
+                format_count(paths.len()),
+                paths.join("\n  ")
+            );
+            human_println!("warning: {}", message);
+            report.warn(WarningKind::Clobbered, message);
+        }
+    }
+    if options.dry_run {
+        report.diff = shown_diff(&diff);
+        // the original prefix of a recreated env would need `conda info`
+        let create_target = need_create_env.then(|| target.args());
+        let mut plan = plan_install(
+            target,
+            create_target.as_deref(),
+            &collect_packages(&diff),
+            &channels,
+            options,
+            &solver_args,
+            executable,
+        );
+        if need_create_env && !env_exists {
+            // a fresh env has nothing to remove
+            plan.create.remove(0);
+        }
+        plan.explanations = explanations;
+        human_println!("{}", plan);
+        report.plan = Some(plan);
+        return Ok(());
+    }
+    precheck_space(target, &diff).await?;
+    let sandbox = match &options.sandbox {
+        Some(_) if !Sandbox::is_available() => {
+            human_println!(
+                "warning: `bwrap` isn't available, the post-link scripts of the packages run unsandboxed"
+            );
+            None
+        }
+        Some(_) => {
+            report.commands += 1;
+            Some(EnvSandbox::new(target).await?)
+        }
+        None => None,
+    };
+    *original = snapshot_recipe;
+    report.create_env = need_create_env;
+    report.diff = shown_diff(&diff);
 
-    let default_style = ProgressStyle::default_bar().template("{prefix:.bold.dim} {msg}");
-    let pb = ProgressBar::new(1)
-        .with_style(default_style.clone())
-        .with_prefix("[1/3]")
-        .with_message("checking env...");
-    pb.tick();
+    progress.start("[1/3]", "checking env...", 0);
     if need_create_env {
-        pb.set_message(format!("creating env '{}'...", env_name));
-        run_conda(["env", "remove", "-n", env_name]).await?;
-        run_conda(["create", "-y", "--no-default-packages", "-n", env_name]).await?;
-        pb.finish_with_message(format!("create env '{}' success", env_name));
+        progress.set_message(&format!("creating env '{}'...", env_name));
+        // the prefix of the removed env, to count the packages left while it's removed
+        let (location, removed_prefix) = match target {
+            EnvTarget::Name(name) if env_exists => {
+                let (info, source) = load_conda_info().await?;
+                if source == InfoSource::Fresh {
+                    report.commands += 1;
+                }
+                let prefix = info
+                    .envs_dirs
+                    .iter()
+                    .map(|dir| dir.join(name))
+                    .find(|prefix| info.envs.contains(prefix));
+                (create_location(name, &info.envs, &info.envs_dirs), prefix)
+            }
+            EnvTarget::Prefix(prefix) if env_exists => (CreateAt::Name, Some(prefix.clone())),
+            _ => (CreateAt::Name, None),
+        };
+        let create_target = match location {
+            CreateAt::Name => target.args(),
+            CreateAt::Prefix(prefix) => {
+                progress.println(&format!(
+                    "env '{}' is recreated at its original prefix {}",
+                    env_name,
+                    prefix.display()
+                ));
+                vec!["-p".to_string(), prefix.display().to_string()]
+            }
+        };
+        let [remove_args, create_args] = create_env_args(&create_target, &solver_args);
+        // removing a large env takes minutes, so its progress is shown and ctrl c stops it
+        if env_exists {
+            cancellable(remove_env(
+                executable,
+                &remove_args,
+                removed_prefix.as_deref(),
+                env_name,
+                progress.as_ref(),
+            ))
+            .await?;
+            report.commands += 1;
+        }
+        progress.set_message(&format!("creating env '{}'...", env_name));
+        run_in(sandbox.as_ref(), executable, &create_args).await?;
+        report.commands += 1;
+        progress.finish(&format!(
+            "create env '{}' success in {}",
+            env_name,
+            format_duration(started.elapsed())
+        ));
     } else {
-        pb.finish_with_message(format!("check env '{}' done", env_name));
+        progress.finish(&format!(
+            "check env '{}' done in {}",
+            env_name,
+            format_duration(started.elapsed())
+        ));
     }
+    report
+        .phases
+        .push(PhaseReport::new("check", started.elapsed()));
 
-    let collections = collect_packages(&diff);
+    let mut collections = collect_packages(&diff);
+    report.satisfied = collections.satisfied;
 
     // delete conda packages
+    let started = Instant::now();
     let delete_counts = collections.conda_delete_pkgs.len() + collections.pypi_delete_pkgs.len();
-    let pb = ProgressBar::new(1)
-        .with_style(default_style.clone())
-        .with_prefix("[2/3]")
-        .with_message(format!("deleting {} pkgs...", delete_counts));
-    pb.tick();
+    progress.start(
+        "[2/3]",
+        &format!("deleting {} pkgs...", format_count(delete_counts)),
+        0,
+    );
     if !collections.conda_delete_pkgs.is_empty() {
-        let mut args = vec!["remove", "-n", env_name, "--force", "-y"];
-        let delete_pkg_names = collections
-            .conda_delete_pkgs
-            .iter()
-            .map(|p| p.name.as_str())
-            .collect::<Vec<_>>();
-        args.extend(delete_pkg_names);
-        run_conda(args).await?;
+        report.commands += 1;
+        run_in(
+            sandbox.as_ref(),
+            executable,
+            &conda_remove_args(target, &collections.conda_delete_pkgs),
+        )
+        .await?;
+        report
+            .deleted
+            .extend(collections.conda_delete_pkgs.iter().map(|&p| p.clone()));
     }
     // delete pypi packages
-    if !collections.pypi_delete_pkgs.is_empty() {
-        let mut args = vec!["run", "-n", env_name, "pip", "uninstall", "-y"];
-        let delete_pkg_names = collections
-            .pypi_delete_pkgs
-            .iter()
-            .map(|p| p.name.as_str())
-            .collect::<Vec<_>>();
-        args.extend(delete_pkg_names);
-        run_conda(args).await?;
+    let pypi_delete_plan = plan_pypi_deletes(&collections.pypi_delete_pkgs);
+    for batch in &pypi_delete_plan.batches {
+        report.commands += 1;
+        run_conda(pip_uninstall_args(target, batch)).await?;
+        report.deleted.extend(batch.iter().map(|&p| p.clone()));
     }
-    pb.finish_with_message(format!("deleted {} pkgs", delete_counts));
+    if let Some(pip) = pypi_delete_plan.pip {
+        // pip can't safely uninstall itself, and conda doesn't own a pip installed by pip
+        let message = if collections.replaces(pip) {
+            format!("skip deleting {:#}, it will be replaced later", pip)
+        } else {
+            format!(
+                "skip deleting {:#}, pip can't uninstall itself, remove it by hand",
+                pip
+            )
+        };
+        progress.println(&message);
+        report.warn(WarningKind::PipKept, message);
+    }
+    if !report.deleted.is_empty() {
+        // old conda may remove the dependents of a force-removed package anyway
+        report.commands += 1;
+        let after = try_get_target_recipe(target, false)
+            .await?
+            .map(EnvSnapshot::into_recipe)
+            .unwrap_or_default();
+        let removed = collateral_removals(&old_recipe, &report.deleted, &after);
+        if !removed.is_empty() {
+            let repairs = removed
+                .iter()
+                .filter_map(|p| target_recipe.packages.get(&p.key()))
+                .filter(|p| !collections.replaces(p))
+                .collect::<Vec<_>>();
+            let message = format!(
+                "{} pkgs were removed besides the deleted ones, {} of them are reinstalled:\n  {}",
+                format_count(removed.len()),
+                format_count(repairs.len()),
+                removed
+                    .iter()
+                    .map(|p| format!("{:#}", p))
+                    .collect::<Vec<_>>()
+                    .join("\n  ")
+            );
+            progress.println(&format!("warning: {}", message));
+            report.warn(WarningKind::Repaired, message);
+            collections.reinstall(&repairs);
+        }
+    }
+    progress.finish(&format!(
+        "deleted {} pkgs in {}",
+        format_count(delete_counts),
+        format_duration(started.elapsed())
+    ));
+    report
+        .phases
+        .push(PhaseReport::new("delete", started.elapsed()));
 
     // install conda packages
+    let started = Instant::now();
+    let conda_install_pkgs = collections.conda_install_pkgs();
     // spawn a printer
     let (event_tx, mut event_rx) = mpsc::channel::<InstallEvent>(10);
     let printer = spawn({
-        let install_counts =
-            collections.conda_install_pkgs.len() + collections.pypi_install_pkgs.len();
-
-        let pb = if install_counts > 0 {
-            ProgressBar::new(install_counts as u64)
-                .with_style(
-                    ProgressStyle::default_bar()
-                        .template("{prefix:.bold.dim} {msg}\n{wide_bar} {pos}/{len}"),
-                )
-                .with_prefix("[3/3]")
-                .with_message("installing pkgs...")
-        } else {
-            ProgressBar::new(install_counts as u64)
-                .with_style(ProgressStyle::default_bar().template("{prefix:.bold.dim} {msg}"))
-                .with_prefix("[3/3]")
-                .with_message("installing pkgs...")
-        };
-        pb.tick();
+        let install_counts = conda_install_pkgs.len() + collections.pypi_install_pkgs.len();
+
+        let progress = progress.clone();
+        progress.start("[3/3]", "installing pkgs...", install_counts as u64);
+        if collections.satisfied > 0 {
+            progress.println(&format!(
+                "already satisfied ({})",
+                format_count(collections.satisfied)
+            ));
+        }
         async move {
             loop {
                 if let Some(event) = event_rx.recv().await {
                     match event {
-                        InstallEvent::Message(s) => pb.println(s),
-                        InstallEvent::Package(pkg) => pb.println(format!("installing {:#}", pkg)),
-                        InstallEvent::Increase => pb.inc(1),
+                        InstallEvent::Message(s) => progress.println(&s),
+                        InstallEvent::Package(pkg) => {
+                            progress.println(&format!("installing {:#}", pkg))
+                        }
+                        InstallEvent::Increase => progress.inc(),
                         InstallEvent::Done => {
-                            pb.finish_with_message(format!("installed {} pkgs", install_counts));
+                            progress.finish(&format!(
+                                "installed {} pkgs in {}",
+                                format_count(install_counts),
+                                format_duration(started.elapsed())
+                            ));
                             break;
                         }
                     }
@@ -115,141 +888,229 @@ pub async fn install(
         }
     });
 
-    if !collections.conda_install_pkgs.is_empty() {
-        let mut args = vec![
-            "install",
-            "--no-deps",
-            "-S",
-            "--force-reinstall",
-            "-vv",
-            "-y",
-            "-n",
-            env_name,
-        ];
-        let channels = channels
-            .iter()
-            .map(|c| ["-c", c])
-            .collect::<Vec<_>>()
-            .concat();
-        args.extend(channels);
-        let pkgs = collections
-            .conda_install_pkgs
-            .iter()
-            .map(|p| p.to_string())
-            .collect::<Vec<_>>();
-        let pkgs = pkgs.iter().map(|s| s.as_str()).collect::<Vec<_>>();
-        args.extend(pkgs);
-        let mut child = spawn_conda(args)?;
-        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
-        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
-
-        // indexes are used to map id from conda log to pkg
-        let indexes = collections
-            .conda_install_pkgs
-            .iter()
-            .map(|p| {
-                let id = match &p.kind {
-                    crate::recipe::PackageKind::PyPi => format!("{}-{}", p.name, p.version),
-                    crate::recipe::PackageKind::Conda { build, channel: _ } => {
-                        format!("{}-{}-{}", p.name, p.version, build)
-                    }
-                };
-                (id, p.clone())
-            })
-            .collect::<HashMap<_, _>>();
-        let pattern = regex::Regex::new("==> LINKING PACKAGE: (?:.*?)::(.*) <==")?;
-        let mut first_pkg = false;
+    if !conda_install_pkgs.is_empty() {
+        let mut links = LinkTracker::new(&conda_install_pkgs, executable);
 
         let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
-        let _ = event_tx
-            .send(InstallEvent::Message(
-                "verifying environment...".to_string(),
-            ))
-            .await;
-        loop {
-            select! {
-                stdout_line = stdout.next_line() => {
-                    if let Ok(Some(line)) = stdout_line {
-                        if line.starts_with("Verifying transaction: done") {
-                            let _ = event_tx.send(InstallEvent::Message("verifying environment done".to_string())).await;
+        let mut clobber = ClobberDetector::default();
+        for (pkgs, force_reinstall) in collections.conda_install_batches() {
+            let args = conda_install_args(
+                target,
+                &channels,
+                &pkgs,
+                force_reinstall,
+                options.strict_channel_priority,
+                &solver_args,
+            );
+            report.commands += 1;
+            let command = command_in(sandbox.as_ref(), executable, &args)?;
+            let argv = transcript::argv(&command);
+            let mut child = spawn_command(command)?;
+            let mut stdout_lines = vec![];
+            let mut stderr_lines = vec![];
+            let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+            let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+
+            let _ = event_tx
+                .send(InstallEvent::Message(
+                    "verifying environment...".to_string(),
+                ))
+                .await;
+            loop {
+                select! {
+                    stdout_line = stdout.next_line() => {
+                        if let Ok(Some(line)) = stdout_line {
+                            clobber.feed(&line);
+                            if line.starts_with("Verifying transaction: done") {
+                                let _ = event_tx.send(InstallEvent::Message("verifying environment done".to_string())).await;
+                            }
+                            stdout_lines.push(line);
                         }
-                    }
-                },
-                stderr_line = stderr.next_line() => {
-                    if let Ok(Some(line)) = stderr_line {
-                        if let Some(cap) = pattern.captures(&line) {
-                            if !first_pkg {
-                                first_pkg = false;
+                    },
+                    stderr_line = stderr.next_line() => {
+                        if let Ok(Some(line)) = stderr_line {
+                            clobber.feed(&line);
+                            if let Some(LinkEvent::Installed(pkg)) = links.feed(&line) {
                                 let _ = event_tx.send(InstallEvent::Increase).await;
+                                let _ = event_tx.send(InstallEvent::Package(pkg.clone())).await;
                             }
-                            let pkg = cap.get(1).unwrap().as_str().to_string();
-                            let pkg = indexes[&pkg].clone();
-                            let _ = event_tx.send(InstallEvent::Package(pkg)).await;
+                            stderr_lines.push(line);
                         }
+                    },
+                    _ = sigterm.recv() => {
+                        child.kill().await?;
+                        transcript::record(&argv, &stdout_lines.join("\n"), &stderr_lines.join("\n"), None);
+                        return Err(anyhow::anyhow!("receive sigterm"));
+                    }
+                    _ = signal::ctrl_c() => {
+                        child.kill().await?;
+                        transcript::record(&argv, &stdout_lines.join("\n"), &stderr_lines.join("\n"), None);
+                        return Err(anyhow::anyhow!("receive ctrl c"));
+                    }
+                    status = child.wait() => {
+                        let status = status?;
+                        while let Ok(Some(line)) = stdout.next_line().await {
+                            stdout_lines.push(line);
+                        }
+                        while let Ok(Some(line)) = stderr.next_line().await {
+                            stderr_lines.push(line);
+                        }
+                        let stderr = stderr_lines.join("\n");
+                        transcript::record(&argv, &stdout_lines.join("\n"), &stderr, Some(status));
+                        if !status.success() {
+                            report.failed.extend(pkgs.iter().map(|&p| p.clone()));
+                            if let Some(error) = Error::from_conda_stderr(&stderr) {
+                                return Err(error.into());
+                            }
+                            return Err(anyhow::anyhow!("fail to install conda pkgs, conda exited with {}\n{}", status, stderr));
+                        }
+                        report.installed.extend(pkgs.iter().map(|&p| p.clone()));
+                        break
                     }
-                },
-                _ = sigterm.recv() => {
-                    child.kill().await?;
-                    return Err(anyhow::anyhow!("receive sigterm"));
-                }
-                _ = signal::ctrl_c() => {
-                    child.kill().await?;
-                    return Err(anyhow::anyhow!("receive ctrl c"));
-                }
-                _ = child.wait() => {
-                    break
                 }
             }
         }
+
+        let relinked = links.relinked();
+        if !relinked.is_empty() {
+            report.relinked = relinked.to_vec();
+            let _ = event_tx
+                .send(InstallEvent::Message(format!(
+                    "conda additionally relinked {} packages",
+                    format_count(relinked.len())
+                )))
+                .await;
+        }
+
+        // `--no-deps` installs may silently overwrite files of other packages
+        let clobbered = clobber.paths();
+        if !clobbered.is_empty() {
+            report.clobbered_paths = clobbered.to_vec();
+            if options.strict {
+                return Err(Error::Clobbered(clobbered.to_vec()).into());
+            }
+            let message = format!(
+                "{} paths are clobbered by other packages:\n  {}",
+                format_count(clobbered.len()),
+                clobbered.join("\n  ")
+            );
+            let _ = event_tx
+                .send(InstallEvent::Message(format!("warning: {}", message)))
+                .await;
+            report.warn(WarningKind::Clobbered, message);
+        }
     }
     // install pypi packages
-    if !collections.pypi_install_pkgs.is_empty() {
-        if !collections
-            .pypi_install_pkgs
-            .iter()
-            .filter(|p| p.name == "pip")
-            .collect::<Vec<_>>()
-            .is_empty()
-        {
-            // if need install `pip`, we should use conda install pip first, then use conda pip
-            // upgrade pypi pip
-            run_conda(["install", "--no-deps", "-y", "-n", env_name, "pip"]).await?;
+    let mut pip_available = true;
+    if collections.installs_pypi_pip() {
+        // if need install `pip`, we should use conda install pip first, then use conda pip
+        // upgrade pypi pip
+        report.commands += 1;
+        if let Err(err) = run_conda(pip_bootstrap_args(target, &solver_args)).await {
+            let err = Error::PipBootstrap(err.to_string());
+            if !options.keep_going {
+                return Err(err.into());
+            }
+            pip_available = false;
+            report
+                .failed
+                .extend(collections.pypi_install_pkgs.iter().map(|&p| p.clone()));
+            let message = format!(
+                "{}\nskip installing {} pypi pkgs",
+                err,
+                format_count(collections.pypi_install_pkgs.len())
+            );
+            let _ = event_tx.send(InstallEvent::Message(message.clone())).await;
+            report.warn(WarningKind::PipSkipped, message);
         }
-
+    }
+    if pip_available && !collections.pypi_install_pkgs.is_empty() {
+        report.commands += 1;
+        // only the denylist may stop the install, an unknown version isn't checked
+        let pip_version = run_conda(pip_version_args(target))
+            .await
+            .ok()
+            .and_then(|output| parse_pip_version(&output));
+        if let Some(version) = &pip_version {
+            for message in check_tool("pip", version, &options.blocked_tools)? {
+                let _ = event_tx
+                    .send(InstallEvent::Message(format!("warning: {}", message)))
+                    .await;
+                report.warn(WarningKind::BlockedTool, message);
+            }
+        }
+        report.tools.pip = pip_version;
         let mut pkgs = VecDeque::from(collections.pypi_install_pkgs.clone());
-        let max_failed = 50;
+        let max_failed = options
+            .pip_max_failures
+            .unwrap_or(DEFAULT_PIP_MAX_FAILURES)
+            .max(1);
         let mut current_failed = 0;
+        // the pkgs whose last try timed out
+        let mut timed_out = vec![];
+        // the pkgs which failed at least once
+        let mut retried = vec![];
         while !pkgs.is_empty() {
             let pkg = pkgs.pop_front().unwrap();
             let _ = event_tx.send(InstallEvent::Package(pkg.clone())).await;
-            match run_conda([
-                "run",
-                "-n",
-                env_name,
-                "pip",
-                "install",
-                "--no-deps",
-                pkg.to_string().as_str(),
-            ])
-            .await
-            {
+            report.commands += 1;
+            let result = run_conda_with_timeout(
+                pip_install_args(target, pkg, &options.pip_extra_index_urls),
+                options.pip_package_timeout,
+            )
+            .await;
+            timed_out.retain(|&p| p != pkg);
+            match result {
                 Ok(_) => {
+                    if retried.contains(&pkg) {
+                        report.warn(
+                            WarningKind::PipRetried,
+                            format!("{:#} is installed after failed tries", pkg),
+                        );
+                    }
+                    report.installed.push(pkg.clone());
                     let _ = event_tx.send(InstallEvent::Increase).await;
                 }
                 Err(err) => {
+                    if matches!(err.downcast_ref::<Error>(), Some(Error::Timeout(_))) {
+                        timed_out.push(pkg);
+                    }
                     if err.to_string().contains("not find a version") {
+                        report.failed.push(pkg.clone());
+                        if let Some(label) = pkg.local_version_label() {
+                            // local versions are usually only published on a custom index
+                            return Err(anyhow::anyhow!(
+                                "{}\n{:#} has local version label '{}', try to specify the index which provides it by `--pip-extra-index-url`",
+                                err,
+                                pkg,
+                                label
+                            ));
+                        }
                         return Err(err);
                     } else {
                         current_failed += 1;
                         if current_failed == max_failed {
-                            return Err(err);
+                            let failed = std::iter::once(pkg)
+                                .chain(pkgs.iter().copied())
+                                .collect::<Vec<_>>();
+                            report.failed.extend(failed.iter().map(|&p| p.clone()));
+                            return Err(anyhow::anyhow!(
+                                "{}\nfail to install pypi pkgs: {}",
+                                err,
+                                tag_pypi_failures(&failed, &timed_out)
+                            ));
                         }
                         // push current pkg back to pkgs
                         pkgs.push_back(pkg);
-                        let _ = event_tx.send(InstallEvent::Message(format!(
-                            "fail to install {:#}, will try to install it later\n{}",
-                            pkg, err
-                        )));
+                        if !retried.contains(&pkg) {
+                            retried.push(pkg);
+                        }
+                        let _ = event_tx
+                            .send(InstallEvent::Message(format!(
+                                "fail to install {:#}, will try to install it later\n{}",
+                                pkg, err
+                            )))
+                            .await;
                     }
                 }
             }
@@ -258,47 +1119,758 @@ pub async fn install(
 
     let _ = event_tx.send(InstallEvent::Done).await;
     let _ = printer.await;
+    report
+        .phases
+        .push(PhaseReport::new("install", started.elapsed()));
 
+    report.commands += 1;
+    if let Some(prefix) = target_prefix(target).await? {
+        mark_managed(&prefix)?;
+    }
     Ok(())
 }
 
+/// e.g. `requests==2.28.1, numpy==1.23.1 (timeout)`
+fn tag_pypi_failures(failed: &[&Package], timed_out: &[&Package]) -> String {
+    failed
+        .iter()
+        .map(|p| {
+            if timed_out.contains(p) {
+                format!("{} (timeout)", p)
+            } else {
+                p.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[test]
+fn test_tag_pypi_failures() {
+    let recipe: Recipe = r#"
+requests                  2.28.1                   pypi_0    pypi
+numpy                     1.23.1                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let (requests, numpy) = (&recipe["requests"], &recipe["numpy"]);
+    assert_eq!(
+        tag_pypi_failures(&[requests, numpy], &[numpy]),
+        "requests==2.28.1, numpy==1.23.1 (timeout)"
+    );
+    assert_eq!(tag_pypi_failures(&[], &[numpy]), "");
+}
+
+/// the conda commands of an install by phase, printed by `--dry-run`
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct InstallPlan {
+    /// removing the old env and creating the new one
+    pub create: Vec<Vec<String>>,
+    /// the conda packages and then the pypi packages
+    pub delete: Vec<Vec<String>>,
+    /// the conda packages with their channels and then the pypi packages
+    pub install: Vec<Vec<String>>,
+    /// why each package is in the plan, only with `--explain`
+    pub explanations: Vec<Explanation>,
+}
+
+impl Display for InstallPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phases = [
+            ("[1/3]", "creating env", &self.create),
+            ("[2/3]", "deleting pkgs", &self.delete),
+            ("[3/3]", "installing pkgs", &self.install),
+        ];
+        for (i, (prefix, name, commands)) in phases.into_iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if commands.is_empty() {
+                write!(f, "{} {}: nothing to do", prefix, name)?;
+                continue;
+            }
+            write!(f, "{} {}:", prefix, name)?;
+            for args in commands {
+                write!(f, "\n  {}", args.join(" "))?;
+            }
+        }
+        if !self.explanations.is_empty() {
+            write!(f, "\nwhy:")?;
+            for explanation in &self.explanations {
+                write!(f, "\n  {}", explanation)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the same commands as the install runs, in the same order
+fn plan_install(
+    target: &EnvTarget,
+    create_target: Option<&[String]>,
+    collections: &CollectedPackages,
+    channels: &[String],
+    options: &InstallOptions,
+    solver_args: &[String],
+    executable: Executable,
+) -> InstallPlan {
+    let mut plan = InstallPlan::default();
+    // the pypi packages are always handled by conda
+    let program = executable.program().to_string_lossy().into_owned();
+    let conda = Executable::Conda.program().to_string_lossy().into_owned();
+    let by = |program: &str, args: Vec<String>| [vec![program.to_string()], args].concat();
+    if let Some(create_target) = create_target {
+        plan.create = create_env_args(create_target, solver_args)
+            .into_iter()
+            .map(|args| by(&program, args))
+            .collect();
+    }
+    if !collections.conda_delete_pkgs.is_empty() {
+        plan.delete.push(by(
+            &program,
+            conda_remove_args(target, &collections.conda_delete_pkgs),
+        ));
+    }
+    let pypi_delete_plan = plan_pypi_deletes(&collections.pypi_delete_pkgs);
+    for batch in &pypi_delete_plan.batches {
+        plan.delete
+            .push(by(&conda, pip_uninstall_args(target, batch)));
+    }
+    for (pkgs, force_reinstall) in collections.conda_install_batches() {
+        let args = conda_install_args(
+            target,
+            channels,
+            &pkgs,
+            force_reinstall,
+            options.strict_channel_priority,
+            solver_args,
+        );
+        plan.install.push(by(&program, args));
+    }
+    if collections.installs_pypi_pip() {
+        plan.install
+            .push(by(&conda, pip_bootstrap_args(target, solver_args)));
+    }
+    for pkg in &collections.pypi_install_pkgs {
+        let args = pip_install_args(target, pkg, &options.pip_extra_index_urls);
+        plan.install.push(by(&conda, args));
+    }
+    plan
+}
+
+#[test]
+fn test_plan_install() {
+    let old_recipe: Recipe = r#"
+numpy                     1.18.1           py37h7241aed_0
+zlib                      1.2.12               h4dc903c_2
+yarl                      1.7.2                    pypi_0    pypi
+aiohttp                   3.8.1                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+numpy                     1.18.2           py37h7241aed_0
+libcxx                    12.0.0               h2f01273_0
+yarl                      1.7.3                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    let collections = collect_packages(&diff);
+    let plan = plan_install(
+        &"demo".into(),
+        None,
+        &collections,
+        &["defaults".to_string()],
+        &InstallOptions::default(),
+        &[],
+        Executable::Conda,
+    );
+    assert_eq!(
+        plan.to_string(),
+        r#"[1/3] creating env: nothing to do
+[2/3] deleting pkgs:
+  conda remove -n demo --force -y numpy zlib
+  conda run -n demo pip uninstall -y aiohttp
+[3/3] installing pkgs:
+  conda install --no-deps -S -vv -y -n demo -c defaults libcxx=12.0.0=h2f01273_0
+  conda install --no-deps -S -vv -y -n demo --force-reinstall -c defaults numpy=1.18.2=py37h7241aed_0
+  conda run -n demo pip install --no-deps yarl==1.7.3"#
+    );
+    assert_eq!(
+        serde_json::to_value(&plan).unwrap()["delete"][1],
+        serde_json::json!([
+            "conda",
+            "run",
+            "-n",
+            "demo",
+            "pip",
+            "uninstall",
+            "-y",
+            "aiohttp"
+        ])
+    );
+
+    let target = ["-n".to_string(), "demo".to_string()];
+    let plan = plan_install(
+        &"demo".into(),
+        Some(&target),
+        &collect_packages(&Recipe::default().diff(Recipe::default())),
+        &[],
+        &InstallOptions::default(),
+        &[],
+        Executable::Mamba,
+    );
+    assert_eq!(
+        plan.create,
+        [
+            vec!["mamba", "env", "remove", "-n", "demo"],
+            vec![
+                "mamba",
+                "create",
+                "-y",
+                "--no-default-packages",
+                "-n",
+                "demo"
+            ]
+        ]
+    );
+    assert!(plan.delete.is_empty() && plan.install.is_empty());
+
+    // pip can't uninstall itself and conda doesn't own it
+    let old_recipe: Recipe = "pip 22.1.2 pypi_0 pypi\nsix 1.16.0 pypi_0 pypi"
+        .try_into()
+        .unwrap();
+    let plan = plan_install(
+        &"demo".into(),
+        None,
+        &collect_packages(&old_recipe.diff(Recipe::default())),
+        &[],
+        &InstallOptions::default(),
+        &[],
+        Executable::Conda,
+    );
+    assert_eq!(
+        plan.delete.concat().join(" "),
+        "conda run -n demo pip uninstall -y six"
+    );
+
+    let plan = InstallPlan {
+        explanations: super::explain::Provenance::new("zlib 1.2.13 h166bdaf_4\n", &[], true)
+            .explain(
+                &Recipe::default().diff("zlib 1.2.13 h166bdaf_4".try_into().unwrap()),
+                &RecipeDiff::default(),
+            ),
+        ..Default::default()
+    };
+    assert!(plan.to_string().ends_with(
+        "[3/3] installing pkgs: nothing to do\nwhy:\n  + zlib=1.2.13=h166bdaf_4: recipe line 1 → the env is recreated"
+    ));
+}
+
+/// remove the env at the target, then create it empty
+fn create_env_args(target: &[String], solver_args: &[String]) -> [Vec<String>; 2] {
+    let mut remove_args = ["env", "remove"].map(String::from).to_vec();
+    remove_args.extend(target.iter().cloned());
+    let mut create_args = ["create", "-y", "--no-default-packages"]
+        .map(String::from)
+        .to_vec();
+    create_args.extend(target.iter().cloned());
+    create_args.extend(solver_args.iter().cloned());
+    [remove_args, create_args]
+}
+
+fn conda_remove_args(target: &EnvTarget, pkgs: &[&Package]) -> Vec<String> {
+    let mut args = vec!["remove".to_string()];
+    args.extend(target.args());
+    args.extend(["--force", "-y"].map(String::from));
+    args.extend(pkgs.iter().map(|p| p.name.clone()));
+    args
+}
+
+fn pip_uninstall_args(target: &EnvTarget, pkgs: &[&Package]) -> Vec<String> {
+    let mut args = vec!["run".to_string()];
+    args.extend(target.args());
+    args.extend(["pip", "uninstall", "-y"].map(String::from));
+    args.extend(pkgs.iter().map(|p| p.name.clone()));
+    args
+}
+
+fn pip_version_args(target: &EnvTarget) -> Vec<String> {
+    let mut args = vec!["run".to_string()];
+    args.extend(target.args());
+    args.extend(["pip", "--version"].map(String::from));
+    args
+}
+
+/// the conda `pip` which the pypi `pip` is installed by
+fn pip_bootstrap_args(target: &EnvTarget, solver_args: &[String]) -> Vec<String> {
+    let mut args = ["install", "--no-deps", "-y"].map(String::from).to_vec();
+    args.extend(target.args());
+    args.push("pip".to_string());
+    args.extend(solver_args.iter().cloned());
+    args
+}
+
+fn conda_install_args(
+    target: &EnvTarget,
+    channels: &[String],
+    pkgs: &[&Package],
+    force_reinstall: bool,
+    strict_channel_priority: bool,
+    solver_args: &[String],
+) -> Vec<String> {
+    let mut args = ["install", "--no-deps", "-S", "-vv", "-y"]
+        .map(String::from)
+        .to_vec();
+    args.extend(target.args());
+    if force_reinstall {
+        args.push("--force-reinstall".to_string());
+    }
+    if strict_channel_priority {
+        args.push("--strict-channel-priority".to_string());
+    }
+    args.extend(solver_args.iter().cloned());
+    // channels are passed in priority order
+    for channel in channels {
+        args.push("-c".to_string());
+        args.push(channel.clone());
+    }
+    args.extend(pkgs.iter().map(|p| p.to_string()));
+    args
+}
+
+#[test]
+fn test_conda_install_args_channel_order() {
+    let contents = r#"
+# Name                    Version                   Build  Channel
+blas                      1.0                         mkl
+certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
+libcxx                    12.0.0               h2f01273_0    pytorch
+"#;
+    let args = (0..10)
+        .map(|_| {
+            let recipe: Recipe = contents.try_into().unwrap();
+            let pkgs = vec![&recipe["certifi"]];
+            conda_install_args(
+                &"demo".into(),
+                &recipe.channels,
+                &pkgs,
+                true,
+                true,
+                &["--solver".to_string(), "libmamba".to_string()],
+            )
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        args[0],
+        [
+            "install",
+            "--no-deps",
+            "-S",
+            "-vv",
+            "-y",
+            "-n",
+            "demo",
+            "--force-reinstall",
+            "--strict-channel-priority",
+            "--solver",
+            "libmamba",
+            "-c",
+            "conda-forge",
+            "-c",
+            "pytorch",
+            "-c",
+            "defaults",
+            "certifi=2022.6.15=py37hecd8cb5_0",
+        ]
+    );
+    assert!(args.iter().all(|a| a == &args[0]));
+}
+
+/// the clobbers among the conda packages of the env after applying the diff, and the number
+/// of those not in the pkgs cache
+fn preflight_clobbers(
+    old_recipe: &Recipe,
+    diff: &RecipeDiff,
+    pkgs_dirs: &[PathBuf],
+) -> (Vec<Clobber>, usize) {
+    let mut packages = old_recipe.packages.clone();
+    for pkg in diff
+        .deletes
+        .iter()
+        .chain(diff.updates.iter().map(|u| &u.from))
+    {
+        packages.remove(&pkg.key());
+    }
+    for pkg in diff.adds.iter().chain(diff.updates.iter().map(|u| &u.to)) {
+        packages.insert(pkg.key(), pkg.clone());
+    }
+    let mut dists = packages
+        .into_values()
+        .filter_map(|pkg| match pkg.kind {
+            PackageKind::Conda { build, .. } => {
+                Some(format!("{}-{}-{}", pkg.name, pkg.version, build))
+            }
+            PackageKind::PyPi { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    dists.sort();
+
+    let mut uncached = 0;
+    let mut cached = vec![];
+    for dist in dists {
+        match pkgs_dirs
+            .iter()
+            .find_map(|dir| load_paths(&dir.join(&dist)).ok())
+        {
+            Some(paths) => cached.push((dist, paths)),
+            None => uncached += 1,
+        }
+    }
+    (find_clobbers(cached), uncached)
+}
+
+#[test]
+fn test_preflight_clobbers() {
+    let old_recipe: Recipe = r#"
+libfoo                    1.0                           0    conda-forge
+libbar                    1.0                           0    conda-forge
+django                    3.2.14                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+libfoo                    1.0                           0    conda-forge
+libbar                    2.0                           0    conda-forge
+tool                      1.0                           0    conda-forge
+six                       1.16.0             pyh6c4a22f_0    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.clone().diff(new_recipe);
+    let pkgs_dirs = [std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/clobber")];
+    let (clobbers, uncached) = preflight_clobbers(&old_recipe, &diff, &pkgs_dirs);
+    assert_eq!(
+        clobbers.iter().map(|c| c.path.as_str()).collect::<Vec<_>>(),
+        ["bin/tool", "lib/libfoo.so"]
+    );
+    // six
+    assert_eq!(uncached, 1);
+}
+
+/// where `conda create` puts the env back
+#[derive(Debug, PartialEq)]
+enum CreateAt {
+    /// `-n`, in the first envs dir
+    Name,
+    /// `-p`, since `-n` would move the env into the first envs dir
+    Prefix(PathBuf),
+}
+
+/// conda resolves `-n` by the first envs dir which has the env
+fn create_location(env_name: &str, envs: &[PathBuf], envs_dirs: &[PathBuf]) -> CreateAt {
+    let found = envs_dirs
+        .iter()
+        .map(|dir| dir.join(env_name))
+        .enumerate()
+        .find(|(_, prefix)| envs.contains(prefix));
+    match found {
+        Some((i, prefix)) if i > 0 => CreateAt::Prefix(prefix),
+        _ => CreateAt::Name,
+    }
+}
+
+#[test]
+fn test_create_location() {
+    let envs_dirs = ["/opt/conda/envs", "/home/alice/.conda/envs", "/data/envs"].map(PathBuf::from);
+    let envs = [
+        "/opt/conda",
+        "/opt/conda/envs/default",
+        "/data/envs/custom",
+        "/data/envs/both",
+        "/opt/conda/envs/both",
+    ]
+    .map(PathBuf::from);
+
+    assert_eq!(
+        create_location("default", &envs, &envs_dirs),
+        CreateAt::Name
+    );
+    assert_eq!(
+        create_location("custom", &envs, &envs_dirs),
+        CreateAt::Prefix(PathBuf::from("/data/envs/custom"))
+    );
+    // `-n both` is the one in the first envs dir
+    assert_eq!(create_location("both", &envs, &envs_dirs), CreateAt::Name);
+    assert_eq!(
+        create_location("missing", &envs, &envs_dirs),
+        CreateAt::Name
+    );
+}
+
+/// whether a failed install is retried once from scratch with `--force`, a forced install is
+/// never retried
+pub fn should_auto_force(kind: &str, categories: &[String], forced: bool) -> bool {
+    !forced && categories.iter().any(|c| c == kind)
+}
+
+#[test]
+fn test_should_auto_force() {
+    let categories = ["corrupt_meta".to_string(), "verification".to_string()];
+    assert!(should_auto_force("corrupt_meta", &categories, false));
+    assert!(should_auto_force("verification", &categories, false));
+    assert!(!should_auto_force("disk_full", &categories, false));
+    assert!(!should_auto_force("other", &categories, false));
+    assert!(!should_auto_force("corrupt_meta", &[], false));
+    // the retry is forced, so it never escalates again
+    assert!(!should_auto_force("corrupt_meta", &categories, true));
+}
+
+/// run the future unless ctrl c or sigterm comes first, the conda processes of a dropped
+/// future are killed
+async fn cancellable<T>(future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    cancellable_by(future, async {
+        select! {
+            _ = sigterm.recv() => "receive sigterm",
+            _ = signal::ctrl_c() => "receive ctrl c",
+        }
+    })
+    .await
+}
+
+async fn cancellable_by<T>(
+    future: impl Future<Output = anyhow::Result<T>>,
+    cancel: impl Future<Output = &'static str>,
+) -> anyhow::Result<T> {
+    select! {
+        result = future => result,
+        reason = cancel => Err(anyhow::anyhow!(reason)),
+    }
+}
+
+fn pip_install_args(target: &EnvTarget, pkg: &Package, extra_index_urls: &[String]) -> Vec<String> {
+    let mut args = vec!["run".to_string()];
+    args.extend(target.args());
+    args.extend(["pip", "install", "--no-deps"].map(String::from));
+    for url in extra_index_urls {
+        args.push("--extra-index-url".to_string());
+        args.push(url.clone());
+    }
+    // local version labels (e.g. `1.13.1+cu118`) are valid PEP 440, so pin them as is
+    args.push(pkg.to_string());
+    args
+}
+
+#[test]
+fn test_pip_install_args_with_local_version() {
+    let recipe: Recipe = "torch 1.13.1+cu118 pypi_0 pypi".try_into().unwrap();
+    let args = pip_install_args(
+        &"demo".into(),
+        &recipe["torch"],
+        &["https://download.pytorch.org/whl/cu118".to_string()],
+    );
+    assert_eq!(
+        args,
+        [
+            "run",
+            "-n",
+            "demo",
+            "pip",
+            "install",
+            "--no-deps",
+            "--extra-index-url",
+            "https://download.pytorch.org/whl/cu118",
+            "torch==1.13.1+cu118",
+        ]
+    );
+}
+
+#[test]
+fn test_pip_install_args_with_extras() {
+    let recipe: Recipe = "requests[security] 2.28.2 pypi_0 pypi".try_into().unwrap();
+    assert_eq!(
+        pip_install_args(&"demo".into(), &recipe["requests"], &[]),
+        [
+            "run",
+            "-n",
+            "demo",
+            "pip",
+            "install",
+            "--no-deps",
+            "requests[security]==2.28.2",
+        ]
+    );
+}
+
+#[test]
+fn test_args_of_env_at_prefix() {
+    let recipe: Recipe = "numpy 1.24.1 py310h5d7c261_0 conda-forge\nyarl 1.7.3 pypi_0 pypi"
+        .try_into()
+        .unwrap();
+    let target = EnvTarget::Prefix(PathBuf::from("/nfs/envs/demo"));
+    assert_eq!(
+        conda_remove_args(&target, &[&recipe["numpy"]]),
+        ["remove", "-p", "/nfs/envs/demo", "--force", "-y", "numpy"]
+    );
+    assert_eq!(
+        pip_install_args(&target, &recipe["yarl"], &[]),
+        [
+            "run",
+            "-p",
+            "/nfs/envs/demo",
+            "pip",
+            "install",
+            "--no-deps",
+            "yarl==1.7.3"
+        ]
+    );
+    assert_eq!(
+        pip_uninstall_args(&target, &[&recipe["yarl"]]),
+        [
+            "run",
+            "-p",
+            "/nfs/envs/demo",
+            "pip",
+            "uninstall",
+            "-y",
+            "yarl"
+        ]
+    );
+    assert_eq!(
+        pip_version_args(&target),
+        ["run", "-p", "/nfs/envs/demo", "pip", "--version"]
+    );
+    assert!(
+        conda_install_args(&target, &[], &[&recipe["numpy"]], false, false, &[])
+            .ends_with(&["-p", "/nfs/envs/demo", "numpy=1.24.1=py310h5d7c261_0"].map(String::from))
+    );
+}
+
+/// removing python or pip while pypi packages (or conda packages built for a python ABI)
+/// remain is almost always a recipe editing mistake
+fn check_python_removal(diff: &RecipeDiff, target_recipe: &Recipe) -> Result<(), Error> {
+    let python_abi = regex::Regex::new(r"^py(\d|h|_)").unwrap();
+    let needs_python = target_recipe.packages.values().any(|p| match &p.kind {
+        PackageKind::PyPi { .. } => true,
+        PackageKind::Conda { build, .. } => python_abi.is_match(build),
+    });
+    if !needs_python {
+        return Ok(());
+    }
+    for name in ["python", "pip"] {
+        let removed = diff
+            .deletes
+            .iter()
+            .any(|p| p.name == name && matches!(p.kind, PackageKind::Conda { .. }));
+        if removed {
+            return Err(Error::PythonRemoval(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_python_removal() {
+    let old_recipe: Recipe = r#"
+ca-certificates           2022.07.19           hecd8cb5_0
+certifi                   2022.6.15        py37hecd8cb5_0
+pip                       22.1.2           py37hecd8cb5_0
+python                    3.7.13               hdfd78df_0
+django                    3.2.14                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let check = |new_recipe: &str| {
+        let new_recipe: Recipe = new_recipe.try_into().unwrap();
+        let diff = old_recipe.clone().diff(new_recipe.clone());
+        check_python_removal(&diff, &new_recipe)
+    };
+
+    // full teardown to a python free env
+    assert!(check("ca-certificates 2022.07.19 hecd8cb5_0").is_ok());
+    // upgrading python replaces it
+    assert!(check(
+        r#"
+certifi                   2022.6.15        py38hecd8cb5_0
+pip                       22.1.2           py38hecd8cb5_0
+python                    3.8.13               hdfd78df_0
+django                    3.2.14                   pypi_0    pypi
+"#
+    )
+    .is_ok());
+
+    // the python line is dropped by mistake
+    assert!(matches!(
+        check(
+            r#"
+pip                       22.1.2           py37hecd8cb5_0
+django                    3.2.14                   pypi_0    pypi
+"#
+        ),
+        Err(Error::PythonRemoval(name)) if name == "python"
+    ));
+    // conda packages built for python need it too
+    assert!(matches!(
+        check(
+            r#"
+certifi                   2022.6.15        py37hecd8cb5_0
+pip                       22.1.2           py37hecd8cb5_0
+"#
+        ),
+        Err(Error::PythonRemoval(name)) if name == "python"
+    ));
+    assert!(matches!(
+        check(
+            r#"
+python                    3.7.13               hdfd78df_0
+django                    3.2.14                   pypi_0    pypi
+"#
+        ),
+        Err(Error::PythonRemoval(name)) if name == "pip"
+    ));
+}
+
 fn collect_packages<'p>(diff: &'p RecipeDiff) -> CollectedPackages<'p> {
-    let mut conda_install_pkgs = vec![];
+    let mut conda_add_pkgs = vec![];
+    let mut conda_update_pkgs = vec![];
     let mut conda_delete_pkgs = vec![];
     let mut pypi_install_pkgs = vec![];
     let mut pypi_delete_pkgs = vec![];
 
     for pkg in &diff.adds {
         match &pkg.kind {
-            crate::recipe::PackageKind::PyPi => pypi_install_pkgs.push(pkg),
+            crate::recipe::PackageKind::PyPi { .. } => pypi_install_pkgs.push(pkg),
             crate::recipe::PackageKind::Conda {
                 build: _,
                 channel: _,
-            } => conda_install_pkgs.push(pkg),
+            } => conda_add_pkgs.push(pkg),
         }
     }
 
     for update in &diff.updates {
         match (&update.from.kind, &update.to.kind) {
-            (crate::recipe::PackageKind::PyPi, crate::recipe::PackageKind::PyPi) => {
+            (crate::recipe::PackageKind::PyPi { .. }, crate::recipe::PackageKind::PyPi { .. }) => {
                 pypi_install_pkgs.push(&update.to)
             }
             (
-                crate::recipe::PackageKind::PyPi,
+                crate::recipe::PackageKind::PyPi { .. },
                 crate::recipe::PackageKind::Conda {
                     build: _,
                     channel: _,
                 },
             ) => {
                 pypi_delete_pkgs.push(&update.from);
-                conda_install_pkgs.push(&update.to);
+                conda_update_pkgs.push(&update.to);
             }
             (
                 crate::recipe::PackageKind::Conda {
                     build: _,
                     channel: _,
                 },
-                crate::recipe::PackageKind::PyPi,
+                crate::recipe::PackageKind::PyPi { .. },
             ) => {
                 conda_delete_pkgs.push(&update.from);
                 pypi_install_pkgs.push(&update.to);
@@ -314,14 +1886,14 @@ fn collect_packages<'p>(diff: &'p RecipeDiff) -> CollectedPackages<'p> {
                 },
             ) => {
                 conda_delete_pkgs.push(&update.from);
-                conda_install_pkgs.push(&update.to);
+                conda_update_pkgs.push(&update.to);
             }
         }
     }
 
     for pkg in &diff.deletes {
         match pkg.kind {
-            crate::recipe::PackageKind::PyPi => pypi_delete_pkgs.push(pkg),
+            crate::recipe::PackageKind::PyPi { .. } => pypi_delete_pkgs.push(pkg),
             crate::recipe::PackageKind::Conda {
                 build: _,
                 channel: _,
@@ -329,27 +1901,468 @@ fn collect_packages<'p>(diff: &'p RecipeDiff) -> CollectedPackages<'p> {
         }
     }
 
-    // sort pypi pkgs
-    pypi_install_pkgs.sort_by(|a, b| match (a.name.as_str(), b.name.as_str()) {
-        ("pip" | "wheel" | "setuptools" | "six", _) => std::cmp::Ordering::Less,
-        (_, "pip" | "wheel" | "setuptools" | "six") => std::cmp::Ordering::Less,
-        (_, _) => a.name.cmp(&b.name),
-    });
+    sort_pypi_install_pkgs(&mut pypi_install_pkgs);
 
     CollectedPackages {
-        conda_install_pkgs,
+        conda_add_pkgs,
+        conda_update_pkgs,
         conda_delete_pkgs,
         pypi_install_pkgs,
         pypi_delete_pkgs,
+        satisfied: diff.same.len(),
+    }
+}
+
+/// pypi packages which other pypi packages rely on while being installed or uninstalled
+const PACKAGING_TOOLS: [&str; 4] = ["pip", "setuptools", "wheel", "six"];
+
+/// packaging tools go first in `PACKAGING_TOOLS` order, then the others by name, so the order
+/// doesn't depend on the order of the diff
+fn sort_pypi_install_pkgs(pkgs: &mut [&Package]) {
+    pkgs.sort_by(|a, b| {
+        packaging_tool_rank(a)
+            .cmp(&packaging_tool_rank(b))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+/// packaging tools rank in `PACKAGING_TOOLS` order, others rank after them
+fn packaging_tool_rank(pkg: &Package) -> usize {
+    PACKAGING_TOOLS
+        .iter()
+        .position(|name| *name == pkg.name)
+        .unwrap_or(PACKAGING_TOOLS.len())
+}
+
+#[test]
+fn test_pip_is_never_uninstalled_by_pip() {
+    let old_recipe: Recipe = r#"
+pip                       22.1.2                   pypi_0    pypi
+setuptools                65.5.0                   pypi_0    pypi
+wheel                     0.38.4                   pypi_0    pypi
+yarl                      1.7.2                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(Recipe::default());
+    let collections = collect_packages(&diff);
+    let pypi_delete_plan = plan_pypi_deletes(&collections.pypi_delete_pkgs);
+    assert!(pypi_delete_plan
+        .batches
+        .iter()
+        .flatten()
+        .all(|p| p.name != "pip"));
+    let plan = plan_install(
+        &"demo".into(),
+        None,
+        &collections,
+        &[],
+        &InstallOptions::default(),
+        &[],
+        Executable::Conda,
+    );
+    assert_eq!(plan.delete.len(), 3);
+    for args in &plan.delete {
+        let uninstall = args.iter().position(|arg| arg == "uninstall").unwrap();
+        assert!(
+            !args[uninstall..].contains(&"pip".to_string()),
+            "{:?}",
+            args
+        );
     }
+
+    // a conda pip is removed by conda
+    let old_recipe: Recipe = "pip 22.3.1 pyhd8ed1ab_0 conda-forge".try_into().unwrap();
+    let plan = plan_install(
+        &"demo".into(),
+        None,
+        &collect_packages(&old_recipe.diff(Recipe::default())),
+        &[],
+        &InstallOptions::default(),
+        &[],
+        Executable::Conda,
+    );
+    assert_eq!(plan.delete.len(), 1);
+    assert_eq!(plan.delete[0][..2], ["conda", "remove"]);
+    assert_eq!(plan.delete[0].last().unwrap(), "pip");
+}
+
+#[derive(Debug, PartialEq)]
+struct PypiDeletePlan<'p> {
+    /// each batch is uninstalled by one `pip uninstall`, packaging tools come last and alone
+    batches: Vec<Vec<&'p Package>>,
+    /// `pip` must not be uninstalled by pip itself, it's kept in the env
+    pip: Option<&'p Package>,
+}
+
+/// the packages of the env `before` which are missing `after` although they weren't deleted
+fn collateral_removals<'r>(
+    before: &'r Recipe,
+    deleted: &[Package],
+    after: &Recipe,
+) -> Vec<&'r Package> {
+    let mut removed = before
+        .packages
+        .iter()
+        .filter(|(key, _)| !after.packages.contains_key(key))
+        .filter(|(key, _)| !deleted.iter().any(|p| &p.key() == *key))
+        .map(|(_, p)| p)
+        .collect::<Vec<_>>();
+    removed.sort_by_key(|p| p.sort_key());
+    removed
+}
+
+#[test]
+fn test_collateral_removals() {
+    let before: Recipe = r#"
+python                    3.10.8          h4a9ceb5_0_cpython    conda-forge
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+zlib                      1.2.13               h166bdaf_4    conda-forge
+yarl                      1.7.2                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let after: Recipe = r#"
+zlib                      1.2.13               h166bdaf_4    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let deleted = vec![before["python"].clone()];
+    let removed = collateral_removals(&before, &deleted, &after);
+    assert_eq!(removed, [&before["numpy"], &before["yarl"]]);
+
+    assert!(collateral_removals(&before, &deleted, &before).is_empty());
+}
+
+#[test]
+fn test_reinstall_collateral_removals() {
+    let old_recipe: Recipe = r#"
+python                    3.10.8          h4a9ceb5_0_cpython    conda-forge
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+yarl                      1.7.2                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+python                    3.10.9          h4a9ceb5_0_cpython    conda-forge
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+yarl                      1.7.2                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.clone().diff(new_recipe.clone());
+    let mut collections = collect_packages(&diff);
+    // conda removed numpy and yarl with python
+    let after = Recipe::default();
+    let removed = collateral_removals(&old_recipe, &[old_recipe["python"].clone()], &after);
+    let repairs = removed
+        .iter()
+        .filter_map(|p| new_recipe.packages.get(&p.key()))
+        .filter(|p| !collections.replaces(p))
+        .collect::<Vec<_>>();
+    collections.reinstall(&repairs);
+    let args = collections
+        .conda_install_batches()
+        .into_iter()
+        .map(|(pkgs, force_reinstall)| {
+            conda_install_args(&"demo".into(), &[], &pkgs, force_reinstall, false, &[])
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(args.len(), 2);
+    assert!(args[0].ends_with(&["numpy=1.24.1=py310h5d7c261_0".to_string()]));
+    assert!(args[1].ends_with(&["python=3.10.9=h4a9ceb5_0_cpython".to_string()]));
+    assert_eq!(collections.pypi_install_pkgs, [&new_recipe["yarl"]]);
+}
+
+fn plan_pypi_deletes<'p>(pkgs: &[&'p Package]) -> PypiDeletePlan<'p> {
+    let mut pkgs = pkgs.to_vec();
+    pkgs.sort_by(|a, b| {
+        packaging_tool_rank(b)
+            .cmp(&packaging_tool_rank(a))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    let mut plan = PypiDeletePlan {
+        batches: vec![],
+        pip: None,
+    };
+    let mut others = vec![];
+    for pkg in pkgs {
+        match packaging_tool_rank(pkg) {
+            0 => plan.pip = Some(pkg),
+            rank if rank < PACKAGING_TOOLS.len() => plan.batches.push(vec![pkg]),
+            _ => others.push(pkg),
+        }
+    }
+    if !others.is_empty() {
+        plan.batches.insert(0, others);
+    }
+    plan
+}
+
+#[test]
+fn test_plan_pypi_deletes() {
+    let recipe: Recipe = r#"
+pip                       22.1.2                   pypi_0    pypi
+six                       1.16.0                   pypi_0    pypi
+django                    3.2.14                   pypi_0    pypi
+setuptools                61.2.0                   pypi_0    pypi
+aiohttp                   3.8.1                    pypi_0    pypi
+wheel                     0.37.1                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let pkgs = recipe.packages.values().collect::<Vec<_>>();
+    let plan = plan_pypi_deletes(&pkgs);
+
+    let batches = plan
+        .batches
+        .iter()
+        .map(|b| b.iter().map(|p| p.name.as_str()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        batches,
+        vec![
+            vec!["aiohttp", "django"],
+            vec!["six"],
+            vec!["wheel"],
+            vec!["setuptools"],
+        ]
+    );
+    assert_eq!(plan.pip, Some(&recipe["pip"]));
+}
+
+#[test]
+fn test_plan_pypi_deletes_without_packaging_tools() {
+    let recipe: Recipe = "django 3.2.14 pypi_0 pypi".try_into().unwrap();
+    let pkgs = recipe.packages.values().collect::<Vec<_>>();
+    let plan = plan_pypi_deletes(&pkgs);
+
+    assert_eq!(
+        plan,
+        PypiDeletePlan {
+            batches: vec![vec![&recipe["django"]]],
+            pip: None,
+        }
+    );
+}
+
+#[test]
+fn test_sort_pypi_install_pkgs() {
+    let old_recipe = Recipe::default();
+    let new_recipe: Recipe = r#"
+django                    3.2.14                   pypi_0    pypi
+wheel                     0.37.1                   pypi_0    pypi
+aiohttp                   3.8.1                    pypi_0    pypi
+setuptools                61.2.0                   pypi_0    pypi
+pip                       22.1.2                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    let collections = collect_packages(&diff);
+
+    let names = collections
+        .pypi_install_pkgs
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["pip", "setuptools", "wheel", "aiohttp", "django"]);
+
+    // the same pkgs listed in another order, and the diff in another order
+    let shuffled: Recipe = r#"
+aiohttp                   3.8.1                    pypi_0    pypi
+pip                       22.1.2                   pypi_0    pypi
+django                    3.2.14                   pypi_0    pypi
+setuptools                61.2.0                   pypi_0    pypi
+wheel                     0.37.1                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let mut diff = Recipe::default().diff(shuffled);
+    diff.adds.reverse();
+    let shuffled_names = collect_packages(&diff)
+        .pypi_install_pkgs
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(shuffled_names, names);
+}
+
+#[test]
+fn test_collect_packages_skips_satisfied() {
+    // a previous run failed after `certifi` and `ncurses` had been linked
+    let live_recipe: Recipe = r#"
+certifi                   2022.6.15        py37hecd8cb5_0
+ncurses                   6.3                  hca72f7f_3    conda-forge
+numpy                     1.18.1           py37h7241aed_0
+"#
+    .try_into()
+    .unwrap();
+    let contents = r#"
+certifi                   2022.6.15        py37hecd8cb5_0
+ncurses                   6.3                  hca72f7f_3    conda-forge
+numpy                     1.18.2           py37h7241aed_0
+openssl                   1.1.1q               hca72f7f_0
+"#;
+
+    let diff = live_recipe.diff_with_unchanged(contents.try_into().unwrap());
+    let collections = collect_packages(&diff);
+    let names = collections
+        .conda_install_pkgs()
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["openssl", "numpy"]);
+    assert_eq!(collections.satisfied, 2);
+
+    // a force recreated env starts from an empty recipe, so everything is installed again
+    let diff = Recipe::default().diff_with_unchanged(contents.try_into().unwrap());
+    let collections = collect_packages(&diff);
+    assert_eq!(collections.conda_install_pkgs().len(), 4);
+    assert_eq!(collections.satisfied, 0);
+}
+
+#[test]
+fn test_collect_packages_with_coexisting_kinds() {
+    // both are exported from envs where pip installed over the conda protobuf
+    let old_recipe = Recipe::from_env(
+        r#"
+protobuf                  3.20.1               h2f01273_0    conda-forge
+protobuf                  3.20.1                   pypi_0    pypi
+"#,
+    )
+    .unwrap();
+    let new_recipe = Recipe::from_env(
+        r#"
+protobuf                  3.20.1               h2f01273_0    conda-forge
+protobuf                  3.20.3                   pypi_0    pypi
+"#,
+    )
+    .unwrap();
+
+    // only the pypi protobuf is touched
+    let diff = old_recipe.diff(new_recipe);
+    let collections = collect_packages(&diff);
+    assert!(collections.conda_install_pkgs().is_empty());
+    assert!(collections.conda_delete_pkgs.is_empty());
+    assert!(collections.pypi_delete_pkgs.is_empty());
+    assert_eq!(collections.pypi_install_pkgs.len(), 1);
+    assert_eq!(
+        collections.pypi_install_pkgs[0].to_string(),
+        "protobuf==3.20.3"
+    );
 }
 
 #[derive(Debug)]
 struct CollectedPackages<'p> {
-    conda_install_pkgs: Vec<&'p Package>,
+    conda_add_pkgs: Vec<&'p Package>,
+    conda_update_pkgs: Vec<&'p Package>,
     conda_delete_pkgs: Vec<&'p Package>,
     pypi_install_pkgs: Vec<&'p Package>,
     pypi_delete_pkgs: Vec<&'p Package>,
+    /// the packages of the recipe which are already in the env as is, so they are neither
+    /// installed nor reinstalled, 0 for an env recreated by `--force`
+    satisfied: usize,
+}
+
+impl<'p> CollectedPackages<'p> {
+    fn conda_install_pkgs(&self) -> Vec<&'p Package> {
+        [self.conda_add_pkgs.clone(), self.conda_update_pkgs.clone()].concat()
+    }
+
+    /// whether the package is installed again, by either kind
+    fn replaces(&self, pkg: &Package) -> bool {
+        self.conda_install_pkgs()
+            .into_iter()
+            .chain(self.pypi_install_pkgs.iter().copied())
+            .any(|p| p.name == pkg.name)
+    }
+
+    /// install the packages again, e.g. the ones conda removed by accident
+    fn reinstall(&mut self, pkgs: &[&'p Package]) {
+        for &pkg in pkgs {
+            match pkg.kind {
+                PackageKind::PyPi { .. } => self.pypi_install_pkgs.push(pkg),
+                PackageKind::Conda { .. } => self.conda_add_pkgs.push(pkg),
+            }
+        }
+        sort_pypi_install_pkgs(&mut self.pypi_install_pkgs);
+    }
+
+    fn installs_pypi_pip(&self) -> bool {
+        self.pypi_install_pkgs.iter().any(|p| p.name == "pip")
+    }
+
+    /// pure adds are installed plainly, only updates need `--force-reinstall`
+    fn conda_install_batches(&self) -> Vec<(Vec<&'p Package>, bool)> {
+        [
+            (self.conda_add_pkgs.clone(), false),
+            (self.conda_update_pkgs.clone(), true),
+        ]
+        .into_iter()
+        .filter(|(pkgs, _)| !pkgs.is_empty())
+        .collect()
+    }
+}
+
+#[test]
+fn test_conda_install_batches() {
+    let old_recipe: Recipe = r#"
+numpy                     1.18.1           py37h7241aed_0
+yarl                      1.7.2                    pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+numpy                     1.18.2           py37h7241aed_0
+yarl                      1.7.3                xaa72f7f_3    conda-forge
+libcxx                    12.0.0               h2f01273_0
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    let collections = collect_packages(&diff);
+    let args = collections
+        .conda_install_batches()
+        .into_iter()
+        .map(|(pkgs, force_reinstall)| {
+            conda_install_args(&"demo".into(), &[], &pkgs, force_reinstall, false, &[])
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(args.len(), 2);
+    assert!(!args[0].contains(&"--force-reinstall".to_string()));
+    assert!(args[0].ends_with(&["libcxx=12.0.0=h2f01273_0".to_string()]));
+    assert!(args[1].contains(&"--force-reinstall".to_string()));
+    assert!(args[1].ends_with(&[
+        "numpy=1.18.2=py37h7241aed_0".to_string(),
+        "yarl=1.7.3=xaa72f7f_3".to_string()
+    ]));
+
+    // nothing to update
+    let diff = Recipe::default().diff(r#"libcxx 12.0.0 h2f01273_0"#.try_into().unwrap());
+    let collections = collect_packages(&diff);
+    assert_eq!(collections.conda_install_batches().len(), 1);
+}
+
+#[tokio::test]
+async fn test_cancellable_by() {
+    let touched = std::sync::atomic::AtomicBool::new(false);
+    let result = cancellable_by(
+        async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            touched.store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        },
+        async { "receive ctrl c" },
+    )
+    .await;
+    assert_eq!(result.unwrap_err().to_string(), "receive ctrl c");
+    assert!(!touched.load(std::sync::atomic::Ordering::SeqCst));
+
+    let result = cancellable_by(async { Ok(1) }, std::future::pending()).await;
+    assert_eq!(result.unwrap(), 1);
 }
 
 enum InstallEvent {
@@ -362,7 +2375,7 @@ enum InstallEvent {
 #[tokio::test]
 async fn t() -> anyhow::Result<()> {
     install(
-        "demo",
+        &"demo".into(),
         r#"
 # Name                    Version                   Build  Channel
 ca-certificates           2022.07.19           hecd8cb5_0
@@ -382,8 +2395,11 @@ xz                        5.2.5                hca72f7f_1
 zlib                      1.2.12               h4dc903c_2
 django                    3.2.14                   pypi_0    pypi
 "#,
-        false,
-        true,
+        &InstallOptions {
+            show_diff: true,
+            ..Default::default()
+        },
+        &mut EnvReport::new("demo"),
     )
     .await?;
 
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+
+use super::priority::Executable;
+use crate::recipe::{Package, PackageKind};
+
+/// `==> LINKING PACKAGE: <channel>::<id> <==` of `conda install -vv`, which mamba 1 also prints
+/// since it links by conda
+const CONDA_LINK_PATTERN: &str = "==> LINKING PACKAGE: (?:.*?)::(.*) <==";
+/// mamba 2 links by libmamba, which logs `Linking package [<channel>::]<id>`
+const MAMBA_LINK_PATTERN: &str =
+    r"==> LINKING PACKAGE: (?:.*?)::(.*) <==|libmamba\s+Linking package (?:\S*::)?(\S+)";
+
+/// what a `==> LINKING PACKAGE: <channel>::<id> <==` line of `conda install -vv` means
+#[derive(Debug, PartialEq)]
+pub(super) enum LinkEvent<'p> {
+    /// the first link of a package which is going to be installed
+    Installed(&'p Package),
+    /// conda relinked a package which isn't in the install set
+    Relinked(String),
+}
+
+/// maps the linked packages from conda output to the packages being installed
+#[derive(Debug)]
+pub(super) struct LinkTracker<'p> {
+    pattern: Regex,
+    /// the packages not linked yet, keyed by the id used in conda output
+    pending: HashMap<String, &'p Package>,
+    /// the ids of the installed packages which are already linked
+    installed: HashSet<String>,
+    relinked: Vec<String>,
+}
+
+impl<'p> LinkTracker<'p> {
+    pub fn new(pkgs: &[&'p Package], executable: Executable) -> Self {
+        let pending = pkgs
+            .iter()
+            .map(|&p| {
+                let id = match &p.kind {
+                    PackageKind::PyPi { .. } => format!("{}-{}", p.name, p.version),
+                    PackageKind::Conda { build, channel: _ } => {
+                        format!("{}-{}-{}", p.name, p.version, build)
+                    }
+                };
+                (id, p)
+            })
+            .collect();
+        Self {
+            pattern: Regex::new(match executable {
+                Executable::Conda => CONDA_LINK_PATTERN,
+                Executable::Mamba => MAMBA_LINK_PATTERN,
+            })
+            .unwrap(),
+            pending,
+            installed: HashSet::new(),
+            relinked: vec![],
+        }
+    }
+
+    pub fn feed(&mut self, line: &str) -> Option<LinkEvent<'p>> {
+        let captures = self.pattern.captures(line)?;
+        let id = captures.get(1).or_else(|| captures.get(2))?.as_str();
+        if let Some(pkg) = self.pending.remove(id) {
+            self.installed.insert(id.to_string());
+            return Some(LinkEvent::Installed(pkg));
+        }
+        // a package linked twice is only counted once
+        if self.installed.contains(id) || self.relinked.iter().any(|r| r == id) {
+            return None;
+        }
+        self.relinked.push(id.to_string());
+        Some(LinkEvent::Relinked(id.to_string()))
+    }
+
+    /// the ids of the packages which were linked but not in the install set
+    pub fn relinked(&self) -> &[String] {
+        &self.relinked
+    }
+}
+
+#[test]
+fn test_track_links() {
+    use crate::recipe::Recipe;
+
+    let recipe: Recipe = r#"
+numpy                     1.18.2           py37h7241aed_0    conda-forge
+libcxx                    12.0.0               h2f01273_0
+"#
+    .try_into()
+    .unwrap();
+    let pkgs = vec![&recipe["numpy"], &recipe["libcxx"]];
+    let mut links = LinkTracker::new(&pkgs, Executable::Conda);
+
+    let events = include_str!("../../fixtures/link/force-reinstall-vv.txt")
+        .lines()
+        .filter_map(|line| links.feed(line))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            LinkEvent::Installed(&recipe["libcxx"]),
+            LinkEvent::Installed(&recipe["numpy"]),
+            LinkEvent::Relinked("ca-certificates-2022.07.19-hecd8cb5_0".to_string()),
+        ]
+    );
+    assert_eq!(links.relinked(), ["ca-certificates-2022.07.19-hecd8cb5_0"]);
+
+    // the unexpected relink is reported once
+    assert_eq!(
+        links.feed("==> LINKING PACKAGE: defaults::ca-certificates-2022.07.19-hecd8cb5_0 <=="),
+        None
+    );
+}
+
+#[test]
+fn test_track_mamba_links() {
+    use crate::recipe::Recipe;
+
+    let recipe: Recipe = r#"
+numpy                     1.18.2           py37h7241aed_0    conda-forge
+libcxx                    12.0.0               h2f01273_0
+"#
+    .try_into()
+    .unwrap();
+    let pkgs = vec![&recipe["numpy"], &recipe["libcxx"]];
+    let mut links = LinkTracker::new(&pkgs, Executable::Mamba);
+
+    let events = include_str!("../../fixtures/link/mamba-vv.txt")
+        .lines()
+        .filter_map(|line| links.feed(line))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            LinkEvent::Installed(&recipe["libcxx"]),
+            LinkEvent::Installed(&recipe["numpy"]),
+            LinkEvent::Relinked("ca-certificates-2022.07.19-hecd8cb5_0".to_string()),
+        ]
+    );
+    // mamba 1 links by conda
+    let mut links = LinkTracker::new(&pkgs, Executable::Mamba);
+    assert_eq!(
+        links.feed("==> LINKING PACKAGE: defaults::libcxx-12.0.0-h2f01273_0 <=="),
+        Some(LinkEvent::Installed(&recipe["libcxx"]))
+    );
+    // conda doesn't log by libmamba
+    let mut links = LinkTracker::new(&pkgs, Executable::Conda);
+    assert_eq!(
+        links.feed("info     libmamba Linking package libcxx-12.0.0-h2f01273_0"),
+        None
+    );
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde_json::{json, Map, Value};
+
+use super::archive::{read_index_json, PACKAGE_EXTS};
+
+/// turn a directory of packages into a `file://` channel, a directory indexed by `conda index`
+/// is used as is, a plain folder of `.tar.bz2`/`.conda` packages is laid out by the subdirs of
+/// the packages into a temp channel with a repodata.json for each subdir
+pub(super) fn local_channel_url(dir: &Path) -> anyhow::Result<String> {
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("invalid local channel '{}': {}", dir.display(), e))?;
+    let indexed = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.path().join("repodata.json").is_file());
+    if indexed {
+        return Ok(format!("file://{}", dir.display()));
+    }
+
+    let packages = package_files(&dir)?;
+    if packages.is_empty() {
+        return Err(anyhow::anyhow!(
+            "local channel '{}' has neither a repodata.json nor any .tar.bz2/.conda package",
+            dir.display()
+        ));
+    }
+    let channel = channel_dir(&dir, &packages)?;
+    if !channel.is_dir() {
+        build_channel(&channel, &packages)?;
+    }
+    Ok(format!("file://{}", channel.display()))
+}
+
+/// the packages right in the dir, sorted by file name
+fn package_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut packages = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| PACKAGE_EXTS.iter().any(|ext| name.ends_with(ext)))
+        })
+        .collect::<Vec<_>>();
+    packages.sort();
+    Ok(packages)
+}
+
+/// the temp channel built for a plain folder, keyed by a hash of the path of the folder and
+/// the name, size and mtime of its packages, so a changed folder gets a new channel and a
+/// channel never changes under a run which uses it
+fn channel_dir(dir: &Path, packages: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    dir.hash(&mut hasher);
+    for package in packages {
+        let metadata = package.metadata()?;
+        package.file_name().hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+    }
+    let name = dir
+        .file_name()
+        .map_or("channel".into(), |name| name.to_string_lossy());
+    Ok(std::env::temp_dir()
+        .join("conda-cage-channels")
+        .join(format!("{}-{:016x}", name, hasher.finish())))
+}
+
+/// build the channel in a dir of this run and rename it to `channel`, a concurrent run which
+/// renamed its own first wins and ours is dropped
+fn build_channel(channel: &Path, packages: &[PathBuf]) -> anyhow::Result<()> {
+    let mut staging = channel.as_os_str().to_owned();
+    staging.push(format!(".tmp-{}", std::process::id()));
+    let staging = PathBuf::from(staging);
+    let _ = std::fs::remove_dir_all(&staging);
+    let built =
+        write_channel(&staging, packages).and_then(|_| match std::fs::rename(&staging, channel) {
+            Ok(_) => Ok(()),
+            // another run built the same channel first
+            Err(_) if channel.is_dir() => Ok(()),
+            Err(e) => Err(e.into()),
+        });
+    let _ = std::fs::remove_dir_all(&staging);
+    built
+}
+
+/// link or copy the packages into `<channel>/<subdir>/` and write the repodata.json of every
+/// subdir, `noarch` always exists since conda requires it
+fn write_channel(channel: &Path, packages: &[PathBuf]) -> anyhow::Result<()> {
+    let mut subdirs = BTreeMap::<String, (Map<String, Value>, Map<String, Value>)>::new();
+    subdirs.entry("noarch".to_string()).or_default();
+    for package in packages {
+        let mut index = read_index_json(package)?;
+        let subdir = index
+            .get("subdir")
+            .and_then(|subdir| subdir.as_str())
+            .unwrap_or("noarch")
+            .to_string();
+        let file_name = package.file_name().unwrap().to_string_lossy().to_string();
+        let target = channel.join(&subdir).join(&file_name);
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        if std::fs::hard_link(package, &target).is_err() {
+            std::fs::copy(package, &target)?;
+        }
+        index.insert("size".to_string(), package.metadata()?.len().into());
+
+        let (tar_bz2, conda) = subdirs.entry(subdir).or_default();
+        if file_name.ends_with(".conda") {
+            conda.insert(file_name, Value::Object(index));
+        } else {
+            tar_bz2.insert(file_name, Value::Object(index));
+        }
+    }
+    for (subdir, (tar_bz2, conda)) in subdirs {
+        let dir = channel.join(&subdir);
+        std::fs::create_dir_all(&dir)?;
+        let repodata = json!({
+            "info": { "subdir": subdir },
+            "packages": tar_bz2,
+            "packages.conda": conda,
+            "repodata_version": 1,
+        });
+        std::fs::write(dir.join("repodata.json"), serde_json::to_vec(&repodata)?)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_local_channel_url() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("conda-cage-test-local-channel");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("noarch"))?;
+    assert!(local_channel_url(&dir).is_err());
+
+    std::fs::write(dir.join("noarch").join("repodata.json"), "{}")?;
+    assert_eq!(
+        local_channel_url(&dir)?,
+        format!("file://{}", dir.canonicalize()?.display())
+    );
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_local_channel_from_plain_folder() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("conda-cage-test-local-channel-plain");
+    let _ = std::fs::remove_dir_all(&dir);
+    let info = dir.join("build").join("info");
+    std::fs::create_dir_all(&info)?;
+    std::fs::write(
+        info.join("index.json"),
+        r#"{"name": "demo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64", "depends": []}"#,
+    )?;
+    let status = std::process::Command::new("tar")
+        .arg("-cjf")
+        .arg(dir.join("demo-1.0-0.tar.bz2"))
+        .arg("-C")
+        .arg(dir.join("build"))
+        .arg("info")
+        .status()?;
+    assert!(status.success());
+    std::fs::remove_dir_all(dir.join("build"))?;
+    std::fs::write(dir.join("README.md"), "not a package")?;
+
+    let url = local_channel_url(&dir)?;
+    let package = dir.canonicalize()?.join("demo-1.0-0.tar.bz2");
+    let channel = channel_dir(&dir.canonicalize()?, std::slice::from_ref(&package))?;
+    assert_eq!(url, format!("file://{}", channel.display()));
+    // the built channel is reused as is
+    assert_eq!(local_channel_url(&dir)?, url);
+    assert!(channel
+        .join("linux-64")
+        .join("demo-1.0-0.tar.bz2")
+        .is_file());
+    let repodata: Value = serde_json::from_slice(&std::fs::read(
+        channel.join("linux-64").join("repodata.json"),
+    )?)?;
+    assert_eq!(repodata["info"]["subdir"], "linux-64");
+    assert_eq!(repodata["packages"]["demo-1.0-0.tar.bz2"]["name"], "demo");
+    assert!(repodata["packages"]["demo-1.0-0.tar.bz2"]["size"].as_u64() > Some(0));
+    assert!(channel.join("noarch").join("repodata.json").is_file());
+
+    // a changed folder gets a new channel, the old one is left to the runs using it
+    std::fs::write(&package, "rebuilt")?;
+    let changed = channel_dir(&dir.canonicalize()?, std::slice::from_ref(&package))?;
+    assert_ne!(changed, channel);
+    assert!(channel.is_dir());
+
+    std::fs::remove_dir_all(&dir)?;
+    std::fs::remove_dir_all(&channel)?;
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use std::{fs::File, path::PathBuf};
+
+use fs2::FileExt;
+
+/// an advisory lock of an env, installs hold it exclusively while readers share it
+///
+/// the lock is keyed by how the env is addressed, so an env name and its prefix
+/// are different locks
+pub struct EnvLock {
+    file: File,
+}
+
+impl EnvLock {
+    fn path(key: &str) -> PathBuf {
+        let key = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        std::env::temp_dir()
+            .join("conda-cage-locks")
+            .join(format!("{}.lock", key))
+    }
+
+    fn open(key: &str) -> anyhow::Result<File> {
+        let path = Self::path(key);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?)
+    }
+
+    /// wait until no install holds the env
+    pub async fn shared(key: &str) -> anyhow::Result<Self> {
+        let file = Self::open(key)?;
+        let file = tokio::task::spawn_blocking(move || FileExt::lock_shared(&file).map(|_| file))
+            .await??;
+        Ok(Self { file })
+    }
+
+    /// wait until nobody else holds the env
+    pub async fn exclusive(key: &str) -> anyhow::Result<Self> {
+        let file = Self::open(key)?;
+        let file =
+            tokio::task::spawn_blocking(move || file.lock_exclusive().map(|_| file)).await??;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for EnvLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[tokio::test]
+async fn test_env_lock() -> anyhow::Result<()> {
+    let key = "name-conda-cage-test-env-lock";
+
+    let reader = EnvLock::shared(key).await?;
+    let another_reader = EnvLock::shared(key).await?;
+    assert!(EnvLock::open(key)?.try_lock_exclusive().is_err());
+    drop(reader);
+    drop(another_reader);
+
+    let writer = EnvLock::exclusive(key).await?;
+    assert!(EnvLock::open(key)?.try_lock_shared().is_err());
+    drop(writer);
+    assert!(EnvLock::open(key)?.try_lock_shared().is_ok());
+
+    Ok(())
+}
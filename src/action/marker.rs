@@ -0,0 +1,118 @@
+use std::path::{Path, PathBuf};
+
+use super::{env_lock_key, env_prefixes, lock::EnvLock, EnvTarget};
+use crate::error::Error;
+
+/// the file in the prefix of the envs conda-cage manages
+const MARKER_FILE: &str = ".cage-managed";
+
+/// the prefix of the env by name, `None` if it doesn't exist
+pub(super) async fn env_prefix(env_name: &str) -> anyhow::Result<Option<PathBuf>> {
+    Ok(env_prefixes().await?.remove(env_name))
+}
+
+/// the prefix of the env addressed either way, `None` if it doesn't exist
+pub(super) async fn target_prefix(target: &EnvTarget) -> anyhow::Result<Option<PathBuf>> {
+    match target {
+        EnvTarget::Name(name) => env_prefix(name).await,
+        EnvTarget::Prefix(prefix) => Ok(prefix.join("conda-meta").is_dir().then(|| prefix.clone())),
+    }
+}
+
+pub(super) fn is_managed(prefix: &Path) -> bool {
+    prefix.join(MARKER_FILE).exists()
+}
+
+pub(super) fn mark_managed(prefix: &Path) -> std::io::Result<()> {
+    std::fs::write(
+        prefix.join(MARKER_FILE),
+        "this env is managed by conda-cage, run `conda-cage disown <env>` to stop it\n",
+    )
+}
+
+/// the tool which seems to manage the env otherwise
+fn other_manager(prefix: &Path) -> Option<&'static str> {
+    if prefix.join("conda-meta").join("pixi").exists()
+        || prefix.components().any(|c| c.as_os_str() == ".pixi")
+    {
+        return Some("pixi");
+    }
+    None
+}
+
+/// an existing env which isn't marked is only touched by `--adopt` or `--force`
+pub(super) fn check_managed(
+    env_name: &str,
+    prefix: &Path,
+    adopt: bool,
+    force: bool,
+    deletes: usize,
+) -> Result<(), Error> {
+    if adopt || force || is_managed(prefix) {
+        return Ok(());
+    }
+    Err(Error::Unmanaged {
+        env: env_name.to_string(),
+        deletes,
+        manager: other_manager(prefix),
+    })
+}
+
+/// stop managing the env, returns whether it was managed
+pub async fn disown(env_name: &str) -> anyhow::Result<bool> {
+    let _lock = EnvLock::exclusive(&env_lock_key(env_name)).await?;
+    let prefix = env_prefix(env_name)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("env '{}' doesn't exist", env_name))?;
+    if !is_managed(&prefix) {
+        return Ok(false);
+    }
+    std::fs::remove_file(prefix.join(MARKER_FILE))?;
+    Ok(true)
+}
+
+#[test]
+fn test_check_managed() -> anyhow::Result<()> {
+    let prefix = std::env::temp_dir().join("conda-cage-test-marker/envs/demo");
+    let _ = std::fs::remove_dir_all(&prefix);
+    std::fs::create_dir_all(prefix.join("conda-meta"))?;
+
+    // not marked
+    assert!(check_managed("demo", &prefix, true, false, 3).is_ok());
+    assert!(check_managed("demo", &prefix, false, true, 3).is_ok());
+    match check_managed("demo", &prefix, false, false, 3) {
+        Err(Error::Unmanaged {
+            env,
+            deletes,
+            manager,
+        }) => {
+            assert_eq!((env.as_str(), deletes, manager), ("demo", 3, None));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    std::fs::write(prefix.join("conda-meta").join("pixi"), "")?;
+    assert!(matches!(
+        check_managed("demo", &prefix, false, false, 0),
+        Err(Error::Unmanaged {
+            manager: Some("pixi"),
+            ..
+        })
+    ));
+
+    // marked
+    mark_managed(&prefix)?;
+    assert!(check_managed("demo", &prefix, false, false, 3).is_ok());
+    assert!(check_managed("demo", &prefix, true, false, 3).is_ok());
+
+    std::fs::remove_dir_all(std::env::temp_dir().join("conda-cage-test-marker"))?;
+    Ok(())
+}
+
+#[test]
+fn test_other_manager() {
+    assert_eq!(
+        other_manager(Path::new("/home/alice/project/.pixi/envs/default")),
+        Some("pixi")
+    );
+    assert_eq!(other_manager(Path::new("/opt/conda/envs/demo")), None);
+}
@@ -1,26 +1,91 @@
+mod archive;
+mod clobber;
+mod explain;
+mod hook;
+mod info_cache;
+mod inspect;
 mod install;
+mod link;
+mod local_channel;
+mod lock;
+mod marker;
+mod prefix;
+mod priority;
+mod removal;
+mod sandbox;
+mod search;
+mod solver;
+mod space;
+mod staged;
+mod tools;
+mod transcript;
+mod uninstall;
 
-pub use install::install;
+pub use explain::{Explanation, Operation, Reason};
+pub use hook::run_post_install_hooks;
+pub use info_cache::{set_info_cache, InfoSource};
+pub use inspect::{inspect, PackageFile, PackageInspection};
+pub use install::{
+    install, install_recipe, parse_recipe, should_auto_force, InstallOptions, InstallPlan,
+    OnConflict, Rollback, DEFAULT_PIP_MAX_FAILURES,
+};
+pub use lock::EnvLock;
+pub use marker::disown;
+pub use prefix::read_prefix_recipe;
+pub use priority::{set_child_priority, set_conda_bin, ChildPriority, IoClass, IoNice};
+pub use sandbox::Sandbox;
+pub use search::{search, SearchHit};
+pub use solver::{conflict_summary, Solver};
+pub use staged::{
+    discard_staged, prepare_staged, prev_env_name, rollback_staged, staged_env_name, swap_staged,
+};
+pub use tools::{BlockedTool, Severity};
+pub use transcript::{record_transcript, take_transcript};
+pub use uninstall::uninstall;
 
-use std::{ffi::OsStr, process::Stdio};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output, Stdio},
+    time::{Duration, SystemTime},
+};
 
+use serde::{Deserialize, Serialize};
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncRead, AsyncReadExt},
     process::{Child, Command},
+    task::JoinHandle,
 };
 
-use crate::recipe::Recipe;
+use crate::{error::Error, output::human::format_count, recipe::Recipe, selector::VirtualPackages};
+use priority::Executable;
+use sandbox::EnvSandbox;
 
-/// this function will not block and return Child
-fn spawn_conda<I, S>(args: I) -> std::io::Result<Child>
-where
-    I: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
-{
-    Command::new("conda")
-        .args(args)
+/// the command of the executable, in the sandbox of the env when there is one
+fn command_in(
+    sandbox: Option<&EnvSandbox>,
+    executable: Executable,
+    args: &[String],
+) -> std::io::Result<Command> {
+    match sandbox {
+        Some(sandbox) => sandbox.command(executable, args),
+        None => {
+            let mut command = priority::command(executable);
+            command.args(args);
+            Ok(command)
+        }
+    }
+}
+
+/// this function will not block and return Child, the caller records its output by
+/// `transcript::record`
+fn spawn_command(mut command: Command) -> std::io::Result<Child> {
+    command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        // conda is killed when a cancelled future drops it
+        .kill_on_drop(true)
         .spawn()
 }
 
@@ -30,29 +95,393 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let mut process = spawn_conda(args)?;
-    let mut msg = String::new();
-    if process.wait().await.unwrap().success() {
-        let mut stdout = process.stdout.unwrap();
-        let _ = stdout.read_to_string(&mut msg).await;
-        Ok(msg)
+    run_conda_with_timeout(args, None).await
+}
+
+/// the same as `run_conda`, but conda is killed when it runs longer than `timeout`
+async fn run_conda_with_timeout<I, S>(args: I, timeout: Option<Duration>) -> anyhow::Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = priority::command(Executable::Conda);
+    command.args(args);
+    run_command(command, timeout).await
+}
+
+/// the same as `run_conda`, but by the given executable, in the sandbox of the env when there
+/// is one
+async fn run_in(
+    sandbox: Option<&EnvSandbox>,
+    executable: Executable,
+    args: &[String],
+) -> anyhow::Result<String> {
+    run_command(command_in(sandbox, executable, args)?, None).await
+}
+
+/// the stdout of the command when it succeeds
+async fn run_command(command: Command, timeout: Option<Duration>) -> anyhow::Result<String> {
+    let argv = transcript::argv(&command);
+    let mut process = spawn_command(command).map_err(spawn_error)?;
+    // both pipes are drained while waiting, a full pipe would block conda
+    let stdout = read_pipe(process.stdout.take());
+    let stderr = read_pipe(process.stderr.take());
+    let status = match wait_with_timeout(&mut process, timeout).await {
+        Ok(status) => status,
+        Err(err) => {
+            transcript::record(&argv, "", "", None);
+            return Err(err);
+        }
+    };
+    let (stdout, stderr) = (
+        stdout.await.unwrap_or_default(),
+        stderr.await.unwrap_or_default(),
+    );
+    transcript::record(&argv, &stdout, &stderr, Some(status));
+    if status.success() {
+        Ok(stdout)
     } else {
-        let mut stderr = process.stderr.unwrap();
-        let _ = stderr.read_to_string(&mut msg).await;
-        Err(anyhow::anyhow!(msg))
+        match Error::from_conda_stderr(&stderr) {
+            Some(error) => Err(error.into()),
+            None => Err(anyhow::anyhow!(stderr)),
+        }
+    }
+}
+
+fn read_pipe(pipe: Option<impl AsyncRead + Unpin + Send + 'static>) -> JoinHandle<String> {
+    tokio::spawn(async move {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output).await;
+        }
+        output
+    })
+}
+
+/// run conda to the end, its output is returned whether it succeeds or not, e.g. for the
+/// `--json` commands which report their errors on stdout
+async fn conda_output<I, S>(args: I) -> anyhow::Result<Output>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = priority::command(Executable::Conda);
+    command.args(args);
+    let argv = transcript::argv(&command);
+    let output = spawn_command(command)
+        .map_err(spawn_error)?
+        .wait_with_output()
+        .await?;
+    transcript::record(
+        &argv,
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+        Some(output.status),
+    );
+    Ok(output)
+}
+
+fn spawn_error(e: std::io::Error) -> anyhow::Error {
+    match Error::from_io_error(&e) {
+        Some(error) => anyhow::Error::from(error),
+        None => anyhow::Error::from(e),
+    }
+}
+
+async fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> anyhow::Result<ExitStatus> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(child.wait().await?),
+    };
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => Ok(status?),
+        Err(_) => {
+            child.kill().await?;
+            Err(Error::Timeout(timeout).into())
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_wait_with_timeout() -> anyhow::Result<()> {
+    let mut child = tokio::process::Command::new("sleep").arg("10").spawn()?;
+    let err = wait_with_timeout(&mut child, Some(Duration::from_millis(100)))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::Timeout(_))
+    ));
+    assert!(child.try_wait()?.is_some());
+
+    let mut child = tokio::process::Command::new("true").spawn()?;
+    let status = wait_with_timeout(&mut child, Some(Duration::from_secs(10))).await?;
+    assert!(status.success());
+
+    Ok(())
+}
+
+/// keep only the last `max_lines` lines of the output of a failed conda command
+#[doc(hidden)]
+pub fn tail_log(log: &str, max_lines: usize) -> String {
+    let lines = log.lines().collect::<Vec<_>>();
+    if lines.len() <= max_lines {
+        return log.to_string();
+    }
+    format!(
+        "... truncated {} lines ...\n{}",
+        format_count(lines.len() - max_lines),
+        lines[lines.len() - max_lines..].join("\n")
+    )
+}
+
+#[test]
+fn test_tail_log() {
+    let log = "line1\nline2\nline3\nline4";
+    assert_eq!(tail_log(log, 2), "... truncated 2 lines ...\nline3\nline4");
+    assert_eq!(tail_log(log, 4), log);
+    assert_eq!(tail_log(log, 10), log);
+}
+
+/// the recipe of a local env and when it was taken
+#[derive(Debug, Clone)]
+pub struct EnvSnapshot {
+    recipe: Recipe,
+    taken_at: SystemTime,
+    conda_version: Option<String>,
+}
+
+impl EnvSnapshot {
+    pub fn recipe(&self) -> &Recipe {
+        &self.recipe
+    }
+
+    pub fn into_recipe(self) -> Recipe {
+        self.recipe
     }
+
+    /// e.g. `23.7.4`, `None` if `conda --version` failed
+    pub fn conda_version(&self) -> Option<&str> {
+        self.conda_version.as_deref()
+    }
+
+    pub fn age(&self) -> Duration {
+        self.taken_at.elapsed().unwrap_or_default()
+    }
+}
+
+/// the args of `conda list` for the env addressed by name or by prefix
+fn list_args(flag: &str, env: &OsStr) -> Vec<OsString> {
+    vec!["list".into(), flag.into(), env.into()]
+}
+
+#[test]
+fn test_list_args() {
+    assert_eq!(
+        list_args("-p", std::path::Path::new("/opt/envs/demo").as_os_str()),
+        ["list", "-p", "/opt/envs/demo"]
+    );
+    assert_eq!(list_args("-n", OsStr::new("demo")), ["list", "-n", "demo"]);
 }
 
-pub async fn try_get_env_recipe(env_name: &str) -> anyhow::Result<Option<Recipe>> {
-    Ok(match run_conda(["list", "-n", env_name]).await {
-        Ok(contents) => Some(Recipe::try_from(contents.as_str()).map_err(|e| anyhow::anyhow!(e))?),
+async fn get_env_snapshot(
+    args: Vec<OsString>,
+    lock_key: String,
+    lock: bool,
+) -> anyhow::Result<Option<EnvSnapshot>> {
+    // the lock makes sure no install is mutating the env while listing it
+    let _lock = if lock {
+        Some(EnvLock::shared(&lock_key).await?)
+    } else {
+        None
+    };
+    let recipe = match run_conda(args).await {
+        Ok(contents) => Recipe::from_env(&contents).map_err(|e| anyhow::anyhow!(e))?,
         Err(error) => {
-            if error.to_string().contains("EnvironmentLocationNotFound") {
-                None
+            if let Some(Error::EnvNotFound(_)) = error.downcast_ref::<Error>() {
+                return Ok(None);
             } else {
                 // get env recipe failed
                 return Err(error);
             }
         }
-    })
+    };
+    let taken_at = SystemTime::now();
+    let (conda_version, _) = info_cache::probe_conda_version().await;
+    Ok(Some(EnvSnapshot {
+        recipe,
+        taken_at,
+        conda_version,
+    }))
+}
+
+/// an env addressed by name or by prefix, like `-n` and `-p` of conda
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvTarget {
+    Name(String),
+    Prefix(PathBuf),
+}
+
+impl EnvTarget {
+    /// `-n <name>` or `-p <prefix>`
+    pub fn args(&self) -> Vec<String> {
+        match self {
+            EnvTarget::Name(name) => vec!["-n".to_string(), name.clone()],
+            EnvTarget::Prefix(prefix) => vec!["-p".to_string(), prefix.display().to_string()],
+        }
+    }
+
+    fn lock_key(&self) -> String {
+        match self {
+            EnvTarget::Name(name) => env_lock_key(name),
+            EnvTarget::Prefix(prefix) => format!("prefix-{}", prefix.display()),
+        }
+    }
+}
+
+impl From<&str> for EnvTarget {
+    fn from(env_name: &str) -> Self {
+        EnvTarget::Name(env_name.to_string())
+    }
+}
+
+impl std::fmt::Display for EnvTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvTarget::Name(name) => write!(f, "{}", name),
+            EnvTarget::Prefix(prefix) => write!(f, "{}", prefix.display()),
+        }
+    }
+}
+
+/// `lock` waits for the running install of the env, don't set it while holding the env lock
+pub async fn try_get_target_recipe(
+    target: &EnvTarget,
+    lock: bool,
+) -> anyhow::Result<Option<EnvSnapshot>> {
+    let args = match target {
+        EnvTarget::Name(name) => list_args("-n", OsStr::new(name)),
+        EnvTarget::Prefix(prefix) => list_args("-p", prefix.as_os_str()),
+    };
+    get_env_snapshot(args, target.lock_key(), lock).await
+}
+
+/// the same as `try_get_target_recipe`, but the env is addressed by its name
+pub async fn try_get_env_recipe(env_name: &str, lock: bool) -> anyhow::Result<Option<EnvSnapshot>> {
+    try_get_target_recipe(&env_name.into(), lock).await
+}
+
+/// the same as `try_get_target_recipe`, but the env is addressed by its prefix
+pub async fn try_get_env_recipe_at(
+    prefix: &Path,
+    lock: bool,
+) -> anyhow::Result<Option<EnvSnapshot>> {
+    try_get_target_recipe(&EnvTarget::Prefix(prefix.to_path_buf()), lock).await
+}
+
+fn env_lock_key(env_name: &str) -> String {
+    format!("name-{}", env_name)
+}
+
+#[test]
+fn test_env_target() {
+    let target = EnvTarget::from("demo");
+    assert_eq!(target.args(), ["-n", "demo"]);
+    assert_eq!(target.lock_key(), "name-demo");
+    assert_eq!(target.to_string(), "demo");
+
+    let target = EnvTarget::Prefix(PathBuf::from("/nfs/envs/demo"));
+    assert_eq!(target.args(), ["-p", "/nfs/envs/demo"]);
+    assert_eq!(target.lock_key(), "prefix-/nfs/envs/demo");
+    assert_eq!(target.to_string(), "/nfs/envs/demo");
+}
+
+/// the parts of `conda info --json` in use
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CondaInfo {
+    /// the prefixes of all known envs, including the base env
+    pub envs: Vec<PathBuf>,
+    /// `conda create -n` creates envs in the first of them
+    pub envs_dirs: Vec<PathBuf>,
+    pub pkgs_dirs: Vec<PathBuf>,
+    /// `[name, version, build]` of each detected virtual package
+    #[serde(default)]
+    pub virtual_pkgs: Vec<Vec<String>>,
+}
+
+async fn conda_info() -> anyhow::Result<CondaInfo> {
+    Ok(load_conda_info().await?.0)
+}
+
+/// `conda info --json`, and whether it was served from the cache, see `set_info_cache`
+pub async fn load_conda_info() -> anyhow::Result<(CondaInfo, InfoSource)> {
+    info_cache::cached_conda_info().await
+}
+
+/// the package caches of conda, in the order conda looks them up
+pub async fn pkgs_dirs() -> anyhow::Result<Vec<PathBuf>> {
+    Ok(conda_info().await?.pkgs_dirs)
+}
+
+/// the virtual packages conda detects on this machine, with the `CONDA_OVERRIDE_*` variables
+/// applied, never cached since an upgraded glibc or CUDA driver changes them
+async fn virtual_packages() -> anyhow::Result<VirtualPackages> {
+    let detected = info_cache::fresh_info()
+        .await?
+        .virtual_pkgs
+        .into_iter()
+        .filter_map(|pkg| match pkg.as_slice() {
+            [name, version, ..] => Some((name.clone(), version.clone())),
+            _ => None,
+        });
+    Ok(VirtualPackages::new(detected).with_overrides(std::env::vars()))
+}
+
+#[derive(Deserialize)]
+struct EnvList {
+    envs: Vec<PathBuf>,
+}
+
+/// the prefixes of the envs under the `envs` dirs by name, by `conda env list --json`
+async fn env_prefixes() -> anyhow::Result<HashMap<String, PathBuf>> {
+    let list: EnvList = serde_json::from_str(&run_conda(["env", "list", "--json"]).await?)?;
+    Ok(named_prefixes(list.envs))
+}
+
+fn named_prefixes(prefixes: Vec<PathBuf>) -> HashMap<String, PathBuf> {
+    prefixes
+        .into_iter()
+        .filter_map(|prefix| {
+            // the base env isn't under an `envs` dir
+            if prefix.parent()?.file_name()? != "envs" {
+                return None;
+            }
+            let name = prefix.file_name()?.to_str()?.to_string();
+            Some((name, prefix))
+        })
+        .collect()
+}
+
+#[test]
+fn test_named_prefixes() {
+    let prefixes = named_prefixes(
+        [
+            "/opt/conda",
+            "/opt/conda/envs/demo",
+            "/data/envs/other",
+            "/data/custom",
+        ]
+        .map(PathBuf::from)
+        .to_vec(),
+    );
+    assert_eq!(
+        prefixes,
+        HashMap::from([
+            ("demo".to_string(), PathBuf::from("/opt/conda/envs/demo")),
+            ("other".to_string(), PathBuf::from("/data/envs/other")),
+        ])
+    );
 }
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    recipe::{canonical_channel, Recipe},
+    selector::PLATFORMS,
+};
+
+/// a package record in `conda-meta/`, only the fields a recipe needs
+#[derive(Debug, Deserialize)]
+struct PrefixRecord {
+    name: String,
+    version: String,
+    build: String,
+    /// e.g. `https://conda.anaconda.org/conda-forge/linux-64`
+    #[serde(default)]
+    channel: String,
+    /// the paths of the package relative to the prefix
+    #[serde(default)]
+    files: Vec<String>,
+}
+
+/// the recipe of the env at the prefix, read from its files without conda, e.g. of the
+/// mounted filesystem of a docker image
+pub fn read_prefix_recipe(prefix: &Path) -> anyhow::Result<Recipe> {
+    let conda_meta = prefix.join("conda-meta");
+    if !conda_meta.is_dir() {
+        anyhow::bail!("'{}' isn't a conda env prefix", prefix.display());
+    }
+    let records = read_prefix_records(&conda_meta)?;
+    let conda_files = records
+        .iter()
+        .flat_map(|r| r.files.iter().map(|f| f.replace('\\', "/")))
+        .collect::<HashSet<_>>();
+    let mut lines = records
+        .iter()
+        .map(|r| match channel_name(&r.channel) {
+            // `defaults` is implicit like in `conda list`
+            Some(channel) if channel != "defaults" => {
+                format!("{} {} {} {}", r.name, r.version, r.build, channel)
+            }
+            _ => format!("{} {} {}", r.name, r.version, r.build),
+        })
+        .collect::<Vec<_>>();
+    for (name, version) in scan_dist_infos(prefix, &conda_files)? {
+        lines.push(format!("{} {} pypi_0 pypi", name, version));
+    }
+    Recipe::from_env(&lines.join("\n")).map_err(|e| anyhow::anyhow!(e))
+}
+
+fn read_prefix_records(conda_meta: &Path) -> anyhow::Result<Vec<PrefixRecord>> {
+    let mut records = vec![];
+    for entry in std::fs::read_dir(conda_meta)? {
+        let path = entry?.path();
+        // `history` and the markers of other tools live there too
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let record = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|e| anyhow::anyhow!("invalid package record '{}': {}", path.display(), e))?;
+        records.push(record);
+    }
+    records.sort_by(|a: &PrefixRecord, b| a.name.cmp(&b.name));
+    Ok(records)
+}
+
+/// the channel name of a record, without the subdir, `None` if the record has none
+pub(super) fn channel_name(channel: &str) -> Option<String> {
+    let channel = channel.trim_end_matches('/');
+    if channel.is_empty() || channel == "<unknown>" {
+        return None;
+    }
+    let channel = match channel.rsplit_once('/') {
+        Some((channel, subdir)) if subdir == "noarch" || PLATFORMS.contains(&subdir) => channel,
+        _ => channel,
+    };
+    Some(canonical_channel(channel, &[]))
+}
+
+/// the `(name, version)` of the pypi dists in the site-packages which aren't owned by a conda
+/// package, sorted by name
+fn scan_dist_infos(
+    prefix: &Path,
+    conda_files: &HashSet<String>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut dists = vec![];
+    for site_packages in site_packages_dirs(prefix)? {
+        for entry in std::fs::read_dir(&site_packages)? {
+            let dist_info = entry?.path();
+            if dist_info.extension().is_none_or(|ext| ext != "dist-info") {
+                continue;
+            }
+            let metadata = dist_info.join("METADATA");
+            let relative = metadata
+                .strip_prefix(prefix)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            if conda_files.contains(&relative) || !metadata.is_file() {
+                continue;
+            }
+            if let Some(dist) = parse_metadata(&std::fs::read_to_string(&metadata)?) {
+                dists.push(dist);
+            }
+        }
+    }
+    dists.sort();
+    Ok(dists)
+}
+
+/// `lib/python3.*/site-packages` on unix and `Lib/site-packages` on windows
+fn site_packages_dirs(prefix: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+    let lib = prefix.join("lib");
+    if lib.is_dir() {
+        for entry in std::fs::read_dir(&lib)? {
+            let path = entry?.path();
+            let is_python = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("python"));
+            if is_python && path.join("site-packages").is_dir() {
+                dirs.push(path.join("site-packages"));
+            }
+        }
+    }
+    let windows = prefix.join("Lib").join("site-packages");
+    if windows.is_dir() && !dirs.contains(&windows) {
+        dirs.push(windows);
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// the `Name` and `Version` headers of a `METADATA` file, which end at the first blank line
+fn parse_metadata(contents: &str) -> Option<(String, String)> {
+    let mut name = None;
+    let mut version = None;
+    for line in contents.lines().take_while(|line| !line.trim().is_empty()) {
+        match line.split_once(':') {
+            Some(("Name", value)) => name = Some(value.trim().to_string()),
+            Some(("Version", value)) => version = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    Some((name?, version?))
+}
+
+#[test]
+fn test_parse_metadata() {
+    assert_eq!(
+        parse_metadata(
+            "Metadata-Version: 2.1\nName: typing_extensions\nVersion: 4.4.0\nSummary: x\n\nName: body\n"
+        ),
+        Some(("typing_extensions".to_string(), "4.4.0".to_string()))
+    );
+    assert_eq!(
+        parse_metadata("Metadata-Version: 2.1\nName: broken\n"),
+        None
+    );
+    assert_eq!(parse_metadata("\nName: late\nVersion: 1.0\n"), None);
+}
+
+#[test]
+fn test_channel_name() {
+    for (channel, expected) in [
+        (
+            "https://conda.anaconda.org/conda-forge/linux-64",
+            Some("conda-forge"),
+        ),
+        (
+            "https://conda.anaconda.org/conda-forge/noarch/",
+            Some("conda-forge"),
+        ),
+        (
+            "https://repo.anaconda.com/pkgs/main/osx-arm64",
+            Some("defaults"),
+        ),
+        ("pkgs/main", Some("defaults")),
+        ("file:///opt/channels/local/linux-64", Some("local")),
+        ("<unknown>", None),
+        ("", None),
+    ] {
+        assert_eq!(channel_name(channel).as_deref(), expected, "{}", channel);
+    }
+}
+
+#[test]
+fn test_read_prefix_recipe() {
+    let prefix = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/prefix");
+    let recipe = read_prefix_recipe(&prefix).unwrap();
+    assert_eq!(
+        recipe.to_string(),
+        Recipe::try_from(
+            r#"
+numpy                     1.24.1           py310h8deb116_0    conda-forge
+python                    3.10.8           h4a9ceb5_0_cpython    conda-forge
+zlib                      1.2.13               h166bdaf_4
+requests                  2.28.2                   pypi_0    pypi
+typing_extensions         4.4.0                    pypi_0    pypi
+"#
+        )
+        .unwrap()
+        .to_string()
+    );
+
+    assert!(read_prefix_recipe(&prefix.join("lib")).is_err());
+}
@@ -0,0 +1,212 @@
+use std::{
+    ffi::{OsStr, OsString},
+    sync::OnceLock,
+};
+
+use tokio::process::Command;
+
+static CHILD_PRIORITY: OnceLock<ChildPriority> = OnceLock::new();
+static CONDA_BIN: OnceLock<OsString> = OnceLock::new();
+
+/// limits the resources of the spawned conda processes, e.g. on a shared login node
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChildPriority {
+    /// the niceness added by `nice -n`
+    pub nice: Option<i32>,
+    /// only applied on linux
+    pub ionice: Option<IoNice>,
+    /// the number of threads conda uses, by `CONDA_DEFAULT_THREADS`
+    pub threads: Option<usize>,
+}
+
+/// the io scheduling class and level of `ionice`, parsed from `class[:level]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoNice {
+    pub class: IoClass,
+    /// 0 (highest) to 7 (lowest), only for the realtime and best-effort classes
+    pub level: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl std::str::FromStr for IoNice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid ionice '{}', expected `idle`, `best-effort[:0-7]` or `realtime[:0-7]`",
+                s
+            )
+        };
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level.parse::<u8>().map_err(|_| err())?)),
+            None => (s, None),
+        };
+        let class = match class {
+            "realtime" | "1" => IoClass::Realtime,
+            "best-effort" | "2" => IoClass::BestEffort,
+            "idle" | "3" => IoClass::Idle,
+            _ => return Err(err()),
+        };
+        match (class, level) {
+            (_, Some(level)) if level > 7 => Err(err()),
+            (IoClass::Idle, Some(_)) => Err(err()),
+            _ => Ok(Self { class, level }),
+        }
+    }
+}
+
+impl ChildPriority {
+    /// the argv which runs `program` with the priority, by wrapping it with `ionice` and `nice`
+    fn wrap(&self, program: &OsStr, linux: bool) -> Vec<OsString> {
+        let mut argv: Vec<OsString> = vec![];
+        if let Some(ionice) = self.ionice.filter(|_| linux) {
+            let class = match ionice.class {
+                IoClass::Realtime => "1",
+                IoClass::BestEffort => "2",
+                IoClass::Idle => "3",
+            };
+            argv.extend(["ionice".into(), "-c".into(), class.into()]);
+            if let Some(level) = ionice.level {
+                argv.extend(["-n".into(), level.to_string().into()]);
+            }
+        }
+        if let Some(nice) = self.nice {
+            argv.extend(["nice".into(), "-n".into(), nice.to_string().into()]);
+        }
+        argv.push(program.to_os_string());
+        argv
+    }
+}
+
+/// set the priority of all the conda processes spawned later, it can only be set once
+pub fn set_child_priority(priority: ChildPriority) -> anyhow::Result<()> {
+    CHILD_PRIORITY
+        .set(priority)
+        .map_err(|_| anyhow::anyhow!("the priority of child processes is already set"))
+}
+
+/// set the conda binary of all the conda processes spawned later, e.g. `mamba` or an absolute
+/// path, it can only be set once
+pub fn set_conda_bin(conda_bin: impl Into<OsString>) -> anyhow::Result<()> {
+    CONDA_BIN
+        .set(conda_bin.into())
+        .map_err(|_| anyhow::anyhow!("the conda binary is already set"))
+}
+
+/// `conda` found in `PATH` unless it's set
+fn conda_bin() -> &'static OsStr {
+    CONDA_BIN
+        .get()
+        .map_or(OsStr::new("conda"), OsString::as_os_str)
+}
+
+/// the binary which runs the conda subcommands
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(super) enum Executable {
+    #[default]
+    Conda,
+    /// `mamba` in `PATH`, which accepts the `create`, `install` and `remove` subcommands of conda
+    Mamba,
+}
+
+impl Executable {
+    pub fn program(self) -> &'static OsStr {
+        match self {
+            Executable::Conda => conda_bin(),
+            Executable::Mamba => OsStr::new("mamba"),
+        }
+    }
+}
+
+/// a command of the executable with the priority applied
+pub(super) fn command(executable: Executable) -> Command {
+    program_command(executable.program())
+}
+
+/// a command of any program with the priority applied, e.g. the sandbox of the hooks
+pub(super) fn program_command(program: &OsStr) -> Command {
+    let priority = CHILD_PRIORITY.get().cloned().unwrap_or_default();
+    let argv = priority.wrap(program, cfg!(target_os = "linux"));
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    if let Some(threads) = priority.threads {
+        command.env("CONDA_DEFAULT_THREADS", threads.to_string());
+    }
+    command
+}
+
+#[test]
+fn test_parse_ionice() {
+    assert_eq!(
+        "idle".parse(),
+        Ok(IoNice {
+            class: IoClass::Idle,
+            level: None
+        })
+    );
+    assert_eq!(
+        "best-effort:7".parse(),
+        Ok(IoNice {
+            class: IoClass::BestEffort,
+            level: Some(7)
+        })
+    );
+    assert_eq!(
+        "1:0".parse(),
+        Ok(IoNice {
+            class: IoClass::Realtime,
+            level: Some(0)
+        })
+    );
+    for invalid in ["", "low", "best-effort:8", "idle:1", "realtime:"] {
+        assert!(invalid.parse::<IoNice>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_wrap_with_priority() {
+    assert_eq!(
+        ChildPriority::default().wrap(OsStr::new("conda"), true),
+        ["conda"]
+    );
+
+    let priority = ChildPriority {
+        nice: Some(10),
+        ionice: Some("best-effort:7".parse().unwrap()),
+        threads: Some(2),
+    };
+    assert_eq!(
+        priority.wrap(OsStr::new("conda"), true),
+        ["ionice", "-c", "2", "-n", "7", "nice", "-n", "10", "conda"]
+    );
+    // ionice is ignored out of linux
+    assert_eq!(
+        priority.wrap(OsStr::new("conda"), false),
+        ["nice", "-n", "10", "conda"]
+    );
+
+    let priority = ChildPriority {
+        ionice: Some("idle".parse().unwrap()),
+        ..Default::default()
+    };
+    assert_eq!(
+        priority.wrap(OsStr::new("conda"), true),
+        ["ionice", "-c", "3", "conda"]
+    );
+
+    assert_eq!(
+        ChildPriority {
+            nice: Some(5),
+            ..Default::default()
+        }
+        .wrap(OsStr::new("/opt/mamba/bin/mamba"), true),
+        ["nice", "-n", "5", "/opt/mamba/bin/mamba"]
+    );
+}
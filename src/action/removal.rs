@@ -0,0 +1,225 @@
+use std::{
+    future::Future,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    select,
+};
+
+use super::{
+    priority::{self, Executable},
+    spawn_command, spawn_error, transcript,
+};
+use crate::{
+    error::Error,
+    output::{
+        human::{format_count, format_duration},
+        progress::ProgressSink,
+    },
+};
+
+/// `==> UNLINKING PACKAGE: <channel>::<id> <==` of conda at verbosity 2
+const UNLINK_PATTERN: &str = "==> UNLINKING PACKAGE: (?:.*?)::(.*) <==";
+
+/// how often the records left in the prefix are counted
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the id of the package the line of the removal output unlinks
+fn parse_unlink<'l>(pattern: &Regex, line: &'l str) -> Option<&'l str> {
+    pattern
+        .captures(line)
+        .and_then(|cap| cap.get(1))
+        .map(|m| m.as_str())
+}
+
+/// the package records left in the `conda-meta` of the prefix, 0 once it's gone
+fn count_records(prefix: &Path) -> usize {
+    std::fs::read_dir(prefix.join("conda-meta")).map_or(0, |entries| {
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+            .count()
+    })
+}
+
+/// run `removal` while counting the records left in the prefix every `interval`, `on_tick`
+/// gets the count, or `None` without a prefix
+async fn poll_records<T>(
+    prefix: Option<&Path>,
+    interval: Duration,
+    removal: impl Future<Output = T>,
+    mut on_tick: impl FnMut(Option<usize>),
+) -> T {
+    tokio::pin!(removal);
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        select! {
+            result = &mut removal => return result,
+            _ = ticks.tick() => on_tick(prefix.map(count_records)),
+        }
+    }
+}
+
+/// run `conda env remove`, the progress message counts the removed packages by the unlink
+/// lines of conda, or by the records left in `prefix` when conda doesn't print them
+///
+/// conda is killed when the future is dropped, e.g. on ctrl c
+pub(super) async fn remove_env(
+    executable: Executable,
+    args: &[String],
+    prefix: Option<&Path>,
+    env_name: &str,
+    progress: &dyn ProgressSink,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let total = prefix.map_or(0, count_records);
+    let removed = AtomicUsize::new(0);
+    let message = |removed: usize| {
+        if total > 0 {
+            format!(
+                "removing env '{}': {} of {} pkgs removed in {}...",
+                env_name,
+                format_count(removed.min(total)),
+                format_count(total),
+                format_duration(started.elapsed())
+            )
+        } else {
+            format!(
+                "removing env '{}' in {}...",
+                env_name,
+                format_duration(started.elapsed())
+            )
+        }
+    };
+    let removal = async {
+        let pattern = Regex::new(UNLINK_PATTERN).unwrap();
+        let mut command = priority::command(executable);
+        command
+            .args(args)
+            // the unlink lines are only printed at verbosity 2
+            .env("CONDA_VERBOSITY", "2");
+        let argv = transcript::argv(&command);
+        let mut child = spawn_command(command).map_err(spawn_error)?;
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+        let mut stdout_lines = vec![];
+        let mut stderr_lines = vec![];
+        let (mut stdout_done, mut stderr_done) = (false, false);
+        let feed = |line: &str| {
+            if parse_unlink(&pattern, line).is_some() {
+                let count = removed.fetch_add(1, Ordering::Relaxed) + 1;
+                progress.set_message(&message(count));
+            }
+        };
+        while !stdout_done || !stderr_done {
+            select! {
+                line = stdout.next_line(), if !stdout_done => match line {
+                    Ok(Some(line)) => {
+                        feed(&line);
+                        stdout_lines.push(line);
+                    }
+                    _ => stdout_done = true,
+                },
+                line = stderr.next_line(), if !stderr_done => match line {
+                    Ok(Some(line)) => {
+                        feed(&line);
+                        stderr_lines.push(line);
+                    }
+                    _ => stderr_done = true,
+                },
+            }
+        }
+        let status = child.wait().await?;
+        let stderr = stderr_lines.join("\n");
+        transcript::record(&argv, &stdout_lines.join("\n"), &stderr, Some(status));
+        if !status.success() {
+            return Err(match Error::from_conda_stderr(&stderr) {
+                Some(error) => error.into(),
+                None => anyhow::anyhow!(stderr),
+            });
+        }
+        anyhow::Ok(())
+    };
+    poll_records(prefix, POLL_INTERVAL, removal, |left| {
+        let unlinked = removed.load(Ordering::Relaxed);
+        let removed = match left {
+            Some(left) => unlinked.max(total.saturating_sub(left)),
+            None => unlinked,
+        };
+        progress.set_message(&message(removed));
+    })
+    .await
+}
+
+#[test]
+fn test_parse_unlink() {
+    let pattern = Regex::new(UNLINK_PATTERN).unwrap();
+    let output = include_str!("../../fixtures/removal/env-remove-vv.txt");
+    let unlinked = output
+        .lines()
+        .filter_map(|line| parse_unlink(&pattern, line))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        unlinked,
+        [
+            "numpy-1.24.1-py310h8deb116_0",
+            "python-3.10.8-h4a9ceb5_0_cpython",
+            "zlib-1.2.13-h166bdaf_4",
+        ]
+    );
+    assert_eq!(
+        parse_unlink(
+            &pattern,
+            "==> LINKING PACKAGE: defaults::zlib-1.2.13-h166bdaf_4 <=="
+        ),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_poll_shrinking_prefix() -> anyhow::Result<()> {
+    let prefix = std::env::temp_dir().join("conda-cage-test-removal");
+    let _ = std::fs::remove_dir_all(&prefix);
+    let conda_meta = prefix.join("conda-meta");
+    std::fs::create_dir_all(&conda_meta)?;
+    let records = [
+        "numpy-1.24.1-0.json",
+        "python-3.10.8-0.json",
+        "zlib-1.2.13-0.json",
+    ];
+    for record in records {
+        std::fs::write(conda_meta.join(record), "{}")?;
+    }
+    std::fs::write(conda_meta.join("history"), "")?;
+    assert_eq!(count_records(&prefix), 3);
+
+    let removal = async {
+        for record in records {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            std::fs::remove_file(conda_meta.join(record))?;
+        }
+        std::fs::remove_dir_all(&prefix)?;
+        anyhow::Ok("removed")
+    };
+    let mut counts = vec![];
+    let result = poll_records(Some(&prefix), Duration::from_millis(10), removal, |left| {
+        counts.push(left.unwrap())
+    })
+    .await?;
+
+    assert_eq!(result, "removed");
+    assert_eq!(counts.first(), Some(&3));
+    assert!(counts.windows(2).all(|w| w[0] >= w[1]), "{:?}", counts);
+    // polling stops with the removal, the gone prefix counts as empty
+    assert_eq!(count_records(&prefix), 0);
+
+    // a removal which finishes first isn't held up by the poller
+    let result = poll_records(None, Duration::from_secs(60), async { 42 }, |_| {}).await;
+    assert_eq!(result, 42);
+    Ok(())
+}
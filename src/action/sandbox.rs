@@ -0,0 +1,198 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use super::{
+    conda_info,
+    marker::target_prefix,
+    priority::{program_command, Executable},
+    EnvTarget,
+};
+
+/// run the post install hooks, and the post-link scripts of the packages conda installs, under
+/// `bwrap`, the env prefix and the pkgs dirs are writable while the rest of the filesystem is
+/// read-only
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sandbox {
+    /// keep the network of the hooks
+    pub network: bool,
+}
+
+impl Sandbox {
+    /// the argv which runs `argv` in the sandbox
+    pub fn wrap(&self, prefix: &Path, pkgs_dirs: &[PathBuf], argv: &[String]) -> Vec<String> {
+        let mut wrapped = [
+            "bwrap",
+            "--ro-bind",
+            "/",
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+        ]
+        .map(String::from)
+        .to_vec();
+        // conda run writes its script to the temp dir
+        wrapped.extend(["--tmpfs", "/tmp"].map(String::from));
+        for dir in [prefix]
+            .into_iter()
+            .chain(pkgs_dirs.iter().map(PathBuf::as_path))
+        {
+            let dir = dir.display().to_string();
+            wrapped.extend(["--bind".to_string(), dir.clone(), dir]);
+        }
+        if !self.network {
+            wrapped.push("--unshare-net".to_string());
+        }
+        wrapped.extend(
+            [
+                "--setenv",
+                "CONDA_CAGE_SANDBOX",
+                "1",
+                "--die-with-parent",
+                "--",
+            ]
+            .map(String::from),
+        );
+        wrapped.extend(argv.iter().cloned());
+        wrapped
+    }
+
+    /// whether `bwrap` can be run, it's only available on linux
+    pub fn is_available() -> bool {
+        cfg!(target_os = "linux")
+            && std::process::Command::new("bwrap")
+                .arg("--version")
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+    }
+}
+
+/// the sandbox of the conda commands which change an env, so the post-link scripts of its
+/// packages only write the env and the pkgs dirs
+#[derive(Debug, Clone)]
+pub(super) struct EnvSandbox {
+    sandbox: Sandbox,
+    prefix: PathBuf,
+    pkgs_dirs: Vec<PathBuf>,
+}
+
+impl EnvSandbox {
+    /// the sandbox of the env, which may not exist yet, conda downloads the packages so the
+    /// network is always kept
+    pub(super) async fn new(target: &EnvTarget) -> anyhow::Result<Self> {
+        let info = conda_info().await?;
+        let prefix = match target_prefix(target).await? {
+            Some(prefix) => prefix,
+            None => match target {
+                // `conda create -n` creates the env in the first envs dir
+                EnvTarget::Name(name) => info
+                    .envs_dirs
+                    .first()
+                    .map(|dir| dir.join(name))
+                    .ok_or_else(|| anyhow::anyhow!("conda has no envs dir to create '{}'", name))?,
+                EnvTarget::Prefix(prefix) => prefix.clone(),
+            },
+        };
+        Ok(Self {
+            sandbox: Sandbox { network: true },
+            prefix,
+            pkgs_dirs: info.pkgs_dirs,
+        })
+    }
+
+    /// the command which runs the executable with the args in the sandbox
+    pub(super) fn command(
+        &self,
+        executable: Executable,
+        args: &[String],
+    ) -> std::io::Result<Command> {
+        // `bwrap` only binds existing dirs, and conda creates an env into an empty dir
+        std::fs::create_dir_all(&self.prefix)?;
+        let program = executable.program().to_string_lossy().into_owned();
+        let argv = self.sandbox.wrap(
+            &self.prefix,
+            &self.pkgs_dirs,
+            &[vec![program], args.to_vec()].concat(),
+        );
+        let mut command = program_command(OsStr::new(&argv[0]));
+        command.args(&argv[1..]);
+        Ok(command)
+    }
+}
+
+#[test]
+fn test_wrap_in_sandbox() {
+    let argv = ["conda", "run", "-n", "demo", "sh", "-c", "make"].map(String::from);
+    let prefix = Path::new("/opt/conda/envs/demo");
+    let pkgs_dirs = [PathBuf::from("/opt/conda/pkgs")];
+    assert_eq!(
+        Sandbox::default().wrap(prefix, &pkgs_dirs, &argv),
+        [
+            "bwrap",
+            "--ro-bind",
+            "/",
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "--tmpfs",
+            "/tmp",
+            "--bind",
+            "/opt/conda/envs/demo",
+            "/opt/conda/envs/demo",
+            "--bind",
+            "/opt/conda/pkgs",
+            "/opt/conda/pkgs",
+            "--unshare-net",
+            "--setenv",
+            "CONDA_CAGE_SANDBOX",
+            "1",
+            "--die-with-parent",
+            "--",
+            "conda",
+            "run",
+            "-n",
+            "demo",
+            "sh",
+            "-c",
+            "make",
+        ]
+    );
+
+    let wrapped = Sandbox { network: true }.wrap(prefix, &[], &argv);
+    assert!(!wrapped.contains(&"--unshare-net".to_string()));
+    assert!(!wrapped.contains(&"/opt/conda/pkgs".to_string()));
+    assert!(wrapped.ends_with(&argv));
+}
+
+#[test]
+fn test_env_sandbox_command() {
+    let root = std::env::temp_dir().join("conda-cage-test-env-sandbox");
+    let _ = std::fs::remove_dir_all(&root);
+    let sandbox = EnvSandbox {
+        sandbox: Sandbox { network: true },
+        prefix: root.join("envs/demo"),
+        pkgs_dirs: vec![root.join("pkgs")],
+    };
+    let args = ["create", "-y", "-n", "demo"].map(String::from);
+    let command = sandbox.command(Executable::Conda, &args).unwrap();
+    let argv = command
+        .as_std()
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    // the env isn't created yet, but its dir is there to be bound
+    assert!(sandbox.prefix.is_dir());
+    assert!(argv.ends_with(&args));
+    assert!(!argv.contains(&"--unshare-net".to_string()));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
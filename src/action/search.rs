@@ -0,0 +1,227 @@
+use std::{collections::HashMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use super::{conda_output, prefix::channel_name};
+use crate::{
+    output::human::format_bytes,
+    query::{compare_versions, PackageQuery, QueryKind},
+    recipe::{Package, PackageKind},
+};
+
+/// a package of the channels which matches a search
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    /// the channel name, e.g. `conda-forge`
+    pub channel: String,
+    pub subdir: String,
+    /// the bytes of the tarball, `None` if the channel doesn't tell
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+impl SearchHit {
+    fn package(&self) -> Package {
+        Package {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            kind: PackageKind::Conda {
+                build: self.build.clone(),
+                channel: self.channel.clone(),
+            },
+        }
+    }
+}
+
+impl Display for SearchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<24} {:<14} {:<24} {:<16} {:<14} {}",
+            self.name,
+            self.version,
+            self.build,
+            self.channel,
+            self.subdir,
+            self.size
+                .map(format_bytes)
+                .unwrap_or_else(|| "-".to_string())
+        )
+    }
+}
+
+/// the packages matching the query in the channels of conda, or in `channels` instead, the
+/// oldest version first
+///
+/// `query` is a package query like `numpy`, `numpy>=1.24` or `conda-forge::numpy=1.24=py310*`,
+/// `version` adds a constraint like `1.24.*` or `>=1.24`
+pub async fn search(
+    query: &str,
+    channels: &[String],
+    version: Option<&str>,
+) -> anyhow::Result<Vec<SearchHit>> {
+    let package_query = search_query(query, version).map_err(|e| anyhow::anyhow!(e))?;
+    let mut args = vec!["search".to_string(), "--json".to_string()];
+    // a channel prefix is searched when no channel is given
+    let channels = match &package_query.channel {
+        Some(channel) if channels.is_empty() => vec![channel.clone()],
+        _ => channels.to_vec(),
+    };
+    for channel in &channels {
+        args.push("-c".to_string());
+        args.push(channel.clone());
+    }
+    if !channels.is_empty() {
+        args.push("--override-channels".to_string());
+    }
+    // conda only filters by name, the rest of the query is matched here
+    args.push(package_query.name.clone());
+    let output = conda_output(&args)
+        .await
+        .map_err(|e| e.context(format!("fail to search '{}'", query)))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        if packages_not_found(&stdout) {
+            return Ok(vec![]);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = if stderr.trim().is_empty() {
+            stdout
+        } else {
+            stderr
+        };
+        return Err(anyhow::anyhow!("{}", message).context(format!("fail to search '{}'", query)));
+    }
+    Ok(parse_search_output(&stdout)?
+        .into_iter()
+        .filter(|hit| package_query.matches(&hit.package()))
+        .collect())
+}
+
+/// parse the query of `search`, `version` is a conda style version like `1.24.*` or a range
+/// like `>=1.24,<2`
+fn search_query(query: &str, version: Option<&str>) -> Result<PackageQuery, String> {
+    let mut package_query = PackageQuery::try_from(query)?;
+    if package_query.kind == Some(QueryKind::PyPi) {
+        return Err(format!(
+            "conda search only finds conda packages, invalid query: {}",
+            query
+        ));
+    }
+    if let Some(version) = version.map(str::trim) {
+        let spec = if version.starts_with(['=', '!', '<', '>']) {
+            format!("{}{}", package_query.name, version)
+        } else {
+            format!("{}={}", package_query.name, version)
+        };
+        let version_query = PackageQuery::try_from(spec.as_str())
+            .map_err(|_| format!("invalid version '{}' of search", version))?;
+        package_query.constraints.extend(version_query.constraints);
+        if version_query.build.is_some() {
+            package_query.build = version_query.build;
+        }
+    }
+    Ok(package_query)
+}
+
+#[test]
+fn test_search_query() {
+    let matched = |query: &str, version: Option<&str>| {
+        let query = search_query(query, version).unwrap();
+        parse_search_output(include_str!("../../fixtures/search/numpy.json"))
+            .unwrap()
+            .into_iter()
+            .filter(|hit| query.matches(&hit.package()))
+            .map(|hit| hit.version)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(matched("numpy", None), ["1.9.3", "1.24.1", "1.24.3"]);
+    assert_eq!(matched("num*", Some("1.24.*")), ["1.24.1", "1.24.3"]);
+    assert_eq!(matched("numpy", Some(">=1.10")), ["1.24.1", "1.24.3"]);
+    assert_eq!(matched("numpy>=1.10", Some("<1.24.2")), ["1.24.1"]);
+    assert_eq!(matched("conda-forge::numpy", None), ["1.9.3", "1.24.1"]);
+    assert_eq!(matched("numpy=1.24=py310hd5*", None), ["1.24.3"]);
+    assert_eq!(matched("numpy", Some("1.24=py310h8*")), ["1.24.1"]);
+    assert!(matched("scipy", None).is_empty());
+
+    assert!(search_query("pypi:requests", None).is_err());
+    assert!(search_query("numpy", Some(">=")).is_err());
+}
+
+/// the error of `conda search --json` when nothing matches, it exits with 1 then
+#[derive(Deserialize)]
+struct SearchError {
+    exception_name: String,
+}
+
+/// whether conda failed since no package matches, `PackagesNotFoundInChannelsError` of newer
+/// conda included
+fn packages_not_found(output: &str) -> bool {
+    serde_json::from_str::<SearchError>(output)
+        .is_ok_and(|error| error.exception_name.starts_with("PackagesNotFound"))
+}
+
+#[test]
+fn test_packages_not_found() {
+    assert!(packages_not_found(include_str!(
+        "../../fixtures/search/not-found.json"
+    )));
+    assert!(packages_not_found(
+        r#"{"exception_name": "PackagesNotFoundInChannelsError"}"#
+    ));
+    assert!(!packages_not_found(
+        r#"{"exception_name": "CondaHTTPError"}"#
+    ));
+    assert!(!packages_not_found(include_str!(
+        "../../fixtures/search/numpy.json"
+    )));
+}
+
+/// `conda search --json` is keyed by the package name
+fn parse_search_output(output: &str) -> anyhow::Result<Vec<SearchHit>> {
+    let by_name: HashMap<String, Vec<SearchHit>> = serde_json::from_str(output)
+        .map_err(|e| anyhow::anyhow!("invalid `conda search --json` output: {}", e))?;
+    let mut hits = by_name
+        .into_values()
+        .flatten()
+        .map(|mut hit| {
+            hit.channel = channel_name(&hit.channel).unwrap_or(hit.channel);
+            hit
+        })
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| compare_versions(&a.version, &b.version))
+            .then_with(|| a.channel.cmp(&b.channel))
+            .then_with(|| a.subdir.cmp(&b.subdir))
+            .then_with(|| a.build.cmp(&b.build))
+    });
+    Ok(hits)
+}
+
+#[test]
+fn test_parse_search_output() {
+    let hits = parse_search_output(include_str!("../../fixtures/search/numpy.json")).unwrap();
+    assert_eq!(
+        hits.iter()
+            .map(|h| (h.version.as_str(), h.channel.as_str(), h.size))
+            .collect::<Vec<_>>(),
+        [
+            ("1.9.3", "conda-forge", None),
+            ("1.24.1", "conda-forge", Some(6876501)),
+            ("1.24.3", "defaults", Some(10993)),
+        ]
+    );
+    assert_eq!(
+        hits[1].to_string(),
+        "numpy                    1.24.1         py310h8deb116_0          conda-forge      linux-64       6.6 MiB"
+    );
+    assert!(hits[0].to_string().ends_with(" -"));
+
+    assert!(parse_search_output("{}").unwrap().is_empty());
+    assert!(parse_search_output("numpy 1.24.1").is_err());
+}
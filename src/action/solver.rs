@@ -0,0 +1,174 @@
+use std::cmp::Ordering;
+
+use super::priority::Executable;
+use crate::query::compare_versions;
+
+/// the first conda version which accepts `--solver`
+const MIN_SOLVER_CONDA_VERSION: &str = "22.11";
+
+/// the solver used by conda when resolving the env
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Solver {
+    Libmamba,
+    Classic,
+    /// run `mamba` instead of conda to change the env, it always solves with libmamba
+    Mamba,
+}
+
+impl Solver {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Solver::Libmamba => "libmamba",
+            Solver::Classic => "classic",
+            Solver::Mamba => "mamba",
+        }
+    }
+
+    /// the binary which creates the env and installs and removes the conda packages, the pypi
+    /// packages are always handled by `conda run`
+    pub(super) fn executable(self) -> Executable {
+        match self {
+            Solver::Mamba => Executable::Mamba,
+            Solver::Libmamba | Solver::Classic => Executable::Conda,
+        }
+    }
+}
+
+impl std::str::FromStr for Solver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "libmamba" => Ok(Self::Libmamba),
+            "classic" => Ok(Self::Classic),
+            "mamba" => Ok(Self::Mamba),
+            _ => Err(format!(
+                "invalid solver '{}', expected one of: libmamba, classic, mamba",
+                s
+            )),
+        }
+    }
+}
+
+/// parse the output of `conda --version`, e.g. `conda 22.11.1`
+pub(super) fn parse_conda_version(output: &str) -> Option<String> {
+    let version = output.trim().strip_prefix("conda ")?.trim();
+    if version.is_empty() {
+        return None;
+    }
+    Some(version.to_string())
+}
+
+/// the `--solver` args, empty if conda is too old to accept them
+pub(super) fn solver_args(solver: Solver, conda_version: &str) -> Option<Vec<String>> {
+    // mamba isn't told the solver
+    if solver == Solver::Mamba {
+        return Some(vec![]);
+    }
+    if compare_versions(conda_version, MIN_SOLVER_CONDA_VERSION) == Ordering::Less {
+        return None;
+    }
+    Some(vec!["--solver".to_string(), solver.as_str().to_string()])
+}
+
+#[test]
+fn test_solver_capability() {
+    assert_eq!(
+        parse_conda_version("conda 22.11.1\n"),
+        Some("22.11.1".to_string())
+    );
+    assert_eq!(parse_conda_version("conda 4.12.0"), Some("4.12.0".into()));
+    assert_eq!(parse_conda_version("mamba 1.0.0"), None);
+    assert_eq!(parse_conda_version("conda "), None);
+
+    assert_eq!(
+        solver_args(Solver::Libmamba, "23.1.0"),
+        Some(vec!["--solver".to_string(), "libmamba".to_string()])
+    );
+    assert_eq!(
+        solver_args(Solver::Classic, "22.11.0"),
+        Some(vec!["--solver".to_string(), "classic".to_string()])
+    );
+    assert_eq!(solver_args(Solver::Libmamba, "22.9.0"), None);
+    assert_eq!(solver_args(Solver::Libmamba, "4.12.0"), None);
+    assert_eq!(solver_args(Solver::Mamba, "4.12.0"), Some(vec![]));
+    assert_eq!(Solver::Mamba.executable(), Executable::Mamba);
+    assert_eq!(Solver::Libmamba.executable(), Executable::Conda);
+}
+
+/// condense the unsatisfiable error of both solvers into the conflicting specs
+pub fn conflict_summary(output: &str) -> Option<Vec<String>> {
+    let mut conflicts: Vec<String> = vec![];
+    let mut push = |conflict: &str| {
+        let conflict = conflict.trim().to_string();
+        if !conflict.is_empty() && !conflicts.contains(&conflict) {
+            conflicts.push(conflict);
+        }
+    };
+
+    if output.contains("LibMambaUnsatisfiableError") {
+        let mut in_problems = false;
+        for line in output.lines() {
+            if line.contains("Encountered problems while solving:") {
+                in_problems = true;
+            } else if in_problems {
+                match line.trim_start().strip_prefix("- ") {
+                    Some(problem) => push(problem.strip_prefix("package ").unwrap_or(problem)),
+                    None => in_problems = false,
+                }
+            }
+        }
+    } else if output.contains("UnsatisfiableError") {
+        let mut in_chains = false;
+        for line in output.lines() {
+            if line.starts_with("Specifications:") || line.ends_with(" conflicts for:") {
+                in_chains = true;
+            } else if line.trim().is_empty() {
+                // the `Specifications:` header is followed by a blank line
+                continue;
+            } else if in_chains {
+                if line.contains(" -> ") {
+                    push(line.trim_start().trim_start_matches("- "));
+                } else if !line.starts_with(char::is_whitespace) {
+                    in_chains = false;
+                }
+            }
+        }
+    } else {
+        return None;
+    }
+    Some(conflicts)
+}
+
+#[test]
+fn test_conflict_summary_classic() {
+    let output = include_str!("../../fixtures/solver/classic-conflict.txt");
+    assert_eq!(
+        conflict_summary(output).unwrap(),
+        [
+            "numpy=1.18.1 -> python[version='>=3.7,<3.8.0a0']",
+            "pytorch=1.12.0 -> libcxx[version='>=12.0.0']",
+            "numpy=1.18.1 -> mkl_fft -> libcxx[version='>=4.0.1']",
+        ]
+    );
+}
+
+#[test]
+fn test_conflict_summary_libmamba() {
+    let output = include_str!("../../fixtures/solver/libmamba-conflict.txt");
+    assert_eq!(
+        conflict_summary(output).unwrap(),
+        [
+            "numpy-1.18.1-py37h7241aed_0 requires python >=3.7,<3.8.0a0, but none of the providers can be installed",
+            "pytorch-1.12.0-py3.9_0 requires libcxx >=12.0.0, but none of the providers can be installed",
+        ]
+    );
+}
+
+#[test]
+fn test_conflict_summary_other_errors() {
+    assert_eq!(
+        conflict_summary("PackagesNotFoundError: The following packages are not available"),
+        None
+    );
+}
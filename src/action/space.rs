@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use super::{conda_info, EnvTarget};
+use crate::{
+    error::Error,
+    growth::cached_size,
+    recipe::{Package, PackageKind, RecipeDiff},
+};
+
+/// the conda packages the diff installs
+fn conda_installs(diff: &RecipeDiff) -> impl Iterator<Item = &Package> {
+    diff.adds
+        .iter()
+        .chain(diff.updates.iter().map(|u| &u.to))
+        .filter(|pkg| matches!(pkg.kind, PackageKind::Conda { .. }))
+}
+
+/// the record of a tarball in a repodata, only its size is in use
+#[derive(Deserialize)]
+struct RepodataRecord {
+    size: Option<u64>,
+}
+
+/// the parts of a repodata.json in use, keyed by the file name of the tarball
+#[derive(Deserialize)]
+struct Repodata {
+    #[serde(default)]
+    packages: HashMap<String, RepodataRecord>,
+    #[serde(default, rename = "packages.conda")]
+    packages_conda: HashMap<String, RepodataRecord>,
+}
+
+/// the sizes of the tarballs of the packages by the repodata conda keeps in
+/// `<pkgs dir>/cache`, the packages which aren't found there are left out
+fn repodata_sizes(pkgs: &[&Package], pkgs_dirs: &[PathBuf]) -> HashMap<String, u64> {
+    let dists = pkgs
+        .iter()
+        .filter_map(|pkg| match &pkg.kind {
+            PackageKind::Conda { build, .. } => {
+                Some(format!("{}-{}-{}", pkg.name, pkg.version, build))
+            }
+            PackageKind::PyPi { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    let mut sizes = HashMap::new();
+    let caches = pkgs_dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir.join("cache")).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            // `<hash>.json` is the repodata, `<hash>.info.json` and `<hash>.state.json` are
+            // the metadata of newer conda
+            path.extension().is_some_and(|ext| ext == "json")
+                && path
+                    .file_stem()
+                    .is_some_and(|stem| !stem.to_string_lossy().contains('.'))
+        });
+    for cache in caches {
+        if sizes.len() == dists.len() {
+            break;
+        }
+        let repodata = match std::fs::read(&cache)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<Repodata>(&contents).ok())
+        {
+            Some(repodata) => repodata,
+            None => continue,
+        };
+        for dist in &dists {
+            if sizes.contains_key(dist) {
+                continue;
+            }
+            let size = repodata
+                .packages_conda
+                .get(&format!("{}.conda", dist))
+                .or_else(|| repodata.packages.get(&format!("{}.tar.bz2", dist)))
+                .and_then(|record| record.size);
+            if let Some(size) = size {
+                sizes.insert(dist.clone(), size);
+            }
+        }
+    }
+    sizes
+}
+
+/// a rough lower bound of the bytes the diff downloads into the pkgs cache, by the sizes of
+/// the installed conda packages which aren't cached yet, `None` if none of their sizes is
+/// known, e.g. nothing is downloaded or conda hasn't cached the repodata
+fn needed_space(diff: &RecipeDiff, pkgs_dirs: &[PathBuf]) -> Option<u64> {
+    let uncached = conda_installs(diff)
+        .filter(|pkg| cached_size(pkgs_dirs, pkg).is_none())
+        .collect::<Vec<_>>();
+    let sizes = repodata_sizes(&uncached, pkgs_dirs);
+    if sizes.is_empty() {
+        return None;
+    }
+    Some(sizes.values().sum())
+}
+
+/// the free bytes of the filesystem of the path, which may not exist yet
+fn available_space(path: &Path) -> io::Result<u64> {
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+    fs2::available_space(existing)
+}
+
+/// `DiskFull` for the first dir whose filesystem has less than `needed` bytes free, the dirs
+/// whose free space can't be read are skipped
+fn check_space(
+    dirs: &[PathBuf],
+    needed: u64,
+    available: impl Fn(&Path) -> io::Result<u64>,
+) -> Result<(), Error> {
+    for dir in dirs {
+        match available(dir) {
+            Ok(available) if available < needed => {
+                return Err(Error::DiskFull {
+                    path: Some(dir.clone()),
+                    needed_estimate: Some(needed),
+                    available: Some(available),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// fail before conda touches the env when the filesystems of the pkgs cache or of the env
+/// clearly lack the space for the downloads, the check is skipped when `conda info` fails or
+/// the size of no download is known
+pub(super) async fn precheck_space(target: &EnvTarget, diff: &RecipeDiff) -> Result<(), Error> {
+    if conda_installs(diff).next().is_none() {
+        return Ok(());
+    }
+    let info = match conda_info().await {
+        Ok(info) => info,
+        Err(_) => return Ok(()),
+    };
+    let env_dir = match target {
+        EnvTarget::Name(name) => info.envs_dirs.first().map(|dir| dir.join(name)),
+        EnvTarget::Prefix(prefix) => Some(prefix.clone()),
+    };
+    let dirs = info
+        .pkgs_dirs
+        .first()
+        .cloned()
+        .into_iter()
+        .chain(env_dir)
+        .collect::<Vec<_>>();
+    match needed_space(diff, &info.pkgs_dirs) {
+        Some(needed) => check_space(&dirs, needed, available_space),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_needed_space() {
+    use crate::recipe::Recipe;
+
+    let pkgs_dir = std::env::temp_dir().join("conda-cage-test-needed-space");
+    let _ = std::fs::remove_dir_all(&pkgs_dir);
+    std::fs::create_dir_all(pkgs_dir.join("cache")).unwrap();
+    // zlib is cached, it isn't downloaded again
+    std::fs::write(pkgs_dir.join("zlib-1.2.13-h166bdaf_4.conda"), [0; 100]).unwrap();
+
+    let old_recipe: Recipe = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+requests                  2.28.1                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+numpy                     1.24.3          py310h5d7c261_0    conda-forge
+zlib                      1.2.13               h166bdaf_4    conda-forge
+scipy                     1.10.1          py310h8deb116_0    conda-forge
+requests                  2.28.2                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    let pkgs_dirs = [pkgs_dir.clone()];
+
+    // conda hasn't cached the repodata
+    assert_eq!(needed_space(&diff, &pkgs_dirs), None);
+
+    std::fs::write(
+        pkgs_dir.join("cache/3e39a7aa.json"),
+        r#"{
+            "packages": {
+                "numpy-1.24.3-py310h5d7c261_0.tar.bz2": {"size": 7000},
+                "zlib-1.2.13-h166bdaf_4.tar.bz2": {"size": 100}
+            },
+            "packages.conda": {
+                "scipy-1.10.1-py310h8deb116_0.conda": {"size": 25000}
+            }
+        }"#,
+    )
+    .unwrap();
+    std::fs::write(
+        pkgs_dir.join("cache/3e39a7aa.info.json"),
+        r#"{"url": "https://conda.anaconda.org/conda-forge/linux-64"}"#,
+    )
+    .unwrap();
+    assert_eq!(needed_space(&diff, &pkgs_dirs), Some(32000));
+    // the downloads don't fit, however little the cached tarballs take
+    assert!(check_space(&pkgs_dirs, 32000, |_: &Path| Ok(20000)).is_err());
+    assert_eq!(needed_space(&RecipeDiff::default(), &pkgs_dirs), None);
+
+    std::fs::remove_dir_all(&pkgs_dir).unwrap();
+}
+
+#[test]
+fn test_check_space() {
+    let dirs = [
+        PathBuf::from("/opt/conda/pkgs"),
+        PathBuf::from("/data/envs/demo"),
+    ];
+    let available = |path: &Path| match path.to_str() {
+        Some("/opt/conda/pkgs") => Ok(1000),
+        Some("/data/envs/demo") => Ok(100),
+        _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+    };
+    assert!(check_space(&dirs, 100, available).is_ok());
+    match check_space(&dirs, 500, available) {
+        Err(Error::DiskFull {
+            path,
+            needed_estimate,
+            available,
+        }) => {
+            assert_eq!(path, Some(PathBuf::from("/data/envs/demo")));
+            assert_eq!((needed_estimate, available), (Some(500), Some(100)));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // the free space is unknown
+    assert!(check_space(&[PathBuf::from("/nfs")], u64::MAX, available).is_ok());
+
+    // the env doesn't exist yet
+    assert!(available_space(&std::env::temp_dir().join("conda-cage-missing/envs/demo")).is_ok());
+}
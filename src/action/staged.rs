@@ -0,0 +1,274 @@
+use std::{cmp::Ordering, collections::HashSet};
+
+use super::{
+    env_lock_key, env_prefixes,
+    info_cache::probe_conda_version,
+    lock::EnvLock,
+    marker::{env_prefix, is_managed, mark_managed},
+    run_conda,
+};
+use crate::{human_println, query::compare_versions};
+
+/// `conda rename` is available since conda 4.14
+const MIN_RENAME_CONDA_VERSION: &str = "4.14";
+const STAGED_SUFFIX: &str = ".cage-staged";
+const PREV_SUFFIX: &str = ".cage-prev";
+
+/// the env which `install --staged` builds into
+pub fn staged_env_name(env_name: &str) -> String {
+    format!("{}{}", env_name, STAGED_SUFFIX)
+}
+
+/// the env which is kept for `rollback` after a swap
+pub fn prev_env_name(env_name: &str) -> String {
+    format!("{}{}", env_name, PREV_SUFFIX)
+}
+
+/// a conda operation of the swap
+#[derive(Debug, Clone, PartialEq)]
+enum SwapStep {
+    Remove(String),
+    Rename { from: String, to: String },
+}
+
+impl SwapStep {
+    fn rename(from: &str, to: &str) -> Self {
+        Self::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            Self::Remove(env) => ["env", "remove", "-y", "-n", env]
+                .map(String::from)
+                .to_vec(),
+            Self::Rename { from, to } => ["rename", "-n", from, to].map(String::from).to_vec(),
+        }
+    }
+}
+
+/// the steps which repair the leftovers of an interrupted staged install, given the names of
+/// the existing envs
+fn recover_steps(env_name: &str, envs: &HashSet<String>) -> Vec<SwapStep> {
+    let staged = staged_env_name(env_name);
+    let prev = prev_env_name(env_name);
+    match (
+        envs.contains(env_name),
+        envs.contains(&staged),
+        envs.contains(&prev),
+    ) {
+        // the swap stopped after the live env was moved away, finish it
+        (false, true, true) => vec![SwapStep::rename(&staged, env_name)],
+        // the staged build was interrupted
+        (true, true, _) => vec![SwapStep::Remove(staged)],
+        // a rollback stopped after the live env was moved away, put the previous env back
+        (false, false, true) => vec![SwapStep::rename(&prev, env_name)],
+        _ => vec![],
+    }
+}
+
+/// move the live env to the previous one and the staged env to the live one, the old
+/// previous env is pruned
+fn swap_steps(env_name: &str, envs: &HashSet<String>) -> Vec<SwapStep> {
+    let staged = staged_env_name(env_name);
+    let prev = prev_env_name(env_name);
+    let mut steps = vec![];
+    if envs.contains(&prev) {
+        steps.push(SwapStep::Remove(prev.clone()));
+    }
+    if envs.contains(env_name) {
+        steps.push(SwapStep::rename(env_name, &prev));
+    }
+    steps.push(SwapStep::rename(&staged, env_name));
+    steps
+}
+
+/// swap the live env and the previous one, so the rollback can be rolled back as well
+fn rollback_steps(env_name: &str, envs: &HashSet<String>) -> Result<Vec<SwapStep>, String> {
+    let staged = staged_env_name(env_name);
+    let prev = prev_env_name(env_name);
+    if !envs.contains(&prev) {
+        return Err(format!(
+            "env '{}' has no previous env to roll back to, it's kept by `install --staged`",
+            env_name
+        ));
+    }
+    Ok(vec![
+        SwapStep::rename(env_name, &staged),
+        SwapStep::rename(&prev, env_name),
+        SwapStep::rename(&staged, &prev),
+    ])
+}
+
+/// the names of the envs under the `envs` dirs
+async fn env_names() -> anyhow::Result<HashSet<String>> {
+    Ok(env_prefixes().await?.into_keys().collect())
+}
+
+async fn run_steps(steps: &[SwapStep]) -> anyhow::Result<()> {
+    for step in steps {
+        run_conda(step.args()).await?;
+    }
+    Ok(())
+}
+
+/// repair the leftovers of an interrupted staged install, and clone the live env into the
+/// staged env, so the install only applies the diff
+pub async fn prepare_staged(env_name: &str) -> anyhow::Result<()> {
+    let (conda_version, _) = probe_conda_version().await;
+    match conda_version.as_deref() {
+        Some(version) if compare_versions(version, MIN_RENAME_CONDA_VERSION) != Ordering::Less => {}
+        version => anyhow::bail!(
+            "`--staged` needs conda {} or newer for `conda rename`, but got conda {}",
+            MIN_RENAME_CONDA_VERSION,
+            version.unwrap_or("of unknown version")
+        ),
+    }
+
+    let _lock = EnvLock::exclusive(&env_lock_key(env_name)).await?;
+    let steps = recover_steps(env_name, &env_names().await?);
+    if !steps.is_empty() {
+        human_println!(
+            "repairing the leftovers of an interrupted staged install of env '{}'...",
+            env_name
+        );
+        run_steps(&steps).await?;
+    }
+    if let Some(prefix) = env_prefix(env_name).await? {
+        let staged = staged_env_name(env_name);
+        run_conda(["create", "-y", "-n", &staged, "--clone", env_name]).await?;
+        // the staged env is managed as long as the live env is
+        if is_managed(&prefix) {
+            if let Some(staged_prefix) = env_prefix(&staged).await? {
+                mark_managed(&staged_prefix)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// remove the staged env after its build or verification failed, the live env is untouched
+pub async fn discard_staged(env_name: &str) -> anyhow::Result<()> {
+    run_steps(&[SwapStep::Remove(staged_env_name(env_name))]).await
+}
+
+/// make the staged env the live one, the replaced env is kept for `rollback`
+pub async fn swap_staged(env_name: &str) -> anyhow::Result<()> {
+    let _lock = EnvLock::exclusive(&env_lock_key(env_name)).await?;
+    run_steps(&swap_steps(env_name, &env_names().await?)).await?;
+    human_println!(
+        "warning: env '{}' is swapped, the processes started before keep using the files of '{}', and the paths under its prefix are rewritten",
+        env_name,
+        prev_env_name(env_name)
+    );
+    Ok(())
+}
+
+/// swap back the env replaced by the last staged install
+pub async fn rollback_staged(env_name: &str) -> anyhow::Result<()> {
+    let _lock = EnvLock::exclusive(&env_lock_key(env_name)).await?;
+    let envs = env_names().await?;
+    let steps = recover_steps(env_name, &envs);
+    if !steps.is_empty() {
+        anyhow::bail!(
+            "env '{}' has leftovers of an interrupted staged install, run `install --staged` to repair it first",
+            env_name
+        );
+    }
+    run_steps(&rollback_steps(env_name, &envs).map_err(|e| anyhow::anyhow!(e))?).await
+}
+
+/// apply the steps to the env names like conda would, stopping before the step `crash_at`
+#[cfg(test)]
+fn apply_steps(envs: &mut HashSet<String>, steps: &[SwapStep], crash_at: Option<usize>) {
+    for (i, step) in steps.iter().enumerate() {
+        if Some(i) == crash_at {
+            return;
+        }
+        match step {
+            SwapStep::Remove(env) => {
+                assert!(envs.remove(env), "remove missing env {}", env);
+            }
+            SwapStep::Rename { from, to } => {
+                assert!(envs.remove(from), "rename missing env {}", from);
+                assert!(envs.insert(to.clone()), "rename onto existing env {}", to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn env_set(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+#[test]
+fn test_swap_steps() {
+    let mut envs = env_set(&["demo", "demo.cage-staged", "demo.cage-prev"]);
+    let steps = swap_steps("demo", &envs);
+    assert_eq!(
+        steps,
+        [
+            SwapStep::Remove("demo.cage-prev".into()),
+            SwapStep::rename("demo", "demo.cage-prev"),
+            SwapStep::rename("demo.cage-staged", "demo"),
+        ]
+    );
+    apply_steps(&mut envs, &steps, None);
+    assert_eq!(envs, env_set(&["demo", "demo.cage-prev"]));
+
+    // the first staged install of a new env
+    let mut envs = env_set(&["demo.cage-staged"]);
+    let steps = swap_steps("demo", &envs);
+    apply_steps(&mut envs, &steps, None);
+    assert_eq!(envs, env_set(&["demo"]));
+
+    assert_eq!(
+        SwapStep::rename("demo", "demo.cage-prev").args(),
+        ["rename", "-n", "demo", "demo.cage-prev"]
+    );
+}
+
+#[test]
+fn test_recover_from_crashed_swap() {
+    let initial = env_set(&["demo", "demo.cage-staged", "demo.cage-prev", "other"]);
+    let steps = swap_steps("demo", &initial);
+    for crash_at in 0..steps.len() {
+        let mut envs = initial.clone();
+        apply_steps(&mut envs, &steps, Some(crash_at));
+        let recovery = recover_steps("demo", &envs);
+        apply_steps(&mut envs, &recovery, None);
+        // either the swap is finished or the staged build is discarded
+        assert!(envs.contains("demo"), "crash at {}", crash_at);
+        assert!(!envs.contains("demo.cage-staged"), "crash at {}", crash_at);
+        assert!(envs.contains("other"));
+        assert!(recover_steps("demo", &envs).is_empty());
+    }
+    assert_eq!(
+        recover_steps("demo", &initial),
+        [SwapStep::Remove("demo.cage-staged".into())]
+    );
+    assert!(recover_steps("demo", &env_set(&["demo", "demo.cage-prev"])).is_empty());
+}
+
+#[test]
+fn test_rollback_steps() {
+    let initial = env_set(&["demo", "demo.cage-prev"]);
+    let steps = rollback_steps("demo", &initial).unwrap();
+    let mut envs = initial.clone();
+    apply_steps(&mut envs, &steps, None);
+    assert_eq!(envs, initial);
+
+    for crash_at in 1..steps.len() {
+        let mut envs = initial.clone();
+        apply_steps(&mut envs, &steps, Some(crash_at));
+        let recovery = recover_steps("demo", &envs);
+        apply_steps(&mut envs, &recovery, None);
+        assert!(envs.contains("demo"), "crash at {}", crash_at);
+        assert!(!envs.contains("demo.cage-staged"), "crash at {}", crash_at);
+    }
+
+    assert!(rollback_steps("demo", &env_set(&["demo"])).is_err());
+}
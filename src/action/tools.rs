@@ -0,0 +1,159 @@
+use crate::error::Error;
+
+/// the releases which are known to corrupt envs
+const BUILTIN_BLOCKED_TOOLS: [(&str, &str, Severity); 2] = [
+    ("conda", "4.13.0", Severity::Error),
+    ("pip", "23.0", Severity::Warn),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// only warn about the release
+    Warn,
+    /// refuse to install with the release
+    Error,
+}
+
+/// a release of conda or pip which isn't trusted, parsed from `<tool>==<version>[:warn]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockedTool {
+    pub tool: String,
+    pub version: String,
+    pub severity: Severity,
+}
+
+impl std::str::FromStr for BlockedTool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid blocked tool '{}', expected `conda==<version>` or `pip==<version>`, with an optional `:warn`",
+                s
+            )
+        };
+        let (spec, severity) = match s.strip_suffix(":warn") {
+            Some(spec) => (spec, Severity::Warn),
+            None => (s, Severity::Error),
+        };
+        match spec.split_once("==") {
+            Some((tool @ ("conda" | "pip"), version)) if !version.is_empty() => Ok(Self {
+                tool: tool.to_string(),
+                version: version.to_string(),
+                severity,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// parse the output of `pip --version`, e.g. `pip 23.0 from /opt/conda/lib/python3.10/site-packages/pip (python 3.10)`
+pub(super) fn parse_pip_version(output: &str) -> Option<String> {
+    let version = output
+        .trim()
+        .strip_prefix("pip ")?
+        .split_whitespace()
+        .next()?;
+    Some(version.to_string())
+}
+
+/// the warnings of the blocked release of the tool, or the error of the first one which is
+/// blocked as an error, the given blocked tools extend the builtin ones
+pub(super) fn check_tool(
+    tool: &str,
+    version: &str,
+    blocked: &[BlockedTool],
+) -> Result<Vec<String>, Error> {
+    let builtin = BUILTIN_BLOCKED_TOOLS.map(|(tool, version, severity)| BlockedTool {
+        tool: tool.to_string(),
+        version: version.to_string(),
+        severity,
+    });
+    let mut warnings = vec![];
+    for entry in builtin.iter().chain(blocked) {
+        if entry.tool != tool || entry.version != version {
+            continue;
+        }
+        match entry.severity {
+            Severity::Error => {
+                return Err(Error::BlockedTool {
+                    tool: tool.to_string(),
+                    version: version.to_string(),
+                })
+            }
+            Severity::Warn => warnings.push(format!(
+                "{} {} is known to corrupt envs, consider upgrading it",
+                tool, version
+            )),
+        }
+    }
+    Ok(warnings)
+}
+
+#[test]
+fn test_parse_pip_version() {
+    assert_eq!(
+        parse_pip_version(
+            "pip 23.0 from /opt/conda/envs/demo/lib/python3.10/site-packages/pip (python 3.10)\n"
+        ),
+        Some("23.0".to_string())
+    );
+    assert_eq!(
+        parse_pip_version("pip 22.1.2 from /tmp (python 3.7)"),
+        Some("22.1.2".to_string())
+    );
+    assert_eq!(parse_pip_version("conda 22.11.1"), None);
+    assert_eq!(parse_pip_version(""), None);
+}
+
+#[test]
+fn test_parse_blocked_tool() {
+    assert_eq!(
+        "pip==23.0".parse(),
+        Ok(BlockedTool {
+            tool: "pip".into(),
+            version: "23.0".into(),
+            severity: Severity::Error,
+        })
+    );
+    assert_eq!(
+        "conda==23.1.0:warn".parse(),
+        Ok(BlockedTool {
+            tool: "conda".into(),
+            version: "23.1.0".into(),
+            severity: Severity::Warn,
+        })
+    );
+    for invalid in ["pip", "pip==", "mamba==1.0", "pip>=23.0", ""] {
+        assert!(invalid.parse::<BlockedTool>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_check_tool() {
+    // builtin
+    assert!(matches!(
+        check_tool("conda", "4.13.0", &[]),
+        Err(Error::BlockedTool { .. })
+    ));
+    assert_eq!(
+        check_tool("pip", "23.0", &[]).unwrap(),
+        ["pip 23.0 is known to corrupt envs, consider upgrading it"]
+    );
+    assert!(check_tool("conda", "22.11.1", &[]).unwrap().is_empty());
+    assert!(check_tool("pip", "4.13.0", &[]).unwrap().is_empty());
+
+    // configured
+    let blocked = [
+        "conda==22.11.1:warn".parse().unwrap(),
+        "pip==23.0".parse().unwrap(),
+    ];
+    assert_eq!(check_tool("conda", "22.11.1", &blocked).unwrap().len(), 1);
+    match check_tool("pip", "23.0", &blocked) {
+        Err(error) => assert_eq!(
+            error.to_string(),
+            "pip 23.0 is blocked since it's known to corrupt envs, upgrade or downgrade it and install again"
+        ),
+        other => panic!("unexpected {:?}", other),
+    }
+}
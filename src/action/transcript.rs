@@ -0,0 +1,71 @@
+use std::{process::ExitStatus, sync::Mutex};
+
+use tokio::process::Command;
+
+static TRANSCRIPT: Mutex<Option<String>> = Mutex::new(None);
+
+/// record the conda commands with their output from now on, until `take_transcript`
+pub fn record_transcript() {
+    *TRANSCRIPT.lock().unwrap() = Some(String::new());
+}
+
+/// the conda commands recorded since `record_transcript`, the recording stops
+pub fn take_transcript() -> Option<String> {
+    TRANSCRIPT.lock().unwrap().take()
+}
+
+/// the command line of the command, e.g. `nice -n 10 conda install -n demo ...`
+pub(super) fn argv(command: &Command) -> String {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// add a finished command to the transcript if it's recording, `status` is `None` when the
+/// command was killed
+pub(super) fn record(argv: &str, stdout: &str, stderr: &str, status: Option<ExitStatus>) {
+    if let Some(transcript) = TRANSCRIPT.lock().unwrap().as_mut() {
+        transcript.push_str(&format_entry(argv, stdout, stderr, status));
+    }
+}
+
+fn format_entry(argv: &str, stdout: &str, stderr: &str, status: Option<ExitStatus>) -> String {
+    let mut entry = format!("$ {}\n", argv);
+    for (title, output) in [("stdout", stdout), ("stderr", stderr)] {
+        if !output.trim().is_empty() {
+            entry.push_str(&format!("# {}\n{}\n", title, output.trim_end()));
+        }
+    }
+    match status {
+        Some(status) => entry.push_str(&format!("# {}\n\n", status)),
+        None => entry.push_str("# killed\n\n"),
+    }
+    entry
+}
+
+#[test]
+fn test_format_entry() {
+    use std::os::unix::process::ExitStatusExt;
+
+    assert_eq!(
+        format_entry(
+            "conda install -n demo numpy",
+            "Collecting package metadata: done\n",
+            "",
+            Some(ExitStatus::from_raw(0)),
+        ),
+        "$ conda install -n demo numpy\n# stdout\nCollecting package metadata: done\n# exit status: 0\n\n"
+    );
+    assert_eq!(
+        format_entry(
+            "conda run -n demo pip install requests",
+            "",
+            "ERROR: No matching distribution",
+            None
+        ),
+        "$ conda run -n demo pip install requests\n# stderr\nERROR: No matching distribution\n# killed\n\n"
+    );
+}
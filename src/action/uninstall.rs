@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+
+use super::{env_lock_key, env_prefixes, lock::EnvLock, prev_env_name, run_conda, staged_env_name};
+use crate::error::Error;
+
+/// the envs removed for the env, with `purge` the ones kept by `install --staged` as well
+fn uninstall_targets(
+    env_name: &str,
+    envs: &HashSet<String>,
+    purge: bool,
+) -> Result<Vec<String>, Error> {
+    if !envs.contains(env_name) {
+        return Err(Error::EnvNotFound(Some(env_name.to_string())));
+    }
+    let mut targets = vec![env_name.to_string()];
+    if purge {
+        targets.extend(
+            [staged_env_name(env_name), prev_env_name(env_name)]
+                .into_iter()
+                .filter(|env| envs.contains(env)),
+        );
+    }
+    Ok(targets)
+}
+
+/// remove the env by `conda env remove`, returns the names of the removed envs
+pub async fn uninstall(env_name: &str, purge: bool) -> anyhow::Result<Vec<String>> {
+    let _lock = EnvLock::exclusive(&env_lock_key(env_name)).await?;
+    let envs = env_prefixes().await?.into_keys().collect();
+    let targets = uninstall_targets(env_name, &envs, purge)?;
+    for env in &targets {
+        run_conda(["env", "remove", "-y", "-n", env]).await?;
+    }
+    Ok(targets)
+}
+
+#[test]
+fn test_uninstall_targets() {
+    let envs =
+        HashSet::from(["demo", "demo.cage-prev", "demo.cage-staged", "other"].map(String::from));
+    assert_eq!(uninstall_targets("demo", &envs, false).unwrap(), ["demo"]);
+    assert_eq!(
+        uninstall_targets("demo", &envs, true).unwrap(),
+        ["demo", "demo.cage-staged", "demo.cage-prev"]
+    );
+    assert_eq!(uninstall_targets("other", &envs, true).unwrap(), ["other"]);
+    assert!(matches!(
+        uninstall_targets("missing", &envs, true),
+        Err(Error::EnvNotFound(_))
+    ));
+}
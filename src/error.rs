@@ -0,0 +1,307 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::output::human::format_bytes;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(
+        "no space left on device{}, try to free some space (e.g. `conda clean --all` cleans the conda pkgs cache) and install again",
+        disk_full_detail(.path.as_deref(), *.needed_estimate, *.available)
+    )]
+    DiskFull {
+        path: Option<PathBuf>,
+        /// the bytes the install needs at least, `None` when conda or the os ran out of space
+        needed_estimate: Option<u64>,
+        /// the free bytes of the filesystem of `path`
+        available: Option<u64>,
+    },
+
+    #[error("failed to bootstrap pip; pypi packages cannot be installed\n{0}")]
+    PipBootstrap(String),
+
+    #[error(
+        "post install hook '{hook}' failed{}",
+        .code.map(|c| format!(" with exit code {}", c)).unwrap_or_default()
+    )]
+    HookFailed { hook: String, code: Option<i32> },
+
+    #[error("paths are clobbered by other packages:\n  {}", .0.join("\n  "))]
+    Clobbered(Vec<String>),
+
+    #[error("the recipe removes '{0}' while it still has packages which need it, pass `--allow-remove-python` if it is intended")]
+    PythonRemoval(String),
+
+    #[error("conda failed to solve the env, the conflicting specs are:\n  {}", .0.join("\n  "))]
+    SolveConflict(Vec<String>),
+
+    #[error("timed out after {}", crate::output::human::format_duration(*.0))]
+    Timeout(std::time::Duration),
+
+    #[error(
+        "env '{env}' isn't managed by conda-cage{}, converging it would delete {deletes} packages; pass `--adopt` to manage it or `--force` to recreate it",
+        .manager.map(|m| format!(" and looks managed by {}", m)).unwrap_or_default()
+    )]
+    Unmanaged {
+        env: String,
+        deletes: usize,
+        manager: Option<&'static str>,
+    },
+
+    #[error(
+        "no version matches '{pattern}', the candidates are: {}",
+        if .candidates.is_empty() { "none".to_string() } else { .candidates.join(", ") }
+    )]
+    NoMatchingVersion {
+        pattern: String,
+        candidates: Vec<String>,
+    },
+
+    #[error("the package record '{0}' of the env is corrupt, pass `--force` to recreate the env")]
+    CorruptMeta(String),
+
+    #[error("conda failed to verify the transaction:\n{0}")]
+    Verification(String),
+
+    #[error("{tool} {version} is blocked since it's known to corrupt envs, upgrade or downgrade it and install again")]
+    BlockedTool { tool: String, version: String },
+
+    #[error(
+        "env{} doesn't exist",
+        .0.as_ref().map(|env| format!(" '{}'", env)).unwrap_or_default()
+    )]
+    EnvNotFound(Option<String>),
+
+    #[error("env '{0}' isn't on the recipe server")]
+    UnknownEnv(String),
+}
+
+fn disk_full_detail(path: Option<&Path>, needed: Option<u64>, available: Option<u64>) -> String {
+    match (path, needed, available) {
+        (Some(path), Some(needed), Some(available)) => format!(
+            " for the filesystem of '{}', about {} is needed but {} is available",
+            path.display(),
+            format_bytes(needed),
+            format_bytes(available)
+        ),
+        (Some(path), _, _) => format!(" while writing '{}'", path.display()),
+        _ => String::new(),
+    }
+}
+
+impl Error {
+    /// a short stable name of the error, e.g. `disk_full`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::DiskFull { .. } => "disk_full",
+            Error::PipBootstrap(_) => "pip_bootstrap",
+            Error::HookFailed { .. } => "hook_failed",
+            Error::Clobbered(_) => "clobbered",
+            Error::PythonRemoval(_) => "python_removal",
+            Error::SolveConflict(_) => "solve_conflict",
+            Error::Timeout(_) => "timeout",
+            Error::Unmanaged { .. } => "unmanaged",
+            Error::NoMatchingVersion { .. } => "no_matching_version",
+            Error::CorruptMeta(_) => "corrupt_meta",
+            Error::Verification(_) => "verification",
+            Error::BlockedTool { .. } => "blocked_tool",
+            Error::EnvNotFound(_) => "env_not_found",
+            Error::UnknownEnv(_) => "unknown_env",
+        }
+    }
+
+    /// all the kinds, see `kind`
+    pub const KINDS: [&'static str; 14] = [
+        "disk_full",
+        "pip_bootstrap",
+        "hook_failed",
+        "clobbered",
+        "python_removal",
+        "solve_conflict",
+        "timeout",
+        "unmanaged",
+        "no_matching_version",
+        "corrupt_meta",
+        "verification",
+        "blocked_tool",
+        "env_not_found",
+        "unknown_env",
+    ];
+
+    /// classify the stderr of a failed conda command
+    pub fn from_conda_stderr(stderr: &str) -> Option<Self> {
+        let pattern =
+            regex::Regex::new("(?:No space left on device|Disk quota exceeded)(?:: '([^']+)')?")
+                .unwrap();
+        if let Some(cap) = pattern.captures(stderr) {
+            return Some(Error::DiskFull {
+                path: cap.get(1).map(|m| PathBuf::from(m.as_str())),
+                needed_estimate: None,
+                available: None,
+            });
+        }
+        if stderr.contains("JSONDecodeError") {
+            let record = regex::Regex::new(r#"[^\s'"]*conda-meta/[^\s'"]+\.json"#).unwrap();
+            if let Some(m) = record.find(stderr) {
+                return Some(Error::CorruptMeta(m.as_str().to_string()));
+            }
+        }
+        let not_found =
+            regex::Regex::new(r"EnvironmentLocationNotFound(?:: Not a conda environment: (\S+))?")
+                .unwrap();
+        if let Some(cap) = not_found.captures(stderr) {
+            return Some(Error::EnvNotFound(
+                cap.get(1).map(|m| m.as_str().to_string()),
+            ));
+        }
+        if let Some(start) = stderr.find("CondaVerificationError") {
+            return Some(Error::Verification(stderr[start..].trim_end().to_string()));
+        }
+        crate::action::conflict_summary(stderr)
+            .filter(|conflicts| !conflicts.is_empty())
+            .map(Error::SolveConflict)
+    }
+
+    pub fn from_io_error(error: &std::io::Error) -> Option<Self> {
+        match error.kind() {
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                Some(Error::DiskFull {
+                    path: None,
+                    needed_estimate: None,
+                    available: None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_classify_disk_full_from_conda_stderr() {
+    let stderr = r#"
+CondaError: Unable to create prefix directory '/opt/envs/demo'.
+[Errno 28] No space left on device: '/opt/conda/pkgs/numpy-1.18.1-py37h7241aed_0'
+"#;
+    match Error::from_conda_stderr(stderr) {
+        Some(Error::DiskFull { path, .. }) => assert_eq!(
+            path,
+            Some(PathBuf::from("/opt/conda/pkgs/numpy-1.18.1-py37h7241aed_0"))
+        ),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    assert!(matches!(
+        Error::from_conda_stderr("OSError: [Errno 122] Disk quota exceeded"),
+        Some(Error::DiskFull { path: None, .. })
+    ));
+    assert!(Error::from_conda_stderr("PackagesNotFoundError").is_none());
+}
+
+#[test]
+fn test_disk_full_message() {
+    let error = Error::DiskFull {
+        path: Some(PathBuf::from("/opt/conda/pkgs")),
+        needed_estimate: Some(3 * 1024 * 1024 * 1024),
+        available: Some(512 * 1024 * 1024),
+    };
+    assert!(error.to_string().starts_with(
+        "no space left on device for the filesystem of '/opt/conda/pkgs', about 3.0 GiB is needed but 512.0 MiB is available, "
+    ));
+
+    let error = Error::DiskFull {
+        path: Some(PathBuf::from("/opt/conda/pkgs/numpy")),
+        needed_estimate: None,
+        available: None,
+    };
+    assert!(error
+        .to_string()
+        .starts_with("no space left on device while writing '/opt/conda/pkgs/numpy', "));
+}
+
+#[test]
+fn test_classify_disk_full_from_io_error() {
+    let error = std::io::Error::from(std::io::ErrorKind::StorageFull);
+    assert!(matches!(
+        Error::from_io_error(&error),
+        Some(Error::DiskFull { path: None, .. })
+    ));
+
+    let error = std::io::Error::from(std::io::ErrorKind::NotFound);
+    assert!(Error::from_io_error(&error).is_none());
+}
+
+#[test]
+fn test_pip_bootstrap_message() {
+    let error = Error::PipBootstrap("PackagesNotFoundError: pip".to_string());
+    assert_eq!(
+        error.to_string(),
+        "failed to bootstrap pip; pypi packages cannot be installed\nPackagesNotFoundError: pip"
+    );
+}
+
+#[test]
+fn test_classify_solve_conflict_from_conda_stderr() {
+    let stderr = include_str!("../fixtures/solver/libmamba-conflict.txt");
+    match Error::from_conda_stderr(stderr) {
+        Some(error @ Error::SolveConflict(_)) => assert!(error.to_string().starts_with(
+            "conda failed to solve the env, the conflicting specs are:\n  numpy-1.18.1-py37h7241aed_0 requires python"
+        )),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_classify_broken_env_from_conda_stderr() {
+    let stderr = r#"
+Traceback (most recent call last):
+  File "/opt/conda/lib/python3.9/site-packages/conda/core/prefix_data.py", line 237, in _load_single_record
+    prefix_record_json = json.load(fh)
+json.decoder.JSONDecodeError: Expecting value: line 1 column 1 (char 0)
+while loading '/opt/conda/envs/demo/conda-meta/numpy-1.24.1-py310h5d7c261_0.json'
+"#;
+    match Error::from_conda_stderr(stderr) {
+        Some(error @ Error::CorruptMeta(_)) => {
+            assert_eq!(error.kind(), "corrupt_meta");
+            assert!(error.to_string().starts_with(
+                "the package record '/opt/conda/envs/demo/conda-meta/numpy-1.24.1-py310h5d7c261_0.json' of the env is corrupt"
+            ));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let stderr = r#"
+Preparing transaction: done
+Verifying transaction: failed
+
+CondaVerificationError: The package for tk located at /opt/conda/pkgs/tk-8.6.12-h1ccaba5_0
+appears to be corrupted. The path 'lib/libtk8.6.so'
+specified in the package manifest cannot be found.
+"#;
+    match Error::from_conda_stderr(stderr) {
+        Some(Error::Verification(message)) => {
+            assert!(message.starts_with("CondaVerificationError: The package for tk"));
+            assert!(message.ends_with("cannot be found."));
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_classify_env_not_found_from_conda_stderr() {
+    let stderr = "\nEnvironmentLocationNotFound: Not a conda environment: /opt/conda/envs/demo\n\n";
+    match Error::from_conda_stderr(stderr) {
+        Some(error @ Error::EnvNotFound(_)) => {
+            assert_eq!(error.kind(), "env_not_found");
+            assert_eq!(
+                error.to_string(),
+                "env '/opt/conda/envs/demo' doesn't exist"
+            );
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(
+        Error::from_conda_stderr("EnvironmentLocationNotFound"),
+        Some(Error::EnvNotFound(None))
+    ));
+}
@@ -0,0 +1,246 @@
+//! how much a recipe grows between two versions, checked by `compare-versions`
+
+use std::{fmt::Display, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    output::human::{format_bytes, format_count},
+    recipe::{Package, PackageKind, RecipeDiff},
+};
+
+/// a size like `500M`, `1.5G` or `2GiB` in binary units, a bare number is in bytes
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let err = || {
+        format!(
+            "invalid size '{}', expected a number with an optional unit K, M, G or T, e.g. `1.5G`",
+            s
+        )
+    };
+    let unit_at = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_at);
+    let number = number.parse::<f64>().map_err(|_| err())?;
+    let unit = unit.trim_end_matches("iB").trim_end_matches('B');
+    let power = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(err()),
+    };
+    Ok((number * 1024f64.powi(power)).round() as u64)
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("100"), Ok(100));
+    assert_eq!(parse_size("4K"), Ok(4096));
+    assert_eq!(parse_size("500M"), Ok(500 * 1024 * 1024));
+    assert_eq!(parse_size("500MB"), Ok(500 * 1024 * 1024));
+    assert_eq!(parse_size("1.5g"), Ok(1536 * 1024 * 1024));
+    assert_eq!(parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+    assert_eq!(parse_size("1T"), Ok(1024u64.pow(4)));
+    for invalid in ["", "G", "1.5.0G", "-1G", "10X", "10 G"] {
+        assert!(parse_size(invalid).is_err(), "{}", invalid);
+    }
+}
+
+/// the growth which needs a sign-off, `None` is unlimited
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GrowthLimits {
+    pub max_new_packages: Option<usize>,
+    pub max_size_growth: Option<u64>,
+}
+
+/// the growth of a recipe between two versions
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GrowthReport {
+    pub from: String,
+    pub to: String,
+    /// the packages added by the new version
+    pub new_packages: usize,
+    /// the estimated bytes the env grows by, negative when it shrinks
+    pub size_growth: i64,
+    /// the conda packages of the diff whose size is unknown, they count as 0 bytes
+    pub unknown_sizes: usize,
+    /// the limits which are exceeded
+    pub exceeded: Vec<String>,
+}
+
+impl GrowthReport {
+    /// `size` is the size of a conda package, pypi packages aren't counted
+    pub fn new(
+        from: &str,
+        to: &str,
+        diff: &RecipeDiff,
+        size: impl Fn(&Package) -> Option<u64>,
+        limits: &GrowthLimits,
+    ) -> Self {
+        let mut unknown_sizes = 0;
+        let mut sum = |pkgs: Vec<&Package>| {
+            let mut bytes = 0;
+            for pkg in pkgs {
+                match (&pkg.kind, size(pkg)) {
+                    (PackageKind::PyPi { .. }, _) => {}
+                    (_, Some(size)) => bytes += size as i64,
+                    (_, None) => unknown_sizes += 1,
+                }
+            }
+            bytes
+        };
+        let grown = sum(diff
+            .adds
+            .iter()
+            .chain(diff.updates.iter().map(|u| &u.to))
+            .collect());
+        let shrunk = sum(diff
+            .deletes
+            .iter()
+            .chain(diff.updates.iter().map(|u| &u.from))
+            .collect());
+        let mut report = Self {
+            from: from.to_string(),
+            to: to.to_string(),
+            new_packages: diff.adds.len(),
+            size_growth: grown - shrunk,
+            unknown_sizes,
+            exceeded: vec![],
+        };
+        if let Some(max) = limits.max_new_packages {
+            if report.new_packages > max {
+                report.exceeded.push(format!(
+                    "{} new packages, more than {}",
+                    format_count(report.new_packages),
+                    format_count(max)
+                ));
+            }
+        }
+        if let Some(max) = limits.max_size_growth {
+            if report.size_growth > max as i64 {
+                report.exceeded.push(format!(
+                    "{} larger, more than {}",
+                    format_bytes(report.size_growth as u64),
+                    format_bytes(max)
+                ));
+            }
+        }
+        report
+    }
+}
+
+impl Display for GrowthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let growth = if self.size_growth < 0 {
+            format!("-{}", format_bytes(self.size_growth.unsigned_abs()))
+        } else {
+            format!("+{}", format_bytes(self.size_growth as u64))
+        };
+        writeln!(f, "{} → {}:", self.from, self.to)?;
+        writeln!(f, "  new packages: {}", format_count(self.new_packages))?;
+        write!(f, "  size growth: {}", growth)?;
+        if self.unknown_sizes > 0 {
+            write!(
+                f,
+                " ({} packages of unknown size)",
+                format_count(self.unknown_sizes)
+            )?;
+        }
+        if self.exceeded.is_empty() {
+            write!(f, "\nwithin the limits")
+        } else {
+            write!(f, "\nneeds sign-off: {}", self.exceeded.join(", "))
+        }
+    }
+}
+
+/// the size of the package tarball in the pkgs cache, `None` if it isn't cached
+pub fn cached_size(pkgs_dirs: &[PathBuf], pkg: &Package) -> Option<u64> {
+    let build = match &pkg.kind {
+        PackageKind::Conda { build, .. } => build,
+        PackageKind::PyPi { .. } => return None,
+    };
+    let dist = format!("{}-{}-{}", pkg.name, pkg.version, build);
+    pkgs_dirs.iter().find_map(|dir| {
+        [".conda", ".tar.bz2"]
+            .iter()
+            .find_map(|ext| std::fs::metadata(dir.join(format!("{}{}", dist, ext))).ok())
+            .map(|metadata| metadata.len())
+    })
+}
+
+#[test]
+fn test_growth_report() {
+    use crate::recipe::Recipe;
+
+    let from: Recipe = r#"
+numpy                     1.24.1           py310h5d7c261_0    conda-forge
+zlib                      1.2.13               h166bdaf_4
+requests                  2.28.1                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let to: Recipe = r#"
+numpy                     1.24.3           py310h5d7c261_0    conda-forge
+scipy                     1.10.1           py310h8deb116_0    conda-forge
+libcxx                    12.0.0               h2f01273_0
+requests                  2.28.2                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let diff = from.clone().diff(to.clone());
+    const MIB: u64 = 1024 * 1024;
+    let size = |pkg: &Package| match (pkg.name.as_str(), pkg.version.as_str()) {
+        ("numpy", "1.24.1") => Some(7 * MIB),
+        ("numpy", "1.24.3") => Some(8 * MIB),
+        ("scipy", _) => Some(30 * MIB),
+        ("zlib", _) => Some(MIB),
+        _ => None,
+    };
+    let report = |limits: GrowthLimits| GrowthReport::new("v1", "v2", &diff, size, &limits);
+
+    let unlimited = report(GrowthLimits::default());
+    assert_eq!(unlimited.new_packages, 2);
+    // scipy and the new numpy, minus zlib and the old numpy
+    assert_eq!(unlimited.size_growth, (30 + 8 - 1 - 7) * MIB as i64);
+    // libcxx, the pypi requests isn't counted
+    assert_eq!(unlimited.unknown_sizes, 1);
+    assert!(unlimited.exceeded.is_empty());
+
+    // the limits themselves are allowed
+    let at_limits = report(GrowthLimits {
+        max_new_packages: Some(2),
+        max_size_growth: Some(30 * MIB),
+    });
+    assert!(at_limits.exceeded.is_empty());
+    assert_eq!(
+        at_limits.to_string(),
+        "v1 → v2:\n  new packages: 2\n  size growth: +30.0 MiB (1 packages of unknown size)\nwithin the limits"
+    );
+
+    let just_over = report(GrowthLimits {
+        max_new_packages: Some(1),
+        max_size_growth: Some(30 * MIB - 1),
+    });
+    assert_eq!(just_over.exceeded.len(), 2);
+    let over_limits = report(GrowthLimits {
+        max_new_packages: Some(1),
+        max_size_growth: Some(20 * MIB),
+    });
+    assert!(over_limits.to_string().ends_with(
+        "needs sign-off: 2 new packages, more than 1, 30.0 MiB larger, more than 20.0 MiB"
+    ));
+
+    // zlib comes back while scipy and libcxx are gone
+    let shrunk = GrowthReport::new(
+        "v2",
+        "v1",
+        &to.diff(from),
+        |_| Some(MIB),
+        &GrowthLimits::default(),
+    );
+    assert_eq!(shrunk.size_growth, -(MIB as i64));
+    assert!(shrunk.to_string().contains("size growth: -1.0 MiB\n"));
+}
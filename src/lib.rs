@@ -1,2 +1,12 @@
 pub mod action;
+pub mod error;
+pub mod growth;
+pub mod notify;
+pub mod output;
+pub mod prelude;
+pub mod query;
 pub mod recipe;
+pub mod repo;
+pub mod report;
+pub mod selector;
+pub mod stats;
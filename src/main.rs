@@ -1,10 +1,31 @@
-use std::path::PathBuf;
+use std::{
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use clap::{Parser, Subcommand, ValueHint};
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use console::style;
 
 use conda_cage::{
-    action::{self, try_get_env_recipe},
-    recipe::Recipe,
+    action::{
+        self, tail_log, try_get_env_recipe, BlockedTool, ChildPriority, EnvTarget, InstallOptions,
+        IoNice, OnConflict, Rollback, Sandbox, Solver,
+    },
+    error::Error,
+    growth::{cached_size, parse_size, GrowthLimits, GrowthReport},
+    human_println,
+    notify::{Notification, NotifyTarget},
+    output::{confirm, human::format_duration, set_machine_mode, set_quiet_mode},
+    recipe::{
+        source::{GitlabRepo, DEFAULT_RECIPE_SERVER},
+        ChannelAlias, DiffOptions, ParseOptions, Recipe, RecipeSource,
+    },
+    repo::{explain_changes, RecipeRepo, VersionKind, VersionMatch},
+    report::{EnvReport, RunReport, Status},
+    selector::parse_platform,
+    stats::{self, parse_since, StatLine, Summary},
+    summary_println,
 };
 
 #[derive(Parser, Debug)]
@@ -13,24 +34,104 @@ use conda_cage::{
 struct Args {
     #[clap(subcommand)]
     command: Commands,
+
+    #[clap(
+        long,
+        value_parser,
+        allow_hyphen_values = true,
+        help = "Run conda with the given niceness"
+    )]
+    nice: Option<i32>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Run conda with the given io priority on linux: idle, best-effort[:0-7] or realtime[:0-7]"
+    )]
+    ionice: Option<IoNice>,
+
+    #[clap(
+        long,
+        value_parser = parse_cpu_limit,
+        help = "Limit the number of threads used by conda-cage and conda"
+    )]
+    cpu_limit: Option<usize>,
+
+    #[clap(
+        long,
+        value_hint = ValueHint::ExecutablePath,
+        value_parser,
+        help = "Run the given conda binary instead of `conda` in PATH, e.g. mamba or an absolute path, defaults to $CONDA_CAGE_BIN"
+    )]
+    conda_bin: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "The gitlab url of the recipes, or a url template like `https://host/{env}/{version}.txt`, defaults to $CONDA_CAGE_RECIPE_SERVER or http://hftgitlab"
+    )]
+    recipe_server: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        help = "Run `conda info` again instead of reusing its output cached by a previous run of the same conda binary"
+    )]
+    no_info_cache: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Allow a recipe to list a conda package and a pypi package of the same name, e.g. the protobuf library and its python binding"
+    )]
+    allow_conda_pypi_pairs: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     #[clap(about = "Install conda conda")]
     Install {
-        #[clap(value_parser, help = "The env name you need to install")]
-        env_name: String,
+        #[clap(
+            value_parser,
+            required_unless_present = "prefix",
+            help = "The env name you need to install"
+        )]
+        env_name: Option<String>,
+
+        #[clap(
+            long,
+            value_hint = ValueHint::DirPath,
+            value_parser,
+            conflicts_with_all = &["env-name", "rename", "staged"],
+            help = "Install the env at the given prefix instead of by name, e.g. a shared env on NFS, the recipe is named after its last directory"
+        )]
+        prefix: Option<PathBuf>,
 
         #[clap(long, value_parser, help = "Specify the version of env")]
         version: Option<String>,
 
+        #[clap(
+            long,
+            value_parser,
+            conflicts_with_all = &["version", "file"],
+            help = "Install the newest version of env matching a glob like `2024.03.*` or a range like `>=2024.03,<2024.04`"
+        )]
+        version_match: Option<VersionMatch>,
+
+        #[clap(
+            long,
+            action,
+            requires = "version-match",
+            help = "Also consider the pre-release versions like `2024.04.0rc1` for `--version-match`"
+        )]
+        include_prerelease: bool,
+
         #[clap(
             short,
             long,
             value_hint = ValueHint::FilePath,
-            value_parser = validate_path,
-            help = "Install the env by the local given file"
+            value_parser = validate_recipe_path,
+            help = "Install the env by the local given file, `-` reads the recipe from stdin"
         )]
         file: Option<PathBuf>,
 
@@ -41,6 +142,14 @@ enum Commands {
         )]
         force: bool,
 
+        #[clap(
+            long,
+            value_parser,
+            conflicts_with = "force",
+            help = "How to handle the existing env which doesn't match the target env: error, skip or overwrite, the default is updating it"
+        )]
+        on_conflict: Option<OnConflict>,
+
         #[clap(
             long,
             action,
@@ -48,14 +157,288 @@ enum Commands {
         )]
         show_diff: bool,
 
+        #[clap(
+            long,
+            action,
+            requires = "show-diff",
+            help = "Also show the unchanged packages in the difference"
+        )]
+        show_unchanged: bool,
+
         #[clap(long, value_parser, help = "Rename the installing env name")]
         rename: Option<String>,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Extra index url used by pip, can be specified multiple times"
+        )]
+        pip_extra_index_url: Vec<String>,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Only show the last n lines of conda output when failed"
+        )]
+        max_log_lines: Option<usize>,
+
+        #[clap(
+            long,
+            value_hint = ValueHint::FilePath,
+            value_parser,
+            help = "Write every conda command with its full output to the given file when failed"
+        )]
+        conda_log: Option<PathBuf>,
+
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = ChannelPriority::Flexible,
+            help = "The channel priority used by conda when installing packages"
+        )]
+        channel_priority: ChannelPriority,
+
+        #[clap(
+            long,
+            value_hint = ValueHint::DirPath,
+            value_parser = validate_path,
+            help = "Use the given directory of packages as a channel, can be specified multiple times"
+        )]
+        local_channel: Vec<PathBuf>,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Also install the same env into the given env name, can be specified multiple times"
+        )]
+        also: Vec<String>,
+
+        #[clap(
+            long,
+            action,
+            help = "Stop installing the other envs when one of them failed"
+        )]
+        fail_fast: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Skip the pypi packages instead of failing when pip can't be installed"
+        )]
+        keep_going: bool,
+
+        #[clap(
+            long,
+            value_hint = ValueHint::FilePath,
+            value_parser,
+            help = "Write a machine-readable JSON report of the run to the given file, `-` means stdout and moves the other output to stderr"
+        )]
+        report: Option<PathBuf>,
+
+        #[clap(
+            short,
+            long,
+            action,
+            help = "Only print the errors and the final summary"
+        )]
+        quiet: bool,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Notify when the run ends by `webhook=<url>` or `command=<cmd>`, can be specified multiple times"
+        )]
+        notify: Vec<NotifyTarget>,
+
+        #[clap(
+            long,
+            value_parser,
+            default_value_t = 10,
+            help = "The timeout seconds of the webhook notification"
+        )]
+        notify_timeout: u64,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Run the command inside the env after a successful install, can be specified multiple times"
+        )]
+        post_install_hook: Vec<String>,
+
+        #[clap(
+            long,
+            action,
+            help = "Run the post-link scripts of the packages conda installs and the post install hooks under bwrap on linux, only the env and the pkgs dirs are writable, and the network of the hooks is cut off"
+        )]
+        sandbox_hooks: bool,
+
+        #[clap(
+            long,
+            action,
+            requires = "sandbox-hooks",
+            help = "Keep the network of the sandboxed post install hooks, conda always keeps it to download the packages"
+        )]
+        sandbox_network: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Fail when the installed packages clobber paths of each other"
+        )]
+        strict: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Allow removing python or pip from the env which still has pypi packages"
+        )]
+        allow_remove_python: bool,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "The solver used by conda: libmamba or classic, which only work with conda >= 22.11, or mamba to run mamba in PATH to change the env, the default is conda's own default"
+        )]
+        solver: Option<Solver>,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Kill a single pip install which runs longer than the given seconds, and retry it later"
+        )]
+        pip_package_timeout: Option<u64>,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Give up the pypi pkgs after this many failed pip installs in total [default: 50]"
+        )]
+        pip_max_failures: Option<usize>,
+
+        #[clap(
+            long,
+            action,
+            help = "Don't reinstall the packages which only moved to another channel"
+        )]
+        ignore_channel_changes: bool,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Treat the channel urls ending with the suffix as the channel, by `<url suffix>=<channel>`, can be specified multiple times"
+        )]
+        channel_alias: Vec<ChannelAlias>,
+
+        #[clap(
+            long,
+            action,
+            help = "Review the difference and deselect the changes not to apply, needs a terminal"
+        )]
+        interactive: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Build into `<env>.cage-staged` and swap it in by `conda rename` after the post install hooks pass, the replaced env is kept for `rollback`"
+        )]
+        staged: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Converge the existing env even if it isn't managed by conda-cage, and manage it from now on"
+        )]
+        adopt: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Look for the paths provided by more than one package before installing, by the packages in the pkgs cache, fail on them with `--strict`"
+        )]
+        preflight_check: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Exit with 10 when an env was changed, and 11 when recoverable issues were met, instead of 0"
+        )]
+        detailed_exit_codes: bool,
+
+        #[clap(
+            long,
+            value_parser = parse_error_kind,
+            value_delimiter = ',',
+            help = "Retry a failed install once from scratch with `--force` when it fails with one of the given error kinds, e.g. corrupt-meta,verification, ignored with `--staged`"
+        )]
+        auto_force_on: Vec<String>,
+
+        #[clap(
+            long,
+            action,
+            conflicts_with = "staged",
+            help = "Print the conda commands which would be run instead of running them, the post install hooks are skipped"
+        )]
+        dry_run: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Print why each package is installed, updated, deleted or skipped: its recipe line, the channel alias and how it differs from the env"
+        )]
+        explain: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Leave the env as it is when the install fails midway, instead of restoring its previous packages"
+        )]
+        no_rollback: bool,
+
+        #[clap(
+            long,
+            action,
+            conflicts_with = "no-rollback",
+            help = "Restore the previous packages of an env which fails midway without asking, it's only asked on a terminal"
+        )]
+        rollback_on_failure: bool,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Refuse to install with the release of conda or pip by `<conda|pip>==<version>`, or only warn about it with a `:warn` suffix, can be specified multiple times"
+        )]
+        blocked_tool: Vec<BlockedTool>,
+
+        #[clap(
+            long,
+            value_parser = parse_platform,
+            help = "The subdir the `# [<subdir>]` and `# [not <subdir>]` selectors of the recipe lines are evaluated against, e.g. osx-arm64, the default is the platform of this machine"
+        )]
+        platform: Option<String>,
+
+        #[clap(
+            long,
+            value_hint = ValueHint::DirPath,
+            value_parser,
+            help = "Append the local usage statistics of the run to the given directory"
+        )]
+        stats_dir: Option<PathBuf>,
     },
     #[clap(about = "Diff remote env and local env")]
     Diff {
-        #[clap(value_parser, help = "The env name you need to diff")]
+        #[clap(
+            value_parser,
+            help = "The env name you need to diff, or the left recipe file or `env:<name>` with the right one"
+        )]
         env_name: String,
 
+        #[clap(
+            value_parser,
+            conflicts_with_all = &["version", "file"],
+            help = "Compare the left recipe with this recipe file or `env:<name>` instead of the remote env, and exit with 1 when they differ"
+        )]
+        right: Option<RecipeSource>,
+
         #[clap(long, value_parser, help = "Specify the version of env")]
         version: Option<String>,
 
@@ -67,24 +450,364 @@ enum Commands {
             help = "Use the given file as remote env"
         )]
         file: Option<PathBuf>,
+
+        #[clap(long, action, help = "Also show the unchanged packages")]
+        show_unchanged: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Don't show the packages which only moved to another channel"
+        )]
+        ignore_channel_changes: bool,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Treat the channel urls ending with the suffix as the channel, by `<url suffix>=<channel>`, can be specified multiple times"
+        )]
+        channel_alias: Vec<ChannelAlias>,
+    },
+    #[clap(
+        about = "Check the local env matches the remote env, exit with 1 when it differs and 2 when it doesn't exist"
+    )]
+    Check {
+        #[clap(value_parser, help = "The env name you need to check")]
+        env_name: String,
+
+        #[clap(long, value_parser, help = "Specify the version of env")]
+        version: Option<String>,
+
+        #[clap(
+            short,
+            long,
+            value_hint = ValueHint::FilePath,
+            value_parser = validate_recipe_path,
+            help = "Use the given file as remote env, `-` reads it from stdin"
+        )]
+        file: Option<PathBuf>,
+
+        #[clap(
+            long,
+            action,
+            help = "Don't count the packages which only moved to another channel"
+        )]
+        ignore_channel_changes: bool,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Treat the channel urls ending with the suffix as the channel, by `<url suffix>=<channel>`, can be specified multiple times"
+        )]
+        channel_alias: Vec<ChannelAlias>,
+    },
+    #[clap(about = "Summarize the local usage statistics")]
+    Stats {
+        #[clap(
+            long,
+            value_hint = ValueHint::DirPath,
+            value_parser = validate_path,
+            help = "The directory given to `install --stats-dir`"
+        )]
+        stats_dir: PathBuf,
+
+        #[clap(
+            long,
+            value_parser = parse_since,
+            help = "Only count the runs in the last period, e.g. 30d, 12h or 2w"
+        )]
+        since: Option<Duration>,
+    },
+    #[clap(about = "Show what conda reports about this machine, and whether it was cached")]
+    Info {
+        #[clap(long, action, help = "Print it as JSON")]
+        json: bool,
+    },
+    #[clap(about = "Stop managing the env, so other tools can take it over")]
+    Disown {
+        #[clap(value_parser, help = "The env name you need to disown")]
+        env_name: String,
+    },
+    #[clap(about = "Remove the env by `conda env remove`")]
+    Uninstall {
+        #[clap(value_parser, help = "The env name you need to uninstall")]
+        env_name: String,
+
+        #[clap(short, long, action, help = "Remove the env without confirming it")]
+        yes: bool,
+
+        #[clap(
+            long,
+            action,
+            help = "Remove the staged and previous envs kept by `install --staged` as well"
+        )]
+        purge: bool,
+    },
+    #[clap(about = "Swap back the env replaced by the last `install --staged`")]
+    Rollback {
+        #[clap(value_parser, help = "The env name you need to roll back")]
+        env_name: String,
+    },
+    #[clap(about = "Explain the changes between two versions of the env recipe")]
+    WhyChanged {
+        #[clap(value_parser, help = "The env name you need to explain")]
+        env_name: String,
+
+        #[clap(long, value_parser, help = "The old version of env")]
+        from: String,
+
+        #[clap(
+            long,
+            value_parser,
+            default_value = "master",
+            help = "The new version of env"
+        )]
+        to: String,
+    },
+    #[clap(about = "List the versions of the env recipe on the recipe server")]
+    Versions {
+        #[clap(value_parser, help = "The env name you need to list")]
+        env_name: String,
+
+        #[clap(long, action, help = "Print the versions as JSON")]
+        json: bool,
+    },
+    #[clap(about = "Search the packages of the given name in the conda channels")]
+    Search {
+        #[clap(
+            value_parser,
+            help = "The package you need to search, e.g. `numpy`, `numpy>=1.24` or `conda-forge::numpy=1.24`"
+        )]
+        name: String,
+
+        #[clap(
+            short,
+            long,
+            value_parser,
+            help = "Search the given channel instead of the channels of conda, can be specified multiple times"
+        )]
+        channel: Vec<String>,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Only show the versions matching the constraint, e.g. `1.24.*` or `>=1.24`"
+        )]
+        version: Option<String>,
+
+        #[clap(long, action, help = "Print the packages as JSON")]
+        json: bool,
+    },
+    #[clap(about = "Show the metadata of a package archive without unpacking it")]
+    Inspect {
+        #[clap(value_parser, help = "The .tar.bz2 or .conda package")]
+        package: PathBuf,
+
+        #[clap(long, action, help = "Print the metadata as JSON")]
+        json: bool,
+
+        #[clap(long, action, help = "List the paths of the payload")]
+        files: bool,
+    },
+    #[clap(about = "Check the growth of the env recipe between two versions against limits")]
+    CompareVersions {
+        #[clap(value_parser, help = "The env name you need to compare")]
+        env_name: String,
+
+        #[clap(long, value_parser, help = "The old version of env")]
+        from: String,
+
+        #[clap(
+            long,
+            value_parser,
+            default_value = "master",
+            help = "The new version of env"
+        )]
+        to: String,
+
+        #[clap(
+            long,
+            value_parser,
+            help = "Exit with 1 when the new version adds more packages"
+        )]
+        max_new_packages: Option<usize>,
+
+        #[clap(
+            long,
+            value_parser = parse_size,
+            help = "Exit with 1 when the packages of the new version are larger by more, e.g. `2G`, the sizes are of the tarballs in the pkgs cache"
+        )]
+        max_size_growth: Option<u64>,
+
+        #[clap(long, action, help = "Print the report as JSON")]
+        json: bool,
+    },
+    #[clap(about = "Dump the installed env to a recipe")]
+    Export {
+        #[clap(
+            value_parser,
+            required_unless_present = "from-prefix",
+            help = "The env name you need to export"
+        )]
+        env_name: Option<String>,
+
+        #[clap(
+            long,
+            value_hint = ValueHint::DirPath,
+            value_parser,
+            conflicts_with = "env-name",
+            help = "Read the env at the given prefix from its files without conda, e.g. the env of a docker image mounted by `docker export <container> | tar -x -C <dir>`"
+        )]
+        from_prefix: Option<PathBuf>,
+
+        #[clap(
+            short,
+            long,
+            value_hint = ValueHint::FilePath,
+            value_parser,
+            help = "Write the recipe to the given file instead of stdout"
+        )]
+        output: Option<PathBuf>,
     },
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// the exit code when the envs are installed but their post install hooks failed
+const HOOK_FAILED_EXIT_CODE: i32 = 3;
+
+/// the exit code of `check` when the env doesn't exist, 1 means it differs
+const CHECK_MISSING_EXIT_CODE: i32 = 2;
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum ChannelPriority {
+    Strict,
+    Flexible,
+}
+
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    match args.command {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(cpu_limit) = args.cpu_limit {
+        runtime.worker_threads(cpu_limit);
+    }
+    action::set_child_priority(ChildPriority {
+        nice: args.nice,
+        ionice: args.ionice,
+        threads: args.cpu_limit,
+    })?;
+    action::set_info_cache(!args.no_info_cache);
+    if let Some(conda_bin) = args
+        .conda_bin
+        .map(PathBuf::into_os_string)
+        .or_else(|| std::env::var_os("CONDA_CAGE_BIN"))
+    {
+        action::set_conda_bin(conda_bin)?;
+    }
+    let recipe_server = args
+        .recipe_server
+        .or_else(|| std::env::var("CONDA_CAGE_RECIPE_SERVER").ok())
+        .unwrap_or_else(|| DEFAULT_RECIPE_SERVER.to_string());
+    let repo = GitlabRepo::new(&recipe_server);
+    let parse_options = ParseOptions {
+        allow_conda_pypi_pairs: args.allow_conda_pypi_pairs,
+    };
+    runtime
+        .enable_all()
+        .build()?
+        .block_on(run(args.command, repo, parse_options))
+}
+
+async fn run(
+    command: Commands,
+    repo: GitlabRepo,
+    parse_options: ParseOptions,
+) -> anyhow::Result<()> {
+    match command {
         Commands::Install {
             env_name,
+            prefix,
             version,
+            version_match,
+            include_prerelease,
             file,
             force,
+            on_conflict,
             show_diff,
+            show_unchanged,
             rename,
+            pip_extra_index_url,
+            max_log_lines,
+            conda_log,
+            channel_priority,
+            local_channel,
+            also,
+            fail_fast,
+            keep_going,
+            report,
+            quiet,
+            notify,
+            notify_timeout,
+            post_install_hook,
+            sandbox_hooks,
+            sandbox_network,
+            strict,
+            allow_remove_python,
+            solver,
+            pip_package_timeout,
+            pip_max_failures,
+            ignore_channel_changes,
+            channel_alias,
+            interactive,
+            staged,
+            adopt,
+            preflight_check,
+            detailed_exit_codes,
+            auto_force_on,
+            dry_run,
+            explain,
+            no_rollback,
+            rollback_on_failure,
+            blocked_tool,
+            platform,
+            stats_dir,
         } => {
+            let started = Instant::now();
+            // the report is the only thing on stdout then
+            let report_to_stdout = report.as_deref() == Some(Path::new("-"));
+            set_machine_mode(report_to_stdout);
+            set_quiet_mode(quiet);
+            // the recipe of an env at a prefix is named after its last directory
+            let env_name = env_name
+                .or_else(|| {
+                    prefix
+                        .as_deref()
+                        .and_then(Path::file_name)
+                        .map(|name| name.to_string_lossy().into_owned())
+                })
+                .ok_or_else(|| anyhow::anyhow!("no env name in the prefix"))?;
+            let version = match version_match {
+                Some(version_match) => {
+                    // only the tags are released versions
+                    let versions = repo
+                        .versions(&env_name)
+                        .await?
+                        .into_iter()
+                        .filter(|v| v.kind == VersionKind::Tag)
+                        .map(|v| v.name)
+                        .collect::<Vec<_>>();
+                    let resolved = version_match.resolve(&versions, include_prerelease)?;
+                    human_println!(
+                        "resolved '{}' to version {} of env '{}'",
+                        version_match,
+                        resolved,
+                        env_name
+                    );
+                    Some(resolved)
+                }
+                None => version,
+            };
             let new_recipe = if let Some(file) = file {
-                std::fs::read_to_string(file)?
+                read_recipe_file(&file)?
             } else {
                 let version = version
                     .or(Some("master".to_string()))
@@ -96,16 +819,275 @@ async fn main() -> anyhow::Result<()> {
                         }
                     })
                     .unwrap();
-                fetch_recipe(&env_name, &version).await?
+                repo.fetch(&env_name, &version).await?
+            };
+            let target = match prefix {
+                Some(prefix) => EnvTarget::Prefix(prefix),
+                None => EnvTarget::Name(rename.unwrap_or(env_name)),
+            };
+            let targets = std::iter::once(target)
+                .chain(also.into_iter().map(EnvTarget::Name))
+                .collect::<Vec<_>>();
+            let sandbox = sandbox_hooks.then_some(Sandbox {
+                network: sandbox_network,
+            });
+            let options = InstallOptions {
+                force_reinstall: force,
+                show_diff,
+                show_unchanged,
+                pip_extra_index_urls: pip_extra_index_url,
+                strict_channel_priority: channel_priority == ChannelPriority::Strict,
+                local_channels: local_channel,
+                on_conflict,
+                keep_going,
+                strict,
+                allow_remove_python,
+                solver,
+                progress: None,
+                pip_package_timeout: pip_package_timeout.map(Duration::from_secs),
+                pip_max_failures,
+                ignore_channel_changes,
+                channel_aliases: channel_alias,
+                interactive,
+                adopt,
+                preflight_check,
+                dry_run,
+                blocked_tools: blocked_tool,
+                platform,
+                explain,
+                rollback: match (no_rollback, rollback_on_failure) {
+                    // a failed staged env is discarded, the live env is untouched anyway
+                    _ if staged => Rollback::Never,
+                    (true, _) => Rollback::Never,
+                    (_, true) => Rollback::Always,
+                    // nobody would answer an unattended run
+                    _ if std::io::stdin().is_terminal() && !report_to_stdout && !quiet => {
+                        Rollback::Ask
+                    }
+                    _ => Rollback::Always,
+                },
+                // a failed staged env is discarded instead
+                auto_force_on: if staged { vec![] } else { auto_force_on },
+                sandbox: sandbox.clone(),
+                allow_conda_pypi_pairs: parse_options.allow_conda_pypi_pairs,
             };
-            let env_name = rename.unwrap_or(env_name);
-            action::install(&env_name, &new_recipe, force, show_diff).await?;
+            // only created once an env fails, a successful run leaves no empty log behind
+            let mut conda_log_file = None;
+            let mut reports = vec![];
+            let mut fatal = None;
+            let mut hook_failed = vec![];
+            let mut failure_kinds = vec![];
+            for target in &targets {
+                // `--staged` only works by name
+                let name = target.to_string();
+                let mut env_report = EnvReport::new(&name);
+                // the hooks verify the staged env before it replaces the live one
+                let build_target = if staged {
+                    EnvTarget::Name(action::staged_env_name(&name))
+                } else {
+                    target.clone()
+                };
+                if conda_log.is_some() {
+                    action::record_transcript();
+                }
+                let result = async {
+                    if staged {
+                        action::prepare_staged(&name).await?;
+                    }
+                    action::install(&build_target, &new_recipe, &options, &mut env_report).await
+                }
+                .await;
+                let transcript = action::take_transcript();
+                let result = result.map_err(|err| {
+                    if let Some(path) = &conda_log {
+                        if conda_log_file.is_none() {
+                            match std::fs::File::create(path) {
+                                Ok(file) => conda_log_file = Some(file),
+                                Err(e) => eprintln!(
+                                    "fail to create conda log '{}': {}",
+                                    path.display(),
+                                    e
+                                ),
+                            }
+                        }
+                        if let Some(file) = conda_log_file.as_mut() {
+                            let _ = writeln!(
+                                file,
+                                "# env: {}\n{}{:#}",
+                                target,
+                                transcript.unwrap_or_default(),
+                                err
+                            );
+                        }
+                    }
+                    match max_log_lines {
+                        // classified errors are already short
+                        Some(n) if err.downcast_ref::<Error>().is_none() => tail_error(&err, n),
+                        _ => err,
+                    }
+                });
+                if let Err(err) = result {
+                    if staged {
+                        discard_staged(&name).await;
+                    }
+                    failure_kinds.push(error_kind(&err));
+                    env_report.status = Status::Failed;
+                    env_report.error = Some(format!("{:#}", err));
+                    reports.push(env_report);
+                    if fail_fast || targets.len() == 1 {
+                        fatal = Some(err);
+                        break;
+                    }
+                    eprintln!("fail to install env '{}': {:?}", target, err);
+                    continue;
+                }
+
+                if env_report.status == Status::Success && !dry_run {
+                    if let Err(err) = action::run_post_install_hooks(
+                        &build_target,
+                        &post_install_hook,
+                        sandbox.as_ref(),
+                    )
+                    .await
+                    {
+                        eprintln!("{}", err);
+                        if staged {
+                            discard_staged(&name).await;
+                        }
+                        failure_kinds.push(error_kind(&err));
+                        env_report.status = Status::Failed;
+                        env_report.error = Some(format!("{:#}", err));
+                        hook_failed.push(name.clone());
+                        if fail_fast {
+                            reports.push(env_report);
+                            break;
+                        }
+                    } else if staged {
+                        if let Err(err) = action::swap_staged(&name).await {
+                            eprintln!("fail to swap env '{}': {:?}", target, err);
+                            failure_kinds.push(error_kind(&err));
+                            env_report.status = Status::Failed;
+                            env_report.error = Some(format!("{:#}", err));
+                            if fail_fast {
+                                reports.push(env_report);
+                                break;
+                            }
+                        }
+                    }
+                } else if staged {
+                    // the staged env is up to date, so is the live env
+                    discard_staged(&name).await;
+                }
+                reports.push(env_report);
+            }
+
+            if targets.len() > 1 {
+                summary_println!("{}", style("Summary:").bold());
+                for env_report in &reports {
+                    let status = match env_report.status {
+                        Status::Success => style("success").green(),
+                        Status::Skipped => style("skipped").yellow(),
+                        Status::Failed => style("failed").red(),
+                    };
+                    let elapsed = env_report.phases.iter().map(|p| p.seconds).sum::<f64>();
+                    summary_println!(
+                        " {:<30} {:<10} {}",
+                        env_report.env_name,
+                        status,
+                        format_duration(Duration::from_secs_f64(elapsed))
+                    );
+                }
+            }
+            let failed = reports
+                .iter()
+                .filter(|r| r.status == Status::Failed)
+                .map(|r| r.env_name.clone())
+                .collect::<Vec<_>>();
+            let run_report = RunReport::new(reports);
+            if let Some(outcome) = run_report.outcome {
+                let warnings = run_report
+                    .envs
+                    .iter()
+                    .map(|e| e.warnings.len())
+                    .sum::<usize>();
+                if warnings > 0 {
+                    summary_println!("Result: {} ({} warnings)", outcome, warnings);
+                } else {
+                    summary_println!("Result: {}", outcome);
+                }
+            }
+            if report_to_stdout {
+                println!("{}", serde_json::to_string_pretty(&run_report)?);
+            } else if let Some(report) = report {
+                std::fs::write(report, serde_json::to_string_pretty(&run_report)?)?;
+            }
+            if let Some(stats_dir) = stats_dir {
+                let line = StatLine::new(&run_report, started.elapsed(), failure_kinds);
+                // statistics never change the result of the run
+                if let Err(err) = stats::append(&stats_dir, &line) {
+                    eprintln!("fail to write statistics: {:?}", err);
+                }
+            }
+            if !notify.is_empty() {
+                let notification = Notification::new(&run_report, started.elapsed())?;
+                for target in &notify {
+                    // failing to notify never changes the result of the run
+                    let notifier = target.notifier(Duration::from_secs(notify_timeout));
+                    if let Err(err) = tokio::task::block_in_place(|| notifier.notify(&notification))
+                    {
+                        eprintln!("fail to notify: {:?}", err);
+                    }
+                }
+            }
+            if let Some(err) = fatal {
+                return Err(err);
+            }
+            if !failed.is_empty() {
+                if failed.iter().all(|e| hook_failed.contains(e)) {
+                    eprintln!(
+                        "Error: post install hooks failed for envs: {}",
+                        failed.join(", ")
+                    );
+                    std::process::exit(HOOK_FAILED_EXIT_CODE);
+                }
+                return Err(anyhow::anyhow!(
+                    "fail to install envs: {}",
+                    failed.join(", ")
+                ));
+            }
+            if detailed_exit_codes {
+                if let Some(outcome) = run_report.outcome.filter(|o| o.exit_code() != 0) {
+                    std::process::exit(outcome.exit_code());
+                }
+            }
         }
         Commands::Diff {
             env_name,
+            right,
             version,
             file,
+            show_unchanged,
+            ignore_channel_changes,
+            channel_alias,
         } => {
+            let diff_options = DiffOptions {
+                with_unchanged: show_unchanged,
+                ignore_channel_changes,
+                channel_aliases: channel_alias,
+            };
+            if let Some(right) = right {
+                let left = env_name
+                    .parse::<RecipeSource>()
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                let diff = load_recipe(&left, &parse_options)
+                    .await?
+                    .diff_with(load_recipe(&right, &parse_options).await?, &diff_options);
+                println!("{:#}", diff);
+                if !diff.is_empty() {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
             let new_recipe = if let Some(file) = file {
                 std::fs::read_to_string(file)?
             } else {
@@ -119,19 +1101,286 @@ async fn main() -> anyhow::Result<()> {
                         }
                     })
                     .unwrap();
-                fetch_recipe(&env_name, &version).await?
+                repo.fetch(&env_name, &version).await?
             };
-            let new_recipe =
-                Recipe::try_from(new_recipe.as_str()).map_err(|e| anyhow::anyhow!(e))?;
-            let old_recipe = try_get_env_recipe(&env_name).await?.unwrap_or_default();
-            let diff = old_recipe.diff(new_recipe);
+            let new_recipe = action::parse_recipe(&new_recipe, &parse_options).await?;
+            let old_recipe = try_get_env_recipe(&env_name, true)
+                .await?
+                .map(|s| s.into_recipe())
+                .unwrap_or_default();
+            let diff = old_recipe.diff_with(new_recipe, &diff_options);
             println!("{:#}", diff);
         }
+        Commands::Check {
+            env_name,
+            version,
+            file,
+            ignore_channel_changes,
+            channel_alias,
+        } => {
+            let new_recipe = if let Some(file) = file {
+                read_recipe_file(&file)?
+            } else {
+                let version = version
+                    .or(Some("master".to_string()))
+                    .map(|v| {
+                        if v == "latest" {
+                            "master".to_string()
+                        } else {
+                            v
+                        }
+                    })
+                    .unwrap();
+                repo.fetch(&env_name, &version).await?
+            };
+            let new_recipe = action::parse_recipe(&new_recipe, &parse_options).await?;
+            let old_recipe = match try_get_env_recipe(&env_name, true).await? {
+                Some(snapshot) => snapshot.into_recipe(),
+                None => {
+                    eprintln!("env '{}' doesn't exist", env_name);
+                    std::process::exit(CHECK_MISSING_EXIT_CODE);
+                }
+            };
+            let diff = old_recipe.diff_with(
+                new_recipe,
+                &DiffOptions {
+                    ignore_channel_changes,
+                    channel_aliases: channel_alias,
+                    ..Default::default()
+                },
+            );
+            if !diff.is_empty() {
+                println!("{:#}", diff);
+                std::process::exit(1);
+            }
+            println!("env '{}' matches the recipe", env_name);
+        }
+        Commands::Stats { stats_dir, since } => {
+            let (lines, corrupt) = stats::read(&stats_dir)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            println!("{}", style("Statistics:").bold());
+            print!("{}", Summary::new(&lines, corrupt, since, now));
+        }
+        Commands::Info { json } => {
+            let (info, source) = action::load_conda_info().await?;
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "source": source,
+                        "info": info,
+                    }))?
+                );
+            } else {
+                println!("conda info: {}", source);
+                for (title, dirs) in [
+                    ("envs dirs", &info.envs_dirs),
+                    ("pkgs dirs", &info.pkgs_dirs),
+                ] {
+                    println!("{}:", title);
+                    for dir in dirs {
+                        println!("  {}", dir.display());
+                    }
+                }
+                println!("virtual packages:");
+                for pkg in &info.virtual_pkgs {
+                    println!("  {}", pkg.join("="));
+                }
+            }
+        }
+        Commands::Disown { env_name } => {
+            if action::disown(&env_name).await? {
+                println!("env '{}' is no longer managed by conda-cage", env_name);
+            } else {
+                println!("env '{}' isn't managed by conda-cage", env_name);
+            }
+        }
+        Commands::Uninstall {
+            env_name,
+            yes,
+            purge,
+        } => {
+            if !yes && !confirm(&format!("remove env '{}'?", env_name))? {
+                println!("env '{}' is kept", env_name);
+                return Ok(());
+            }
+            for env in action::uninstall(&env_name, purge).await? {
+                println!("env '{}' is removed", env);
+            }
+        }
+        Commands::Rollback { env_name } => {
+            action::rollback_staged(&env_name).await?;
+            println!("env '{}' is rolled back", env_name);
+        }
+        Commands::Versions { env_name, json } => {
+            let versions = repo.versions(&env_name).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&versions)?);
+            } else if versions.is_empty() {
+                println!("env '{}' has no versions", env_name);
+            } else {
+                for version in &versions {
+                    println!("{}", version);
+                }
+            }
+        }
+        Commands::Search {
+            name,
+            channel,
+            version,
+            json,
+        } => {
+            let hits = action::search(&name, &channel, version.as_deref()).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&hits)?);
+            } else if hits.is_empty() {
+                println!("no package matches '{}'", name);
+            } else {
+                println!(
+                    "{:<24} {:<14} {:<24} {:<16} {:<14} size",
+                    "name", "version", "build", "channel", "subdir"
+                );
+                for hit in &hits {
+                    println!("{}", hit);
+                }
+            }
+        }
+        Commands::Inspect {
+            package,
+            json,
+            files,
+        } => {
+            let inspection = action::inspect(&package, files)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspection)?);
+            } else {
+                print!("{}", inspection);
+            }
+        }
+        Commands::WhyChanged { env_name, from, to } => {
+            let (from_contents, to_contents) =
+                tokio::try_join!(repo.fetch(&env_name, &from), repo.fetch(&env_name, &to))?;
+            let commits = repo.compare(&env_name, &from, &to).await;
+            let from_recipe = action::parse_recipe(&from_contents, &parse_options).await?;
+            let to_recipe = action::parse_recipe(&to_contents, &parse_options).await?;
+            let diff = from_recipe.diff(to_recipe);
+            println!("{:#}", diff);
+            let commits = match commits {
+                Ok(Some(commits)) => commits,
+                Ok(None) => {
+                    println!("no blame available, the repo can't compare the versions");
+                    vec![]
+                }
+                Err(err) => {
+                    println!("no blame available: {}", err);
+                    vec![]
+                }
+            };
+            for line in explain_changes(&diff, &from_contents, &to_contents, &commits) {
+                println!("{}", line);
+            }
+        }
+        Commands::CompareVersions {
+            env_name,
+            from,
+            to,
+            max_new_packages,
+            max_size_growth,
+            json,
+        } => {
+            let (from_contents, to_contents) =
+                tokio::try_join!(repo.fetch(&env_name, &from), repo.fetch(&env_name, &to))?;
+            let from_recipe = action::parse_recipe(&from_contents, &parse_options).await?;
+            let to_recipe = action::parse_recipe(&to_contents, &parse_options).await?;
+            let pkgs_dirs = action::pkgs_dirs().await?;
+            let limits = GrowthLimits {
+                max_new_packages,
+                max_size_growth,
+            };
+            let report = GrowthReport::new(
+                &from,
+                &to,
+                &from_recipe.diff(to_recipe),
+                |pkg| cached_size(&pkgs_dirs, pkg),
+                &limits,
+            );
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", report);
+            }
+            if !report.exceeded.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Export {
+            env_name,
+            from_prefix,
+            output,
+        } => {
+            let recipe = match (env_name, from_prefix) {
+                (_, Some(prefix)) => action::read_prefix_recipe(&prefix)?,
+                (Some(env_name), None) => try_get_env_recipe(&env_name, true)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("env '{}' doesn't exist", env_name))?
+                    .into_recipe(),
+                (None, None) => unreachable!("the env name is required without --from-prefix"),
+            };
+            match output {
+                Some(output) => std::fs::write(output, recipe.to_string())?,
+                None => print!("{}", recipe),
+            }
+        }
     }
 
     Ok(())
 }
 
+/// remove the staged env of a failed staged install, a leftover is repaired by the next run
+async fn discard_staged(env_name: &str) {
+    if let Err(err) = action::discard_staged(env_name).await {
+        eprintln!("fail to remove the staged env of '{}': {:?}", env_name, err);
+    }
+}
+
+/// the last lines of the conda output which caused the error, under the contexts of the error
+/// such as a rollback
+fn tail_error(err: &anyhow::Error, max_lines: usize) -> anyhow::Error {
+    let mut tailed = anyhow::anyhow!(tail_log(&err.root_cause().to_string(), max_lines));
+    for context in err.chain().rev().skip(1) {
+        tailed = tailed.context(context.to_string());
+    }
+    tailed
+}
+
+/// e.g. `disk_full`, `other` for the unclassified errors
+fn error_kind(err: &anyhow::Error) -> String {
+    err.downcast_ref::<Error>()
+        .map(Error::kind)
+        .unwrap_or("other")
+        .to_string()
+}
+
+/// e.g. `corrupt-meta` for the `corrupt_meta` errors
+fn parse_error_kind(kind: &str) -> std::result::Result<String, String> {
+    let kind = kind.replace('-', "_");
+    if Error::KINDS.contains(&kind.as_str()) {
+        Ok(kind)
+    } else {
+        Err(format!(
+            "unknown error kind, expected one of: {}",
+            Error::KINDS.map(|k| k.replace('_', "-")).join(", ")
+        ))
+    }
+}
+
+fn parse_cpu_limit(limit: &str) -> std::result::Result<usize, String> {
+    match limit.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err("expected a positive number".to_string()),
+    }
+}
+
 fn validate_path(path: &str) -> std::result::Result<PathBuf, String> {
     let path = PathBuf::from(path);
     if !path.exists() {
@@ -141,19 +1390,37 @@ fn validate_path(path: &str) -> std::result::Result<PathBuf, String> {
     Ok(path)
 }
 
-async fn fetch_recipe(env_name: &str, version: &str) -> anyhow::Result<String> {
-    let rsp = reqwest::get(format!(
-        "http://hftgitlab/conda-envs/{}/raw/{}/env.recipe?inline=false",
-        env_name, version
-    ))
-    .await?;
-    if !rsp.status().is_success() {
-        return Err(anyhow::anyhow!(
-            "fail to fetch env: {}, version: {}, err code: {}",
-            env_name,
-            version,
-            rsp.status()
-        ));
+/// the same as `validate_path`, but `-` means stdin
+fn validate_recipe_path(path: &str) -> std::result::Result<PathBuf, String> {
+    if path == "-" {
+        return Ok(PathBuf::from(path));
+    }
+    validate_path(path)
+}
+
+/// read the recipe file, or stdin for `-`
+fn read_recipe_file(path: &Path) -> anyhow::Result<String> {
+    if path != Path::new("-") {
+        return Ok(std::fs::read_to_string(path)?);
+    }
+    let mut contents = String::new();
+    std::io::stdin().read_to_string(&mut contents)?;
+    if contents.trim().is_empty() {
+        anyhow::bail!(
+            "the recipe from stdin is empty, pipe one in like `conda list -n <env> | conda-cage install <env> -f -`"
+        );
+    }
+    Ok(contents)
+}
+
+async fn load_recipe(source: &RecipeSource, options: &ParseOptions) -> anyhow::Result<Recipe> {
+    match source {
+        RecipeSource::Env(env_name) => Ok(try_get_env_recipe(env_name, true)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("env '{}' doesn't exist", env_name))?
+            .into_recipe()),
+        RecipeSource::File(path) => {
+            action::parse_recipe(&std::fs::read_to_string(path)?, options).await
+        }
     }
-    Ok(rsp.text().await?)
 }
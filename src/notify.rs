@@ -0,0 +1,210 @@
+use std::{process::Command, str::FromStr, time::Duration};
+
+use crate::report::{RunReport, Status};
+
+/// what is sent when a run ends
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub status: Status,
+    pub envs: Vec<String>,
+    pub duration: Duration,
+    /// the JSON `RunReport`
+    pub payload: String,
+}
+
+impl Notification {
+    pub fn new(report: &RunReport, duration: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            status: report.status,
+            envs: report.envs.iter().map(|e| e.env_name.clone()).collect(),
+            duration,
+            payload: serde_json::to_string(report)?,
+        })
+    }
+
+    fn status_name(&self) -> &'static str {
+        match self.status {
+            Status::Success => "success",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+        }
+    }
+
+    /// the env variables set for `command=<cmd>` notifiers
+    fn command_envs(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("CAGE_RESULT", self.status_name().to_string()),
+            ("CAGE_ENV", self.envs.join(",")),
+            (
+                "CAGE_DURATION",
+                format!("{:.3}", self.duration.as_secs_f64()),
+            ),
+        ]
+    }
+}
+
+pub trait Notifier {
+    fn notify(&self, notification: &Notification) -> anyhow::Result<()>;
+}
+
+/// POST the report to the url, and retry once when failed, it blocks on the current tokio
+/// runtime, so it must be called in `block_in_place`
+pub struct WebhookNotifier {
+    pub url: String,
+    pub timeout: Duration,
+}
+
+impl WebhookNotifier {
+    async fn post(&self, client: &reqwest::Client, payload: &str) -> anyhow::Result<()> {
+        let rsp = client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await?;
+        if !rsp.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "fail to notify webhook {}, err code: {}",
+                self.url,
+                rsp.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder().timeout(self.timeout).build()?;
+        tokio::runtime::Handle::current().block_on(async {
+            match self.post(&client, &notification.payload).await {
+                Ok(()) => Ok(()),
+                Err(_) => self.post(&client, &notification.payload).await,
+            }
+        })
+    }
+}
+
+/// run the user command by `sh -c`
+pub struct CommandNotifier {
+    pub command: String,
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .envs(notification.command_envs());
+        if crate::output::machine_mode() {
+            command.stdout(std::io::stderr());
+        }
+        let status = command.status()?;
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "notify command '{}' exited with {}",
+                self.command,
+                status
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// parsed from `webhook=<url>` or `command=<cmd>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotifyTarget {
+    Webhook(String),
+    Command(String),
+}
+
+impl NotifyTarget {
+    pub fn notifier(&self, timeout: Duration) -> Box<dyn Notifier> {
+        match self {
+            NotifyTarget::Webhook(url) => Box::new(WebhookNotifier {
+                url: url.clone(),
+                timeout,
+            }),
+            NotifyTarget::Command(command) => Box::new(CommandNotifier {
+                command: command.clone(),
+            }),
+        }
+    }
+}
+
+impl FromStr for NotifyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("webhook", url)) if !url.is_empty() => Ok(Self::Webhook(url.to_string())),
+            Some(("command", cmd)) if !cmd.is_empty() => Ok(Self::Command(cmd.to_string())),
+            _ => Err(format!(
+                "invalid notify target '{}', expected `webhook=<url>` or `command=<cmd>`",
+                s
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_parse_notify_target() {
+    assert_eq!(
+        "webhook=http://localhost/hook?a=b".parse(),
+        Ok(NotifyTarget::Webhook("http://localhost/hook?a=b".into()))
+    );
+    assert_eq!(
+        "command=echo $CAGE_RESULT".parse(),
+        Ok(NotifyTarget::Command("echo $CAGE_RESULT".into()))
+    );
+    assert!("webhook=".parse::<NotifyTarget>().is_err());
+    assert!("email=a@b.c".parse::<NotifyTarget>().is_err());
+}
+
+#[test]
+fn test_notification_payload() {
+    use crate::report::EnvReport;
+
+    let report = RunReport::new(vec![EnvReport::new("demo"), EnvReport::new("demo2")]);
+    let notification = Notification::new(&report, Duration::from_millis(1500)).unwrap();
+
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&notification.payload).unwrap(),
+        serde_json::to_value(&report).unwrap()
+    );
+    assert_eq!(
+        notification.command_envs(),
+        [
+            ("CAGE_RESULT", "success".to_string()),
+            ("CAGE_ENV", "demo,demo2".to_string()),
+            ("CAGE_DURATION", "1.500".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_command_notifier() -> anyhow::Result<()> {
+    use crate::report::EnvReport;
+
+    let output = std::env::temp_dir().join("conda-cage-test-command-notifier");
+    let report = RunReport::new(vec![EnvReport::new("demo")]);
+    let notification = Notification::new(&report, Duration::from_secs(2))?;
+    CommandNotifier {
+        command: format!(
+            "echo \"$CAGE_RESULT $CAGE_ENV $CAGE_DURATION\" > {}",
+            output.display()
+        ),
+    }
+    .notify(&notification)?;
+    assert_eq!(std::fs::read_to_string(&output)?, "success demo 2.000\n");
+    std::fs::remove_file(&output)?;
+
+    assert!(CommandNotifier {
+        command: "exit 1".into()
+    }
+    .notify(&notification)
+    .is_err());
+
+    Ok(())
+}
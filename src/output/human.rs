@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+/// e.g. `850ms`, `5s`, `2m 03s`, `1h 23m 05s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs == 0 {
+        return format!("{}ms", duration.as_millis());
+    }
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {:02}m {:02}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// binary units with one decimal, e.g. `512 B`, `1.5 KiB`, `3.0 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // round first so that e.g. 1023.96 KiB is shown as 1.0 MiB rather than 1024.0 KiB
+    while (size * 10.0).round() / 10.0 >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// with thousands separators, e.g. `1,234,567`
+pub fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(c);
+    }
+    formatted
+}
+
+#[test]
+fn test_format_duration() {
+    for (duration, expected) in [
+        (Duration::ZERO, "0ms"),
+        (Duration::from_micros(999), "0ms"),
+        (Duration::from_millis(850), "850ms"),
+        (Duration::from_millis(999), "999ms"),
+        (Duration::from_millis(1000), "1s"),
+        (Duration::from_millis(5900), "5s"),
+        (Duration::from_secs(59), "59s"),
+        (Duration::from_secs(60), "1m 00s"),
+        (Duration::from_secs(123), "2m 03s"),
+        (Duration::from_secs(3599), "59m 59s"),
+        (Duration::from_secs(3600), "1h 00m 00s"),
+        (Duration::from_secs(5 * 3600 + 23 * 60 + 5), "5h 23m 05s"),
+        (Duration::from_secs(100 * 3600), "100h 00m 00s"),
+    ] {
+        assert_eq!(format_duration(duration), expected, "{:?}", duration);
+    }
+}
+
+#[test]
+fn test_format_bytes() {
+    for (bytes, expected) in [
+        (0, "0 B"),
+        (1, "1 B"),
+        (1023, "1023 B"),
+        (1024, "1.0 KiB"),
+        (1536, "1.5 KiB"),
+        (1024 * 1024 - 1, "1.0 MiB"),
+        (1024 * 1024, "1.0 MiB"),
+        (10 * 1024 * 1024 + 100 * 1024, "10.1 MiB"),
+        (3 * 1024 * 1024 * 1024, "3.0 GiB"),
+        (1024u64.pow(4), "1.0 TiB"),
+        (1024u64.pow(5), "1.0 PiB"),
+        (2048 * 1024u64.pow(5), "2048.0 PiB"),
+    ] {
+        assert_eq!(format_bytes(bytes), expected, "{}", bytes);
+    }
+}
+
+#[test]
+fn test_format_count() {
+    for (count, expected) in [
+        (0, "0"),
+        (7, "7"),
+        (999, "999"),
+        (1000, "1,000"),
+        (12345, "12,345"),
+        (123456, "123,456"),
+        (1234567, "1,234,567"),
+    ] {
+        assert_eq!(format_count(count), expected);
+    }
+}
@@ -0,0 +1,58 @@
+pub mod human;
+pub mod progress;
+pub(crate) mod review;
+pub mod style;
+
+pub use review::confirm;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static MACHINE_MODE: AtomicBool = AtomicBool::new(false);
+static QUIET_MODE: AtomicBool = AtomicBool::new(false);
+
+/// once a machine readable payload is written to stdout, human messages go to stderr
+#[doc(hidden)]
+pub fn set_machine_mode(on: bool) {
+    MACHINE_MODE.store(on, Ordering::SeqCst);
+}
+
+#[doc(hidden)]
+pub fn machine_mode() -> bool {
+    MACHINE_MODE.load(Ordering::SeqCst)
+}
+
+/// only errors and the final summary are printed in quiet mode
+#[doc(hidden)]
+pub fn set_quiet_mode(on: bool) {
+    QUIET_MODE.store(on, Ordering::SeqCst);
+}
+
+#[doc(hidden)]
+pub fn quiet_mode() -> bool {
+    QUIET_MODE.load(Ordering::SeqCst)
+}
+
+/// `println!` for human messages, which keeps stdout clean in machine mode and is silent in
+/// quiet mode
+#[doc(hidden)]
+#[macro_export]
+macro_rules! human_println {
+    ($($arg:tt)*) => {
+        if !$crate::output::quiet_mode() {
+            $crate::summary_println!($($arg)*)
+        }
+    };
+}
+
+/// `human_println!` for the final summary, which is still printed in quiet mode
+#[doc(hidden)]
+#[macro_export]
+macro_rules! summary_println {
+    ($($arg:tt)*) => {
+        if $crate::output::machine_mode() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
@@ -0,0 +1,138 @@
+use std::sync::{Arc, Mutex};
+
+use crate::human_println;
+
+/// where the install phases report their progress
+pub trait ProgressSink: std::fmt::Debug + Send + Sync {
+    /// begin a phase, e.g. `[1/3]`, with `total` steps or 0 if it isn't counted
+    fn start(&self, prefix: &str, message: &str, total: u64);
+    fn set_message(&self, message: &str);
+    fn println(&self, line: &str);
+    fn inc(&self);
+    fn finish(&self, message: &str);
+}
+
+/// the progress bars on the terminal, plain lines when stdout isn't one, e.g. in a CI log
+#[cfg(feature = "cli")]
+pub fn default_sink() -> Arc<dyn ProgressSink> {
+    use std::io::IsTerminal;
+
+    if crate::output::quiet_mode() {
+        Arc::new(QuietProgress)
+    } else if std::io::stdout().is_terminal() {
+        Arc::new(BarProgress::default())
+    } else {
+        Arc::new(PlainProgress::default())
+    }
+}
+
+/// plain lines without the `cli` feature
+#[cfg(not(feature = "cli"))]
+pub fn default_sink() -> Arc<dyn ProgressSink> {
+    if crate::output::quiet_mode() {
+        Arc::new(QuietProgress)
+    } else {
+        Arc::new(PlainProgress::default())
+    }
+}
+
+/// prints nothing, for `--quiet`
+#[derive(Debug, Default)]
+pub struct QuietProgress;
+
+impl ProgressSink for QuietProgress {
+    fn start(&self, _prefix: &str, _message: &str, _total: u64) {}
+
+    fn set_message(&self, _message: &str) {}
+
+    fn println(&self, _line: &str) {}
+
+    fn inc(&self) {}
+
+    fn finish(&self, _message: &str) {}
+}
+
+/// prints one line per message to stdout
+#[derive(Debug, Default)]
+pub struct PlainProgress {
+    prefix: Mutex<String>,
+}
+
+impl ProgressSink for PlainProgress {
+    fn start(&self, prefix: &str, message: &str, _total: u64) {
+        *self.prefix.lock().unwrap() = prefix.to_string();
+        human_println!("{} {}", prefix, message);
+    }
+
+    fn set_message(&self, message: &str) {
+        human_println!("{} {}", self.prefix.lock().unwrap(), message);
+    }
+
+    fn println(&self, line: &str) {
+        human_println!("{}", line);
+    }
+
+    fn inc(&self) {}
+
+    fn finish(&self, message: &str) {
+        self.set_message(message);
+    }
+}
+
+/// a progress bar per phase
+#[cfg(feature = "cli")]
+#[derive(Default)]
+pub struct BarProgress {
+    bar: Mutex<Option<indicatif::ProgressBar>>,
+}
+
+#[cfg(feature = "cli")]
+impl std::fmt::Debug for BarProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BarProgress").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "cli")]
+impl BarProgress {
+    fn with_bar(&self, f: impl FnOnce(&indicatif::ProgressBar)) {
+        if let Some(bar) = self.bar.lock().unwrap().as_ref() {
+            f(bar)
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+impl ProgressSink for BarProgress {
+    fn start(&self, prefix: &str, message: &str, total: u64) {
+        use indicatif::{ProgressBar, ProgressStyle};
+
+        let template = if total > 0 {
+            "{prefix:.bold.dim} {msg}\n{wide_bar} {pos}/{len}"
+        } else {
+            "{prefix:.bold.dim} {msg}"
+        };
+        let bar = ProgressBar::new(total)
+            .with_style(ProgressStyle::default_bar().template(template))
+            .with_prefix(prefix.to_string())
+            .with_message(message.to_string());
+        bar.tick();
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    fn set_message(&self, message: &str) {
+        self.with_bar(|bar| bar.set_message(message.to_string()));
+    }
+
+    fn println(&self, line: &str) {
+        self.with_bar(|bar| bar.println(line));
+    }
+
+    fn inc(&self) {
+        self.with_bar(|bar| bar.inc(1));
+    }
+
+    fn finish(&self, message: &str) {
+        self.with_bar(|bar| bar.finish_with_message(message.to_string()));
+    }
+}
@@ -0,0 +1,268 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use super::human::format_count;
+use crate::recipe::{ChangeGroup, DiffSelection, RecipeDiff};
+
+/// let the operator deselect changes of the diff before they are applied
+pub fn review_diff(diff: &RecipeDiff) -> anyhow::Result<DiffSelection> {
+    if diff.is_empty() {
+        return Ok(DiffSelection::default());
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("`--interactive` needs a terminal to review the diff");
+    }
+    #[cfg(feature = "color")]
+    if std::env::var("TERM").is_ok_and(|term| term != "dumb") && console::Term::stderr().is_term() {
+        return select_by_keys(diff);
+    }
+    select_by_numbers(diff)
+}
+
+fn group_title(group: ChangeGroup) -> &'static str {
+    match group {
+        ChangeGroup::Adds => "Add",
+        ChangeGroup::Updates => "Update",
+        ChangeGroup::Deletes => "Delete",
+    }
+}
+
+/// the lines of the entries in a group
+fn entry_lines(diff: &RecipeDiff, group: ChangeGroup) -> Vec<String> {
+    match group {
+        ChangeGroup::Adds => diff.adds.iter().map(|p| format!("+ {:#}", p)).collect(),
+        ChangeGroup::Updates => diff
+            .updates
+            .iter()
+            .map(|u| format!("* {:#} => {:#}", u.from, u.to))
+            .collect(),
+        ChangeGroup::Deletes => diff.deletes.iter().map(|p| format!("- {:#}", p)).collect(),
+    }
+}
+
+/// a group header or an entry, in the order they are listed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Row {
+    Group(ChangeGroup),
+    Entry(ChangeGroup, usize),
+}
+
+fn rows(diff: &RecipeDiff) -> Vec<Row> {
+    let mut rows = vec![];
+    for group in ChangeGroup::ALL {
+        let len = diff.group_len(group);
+        if len > 0 {
+            rows.push(Row::Group(group));
+            rows.extend((0..len).map(|i| Row::Entry(group, i)));
+        }
+    }
+    rows
+}
+
+/// arrow keys to move, space to toggle the entry or the whole group, enter to confirm
+#[cfg(feature = "color")]
+fn select_by_keys(diff: &RecipeDiff) -> anyhow::Result<DiffSelection> {
+    use console::{Key, Term};
+
+    let term = Term::stderr();
+    let rows = rows(diff);
+    let entries = ChangeGroup::ALL.map(|group| entry_lines(diff, group));
+    let mut selection = DiffSelection::default();
+    let mut cursor = 0;
+    let mut drawn = 0;
+
+    term.write_line("↑/↓ to move, space to toggle, enter to confirm, esc to abort")?;
+    term.hide_cursor()?;
+    let result = loop {
+        term.clear_last_lines(drawn)?;
+        for (i, row) in rows.iter().enumerate() {
+            let pointer = if i == cursor { ">" } else { " " };
+            let line = match *row {
+                Row::Group(group) => {
+                    let len = diff.group_len(group);
+                    let selected = (0..len).filter(|i| selection.is_selected(group, *i));
+                    format!(
+                        "{} {} {}/{} packages:",
+                        pointer,
+                        group_title(group),
+                        selected.count(),
+                        len
+                    )
+                }
+                Row::Entry(group, index) => format!(
+                    "{}   [{}] {}",
+                    pointer,
+                    if selection.is_selected(group, index) {
+                        "x"
+                    } else {
+                        " "
+                    },
+                    entries[group as usize][index]
+                ),
+            };
+            term.write_line(&line)?;
+        }
+        drawn = rows.len();
+
+        match term.read_key()? {
+            Key::ArrowUp | Key::Char('k') => cursor = cursor.saturating_sub(1),
+            Key::ArrowDown | Key::Char('j') => cursor = (cursor + 1).min(rows.len() - 1),
+            Key::Char(' ') => match rows[cursor] {
+                Row::Group(group) => selection.toggle_group(group, diff.group_len(group)),
+                Row::Entry(group, index) => selection.toggle(group, index),
+            },
+            Key::Enter => break Ok(selection),
+            Key::Escape | Key::Char('q') => break Err(anyhow::anyhow!("the review is aborted")),
+            _ => {}
+        }
+    };
+    term.show_cursor()?;
+    result
+}
+
+/// ask the operator a yes or no question, no is the default
+pub fn confirm(question: &str) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "no terminal to confirm '{}', pass `--yes` to skip it",
+            question
+        );
+    }
+    let mut stderr = std::io::stderr();
+    write!(stderr, "{} [y/N] ", question)?;
+    stderr.flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(parse_answer(&line))
+}
+
+fn parse_answer(input: &str) -> bool {
+    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[test]
+fn test_parse_answer() {
+    for yes in ["y", "Y\n", " yes "] {
+        assert!(parse_answer(yes), "{}", yes);
+    }
+    for no in ["", "\n", "n", "no", "yess"] {
+        assert!(!parse_answer(no), "{}", no);
+    }
+}
+
+/// list the entries with numbers, and read the ones to deselect from stdin
+fn select_by_numbers(diff: &RecipeDiff) -> anyhow::Result<DiffSelection> {
+    let mut stderr = std::io::stderr();
+    let mut number = 0;
+    for group in ChangeGroup::ALL {
+        let entries = entry_lines(diff, group);
+        if entries.is_empty() {
+            continue;
+        }
+        writeln!(
+            stderr,
+            "{} {} packages:",
+            group_title(group),
+            format_count(entries.len())
+        )?;
+        for entry in entries {
+            number += 1;
+            writeln!(stderr, " {:>3}) {}", number, entry)?;
+        }
+    }
+    loop {
+        write!(
+            stderr,
+            "numbers (e.g. `1 3-5`) or groups (adds, updates, deletes) to skip, empty to apply all: "
+        )?;
+        stderr.flush()?;
+        let mut line = String::new();
+        if std::io::stdin().lock().read_line(&mut line)? == 0 {
+            anyhow::bail!("the review is aborted");
+        }
+        match parse_deselection(&line, diff) {
+            Ok(selection) => return Ok(selection),
+            Err(err) => writeln!(stderr, "{}", err)?,
+        }
+    }
+}
+
+/// parse the answer of `select_by_numbers`, the entries are numbered from 1 across groups
+fn parse_deselection(input: &str, diff: &RecipeDiff) -> Result<DiffSelection, String> {
+    let entries = rows(diff)
+        .into_iter()
+        .filter_map(|row| match row {
+            Row::Entry(group, index) => Some((group, index)),
+            Row::Group(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let mut selection = DiffSelection::default();
+    for token in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        let group = match token {
+            "" => continue,
+            "adds" => Some(ChangeGroup::Adds),
+            "updates" => Some(ChangeGroup::Updates),
+            "deletes" => Some(ChangeGroup::Deletes),
+            _ => None,
+        };
+        if let Some(group) = group {
+            for index in 0..diff.group_len(group) {
+                selection.deselect(group, index);
+            }
+            continue;
+        }
+        let err = || format!("invalid entry '{}', expected 1 to {}", token, entries.len());
+        let (start, end) = match token.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (token, token),
+        };
+        let start = start.parse::<usize>().map_err(|_| err())?;
+        let end = end.parse::<usize>().map_err(|_| err())?;
+        if start == 0 || start > end || end > entries.len() {
+            return Err(err());
+        }
+        for &(group, index) in &entries[start - 1..end] {
+            selection.deselect(group, index);
+        }
+    }
+    Ok(selection)
+}
+
+#[test]
+fn test_parse_deselection() {
+    use crate::recipe::Recipe;
+
+    let old_recipe: Recipe = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+six                       1.16.0             pyh6c4a22f_0    conda-forge
+tzdata                    2023c                h71feb2d_0    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+numpy                     1.24.3          py310h5d7c261_0    conda-forge
+requests                  2.31.0             pyhd8ed1ab_0    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    // 1) + requests, 2) * numpy, 3) - six, 4) - tzdata
+    let diff = old_recipe.diff(new_recipe);
+
+    assert_eq!(parse_deselection("\n", &diff), Ok(DiffSelection::default()));
+
+    let selection = parse_deselection("1, 3-4\n", &diff).unwrap();
+    let (kept, dropped) = diff.select(&selection);
+    assert_eq!(kept.updates, diff.updates);
+    assert_eq!(dropped.adds, diff.adds);
+    assert_eq!(dropped.deletes, diff.deletes);
+
+    // a group and a number in it are deselected only once
+    assert_eq!(
+        parse_deselection("1 deletes 4", &diff),
+        Ok(selection.clone())
+    );
+    assert_eq!(parse_deselection("adds deletes", &diff), Ok(selection));
+
+    for invalid in ["0", "5", "3-2", "1-", "x", "added"] {
+        assert!(parse_deselection(invalid, &diff).is_err(), "{}", invalid);
+    }
+}
@@ -0,0 +1,46 @@
+//! terminal colors with the `color` feature, plain text otherwise
+
+#[cfg(feature = "color")]
+pub use console::style;
+
+#[cfg(not(feature = "color"))]
+pub use plain::style;
+
+#[cfg(not(feature = "color"))]
+mod plain {
+    use std::fmt::Display;
+
+    /// the uncolored stand-in of `console::StyledObject`
+    pub struct StyledObject<D>(D);
+
+    pub fn style<D>(val: D) -> StyledObject<D> {
+        StyledObject(val)
+    }
+
+    macro_rules! plain_styles {
+        ($($name:ident),*) => {
+            impl<D> StyledObject<D> {
+                $(
+                    pub fn $name(self) -> Self {
+                        self
+                    }
+                )*
+            }
+        };
+    }
+
+    plain_styles!(red, green, yellow, blue, magenta, cyan, white, dim, bold);
+
+    impl<D: Display> Display for StyledObject<D> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(not(feature = "color"))]
+#[test]
+fn test_plain_style() {
+    assert_eq!(style("+").green().bold().to_string(), "+");
+    assert_eq!(format!("{:>3}", style(1).dim()), "  1");
+}
@@ -0,0 +1,13 @@
+//! the supported library surface, `use conda_cage::prelude::*;`
+//!
+//! everything re-exported here follows semver: it is only removed or changed incompatibly
+//! with a bump of the minor version while the crate is 0.x. items out of the prelude may
+//! change in any release.
+
+pub use crate::{
+    action::{install, install_recipe, EnvSnapshot, EnvTarget, InstallOptions, OnConflict},
+    error::Error,
+    output::progress::{PlainProgress, ProgressSink},
+    recipe::{DiffOptions, Package, PackageKind, Recipe, RecipeDiff, Update},
+    report::{EnvReport, RunReport, Status},
+};
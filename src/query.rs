@@ -0,0 +1,483 @@
+use std::cmp::Ordering;
+
+use crate::recipe::{Package, PackageKind};
+
+/// A query matching packages, parsed from strings like
+/// `numpy`, `numpy=1.24`, `conda:numpy`, `pypi:requests==2.*`, `conda-forge::numpy>=1.2,<2`
+/// or `numpy=1.24=py37*`.
+///
+/// The optional kind prefix (`conda:` or `pypi:`) comes before the optional channel
+/// prefix (`channel::`), a channel implies a conda package.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PackageQuery {
+    pub kind: Option<QueryKind>,
+    pub channel: Option<String>,
+    /// name glob
+    pub name: String,
+    pub constraints: Vec<VersionConstraint>,
+    /// build glob, only conda packages have builds
+    pub build: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum QueryKind {
+    Conda,
+    PyPi,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VersionConstraint {
+    pub op: VersionOp,
+    /// version, may contain globs when `op` is `Eq`, `Fuzzy` or `Ne`
+    pub version: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VersionOp {
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `>=`
+    Ge,
+    /// `>`
+    Gt,
+    /// `<=`
+    Le,
+    /// `<`
+    Lt,
+    /// conda's `=`, `numpy=1.24` matches `1.24` and `1.24.*`
+    Fuzzy,
+}
+
+impl TryFrom<&str> for PackageQuery {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let query = value.trim();
+        if query.is_empty() {
+            return Err("empty package query".to_string());
+        }
+
+        // kind prefix
+        let (kind, rest) = match query.split_once("::") {
+            // a single colon before the channel prefix is a kind prefix
+            Some((head, _)) if head.contains(':') => split_kind(query)?,
+            Some(_) => (None, query),
+            None if query.contains(':') => split_kind(query)?,
+            None => (None, query),
+        };
+
+        // channel prefix
+        let (channel, rest) = match rest.split_once("::") {
+            Some((channel, rest)) => {
+                if channel.is_empty() {
+                    return Err(format!("empty channel in package query: {}", value));
+                }
+                if kind == Some(QueryKind::PyPi) {
+                    return Err(format!(
+                        "pypi packages have no channel, ambiguous package query: {}",
+                        value
+                    ));
+                }
+                (Some(channel.to_string()), rest)
+            }
+            None => (None, rest),
+        };
+        let kind = if channel.is_some() {
+            Some(QueryKind::Conda)
+        } else {
+            kind
+        };
+
+        let spec_start = rest.find(['=', '!', '<', '>']).unwrap_or(rest.len());
+        let name = &rest[..spec_start];
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '*' | '?'))
+        {
+            return Err(format!("invalid package name in package query: {}", value));
+        }
+        let spec = &rest[spec_start..];
+
+        let (constraints, build) = if spec.is_empty() {
+            (vec![], None)
+        } else if let Some(spec) = spec.strip_prefix('=').filter(|s| !s.starts_with('=')) {
+            // conda style `name=version[=build]`
+            let (version, build) = match spec.split_once('=') {
+                Some((version, build)) => (version, Some(build)),
+                None => (spec, None),
+            };
+            if version.is_empty() || build.map(|b| b.is_empty() || b.contains('=')) == Some(true) {
+                return Err(format!("invalid version spec in package query: {}", value));
+            }
+            if build.is_some() && kind == Some(QueryKind::PyPi) {
+                return Err(format!(
+                    "pypi packages have no build, ambiguous package query: {}",
+                    value
+                ));
+            }
+            (
+                vec![VersionConstraint {
+                    op: VersionOp::Fuzzy,
+                    version: version.to_string(),
+                }],
+                build.map(String::from),
+            )
+        } else {
+            let constraints = spec
+                .split(',')
+                .map(|c| parse_constraint(c).ok_or_else(|| c.to_string()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|c| {
+                    format!(
+                        "invalid version constraint '{}' in package query: {}",
+                        c, value
+                    )
+                })?;
+            (constraints, None)
+        };
+
+        Ok(Self {
+            kind,
+            channel,
+            name: name.to_string(),
+            constraints,
+            build,
+        })
+    }
+}
+
+impl std::str::FromStr for PackageQuery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
+fn split_kind(query: &str) -> Result<(Option<QueryKind>, &str), String> {
+    let (kind, rest) = query.split_once(':').unwrap();
+    match kind {
+        "conda" => Ok((Some(QueryKind::Conda), rest)),
+        "pypi" => Ok((Some(QueryKind::PyPi), rest)),
+        _ => Err(format!(
+            "unknown package kind '{}' in package query: {}",
+            kind, query
+        )),
+    }
+}
+
+pub(crate) fn parse_constraint(constraint: &str) -> Option<VersionConstraint> {
+    let constraint = constraint.trim();
+    let (op, version) = [
+        ("==", VersionOp::Eq),
+        ("!=", VersionOp::Ne),
+        (">=", VersionOp::Ge),
+        ("<=", VersionOp::Le),
+        (">", VersionOp::Gt),
+        ("<", VersionOp::Lt),
+    ]
+    .into_iter()
+    .find_map(|(prefix, op)| constraint.strip_prefix(prefix).map(|v| (op, v)))?;
+    let version = version.trim();
+    if version.is_empty() || version.contains(['=', '!', '<', '>']) {
+        return None;
+    }
+    if version.contains('*') && !matches!(op, VersionOp::Eq | VersionOp::Ne) {
+        return None;
+    }
+    Some(VersionConstraint {
+        op,
+        version: version.to_string(),
+    })
+}
+
+impl PackageQuery {
+    pub fn matches(&self, pkg: &Package) -> bool {
+        let (kind, build, channel) = match &pkg.kind {
+            PackageKind::PyPi { .. } => (QueryKind::PyPi, None, None),
+            PackageKind::Conda { build, channel } => (
+                QueryKind::Conda,
+                Some(build.as_str()),
+                Some(channel.as_str()),
+            ),
+        };
+
+        self.kind.map(|k| k == kind).unwrap_or(true)
+            && self
+                .channel
+                .as_ref()
+                .map(|c| Some(c.as_str()) == channel)
+                .unwrap_or(true)
+            && glob_match(&self.name.to_lowercase(), &pkg.name.to_lowercase())
+            && self
+                .build
+                .as_ref()
+                .map(|b| build.map(|build| glob_match(b, build)).unwrap_or(false))
+                .unwrap_or(true)
+            && self.constraints.iter().all(|c| c.matches(&pkg.version))
+    }
+}
+
+impl VersionConstraint {
+    pub fn matches(&self, version: &str) -> bool {
+        match self.op {
+            VersionOp::Eq => version_eq(&self.version, version),
+            VersionOp::Ne => !version_eq(&self.version, version),
+            VersionOp::Fuzzy => {
+                version_eq(&self.version, version)
+                    || version_eq(
+                        &format!("{}.*", self.version.trim_end_matches(".*")),
+                        version,
+                    )
+            }
+            VersionOp::Ge => compare_versions(version, &self.version) != Ordering::Less,
+            VersionOp::Gt => compare_versions(version, &self.version) == Ordering::Greater,
+            VersionOp::Le => compare_versions(version, &self.version) != Ordering::Greater,
+            VersionOp::Lt => compare_versions(version, &self.version) == Ordering::Less,
+        }
+    }
+}
+
+fn version_eq(pattern: &str, version: &str) -> bool {
+    if pattern.contains('*') {
+        glob_match(pattern, version)
+    } else {
+        compare_versions(pattern, version) == Ordering::Equal
+    }
+}
+
+/// `*` matches any characters and `?` matches a single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return pattern == text;
+    }
+    let pattern = regex::escape(pattern)
+        .replace("\\*", ".*")
+        .replace("\\?", ".");
+    regex::Regex::new(&format!("^{}$", pattern))
+        .map(|re| re.is_match(text))
+        .unwrap_or(false)
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum VersionPart<'v> {
+    // pre-release tags like `a`, `rc` and `dev` sort before numbers
+    Tag(&'v str),
+    Number(u64),
+}
+
+fn version_parts(version: &str) -> Vec<Vec<VersionPart<'_>>> {
+    version
+        .split(['.', '-', '_', '+'])
+        .map(|segment| {
+            let mut parts = vec![];
+            let mut start = 0;
+            let bytes = segment.as_bytes();
+            for i in 1..=bytes.len() {
+                if i == bytes.len() || bytes[i].is_ascii_digit() != bytes[start].is_ascii_digit() {
+                    let part = &segment[start..i];
+                    parts.push(match part.parse() {
+                        Ok(n) => VersionPart::Number(n),
+                        Err(_) => VersionPart::Tag(part),
+                    });
+                    start = i;
+                }
+            }
+            parts
+        })
+        .collect()
+}
+
+/// whether the version has a pre-release tag like `rc1`, `b2` or `dev`, `post` isn't one
+pub fn is_prerelease(version: &str) -> bool {
+    version_parts(version)
+        .iter()
+        .flatten()
+        .any(|part| matches!(part, VersionPart::Tag(tag) if *tag != "post"))
+}
+
+#[test]
+fn test_is_prerelease() {
+    for version in ["2024.03.1rc1", "1.0b2", "2024.04.0-dev", "1.0a"] {
+        assert!(is_prerelease(version), "{}", version);
+    }
+    for version in ["2024.03.1", "1.0.post1", "1.0"] {
+        assert!(!is_prerelease(version), "{}", version);
+    }
+}
+
+/// a simplified version of conda's version ordering, e.g. `1.0rc1 < 1.0 == 1.0.0 < 1.0.1`
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (version_parts(a), version_parts(b));
+    let zero = vec![VersionPart::Number(0)];
+    for i in 0..a.len().max(b.len()) {
+        let (x, y) = (a.get(i).unwrap_or(&zero), b.get(i).unwrap_or(&zero));
+        for j in 0..x.len().max(y.len()) {
+            let ordering = match (x.get(j), y.get(j)) {
+                (Some(x), Some(y)) => x.cmp(y),
+                // `1.0rc1 < 1.0` but `1.0 < 1.0post1`
+                (Some(VersionPart::Tag(t)), None) | (None, Some(VersionPart::Tag(t)))
+                    if *t != "post" =>
+                {
+                    if x.len() > y.len() {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                }
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+    }
+    Ordering::Equal
+}
+
+#[test]
+fn test_parse_package_query() {
+    let query: PackageQuery = "numpy".try_into().unwrap();
+    assert_eq!(
+        query,
+        PackageQuery {
+            kind: None,
+            channel: None,
+            name: "numpy".into(),
+            constraints: vec![],
+            build: None,
+        }
+    );
+
+    let query: PackageQuery = "conda:conda-forge::numpy=1.24=py37*".try_into().unwrap();
+    assert_eq!(
+        query,
+        PackageQuery {
+            kind: Some(QueryKind::Conda),
+            channel: Some("conda-forge".into()),
+            name: "numpy".into(),
+            constraints: vec![VersionConstraint {
+                op: VersionOp::Fuzzy,
+                version: "1.24".into()
+            }],
+            build: Some("py37*".into()),
+        }
+    );
+
+    let query: PackageQuery = "pypi:requests>=2.1,<3".try_into().unwrap();
+    assert_eq!(
+        query,
+        PackageQuery {
+            kind: Some(QueryKind::PyPi),
+            channel: None,
+            name: "requests".into(),
+            constraints: vec![
+                VersionConstraint {
+                    op: VersionOp::Ge,
+                    version: "2.1".into()
+                },
+                VersionConstraint {
+                    op: VersionOp::Lt,
+                    version: "3".into()
+                }
+            ],
+            build: None,
+        }
+    );
+
+    // a channel implies conda
+    let query: PackageQuery = "pytorch::torch".try_into().unwrap();
+    assert_eq!(query.kind, Some(QueryKind::Conda));
+    assert_eq!(query.channel, Some("pytorch".into()));
+}
+
+#[test]
+fn test_reject_invalid_package_query() {
+    for query in [
+        "",
+        "=1.0",
+        "pypi:conda-forge::numpy",
+        "pypi:numpy=1.0=py37_0",
+        "foo:numpy",
+        "::numpy",
+        "numpy=",
+        "numpy=1.0=",
+        "numpy>=",
+        "numpy>=1.*",
+        "numpy~1.0",
+        "numpy>=1.0,",
+        "conda-forge::pypi:numpy",
+    ] {
+        assert!(
+            PackageQuery::try_from(query).is_err(),
+            "'{}' should be rejected",
+            query
+        );
+    }
+}
+
+#[test]
+fn test_package_matches_query() {
+    let recipe: crate::recipe::Recipe = r#"
+numpy                     1.24.2           py37h7241aed_0    conda-forge
+requests                  2.28.1                   pypi_0    pypi
+blas                      1.0                         mkl
+"#
+    .try_into()
+    .unwrap();
+    let numpy = &recipe["numpy"];
+    let requests = &recipe["requests"];
+    let blas = &recipe["blas"];
+
+    let matches = |query: &str, pkg: &Package| PackageQuery::try_from(query).unwrap().matches(pkg);
+
+    assert!(matches("numpy", numpy));
+    assert!(matches("num*", numpy));
+    assert!(matches("NumPy", numpy));
+    assert!(!matches("numpy", requests));
+
+    // kind and channel
+    assert!(matches("conda:numpy", numpy));
+    assert!(!matches("pypi:numpy", numpy));
+    assert!(matches("pypi:requests", requests));
+    assert!(!matches("conda:requests", requests));
+    assert!(matches("conda-forge::numpy", numpy));
+    assert!(!matches("defaults::numpy", numpy));
+    assert!(matches("defaults::blas", blas));
+    assert!(!matches("defaults::requests", requests));
+
+    // versions
+    assert!(matches("numpy=1.24", numpy));
+    assert!(matches("numpy=1.24.2", numpy));
+    assert!(!matches("numpy=1.2", numpy));
+    assert!(matches("numpy==1.24.2", numpy));
+    assert!(!matches("numpy==1.24", numpy));
+    assert!(matches("requests==2.*", requests));
+    assert!(!matches("requests!=2.*", requests));
+    assert!(matches("numpy>=1.3,<2", numpy));
+    assert!(!matches("numpy>1.24.2", numpy));
+    assert!(matches("numpy<=1.24.2", numpy));
+
+    // builds
+    assert!(matches("numpy=1.24=py37*", numpy));
+    assert!(!matches("numpy=1.24=py38*", numpy));
+    assert!(matches("blas=1.0=mkl", blas));
+}
+
+#[test]
+fn test_compare_versions() {
+    assert_eq!(compare_versions("1.0", "1.0.0"), Ordering::Equal);
+    assert_eq!(compare_versions("1.2", "1.10"), Ordering::Less);
+    assert_eq!(compare_versions("1.0rc1", "1.0"), Ordering::Less);
+    assert_eq!(compare_versions("1.0a1", "1.0b1"), Ordering::Less);
+    assert_eq!(compare_versions("1.0", "1.0post1"), Ordering::Less);
+    assert_eq!(
+        compare_versions("2022.07.19", "2022.6.15"),
+        Ordering::Greater
+    );
+}
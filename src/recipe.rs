@@ -1,20 +1,106 @@
+pub mod source;
+
 use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
 };
 
-use console::style;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    output::{human::format_count, style::style},
+    query::PackageQuery,
+    selector::{current_platform, split_selector, Selector, SelectorTarget},
+};
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Debug, PartialEq, Default, Clone)]
 pub struct Recipe {
-    pub channels: HashSet<String>,
-    pub packages: HashMap<String, Package>,
+    /// channels in priority order, `defaults` comes last unless it's explicitly listed
+    pub channels: Vec<String>,
+    pub packages: HashMap<PackageKey, Package>,
+}
+
+/// how a recipe is parsed, the default is strict
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// a recipe may list a conda package and a pypi package of the same name, e.g. the
+    /// `protobuf` library from conda and its python binding from pypi
+    pub allow_conda_pypi_pairs: bool,
+}
+
+/// a conda package and a pypi package of the same name can coexist in a recipe, see
+/// `ParseOptions::allow_conda_pypi_pairs`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PackageKey {
+    /// normalized by `normalize_name`
+    pub name: String,
+    pub pypi: bool,
+}
+
+impl PackageKey {
+    pub fn new(name: &str, pypi: bool) -> Self {
+        Self {
+            name: normalize_name(name),
+            pypi,
+        }
+    }
+
+    /// the key of the same name but the other kind
+    fn other_kind(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            pypi: !self.pypi,
+        }
+    }
+}
+
+/// the PEP 503 normalized name, e.g. `Typing_Extensions` is `typing-extensions`
+pub fn normalize_name(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[test]
+fn test_normalize_name() {
+    assert_eq!(normalize_name("Typing_Extensions"), "typing-extensions");
+    assert_eq!(normalize_name("zope.interface"), "zope-interface");
+    assert_eq!(normalize_name("a-_.b"), "a-b");
+    assert_eq!(normalize_name("numpy"), "numpy");
+}
+
+impl Recipe {
+    /// the package of the name, the conda one if both kinds exist
+    pub fn get(&self, name: &str) -> Option<&Package> {
+        let key = PackageKey::new(name, false);
+        self.packages
+            .get(&key)
+            .or_else(|| self.packages.get(&key.other_kind()))
+    }
+}
+
+impl std::ops::Index<&str> for Recipe {
+    type Output = Package;
+
+    fn index(&self, name: &str) -> &Self::Output {
+        self.get(name)
+            .unwrap_or_else(|| panic!("no package named '{}' in the recipe", name))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct Package {
     pub name: String,
     pub version: String,
+    #[serde(flatten)]
     pub kind: PackageKind,
 }
 
@@ -25,7 +111,8 @@ impl Display for Package {
         let build_var = style("build").yellow().to_string();
         let channel_var = style("channel").yellow().to_string();
         match &self.kind {
-            PackageKind::PyPi => {
+            PackageKind::PyPi { extras } => {
+                let name = format!("{}{}", self.name, format_extras(extras));
                 if f.alternate() {
                     write!(
                         f,
@@ -33,13 +120,13 @@ impl Display for Package {
                         style("PyPi").magenta().dim().bold().to_string(),
                         style("(").white().dim().to_string(),
                         &name_var,
-                        style(&self.name).cyan().dim().to_string(),
+                        style(name).cyan().dim().to_string(),
                         &version_var,
                         style(&self.version).cyan().dim().to_string(),
                         style(")").white().dim().to_string(),
                     )
                 } else {
-                    write!(f, "{}=={}", self.name, self.version)
+                    write!(f, "{}=={}", name, self.version)
                 }
             }
             PackageKind::Conda { build, channel } => {
@@ -67,70 +154,663 @@ impl Display for Package {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl Package {
+    pub fn key(&self) -> PackageKey {
+        PackageKey::new(&self.name, matches!(self.kind, PackageKind::PyPi { .. }))
+    }
+
+    /// conda packages first, then by normalized name, so the outputs are stable
+    pub fn sort_key(&self) -> (bool, String, String) {
+        let key = self.key();
+        (key.pypi, key.name, self.version.clone())
+    }
+
+    /// the PEP 440 local version label of a pypi package, e.g. `cu118` of `1.13.1+cu118`
+    pub fn local_version_label(&self) -> Option<&str> {
+        match self.kind {
+            PackageKind::PyPi { .. } => self.version.split_once('+').map(|(_, label)| label),
+            PackageKind::Conda { .. } => None,
+        }
+    }
+
+    pub fn matches(&self, query: &PackageQuery) -> bool {
+        query.matches(self)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
 pub enum PackageKind {
-    PyPi,
-    Conda { build: String, channel: String },
+    /// `extras` are passed to `pip install`, e.g. `security` of `requests[security]`
+    PyPi {
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        extras: Vec<String>,
+    },
+    Conda {
+        build: String,
+        channel: String,
+    },
+}
+
+/// the format of the recipe contents, see `sniff_format`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RecipeFormat {
+    /// the output of `conda list`
+    CondaList,
+    /// the output of `conda list --json`
+    CondaListJson,
+    /// any other JSON document
+    Json,
+    /// an `environment.yml`
+    EnvironmentYaml,
+    /// the output of `conda list --explicit`
+    Explicit,
+}
+
+/// guess the format from the first non-comment line
+pub fn sniff_format(contents: &str) -> RecipeFormat {
+    let yaml_key = regex::Regex::new(r"^(name|channels|dependencies|prefix|variables):").unwrap();
+    let line = contents
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .unwrap_or_default();
+    if line.starts_with('[') {
+        RecipeFormat::CondaListJson
+    } else if line.starts_with('{') {
+        RecipeFormat::Json
+    } else if line == "@EXPLICIT" {
+        RecipeFormat::Explicit
+    } else if line == "---" || yaml_key.is_match(line) {
+        RecipeFormat::EnvironmentYaml
+    } else {
+        RecipeFormat::CondaList
+    }
+}
+
+#[test]
+fn test_sniff_format() {
+    use RecipeFormat::*;
+
+    for (contents, expected) in [
+        (
+            "# packages in environment at /opt/envs/demo:\n#\nblas 1.0 mkl\n",
+            CondaList,
+        ),
+        ("\n\nncurses 6.3 hca72f7f_3 conda-forge", CondaList),
+        ("", CondaList),
+        // a package named like a yaml key isn't yaml
+        ("name 1.0 pypi_0 pypi", CondaList),
+        (
+            "[\n  {\n    \"name\": \"blas\"\n  }\n]",
+            CondaListJson,
+        ),
+        ("{\"name\": \"demo\"}", Json),
+        (
+            "name: demo\nchannels:\n  - conda-forge\ndependencies:\n  - numpy",
+            EnvironmentYaml,
+        ),
+        (
+            "# exported\nchannels:\n  - defaults\ndependencies:\n  - numpy",
+            EnvironmentYaml,
+        ),
+        ("---\nname: demo", EnvironmentYaml),
+        (
+            "# platform: linux-64\n@EXPLICIT\nhttps://repo.anaconda.com/pkgs/main/linux-64/blas-1.0-mkl.conda",
+            Explicit,
+        ),
+    ] {
+        assert_eq!(sniff_format(contents), expected, "{}", contents);
+    }
+}
+
+/// an entry of `conda list --json`
+#[derive(Deserialize)]
+struct ListEntry {
+    name: String,
+    version: String,
+    build_string: String,
+    channel: String,
 }
 
 impl TryFrom<&str> for Recipe {
     type Error = String;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::for_platform(value, current_platform()).map(|(recipe, _)| recipe)
+    }
+}
+
+/// a recipe line dropped since its `# [...]` selector is false
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilteredLine {
+    /// 1-based
+    pub line: usize,
+    pub package: Package,
+    pub selector: Selector,
+}
+
+impl Recipe {
+    /// parse the recipe for the platform, the lines whose `# [...]` selector doesn't match it
+    /// are dropped and counted
+    pub fn for_platform(value: &str, platform: Option<&str>) -> Result<(Self, usize), String> {
+        let target = SelectorTarget {
+            platform: platform.map(ToString::to_string),
+            virtual_packages: None,
+        };
+        Self::for_target(value, &target).map(|(recipe, filtered)| (recipe, filtered.len()))
+    }
+
+    /// parse the recipe for the platform and the virtual packages of the target, the lines whose
+    /// `# [...]` selector is false are dropped
+    pub fn for_target(
+        value: &str,
+        target: &SelectorTarget,
+    ) -> Result<(Self, Vec<FilteredLine>), String> {
+        Self::parse_with(value, target, &ParseOptions::default())
+    }
+
+    /// `for_target` with the options
+    pub fn parse_with(
+        value: &str,
+        target: &SelectorTarget,
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<FilteredLine>), String> {
+        let pairs = options.allow_conda_pypi_pairs;
+        const EXPECTED: &str = "a recipe must be the output of `conda list` or `conda list --json`";
+        match sniff_format(value) {
+            RecipeFormat::CondaList => Self::from_conda_list(value, target, pairs),
+            RecipeFormat::CondaListJson => {
+                let entries: Vec<ListEntry> = serde_json::from_str(value).map_err(|e| {
+                    format!("invalid `conda list --json` output: {}, {}", e, EXPECTED)
+                })?;
+                let contents = entries
+                    .iter()
+                    .map(|e| format!("{} {} {} {}", e.name, e.version, e.build_string, e.channel))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Self::from_conda_list(&contents, target, pairs)
+            }
+            RecipeFormat::Json => Err(format!("this looks like a JSON object, {}", EXPECTED)),
+            RecipeFormat::EnvironmentYaml => Err(format!(
+                "this looks like an environment.yml, {}, e.g. `conda list -n <env> > env.recipe`",
+                EXPECTED
+            )),
+            RecipeFormat::Explicit => Err(format!(
+                "this looks like the output of `conda list --explicit`, {}",
+                EXPECTED
+            )),
+        }
+    }
+
+    /// parse the `conda list` of an installed env, which lists a package installed by pip over
+    /// the conda one of the same name as both
+    pub fn from_env(value: &str) -> Result<Self, String> {
+        let target = SelectorTarget {
+            platform: current_platform().map(ToString::to_string),
+            virtual_packages: None,
+        };
+        Self::from_conda_list(value, &target, true).map(|(recipe, _)| recipe)
+    }
+
+    fn from_conda_list(
+        value: &str,
+        target: &SelectorTarget,
+        pairs: bool,
+    ) -> Result<(Self, Vec<FilteredLine>), String> {
         let mut packages = HashMap::new();
-        let mut channels = HashSet::new();
-        for line in value.lines() {
-            let line = line.trim();
-            if line.starts_with("#") || line.is_empty() {
-                continue;
+        let mut channels: Vec<String> = vec![];
+        let mut uses_defaults = false;
+        let mut filtered = vec![];
+        // the 1-based line of each package, to point at both lines of a duplicate
+        let mut lines: HashMap<PackageKey, (usize, &str)> = HashMap::new();
+        for (i, line) in value.lines().enumerate() {
+            let (line, selector) = split_selector(line)?;
+            let (package, implicit_channel) = match parse_list_line(line)? {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            if let Some(selector) = selector {
+                if !selector.matches(target)? {
+                    filtered.push(FilteredLine {
+                        line: i + 1,
+                        package,
+                        selector,
+                    });
+                    continue;
+                }
+            }
+            if implicit_channel {
+                uses_defaults = true;
+            } else if let PackageKind::Conda { channel, .. } = &package.kind {
+                if !channels.iter().any(|c| c == channel) {
+                    channels.push(channel.to_string());
+                }
+            }
+            if let Some((first, first_line)) = lines.insert(package.key(), (i + 1, line)) {
+                return Err(format!(
+                    "'{}' is listed twice, at line {}: `{}` and at line {}: `{}`, a recipe lists each package once",
+                    package.name,
+                    first,
+                    first_line.trim(),
+                    i + 1,
+                    line.trim()
+                ));
+            }
+            if !pairs {
+                if let Some((first, first_line)) = lines.get(&package.key().other_kind()) {
+                    return Err(format!(
+                        "'{}' is listed both as a conda and a pypi package, at line {}: `{}` and at line {}: `{}`, pass `--allow-conda-pypi-pairs` if both are intended",
+                        package.name,
+                        first,
+                        first_line.trim(),
+                        i + 1,
+                        line.trim()
+                    ));
+                }
             }
+            packages.insert(package.key(), package);
+        }
+        if uses_defaults && !channels.iter().any(|c| c == "defaults") {
+            channels.push("defaults".to_string());
+        }
 
-            let splitted = line.split_whitespace().into_iter().collect::<Vec<_>>();
-            let package = match splitted[..] {
-                [name, version, build] => {
-                    // conda package
-                    let channel = "defaults".to_string();
-                    channels.insert(channel.clone());
-                    Package {
-                        name: name.to_string(),
-                        version: version.to_string(),
-                        kind: PackageKind::Conda {
-                            build: build.to_string(),
-                            channel,
-                        },
-                    }
+        Ok((Self { channels, packages }, filtered))
+    }
+
+    /// the 1-based line number of each package in the `conda list` contents
+    pub fn package_lines(contents: &str) -> HashMap<PackageKey, usize> {
+        contents
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let line = split_selector(line).map_or(line, |(line, _)| line);
+                match parse_list_line(line) {
+                    Ok(Some((package, _))) => Some((package.key(), i + 1)),
+                    _ => None,
                 }
-                [name, version, _, "pypi"] => {
-                    // pypi package
-                    Package {
-                        name: name.to_string(),
-                        version: version.to_string(),
-                        kind: PackageKind::PyPi,
-                    }
+            })
+            .collect()
+    }
+}
+
+/// render the recipe in the `conda list` format, which parses back to the same recipe
+impl Display for Recipe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // `defaults` is implicit only when it comes last, like `from_conda_list` assumes
+        let implicit_defaults = self.channels.last().map(String::as_str) == Some("defaults");
+        let channel_rank = |channel: &str| {
+            self.channels
+                .iter()
+                .position(|c| c == channel)
+                .unwrap_or(self.channels.len())
+        };
+        // the first packages of the channels keep their priority order
+        let mut packages = self.packages.values().collect::<Vec<_>>();
+        packages.sort_by_cached_key(|p| {
+            let rank = match &p.kind {
+                PackageKind::Conda { channel, .. } => channel_rank(channel),
+                PackageKind::PyPi { .. } => usize::MAX,
+            };
+            (rank, p.sort_key())
+        });
+
+        writeln!(
+            f,
+            "# Name                    Version                   Build  Channel"
+        )?;
+        for package in packages {
+            let name = match &package.kind {
+                PackageKind::PyPi { extras } => {
+                    format!("{}{}", package.name, format_extras(extras))
                 }
-                [name, version, build, channel] => {
-                    // conda other channel package
-                    channels.insert(channel.to_string());
-                    Package {
-                        name: name.to_string(),
-                        version: version.to_string(),
-                        kind: PackageKind::Conda {
-                            build: build.to_string(),
-                            channel: channel.to_string(),
-                        },
+                PackageKind::Conda { .. } => package.name.clone(),
+            };
+            let (build, channel) = match &package.kind {
+                PackageKind::PyPi { .. } => ("pypi_0", "pypi"),
+                PackageKind::Conda { build, channel } => {
+                    if implicit_defaults && channel == "defaults" {
+                        (build.as_str(), "")
+                    } else {
+                        (build.as_str(), channel.as_str())
                     }
                 }
-                _ => {
-                    return Err(format!("invalid package spec: {}", line));
-                }
             };
-            packages.insert(package.name.clone(), package);
+            let line = format!(
+                "{:<25} {:<15} {:>15}  {}",
+                name, package.version, build, channel
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_render_recipe() {
+    let contents = r#"# Name                    Version                   Build  Channel
+certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
+blas                      1.0                         mkl
+aiohttp                   3.8.1                    pypi_0    pypi
+"#;
+    let recipe = Recipe::try_from(contents).unwrap();
+    assert_eq!(
+        recipe.to_string(),
+        r#"# Name                    Version                   Build  Channel
+certifi                   2022.6.15        py37hecd8cb5_0  conda-forge
+blas                      1.0                         mkl
+aiohttp                   3.8.1                    pypi_0  pypi
+"#
+    );
+}
+
+#[test]
+fn test_render_recipe_round_trip() {
+    for contents in [
+        include_str!("../env.recipe"),
+        r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+zlib                      1.2.13                        0    defaults
+python                    3.10.8          h4a9ceb5_0_cpython    pytorch
+Django                    3.2.14                   pypi_0    pypi
+"#,
+        r#"
+blas                      1.0                         mkl
+Django                    3.2.14                   pypi_0    pypi
+"#,
+        "",
+    ] {
+        let recipe = Recipe::try_from(contents).unwrap();
+        let rendered = recipe.to_string();
+        assert_eq!(Recipe::try_from(rendered.as_str()).unwrap(), recipe);
+        // rendering is stable
+        assert_eq!(
+            Recipe::try_from(rendered.as_str()).unwrap().to_string(),
+            rendered
+        );
+    }
+}
+
+#[test]
+fn test_parse_recipe_for_platform() {
+    let contents = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+libgcc-ng                 12.2.0              h65d4601_19    conda-forge  # [linux-64]
+llvm-openmp               15.0.7              h7cfbb63_0     conda-forge  # [osx-arm64]
+pywin32                   305                      pypi_0    pypi  # [win-64]
+uvloop                    0.17.0                   pypi_0    pypi  # [not win-64]
+"#;
+    let (recipe, filtered) = Recipe::for_platform(contents, Some("linux-64")).unwrap();
+    assert_eq!(filtered, 2);
+    assert!(recipe.get("libgcc-ng").is_some() && recipe.get("uvloop").is_some());
+    assert!(recipe.get("llvm-openmp").is_none() && recipe.get("pywin32").is_none());
+
+    let (recipe, filtered) = Recipe::for_platform(contents, Some("win-64")).unwrap();
+    assert_eq!(filtered, 3);
+    assert_eq!(recipe.packages.len(), 2);
+
+    assert!(Recipe::for_platform(contents, None).is_err());
+    assert!(
+        Recipe::for_platform("numpy 1.24.1 py310h5d7c261_0  # [py>37]", Some("linux-64")).is_err()
+    );
+
+    let lines = Recipe::package_lines(contents);
+    assert_eq!(lines[&PackageKey::new("llvm-openmp", false)], 4);
+}
+
+#[test]
+fn test_parse_recipe_with_duplicates() {
+    let contents = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+zlib                      1.2.13               h166bdaf_4
+numpy                     1.24.3          py310h5d7c261_0    conda-forge
+"#;
+    assert_eq!(
+        Recipe::try_from(contents).unwrap_err(),
+        "'numpy' is listed twice, at line 2: `numpy                     1.24.1          py310h5d7c261_0    conda-forge` and at line 4: `numpy                     1.24.3          py310h5d7c261_0    conda-forge`, a recipe lists each package once"
+    );
+
+    // the names are compared normalized
+    assert!(Recipe::try_from(
+        "typing_extensions 4.4.0 pypi_0 pypi\nTyping-Extensions 4.5.0 pypi_0 pypi"
+    )
+    .unwrap_err()
+    .contains("at line 1"));
+
+    // a conda and a pypi package of the same name are rejected too, unless they're allowed
+    let contents = "numpy 1.24.1 py310h5d7c261_0\nnumpy 1.24.1 pypi_0 pypi";
+    assert_eq!(
+        Recipe::try_from(contents).unwrap_err(),
+        "'numpy' is listed both as a conda and a pypi package, at line 1: `numpy 1.24.1 py310h5d7c261_0` and at line 2: `numpy 1.24.1 pypi_0 pypi`, pass `--allow-conda-pypi-pairs` if both are intended"
+    );
+    let options = ParseOptions {
+        allow_conda_pypi_pairs: true,
+    };
+    let (recipe, _) = Recipe::parse_with(contents, &SelectorTarget::default(), &options).unwrap();
+    assert_eq!(recipe.packages.len(), 2);
+    // an installed env lists both when pip installed over the conda package
+    assert_eq!(Recipe::from_env(contents).unwrap().packages.len(), 2);
+
+    // only one of the lines is left for the platform
+    let (recipe, filtered) = Recipe::for_platform(
+        "numpy 1.24.1 py310h5d7c261_0  # [linux-64]\nnumpy 1.24.3 py310h8deb116_0  # [osx-arm64]",
+        Some("linux-64"),
+    )
+    .unwrap();
+    assert_eq!((recipe.packages.len(), filtered), (1, 1));
+}
+
+#[test]
+fn test_parse_recipe_for_virtual_packages() {
+    use crate::selector::VirtualPackages;
+
+    let contents = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+cudatoolkit               11.8.0              h37601d7_11    conda-forge  # [__cuda]
+cpuonly                   2.0                           0    pytorch      # [not __cuda]
+libgcc-ng                 12.2.0              h65d4601_19    conda-forge  # [__glibc>=2.28]
+"#;
+    let target = |packages: &[(&str, &str)]| SelectorTarget {
+        platform: Some("linux-64".to_string()),
+        virtual_packages: Some(VirtualPackages::new(
+            packages
+                .iter()
+                .map(|(name, version)| (name.to_string(), version.to_string())),
+        )),
+    };
+
+    let (recipe, filtered) = Recipe::for_target(
+        contents,
+        &target(&[("__cuda", "12.2"), ("__glibc", "2.31")]),
+    )
+    .unwrap();
+    assert!(recipe.get("cudatoolkit").is_some() && recipe.get("libgcc-ng").is_some());
+    assert_eq!(filtered.len(), 1);
+    assert_eq!(
+        (filtered[0].line, filtered[0].package.name.as_str()),
+        (4, "cpuonly")
+    );
+    assert_eq!(filtered[0].selector.to_string(), "[not __cuda]");
+
+    let (recipe, filtered) = Recipe::for_target(contents, &target(&[("__glibc", "2.17")])).unwrap();
+    assert_eq!(filtered.len(), 2);
+    assert!(recipe.get("cpuonly").is_some() && recipe.get("numpy").is_some());
+
+    // the platform alone can't evaluate them
+    assert!(Recipe::for_platform(contents, Some("linux-64")).is_err());
+}
+
+/// where a recipe is read from, an installed env by `env:<name>` or a local recipe file
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecipeSource {
+    Env(String),
+    File(std::path::PathBuf),
+}
+
+impl std::str::FromStr for RecipeSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("env:") {
+            Some("") => Err(format!(
+                "invalid recipe source '{}', expected `env:<name>`",
+                s
+            )),
+            Some(env) => Ok(Self::Env(env.to_string())),
+            None if s.is_empty() => {
+                Err("invalid recipe source, expected a file or `env:<name>`".into())
+            }
+            None => Ok(Self::File(s.into())),
+        }
+    }
+}
+
+#[test]
+fn test_parse_recipe_source() {
+    assert_eq!(
+        "env:demo".parse::<RecipeSource>(),
+        Ok(RecipeSource::Env("demo".into()))
+    );
+    assert_eq!(
+        "recipes/demo.txt".parse::<RecipeSource>(),
+        Ok(RecipeSource::File("recipes/demo.txt".into()))
+    );
+    assert!("env:".parse::<RecipeSource>().is_err());
+    assert!("".parse::<RecipeSource>().is_err());
+}
+
+/// split the extras off a pypi name, e.g. `requests[security,socks]`
+fn split_extras(name: &str) -> Result<(&str, Vec<String>), String> {
+    let (name, extras) = match name.split_once('[') {
+        Some((name, extras)) => (name, extras),
+        None => return Ok((name, vec![])),
+    };
+    match extras.strip_suffix(']') {
+        Some(extras) if !name.is_empty() && !extras.contains(['[', ']']) => Ok((
+            name,
+            extras
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(String::from)
+                .collect(),
+        )),
+        _ => Err(format!(
+            "invalid pypi package name '{}[{}', expected `<name>[<extra>,...]`",
+            name, extras
+        )),
+    }
+}
+
+/// `[security,socks]`, or nothing without extras
+fn format_extras(extras: &[String]) -> String {
+    if extras.is_empty() {
+        String::new()
+    } else {
+        format!("[{}]", extras.join(","))
+    }
+}
+
+#[test]
+fn test_parse_pypi_extras() {
+    let recipe: Recipe = r#"
+requests[security,socks]  2.28.2                   pypi_0    pypi
+urllib3                   1.26.14                  pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    assert_eq!(recipe["requests"].name, "requests");
+    assert_eq!(
+        recipe["requests"].kind,
+        PackageKind::PyPi {
+            extras: vec!["security".into(), "socks".into()]
         }
+    );
+    assert_eq!(
+        recipe["requests"].to_string(),
+        "requests[security,socks]==2.28.2"
+    );
+    assert_eq!(recipe["urllib3"].to_string(), "urllib3==1.26.14");
+    assert_eq!(Recipe::try_from(recipe.to_string().as_str()), Ok(recipe));
 
-        Ok(Self { channels, packages })
+    for invalid in ["requests[security", "[security]", "requests[a]b]"] {
+        let line = format!("{} 2.28.2 pypi_0 pypi", invalid);
+        assert!(Recipe::try_from(line.as_str()).is_err(), "{}", invalid);
     }
 }
 
+/// parse a line of `conda list`, `None` for comments and blank lines, and whether the
+/// channel is the implicit `defaults`
+pub(crate) fn parse_list_line(line: &str) -> Result<Option<(Package, bool)>, String> {
+    let line = line.trim();
+    if line.starts_with('#') || line.is_empty() {
+        return Ok(None);
+    }
+
+    let splitted = line.split_whitespace().collect::<Vec<_>>();
+    let parsed = match splitted[..] {
+        [name, version, build] => {
+            // conda package
+            let package = Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                kind: PackageKind::Conda {
+                    build: build.to_string(),
+                    channel: "defaults".to_string(),
+                },
+            };
+            (package, true)
+        }
+        [name, version, _, "pypi"] => {
+            // pypi package
+            let (name, extras) = split_extras(name)?;
+            let package = Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                kind: PackageKind::PyPi { extras },
+            };
+            (package, false)
+        }
+        [name, version, build, channel] => {
+            // conda other channel package
+            let package = Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                kind: PackageKind::Conda {
+                    build: build.to_string(),
+                    channel: channel.to_string(),
+                },
+            };
+            (package, false)
+        }
+        _ => {
+            return Err(format!("invalid package spec: {}", line));
+        }
+    };
+    Ok(Some(parsed))
+}
+
+#[test]
+fn test_package_lines() {
+    let lines = Recipe::package_lines(
+        r#"# packages in environment at /opt/conda/envs/demo:
+#
+# Name                    Version                   Build  Channel
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+
+Django                    3.2.14                   pypi_0    pypi
+broken line
+"#,
+    );
+    assert_eq!(
+        lines,
+        HashMap::from([
+            (PackageKey::new("numpy", false), 4),
+            (PackageKey::new("django", true), 6),
+        ])
+    );
+}
+
 #[test]
 fn test_serialize_recipe() {
     use PackageKind::{Conda, PyPi};
@@ -145,14 +825,14 @@ certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
     assert_eq!(
         recipe,
         Recipe {
-            channels: HashSet::from(["conda-forge".into(), "defaults".into()]),
+            channels: vec!["conda-forge".into(), "defaults".into()],
             packages: [
                 (
                     "aiohttp",
                     Package {
                         name: "aiohttp".into(),
                         version: "3.8.1".into(),
-                        kind: PyPi,
+                        kind: PyPi { extras: vec![] },
                     }
                 ),
                 (
@@ -178,34 +858,265 @@ certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
                     }
                 )
             ]
-            .map(|(n, p)| (n.to_string(), p))
+            .map(|(_, p)| (p.key(), p))
             .into()
         }
     )
 }
 
-#[derive(Debug, Default, PartialEq)]
+#[test]
+fn test_serialize_recipe_from_json() {
+    let recipe: Recipe = r#"
+[
+  {
+    "base_url": "https://repo.anaconda.com/pkgs/main",
+    "build_number": 0,
+    "build_string": "mkl",
+    "channel": "pkgs/main",
+    "dist_name": "blas-1.0-mkl",
+    "name": "blas",
+    "platform": "osx-64",
+    "version": "1.0"
+  },
+  {
+    "base_url": "https://conda.anaconda.org/pypi",
+    "build_number": 0,
+    "build_string": "pypi_0",
+    "channel": "pypi",
+    "dist_name": "aiohttp-3.8.1-pypi_0",
+    "name": "aiohttp",
+    "platform": "pypi",
+    "version": "3.8.1"
+  }
+]
+"#
+    .try_into()
+    .unwrap();
+    assert_eq!(recipe.channels, ["pkgs/main"]);
+    assert_eq!(recipe["blas"].to_string(), "blas=1.0=mkl");
+    assert_eq!(recipe["aiohttp"].kind, PackageKind::PyPi { extras: vec![] });
+
+    let err = Recipe::try_from(r#"[{"name": "blas"}]"#).unwrap_err();
+    assert!(
+        err.starts_with("invalid `conda list --json` output"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_reject_other_recipe_formats() {
+    let err = Recipe::try_from("name: demo\ndependencies:\n  - numpy\n").unwrap_err();
+    assert_eq!(
+        err,
+        "this looks like an environment.yml, a recipe must be the output of `conda list` or `conda list --json`, e.g. `conda list -n <env> > env.recipe`"
+    );
+    let err = Recipe::try_from("@EXPLICIT\nhttps://repo/blas-1.0-mkl.conda").unwrap_err();
+    assert!(err.starts_with("this looks like the output of `conda list --explicit`"));
+    let err = Recipe::try_from(r#"{"name": "demo"}"#).unwrap_err();
+    assert!(err.starts_with("this looks like a JSON object"));
+}
+
+#[test]
+fn test_serialize_channels_in_order() {
+    let recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+blas                      1.0                         mkl
+certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
+libcxx                    12.0.0               h2f01273_0    pytorch
+ncurses                   6.3                  hca72f7f_3    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    assert_eq!(recipe.channels, ["conda-forge", "pytorch", "defaults"]);
+
+    let recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+blas                      1.0                         mkl    defaults
+certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
+ncurses                   6.3                  hca72f7f_3
+"#
+    .try_into()
+    .unwrap();
+    assert_eq!(recipe.channels, ["defaults", "conda-forge"]);
+}
+
+#[test]
+fn test_serialize_local_version_label() {
+    let recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+torch                     1.13.1+cu118             pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let torch = &recipe["torch"];
+    assert_eq!(torch.version, "1.13.1+cu118");
+    assert_eq!(torch.local_version_label(), Some("cu118"));
+    assert_eq!(torch.to_string(), "torch==1.13.1+cu118");
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize)]
 pub struct RecipeDiff {
     pub adds: Vec<Package>,
     pub updates: Vec<Update>,
     pub deletes: Vec<Package>,
+    /// packages identical in both recipes, only populated by `Recipe::diff_with_unchanged`
+    /// or `DiffOptions::with_unchanged`
+    pub same: Vec<Package>,
 }
 
 impl RecipeDiff {
+    /// nothing to add, update or delete
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.updates.is_empty() && self.deletes.is_empty()
+    }
+
     fn sort(&mut self) {
-        self.adds.sort_by(|a, b| a.name.cmp(&b.name));
-        self.updates.sort_by(|a, b| a.from.name.cmp(&b.from.name));
-        self.deletes.sort_by(|a, b| a.name.cmp(&b.name));
+        self.adds.sort_by_key(Package::sort_key);
+        self.updates.sort_by_key(|u| u.from.sort_key());
+        self.deletes.sort_by_key(Package::sort_key);
+        self.same.sort_by_key(Package::sort_key);
+    }
+}
+
+/// the groups of changes in a `RecipeDiff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeGroup {
+    Adds,
+    Updates,
+    Deletes,
+}
+
+impl ChangeGroup {
+    pub const ALL: [ChangeGroup; 3] = [Self::Adds, Self::Updates, Self::Deletes];
+}
+
+/// which changes of a diff are applied, all of them unless deselected
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiffSelection {
+    deselected: HashSet<(ChangeGroup, usize)>,
+}
+
+impl DiffSelection {
+    pub fn is_selected(&self, group: ChangeGroup, index: usize) -> bool {
+        !self.deselected.contains(&(group, index))
+    }
+
+    pub fn deselect(&mut self, group: ChangeGroup, index: usize) {
+        self.deselected.insert((group, index));
+    }
+
+    pub fn toggle(&mut self, group: ChangeGroup, index: usize) {
+        if !self.deselected.remove(&(group, index)) {
+            self.deselected.insert((group, index));
+        }
+    }
+
+    /// deselect the whole group if any of its `len` entries is selected, else select it all
+    pub fn toggle_group(&mut self, group: ChangeGroup, len: usize) {
+        let any_selected = (0..len).any(|i| self.is_selected(group, i));
+        for i in 0..len {
+            if any_selected {
+                self.deselect(group, i);
+            } else {
+                self.deselected.remove(&(group, i));
+            }
+        }
+    }
+}
+
+impl RecipeDiff {
+    pub fn group_len(&self, group: ChangeGroup) -> usize {
+        match group {
+            ChangeGroup::Adds => self.adds.len(),
+            ChangeGroup::Updates => self.updates.len(),
+            ChangeGroup::Deletes => self.deletes.len(),
+        }
+    }
+
+    /// split the diff into the selected changes and the deselected ones
+    pub fn select(&self, selection: &DiffSelection) -> (RecipeDiff, RecipeDiff) {
+        fn split<T: Clone>(
+            items: &[T],
+            group: ChangeGroup,
+            selection: &DiffSelection,
+        ) -> (Vec<T>, Vec<T>) {
+            let (kept, dropped): (Vec<_>, Vec<_>) = items
+                .iter()
+                .enumerate()
+                .partition(|(i, _)| selection.is_selected(group, *i));
+            (
+                kept.into_iter().map(|(_, t)| t.clone()).collect(),
+                dropped.into_iter().map(|(_, t)| t.clone()).collect(),
+            )
+        }
+
+        let (adds, dropped_adds) = split(&self.adds, ChangeGroup::Adds, selection);
+        let (updates, dropped_updates) = split(&self.updates, ChangeGroup::Updates, selection);
+        let (deletes, dropped_deletes) = split(&self.deletes, ChangeGroup::Deletes, selection);
+        (
+            RecipeDiff {
+                adds,
+                updates,
+                deletes,
+                same: self.same.clone(),
+            },
+            RecipeDiff {
+                adds: dropped_adds,
+                updates: dropped_updates,
+                deletes: dropped_deletes,
+                same: vec![],
+            },
+        )
     }
 }
 
+#[test]
+fn test_select_diff() {
+    let old_recipe: Recipe = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+pandas                    1.5.2           py310h769672d_2    conda-forge
+six                       1.16.0             pyh6c4a22f_0    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+numpy                     1.24.3          py310h5d7c261_0    conda-forge
+pandas                    1.5.3           py310h769672d_0    conda-forge
+requests                  2.31.0             pyhd8ed1ab_0    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+
+    let (kept, dropped) = diff.select(&DiffSelection::default());
+    assert_eq!(kept, diff);
+    assert!(dropped.is_empty());
+
+    let mut selection = DiffSelection::default();
+    selection.toggle(ChangeGroup::Updates, 1);
+    selection.toggle_group(ChangeGroup::Deletes, diff.group_len(ChangeGroup::Deletes));
+    let (kept, dropped) = diff.select(&selection);
+    assert_eq!(kept.adds, diff.adds);
+    assert_eq!(kept.updates, diff.updates[..1]);
+    assert!(kept.deletes.is_empty());
+    assert_eq!(dropped.updates, diff.updates[1..]);
+    assert_eq!(dropped.deletes, diff.deletes);
+
+    // toggling again selects them back
+    selection.toggle(ChangeGroup::Updates, 1);
+    selection.toggle_group(ChangeGroup::Deletes, diff.group_len(ChangeGroup::Deletes));
+    assert_eq!(selection, DiffSelection::default());
+}
+
 impl Display for RecipeDiff {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if !self.adds.is_empty() {
             writeln!(
                 f,
                 "{}",
-                style(format!("Add {} packages:", self.adds.len()))
+                style(format!("Add {} packages:", format_count(self.adds.len())))
                     .green()
                     .bold()
             )?;
@@ -218,17 +1129,21 @@ impl Display for RecipeDiff {
             writeln!(
                 f,
                 "{}",
-                style(format!("Update {} packages:", self.updates.len()))
-                    .blue()
-                    .bold()
+                style(format!(
+                    "Update {} packages:",
+                    format_count(self.updates.len())
+                ))
+                .blue()
+                .bold()
             )?;
-            for Update { from, to } in &self.updates {
+            for update in &self.updates {
                 writeln!(
                     f,
-                    " {} {:#} => {:#}",
+                    " {} {:#} => {:#} {}",
                     style("*").blue().to_string(),
-                    from,
-                    to
+                    update.from,
+                    update.to,
+                    style(format!("[{}]", update.describe_changes())).dim()
                 )?;
             }
         }
@@ -237,35 +1152,337 @@ impl Display for RecipeDiff {
             writeln!(
                 f,
                 "{}",
-                style(format!("Delete {} packages:", self.deletes.len()))
-                    .red()
-                    .bold()
+                style(format!(
+                    "Delete {} packages:",
+                    format_count(self.deletes.len())
+                ))
+                .red()
+                .bold()
             )?;
             for pkg in &self.deletes {
                 writeln!(f, " {} {:#}", style("-").red().to_string(), pkg)?;
             }
         }
 
+        if !self.same.is_empty() {
+            writeln!(
+                f,
+                "{}",
+                style(format!(
+                    "Unchanged {} packages:",
+                    format_count(self.same.len())
+                ))
+                .dim()
+                .bold()
+            )?;
+            for pkg in &self.same {
+                writeln!(f, " {} {:#}", style("=").dim(), pkg)?;
+            }
+        }
+
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct Update {
     pub from: Package,
     pub to: Package,
+    /// the fields which differ between `from` and `to`
+    pub changes: Vec<ChangedField>,
+}
+
+impl Update {
+    pub fn new(from: Package, to: Package) -> Self {
+        let changes = changed_fields(&from, &to, &DiffOptions::default());
+        Self { from, to, changes }
+    }
+
+    /// e.g. `version: 1.24.1 → 1.24.3, channel: defaults → conda-forge`
+    fn describe_changes(&self) -> String {
+        let kind_name = |kind: &PackageKind| match kind {
+            PackageKind::PyPi { .. } => "pypi".to_string(),
+            PackageKind::Conda { .. } => "conda".to_string(),
+        };
+        let field = |pkg: &Package, field: ChangedField| match (field, &pkg.kind) {
+            (ChangedField::Version, _) => pkg.version.clone(),
+            (ChangedField::Build, PackageKind::Conda { build, .. }) => build.clone(),
+            (ChangedField::Channel, PackageKind::Conda { channel, .. }) => channel.clone(),
+            (ChangedField::Kind, kind) => kind_name(kind),
+            (_, PackageKind::PyPi { .. }) => String::new(),
+        };
+        self.changes
+            .iter()
+            .map(|&c| format!("{}: {} → {}", c, field(&self.from, c), field(&self.to, c)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangedField {
+    Version,
+    Build,
+    Channel,
+    /// changed between conda and pypi, build and channel are not comparable then
+    Kind,
+}
+
+impl Display for ChangedField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ChangedField::Version => "version",
+            ChangedField::Build => "build",
+            ChangedField::Channel => "channel",
+            ChangedField::Kind => "kind",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// url suffixes which are the `defaults` channel, e.g. `https://repo.anaconda.com/pkgs/main`
+const DEFAULTS_CHANNEL_SUFFIXES: [&str; 4] = ["pkgs/main", "pkgs/free", "pkgs/r", "pkgs/msys2"];
+
+/// maps the channel urls ending with `suffix` to `channel`, parsed from `<suffix>=<channel>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelAlias {
+    pub suffix: String,
+    pub channel: String,
+}
+
+impl std::str::FromStr for ChannelAlias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((suffix, channel)) if !suffix.is_empty() && !channel.is_empty() => Ok(Self {
+                suffix: suffix.trim_matches('/').to_string(),
+                channel: channel.to_string(),
+            }),
+            _ => Err(format!(
+                "invalid channel alias '{}', expected `<url suffix>=<channel>`",
+                s
+            )),
+        }
+    }
+}
+
+/// the first alias whose suffix the channel url ends with
+pub fn find_channel_alias<'a>(
+    channel: &str,
+    aliases: &'a [ChannelAlias],
+) -> Option<&'a ChannelAlias> {
+    let channel = channel.trim_end_matches('/');
+    aliases
+        .iter()
+        .find(|a| channel == a.suffix || channel.ends_with(&format!("/{}", a.suffix)))
+}
+
+/// the name a channel is compared by, so that the same channel on different mirrors is equal,
+/// e.g. both `https://mirror.corp/conda-forge` and `conda-forge` are `conda-forge`
+pub fn canonical_channel(channel: &str, aliases: &[ChannelAlias]) -> String {
+    if let Some(alias) = find_channel_alias(channel, aliases) {
+        return alias.channel.clone();
+    }
+    let channel = channel.trim_end_matches('/');
+    let matches = |suffix: &str| channel == suffix || channel.ends_with(&format!("/{}", suffix));
+    if DEFAULTS_CHANNEL_SUFFIXES.iter().any(|s| matches(s)) {
+        return "defaults".to_string();
+    }
+    match channel.split_once("://") {
+        Some((_, path)) => path.rsplit('/').next().unwrap_or(path).to_string(),
+        None => channel.to_string(),
+    }
+}
+
+#[test]
+fn test_canonical_channel() {
+    let aliases = vec!["mirrors/cf=conda-forge".parse::<ChannelAlias>().unwrap()];
+    for (channel, expected) in [
+        ("conda-forge", "conda-forge"),
+        ("https://mirror.corp/conda-forge", "conda-forge"),
+        (
+            "https://mirror.other.org/anaconda/cloud/conda-forge/",
+            "conda-forge",
+        ),
+        ("https://mirror.corp/mirrors/cf", "conda-forge"),
+        ("https://repo.anaconda.com/pkgs/main", "defaults"),
+        ("pkgs/main", "defaults"),
+        ("defaults", "defaults"),
+        ("https://mirror.corp/pytorch", "pytorch"),
+        ("file:///opt/channels/local", "local"),
+    ] {
+        assert_eq!(
+            canonical_channel(channel, &aliases),
+            expected,
+            "{}",
+            channel
+        );
+    }
+
+    assert!("=conda-forge".parse::<ChannelAlias>().is_err());
+    assert!("mirrors/cf".parse::<ChannelAlias>().is_err());
+}
+
+/// how `Recipe::diff_with` compares two recipes
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// collect the unchanged packages into `RecipeDiff::same`
+    pub with_unchanged: bool,
+    /// packages which only moved to another channel are unchanged
+    pub ignore_channel_changes: bool,
+    pub channel_aliases: Vec<ChannelAlias>,
+}
+
+fn changed_fields(from: &Package, to: &Package, options: &DiffOptions) -> Vec<ChangedField> {
+    let mut changes = vec![];
+    if from.version != to.version {
+        changes.push(ChangedField::Version);
+    }
+    match (&from.kind, &to.kind) {
+        (
+            PackageKind::Conda {
+                build: from_build,
+                channel: from_channel,
+            },
+            PackageKind::Conda {
+                build: to_build,
+                channel: to_channel,
+            },
+        ) => {
+            if from_build != to_build {
+                changes.push(ChangedField::Build);
+            }
+            if !options.ignore_channel_changes
+                && canonical_channel(from_channel, &options.channel_aliases)
+                    != canonical_channel(to_channel, &options.channel_aliases)
+            {
+                changes.push(ChangedField::Channel);
+            }
+        }
+        // `conda list` doesn't know the extras, so they aren't compared
+        (PackageKind::PyPi { .. }, PackageKind::PyPi { .. }) => {}
+        _ => changes.push(ChangedField::Kind),
+    }
+    changes
+}
+
+#[test]
+fn test_changed_fields() {
+    use ChangedField::*;
+
+    let conda = |version: &str, build: &str, channel: &str| Package {
+        name: "numpy".into(),
+        version: version.into(),
+        kind: PackageKind::Conda {
+            build: build.into(),
+            channel: channel.into(),
+        },
+    };
+    let pypi = |version: &str| Package {
+        name: "numpy".into(),
+        version: version.into(),
+        kind: PackageKind::PyPi { extras: vec![] },
+    };
+    let base = conda("1.24.1", "py37_0", "defaults");
+    let options = DiffOptions::default();
+
+    for (to, expected) in [
+        (conda("1.24.1", "py37_0", "defaults"), vec![]),
+        (conda("1.24.3", "py37_0", "defaults"), vec![Version]),
+        (conda("1.24.1", "py38_0", "defaults"), vec![Build]),
+        (conda("1.24.1", "py37_0", "conda-forge"), vec![Channel]),
+        (conda("1.24.3", "py38_0", "defaults"), vec![Version, Build]),
+        (
+            conda("1.24.3", "py37_0", "conda-forge"),
+            vec![Version, Channel],
+        ),
+        (
+            conda("1.24.1", "py38_0", "conda-forge"),
+            vec![Build, Channel],
+        ),
+        (
+            conda("1.24.3", "py38_0", "conda-forge"),
+            vec![Version, Build, Channel],
+        ),
+        (pypi("1.24.1"), vec![Kind]),
+        (pypi("1.24.3"), vec![Version, Kind]),
+    ] {
+        assert_eq!(changed_fields(&base, &to, &options), expected, "{:?}", to);
+    }
+
+    assert_eq!(
+        changed_fields(&pypi("1.24.1"), &pypi("1.24.1"), &options),
+        vec![]
+    );
+    assert_eq!(
+        changed_fields(&pypi("1.24.1"), &pypi("1.24.3"), &options),
+        vec![Version]
+    );
+    assert_eq!(changed_fields(&pypi("1.24.1"), &base, &options), vec![Kind]);
+
+    // mirrors of the same channel
+    let mirrored = conda("1.24.1", "py37_0", "https://mirror.corp/pkgs/main");
+    assert_eq!(changed_fields(&base, &mirrored, &options), vec![]);
+    let ignored = DiffOptions {
+        ignore_channel_changes: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        changed_fields(&base, &conda("1.24.3", "py37_0", "conda-forge"), &ignored),
+        vec![Version]
+    );
+
+    let update = Update::new(base.clone(), conda("1.24.3", "py37_0", "conda-forge"));
+    assert_eq!(
+        update.describe_changes(),
+        "version: 1.24.1 → 1.24.3, channel: defaults → conda-forge"
+    );
+    let update = Update::new(base, pypi("1.24.1"));
+    assert_eq!(update.describe_changes(), "kind: conda → pypi");
 }
 
 impl Recipe {
-    pub fn diff(self, mut new_recipe: Self) -> RecipeDiff {
+    pub fn diff(self, new_recipe: Self) -> RecipeDiff {
+        self.diff_with(new_recipe, &DiffOptions::default())
+    }
+
+    /// like `diff`, but also collects the unchanged packages into `RecipeDiff::same`
+    pub fn diff_with_unchanged(self, new_recipe: Self) -> RecipeDiff {
+        self.diff_with(
+            new_recipe,
+            &DiffOptions {
+                with_unchanged: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// channels are compared by `canonical_channel`, while the packages in the diff keep
+    /// their original channels
+    pub fn diff_with(self, mut new_recipe: Self, options: &DiffOptions) -> RecipeDiff {
         let mut diff = RecipeDiff::default();
-        for (pkg_name, old_pkg) in self.packages {
-            if let Some(new_pkg) = new_recipe.packages.remove(&pkg_name) {
-                if new_pkg != old_pkg {
+        let old_keys = self.packages.keys().cloned().collect::<HashSet<_>>();
+        for (key, old_pkg) in self.packages {
+            // a package moved between conda and pypi, unless both kinds already coexist
+            let new_pkg = new_recipe.packages.remove(&key).or_else(|| {
+                if old_keys.contains(&key.other_kind()) {
+                    None
+                } else {
+                    new_recipe.packages.remove(&key.other_kind())
+                }
+            });
+            if let Some(new_pkg) = new_pkg {
+                let changes = changed_fields(&old_pkg, &new_pkg, options);
+                if !changes.is_empty() {
                     diff.updates.push(Update {
                         from: old_pkg,
                         to: new_pkg,
+                        changes,
                     })
+                } else if options.with_unchanged {
+                    diff.same.push(old_pkg)
                 }
             } else {
                 diff.deletes.push(old_pkg)
@@ -316,7 +1533,7 @@ yarl                      1.7.3                xaa72f7f_3    conda-forge
             Package {
                 name: "idna".into(),
                 version: "3.3".into(),
-                kind: PyPi,
+                kind: PyPi { extras: vec![] },
             },
             Package {
                 name: "libcxx".into(),
@@ -340,8 +1557,9 @@ yarl                      1.7.3                xaa72f7f_3    conda-forge
                 to: Package {
                     name: "numpy".into(),
                     version: "1.18.2".into(),
-                    kind: PyPi,
+                    kind: PyPi { extras: vec![] },
                 },
+                changes: vec![ChangedField::Version, ChangedField::Kind],
             },
             Update {
                 from: Package {
@@ -360,12 +1578,13 @@ yarl                      1.7.3                xaa72f7f_3    conda-forge
                         channel: "defaults".into(),
                     },
                 },
+                changes: vec![ChangedField::Version],
             },
             Update {
                 from: Package {
                     name: "yarl".into(),
                     version: "1.7.2".into(),
-                    kind: PyPi,
+                    kind: PyPi { extras: vec![] },
                 },
                 to: Package {
                     name: "yarl".into(),
@@ -375,25 +1594,27 @@ yarl                      1.7.3                xaa72f7f_3    conda-forge
                         channel: "conda-forge".into(),
                     },
                 },
+                changes: vec![ChangedField::Version, ChangedField::Kind],
             },
             Update {
                 from: Package {
                     name: "aiohttp".into(),
                     version: "3.8.1".into(),
-                    kind: PyPi,
+                    kind: PyPi { extras: vec![] },
                 },
                 to: Package {
                     name: "aiohttp".into(),
                     version: "3.8.2".into(),
-                    kind: PyPi,
+                    kind: PyPi { extras: vec![] },
                 },
+                changes: vec![ChangedField::Version],
             },
         ],
         deletes: vec![
             Package {
                 name: "frozenlist".into(),
                 version: "1.3.0".into(),
-                kind: PyPi,
+                kind: PyPi { extras: vec![] },
             },
             Package {
                 name: "certifi".into(),
@@ -404,7 +1625,150 @@ yarl                      1.7.3                xaa72f7f_3    conda-forge
                 },
             },
         ],
+        same: vec![],
     };
     expected.sort();
     assert_eq!(diff, expected);
 }
+
+#[test]
+fn diff_two_recipe_with_unchanged() {
+    let old_recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+aiohttp                   3.8.1                    pypi_0    pypi
+multidict                 6.0.2                    pypi_0    pypi
+ncurses                   6.3                  hca72f7f_3
+numpy                     1.18.1           py37h7241aed_0
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+aiohttp                   3.8.2                    pypi_0    pypi
+multidict                 6.0.2                    pypi_0    pypi
+ncurses                   6.3                  hca72f7f_3
+numpy                     1.18.1           py37h7241aed_0
+"#
+    .try_into()
+    .unwrap();
+    let same = ["ncurses", "numpy", "multidict"]
+        .map(|name| old_recipe[name].clone())
+        .to_vec();
+
+    let diff = old_recipe.diff_with_unchanged(new_recipe);
+    assert_eq!(diff.same, same);
+    assert_eq!(diff.updates.len(), 1);
+}
+
+#[test]
+fn diff_recipes_across_mirrors() {
+    let old_recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+certifi                   2022.6.15        py37hecd8cb5_0    conda-forge
+libcxx                    12.0.0               h2f01273_0    https://mirror.corp/pytorch
+ncurses                   6.3                  hca72f7f_3    https://mirror.corp/conda-forge
+numpy                     1.18.1           py37h7241aed_0    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+# Name                    Version                   Build  Channel
+certifi                   2022.6.15        py37hecd8cb5_0    https://mirror.corp/conda-forge
+libcxx                    12.0.0               h2f01273_0    https://mirror.other.org/pytorch
+ncurses                   6.3                  hca72f7f_3    https://mirror.other.org/conda-forge
+numpy                     1.18.1           py37h7241aed_0    https://mirror.corp/pytorch
+"#
+    .try_into()
+    .unwrap();
+
+    let diff = old_recipe.clone().diff(new_recipe.clone());
+    assert_eq!(diff.updates.len(), 1);
+    assert_eq!(diff.updates[0].changes, [ChangedField::Channel]);
+    // the original channel is kept for installing
+    assert_eq!(
+        diff.updates[0].to.kind,
+        PackageKind::Conda {
+            build: "py37h7241aed_0".into(),
+            channel: "https://mirror.corp/pytorch".into()
+        }
+    );
+
+    let diff = old_recipe.diff_with(
+        new_recipe,
+        &DiffOptions {
+            ignore_channel_changes: true,
+            ..Default::default()
+        },
+    );
+    assert!(diff.is_empty());
+}
+
+#[test]
+fn diff_recipes_with_coexisting_kinds() {
+    // the pairs are only allowed explicitly
+    let target = SelectorTarget::default();
+    let options = ParseOptions {
+        allow_conda_pypi_pairs: true,
+    };
+    let (old_recipe, _) = Recipe::parse_with(
+        r#"
+# Name                    Version                   Build  Channel
+libprotobuf               3.20.1               h2f01273_0    conda-forge
+protobuf                  3.20.1               h2f01273_0    conda-forge
+protobuf                  3.20.1                   pypi_0    pypi
+yarl                      1.7.2                    pypi_0    pypi
+"#,
+        &target,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(old_recipe.packages.len(), 4);
+    assert!(matches!(
+        old_recipe["protobuf"].kind,
+        PackageKind::Conda { .. }
+    ));
+    assert_eq!(
+        old_recipe.packages[&PackageKey::new("protobuf", true)].kind,
+        PackageKind::PyPi { extras: vec![] }
+    );
+
+    let (new_recipe, _) = Recipe::parse_with(
+        r#"
+# Name                    Version                   Build  Channel
+libprotobuf               3.20.1               h2f01273_0    conda-forge
+protobuf                  3.20.1                   pypi_0    pypi
+yarl                      1.7.3                xaa72f7f_3    conda-forge
+"#,
+        &target,
+        &options,
+    )
+    .unwrap();
+
+    let diff = old_recipe.diff(new_recipe);
+    // the conda protobuf is gone while the pypi one stays
+    assert_eq!(diff.deletes.len(), 1);
+    assert!(matches!(diff.deletes[0].kind, PackageKind::Conda { .. }));
+    // yarl moved from pypi to conda
+    assert_eq!(diff.updates.len(), 1);
+    assert_eq!(
+        diff.updates[0].changes,
+        [ChangedField::Version, ChangedField::Kind]
+    );
+    assert!(diff.adds.is_empty());
+
+    // a pypi package joins the conda one of the same name
+    let old_recipe: Recipe = "protobuf 3.20.1 h2f01273_0 conda-forge".try_into().unwrap();
+    let (new_recipe, _) = Recipe::parse_with(
+        r#"
+protobuf                  3.20.1               h2f01273_0    conda-forge
+Protobuf                  3.20.1                   pypi_0    pypi
+"#,
+        &target,
+        &options,
+    )
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    assert!(diff.updates.is_empty() && diff.deletes.is_empty());
+    assert_eq!(diff.adds.len(), 1);
+    assert_eq!(diff.adds[0].kind, PackageKind::PyPi { extras: vec![] });
+}
@@ -0,0 +1,411 @@
+//! the http layer of the recipe server, where `install` fetches the recipes and `versions`
+//! lists them
+
+use std::collections::HashSet;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{parse_list_line, PackageKey};
+use crate::{
+    error::Error,
+    repo::{sort_env_versions, EnvVersion, RecipeCommit, RecipeRepo, VersionKind, RECIPE_PATH},
+};
+
+/// what is encoded in a path segment of the gitlab api, all but the unreserved characters
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// the recipe server unless `--recipe-server` or `CONDA_CAGE_RECIPE_SERVER` is given
+pub const DEFAULT_RECIPE_SERVER: &str = "http://hftgitlab";
+
+/// the envs are the projects of the `conda-envs` group
+#[derive(Debug, Clone)]
+pub struct GitlabRepo {
+    /// the gitlab url, or the url template of the recipes with `{env}` and `{version}`, e.g.
+    /// `https://recipes.corp/{env}/{version}.txt`, which only fetches the recipes
+    pub base_url: String,
+}
+
+impl Default for GitlabRepo {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECIPE_SERVER)
+    }
+}
+
+fn fetch_error(
+    env_name: &str,
+    version: &str,
+    url: &str,
+    status: reqwest::StatusCode,
+) -> anyhow::Error {
+    anyhow::anyhow!(
+        "fail to fetch env: {}, version: {}, url: {}, err code: {}",
+        env_name,
+        version,
+        url,
+        status
+    )
+}
+
+#[derive(Deserialize)]
+struct GitlabCompare {
+    commits: Vec<GitlabCommit>,
+}
+
+#[derive(Deserialize)]
+struct GitlabCommit {
+    id: String,
+    short_id: String,
+    title: String,
+    author_name: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabTag {
+    name: String,
+    commit: GitlabRef,
+}
+
+#[derive(Deserialize)]
+struct GitlabBranch {
+    name: String,
+    commit: GitlabRef,
+}
+
+#[derive(Deserialize)]
+struct GitlabRef {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabDiff {
+    new_path: String,
+    old_path: String,
+    diff: String,
+}
+
+impl GitlabRepo {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn raw_url(&self, env_name: &str, version: &str) -> String {
+        if self.base_url.contains("{env}") {
+            return self
+                .base_url
+                .replace("{env}", env_name)
+                .replace("{version}", version);
+        }
+        format!(
+            "{}/conda-envs/{}/raw/{}/{}?inline=false",
+            self.base_url, env_name, version, RECIPE_PATH
+        )
+    }
+
+    /// the env name is encoded as a part of the project path, e.g. `conda-envs%2Fdemo`
+    fn api_url(&self, env_name: &str, path: &str) -> String {
+        format!(
+            "{}/api/v4/projects/conda-envs%2F{}/repository/{}",
+            self.base_url,
+            utf8_percent_encode(env_name, PATH_SEGMENT),
+            path
+        )
+    }
+
+    /// all the pages of a list of the repository api, e.g. `tags`
+    async fn list<T: DeserializeOwned>(
+        &self,
+        env_name: &str,
+        path: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        if self.base_url.contains("{env}") {
+            anyhow::bail!(
+                "the url template of the recipes can't list the versions of env '{}'",
+                env_name
+            );
+        }
+        let mut items = vec![];
+        for page in 1.. {
+            let url = self.api_url(env_name, &format!("{}?per_page=100&page={}", path, page));
+            let rsp = reqwest::get(&url).await?;
+            match rsp.status() {
+                status if status.is_success() => {}
+                reqwest::StatusCode::NOT_FOUND => {
+                    return Err(Error::UnknownEnv(env_name.to_string()).into())
+                }
+                status => anyhow::bail!(
+                    "fail to list the {} of env: {}, url: {}, err code: {}",
+                    path,
+                    env_name,
+                    url,
+                    status
+                ),
+            }
+            let page = serde_json::from_str::<Vec<T>>(&rsp.text().await?)?;
+            if page.is_empty() {
+                break;
+            }
+            items.extend(page);
+        }
+        Ok(items)
+    }
+
+    async fn get(&self, url: &str) -> anyhow::Result<Option<String>> {
+        let rsp = reqwest::get(url).await?;
+        if !rsp.status().is_success() {
+            return Ok(None);
+        }
+        Ok(Some(rsp.text().await?))
+    }
+}
+
+impl RecipeRepo for GitlabRepo {
+    async fn fetch(&self, env_name: &str, version: &str) -> anyhow::Result<String> {
+        let url = self.raw_url(env_name, version);
+        let rsp = reqwest::get(&url).await?;
+        if !rsp.status().is_success() {
+            return Err(fetch_error(env_name, version, &url, rsp.status()));
+        }
+        Ok(rsp.text().await?)
+    }
+
+    async fn versions(&self, env_name: &str) -> anyhow::Result<Vec<EnvVersion>> {
+        let tags = self.list::<GitlabTag>(env_name, "tags").await?;
+        let branches = self.list::<GitlabBranch>(env_name, "branches").await?;
+        // `latest` is an alias of the master branch, see `install --version`
+        let master = branches
+            .iter()
+            .find(|b| b.name == "master")
+            .map(|b| b.commit.id.clone());
+        let is_latest = |commit: &GitlabRef| master.as_ref() == Some(&commit.id);
+        let mut versions = tags
+            .iter()
+            .map(|t| EnvVersion {
+                name: t.name.clone(),
+                kind: VersionKind::Tag,
+                latest: is_latest(&t.commit),
+            })
+            .chain(branches.iter().map(|b| EnvVersion {
+                name: b.name.clone(),
+                kind: VersionKind::Branch,
+                latest: b.name == "master",
+            }))
+            .collect::<Vec<_>>();
+        sort_env_versions(&mut versions);
+        Ok(versions)
+    }
+
+    async fn compare(
+        &self,
+        env_name: &str,
+        from: &str,
+        to: &str,
+    ) -> anyhow::Result<Option<Vec<RecipeCommit>>> {
+        // the api may be disabled or the token may lack the permission
+        let compare = match self
+            .get(&self.api_url(env_name, &format!("compare?from={}&to={}", from, to)))
+            .await?
+        {
+            Some(body) => serde_json::from_str::<GitlabCompare>(&body)?,
+            None => return Ok(None),
+        };
+        let mut commits = vec![];
+        for commit in compare.commits {
+            let diffs = match self
+                .get(&self.api_url(env_name, &format!("commits/{}/diff", commit.id)))
+                .await?
+            {
+                Some(body) => serde_json::from_str::<Vec<GitlabDiff>>(&body)?,
+                None => return Ok(None),
+            };
+            let packages = diffs
+                .iter()
+                .filter(|d| d.new_path == RECIPE_PATH || d.old_path == RECIPE_PATH)
+                .flat_map(|d| changed_packages(&d.diff))
+                .collect::<HashSet<_>>();
+            if !packages.is_empty() {
+                commits.push(RecipeCommit {
+                    short_id: commit.short_id,
+                    author: commit.author_name,
+                    title: commit.title,
+                    packages,
+                });
+            }
+        }
+        Ok(Some(commits))
+    }
+}
+
+/// the packages of the added and removed lines of a unified diff of the recipe
+fn changed_packages(diff: &str) -> Vec<PackageKey> {
+    diff.lines()
+        .filter(|line| !line.starts_with("+++") && !line.starts_with("---"))
+        .filter_map(|line| line.strip_prefix('+').or_else(|| line.strip_prefix('-')))
+        .filter_map(|line| parse_list_line(line).ok().flatten())
+        .map(|(package, _)| package.key())
+        .collect()
+}
+
+#[test]
+fn test_changed_packages_of_gitlab_diff() {
+    let diffs: Vec<GitlabDiff> =
+        serde_json::from_str(include_str!("../../fixtures/repo/commit-diff.json")).unwrap();
+    let recipe_diff = diffs.iter().find(|d| d.new_path == RECIPE_PATH).unwrap();
+    assert_eq!(
+        changed_packages(&recipe_diff.diff),
+        [
+            PackageKey::new("numpy", false),
+            PackageKey::new("numpy", false),
+            PackageKey::new("Django", true),
+            PackageKey::new("requests", false),
+        ]
+    );
+}
+
+/// serve the requests on a local port by the recipes of the paths, returns the base url and
+/// the requested paths
+#[cfg(test)]
+fn serve_recipes(
+    recipes: std::collections::HashMap<&'static str, &'static str>,
+    requests: usize,
+) -> (String, std::sync::mpsc::Receiver<String>) {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().take(requests) {
+            let mut stream = stream.unwrap();
+            // a GET request has no body, read it up to the blank line
+            let mut lines = BufReader::new(&stream).lines().map(Result::unwrap);
+            let request_line = lines.next().unwrap();
+            lines.find(|line| line.is_empty());
+            let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+            let response = match recipes.get(path.as_str()) {
+                Some(body) => format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+            tx.send(path).unwrap();
+        }
+    });
+    (base_url, rx)
+}
+
+#[test]
+fn test_raw_url() {
+    let repo = GitlabRepo::new("https://gitlab.corp/");
+    assert_eq!(
+        repo.raw_url("demo", "2024.03.1"),
+        "https://gitlab.corp/conda-envs/demo/raw/2024.03.1/env.recipe?inline=false"
+    );
+    let repo = GitlabRepo::new("https://recipes.corp/{env}/{version}.txt");
+    assert_eq!(
+        repo.raw_url("demo", "master"),
+        "https://recipes.corp/demo/master.txt"
+    );
+}
+
+#[test]
+fn test_api_url() {
+    let repo = GitlabRepo::new("https://gitlab.corp");
+    assert_eq!(
+        repo.api_url("demo-py3.10", "tags"),
+        "https://gitlab.corp/api/v4/projects/conda-envs%2Fdemo-py3.10/repository/tags"
+    );
+    assert_eq!(
+        repo.api_url("team/demo?x#y", "tags"),
+        "https://gitlab.corp/api/v4/projects/conda-envs%2Fteam%2Fdemo%3Fx%23y/repository/tags"
+    );
+}
+
+#[tokio::test]
+async fn test_fetch() {
+    let recipes = std::collections::HashMap::from([(
+        "/conda-envs/demo/raw/master/env.recipe?inline=false",
+        "zlib 1.2.13 h166bdaf_4 conda-forge\n",
+    )]);
+    let (base_url, requests) = serve_recipes(recipes, 2);
+    let repo = GitlabRepo::new(&base_url);
+
+    assert_eq!(
+        repo.fetch("demo", "master").await.unwrap(),
+        "zlib 1.2.13 h166bdaf_4 conda-forge\n"
+    );
+    let err = repo.fetch("demo", "2024.03.1").await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "fail to fetch env: demo, version: 2024.03.1, url: {}/conda-envs/demo/raw/2024.03.1/env.recipe?inline=false, err code: 404 Not Found",
+            base_url
+        )
+    );
+    assert_eq!(
+        requests.iter().collect::<Vec<_>>(),
+        [
+            "/conda-envs/demo/raw/master/env.recipe?inline=false",
+            "/conda-envs/demo/raw/2024.03.1/env.recipe?inline=false"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_versions() {
+    let recipes = std::collections::HashMap::from([
+        (
+            "/api/v4/projects/conda-envs%2Fdemo/repository/tags?per_page=100&page=1",
+            r#"[{"name": "2024.03.1", "commit": {"id": "a1"}}, {"name": "2024.10.0", "commit": {"id": "c3"}}]"#,
+        ),
+        (
+            "/api/v4/projects/conda-envs%2Fdemo/repository/tags?per_page=100&page=2",
+            r#"[{"name": "2024.9.2", "commit": {"id": "b2"}}]"#,
+        ),
+        (
+            "/api/v4/projects/conda-envs%2Fdemo/repository/tags?per_page=100&page=3",
+            "[]",
+        ),
+        (
+            "/api/v4/projects/conda-envs%2Fdemo/repository/branches?per_page=100&page=1",
+            r#"[{"name": "staging", "commit": {"id": "d4"}}, {"name": "master", "commit": {"id": "c3"}}]"#,
+        ),
+        (
+            "/api/v4/projects/conda-envs%2Fdemo/repository/branches?per_page=100&page=2",
+            "[]",
+        ),
+    ]);
+    let (base_url, _requests) = serve_recipes(recipes, 6);
+    let repo = GitlabRepo::new(&base_url);
+
+    let versions = repo.versions("demo").await.unwrap();
+    assert_eq!(
+        versions.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        [
+            "tag     2024.10.0  ← master, latest",
+            "tag     2024.9.2",
+            "tag     2024.03.1",
+            "branch  master  ← master, latest",
+            "branch  staging",
+        ]
+    );
+
+    let err = repo.versions("missing").await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::UnknownEnv(env)) if env == "missing"
+    ));
+
+    let template = GitlabRepo::new("https://recipes.corp/{env}/{version}.txt");
+    assert!(template.versions("demo").await.is_err());
+}
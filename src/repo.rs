@@ -0,0 +1,294 @@
+use std::{cmp::Ordering, collections::HashSet, fmt::Display, future::Future, str::FromStr};
+
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    query::{compare_versions, is_prerelease, parse_constraint, VersionConstraint, VersionOp},
+    recipe::{Package, PackageKey, Recipe, RecipeDiff},
+};
+
+/// the file of the recipe in the repo of an env
+pub(crate) const RECIPE_PATH: &str = "env.recipe";
+
+/// a commit which touched the recipe
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipeCommit {
+    pub short_id: String,
+    pub author: String,
+    pub title: String,
+    /// the packages whose lines the commit added or removed
+    pub packages: HashSet<PackageKey>,
+}
+
+/// where the recipes of the envs are versioned
+pub trait RecipeRepo: Sync {
+    fn fetch(
+        &self,
+        env_name: &str,
+        version: &str,
+    ) -> impl Future<Output = anyhow::Result<String>> + Send;
+
+    /// the versions of the recipe which can be fetched, the tags first, the newest first,
+    /// `Error::UnknownEnv` if the repo has no such env
+    fn versions(
+        &self,
+        env_name: &str,
+    ) -> impl Future<Output = anyhow::Result<Vec<EnvVersion>>> + Send {
+        async move {
+            Err(anyhow::anyhow!(
+                "the repo can't list the versions of env '{}'",
+                env_name
+            ))
+        }
+    }
+
+    /// the commits which touched the recipe between the versions, oldest first, `None` when
+    /// the repo can't tell
+    fn compare(
+        &self,
+        _env_name: &str,
+        _from: &str,
+        _to: &str,
+    ) -> impl Future<Output = anyhow::Result<Option<Vec<RecipeCommit>>>> + Send {
+        async { Ok(None) }
+    }
+}
+
+/// a version of the recipe which `install --version` accepts
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EnvVersion {
+    pub name: String,
+    pub kind: VersionKind,
+    /// what `master` and `latest` install
+    pub latest: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionKind {
+    Tag,
+    Branch,
+}
+
+impl Display for EnvVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            VersionKind::Tag => "tag",
+            VersionKind::Branch => "branch",
+        };
+        write!(f, "{:<6}  {}", kind, self.name)?;
+        if self.latest {
+            write!(f, "  ← master, latest")?;
+        }
+        Ok(())
+    }
+}
+
+/// the tags first, the newest first, then the branches by name
+pub(crate) fn sort_env_versions(versions: &mut [EnvVersion]) {
+    versions.sort_by(|a, b| match (a.kind, b.kind) {
+        (VersionKind::Tag, VersionKind::Tag) => compare_versions(&b.name, &a.name),
+        (VersionKind::Tag, VersionKind::Branch) => Ordering::Less,
+        (VersionKind::Branch, VersionKind::Tag) => Ordering::Greater,
+        (VersionKind::Branch, VersionKind::Branch) => a.name.cmp(&b.name),
+    });
+}
+
+/// a pattern of recipe versions, a glob like `2024.03.*` or a range like
+/// `>=2024.03,<2024.04`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionMatch {
+    pattern: String,
+    constraints: Vec<VersionConstraint>,
+}
+
+impl FromStr for VersionMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim();
+        let constraints = if pattern.starts_with(['=', '!', '<', '>']) {
+            pattern
+                .split(',')
+                .map(|c| {
+                    parse_constraint(c)
+                        .ok_or_else(|| format!("invalid version range '{}'", pattern))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else if !pattern.is_empty() && !pattern.contains(char::is_whitespace) {
+            vec![VersionConstraint {
+                op: VersionOp::Eq,
+                version: pattern.to_string(),
+            }]
+        } else {
+            return Err(format!(
+                "invalid version pattern '{}', expected a glob like `2024.03.*` or a range like `>=2024.03,<2024.04`",
+                pattern
+            ));
+        };
+        Ok(Self {
+            pattern: pattern.to_string(),
+            constraints,
+        })
+    }
+}
+
+impl std::fmt::Display for VersionMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl VersionMatch {
+    /// the newest of the matching versions, pre-releases are only considered when included
+    pub fn resolve(&self, versions: &[String], include_prerelease: bool) -> Result<String, Error> {
+        let mut candidates = versions
+            .iter()
+            .filter(|v| include_prerelease || !is_prerelease(v))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| compare_versions(b, a).then_with(|| b.cmp(a)));
+        candidates
+            .iter()
+            .find(|v| self.constraints.iter().all(|c| c.matches(v)))
+            .map(|v| v.to_string())
+            .ok_or_else(|| Error::NoMatchingVersion {
+                pattern: self.pattern.clone(),
+                candidates: candidates.iter().map(|v| v.to_string()).collect(),
+            })
+    }
+}
+
+#[test]
+fn test_resolve_version_match() {
+    let versions = [
+        "2024.9.0",
+        "2024.10.0",
+        "2024.03.1",
+        "2024.03.10",
+        "2024.03.2",
+        "2024.03.11rc1",
+        "2024.04.0",
+    ]
+    .map(String::from);
+    let resolve = |pattern: &str, include_prerelease: bool| {
+        pattern
+            .parse::<VersionMatch>()
+            .unwrap()
+            .resolve(&versions, include_prerelease)
+    };
+
+    assert_eq!(resolve("2024.03.*", false).unwrap(), "2024.03.10");
+    assert_eq!(resolve("2024.03.*", true).unwrap(), "2024.03.11rc1");
+    assert_eq!(resolve("*", false).unwrap(), "2024.10.0");
+    assert_eq!(resolve(">=2024.03,<2024.04", false).unwrap(), "2024.03.10");
+    assert_eq!(resolve("<2024.10", false).unwrap(), "2024.9.0");
+    assert_eq!(resolve("2024.03.2", false).unwrap(), "2024.03.2");
+    match resolve("2023.*", false) {
+        Err(Error::NoMatchingVersion {
+            pattern,
+            candidates,
+        }) => {
+            assert_eq!(pattern, "2023.*");
+            assert_eq!(candidates.first().map(String::as_str), Some("2024.10.0"));
+            assert_eq!(candidates.len(), 6);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    for invalid in ["", ">=", ">=2024.*", "2024 03"] {
+        assert!(invalid.parse::<VersionMatch>().is_err(), "{}", invalid);
+    }
+}
+
+/// the last commit which touched the package
+pub fn blame<'c>(commits: &'c [RecipeCommit], key: &PackageKey) -> Option<&'c RecipeCommit> {
+    commits.iter().rev().find(|c| c.packages.contains(key))
+}
+
+/// a line per change of the diff, with where the package is in the recipe, e.g.
+/// `+ requests=2.31.0=pyhd8ed1ab_0  env.recipe:7  b2c3d4e alice: add requests`
+pub fn explain_changes(
+    diff: &RecipeDiff,
+    from_contents: &str,
+    to_contents: &str,
+    commits: &[RecipeCommit],
+) -> Vec<String> {
+    let from_lines = Recipe::package_lines(from_contents);
+    let to_lines = Recipe::package_lines(to_contents);
+    let explain = |sign: &str, pkg: &Package, line: Option<&usize>| {
+        let key = pkg.key();
+        let mut explanation = format!("{} {}", sign, pkg);
+        if let Some(line) = line {
+            explanation.push_str(&format!("  {}:{}", RECIPE_PATH, line));
+        }
+        if let Some(commit) = blame(commits, &key) {
+            explanation.push_str(&format!(
+                "  {} {}: {}",
+                commit.short_id, commit.author, commit.title
+            ));
+        }
+        explanation
+    };
+    let adds = diff
+        .adds
+        .iter()
+        .map(|p| explain("+", p, to_lines.get(&p.key())));
+    let updates = diff
+        .updates
+        .iter()
+        .map(|u| explain("*", &u.to, to_lines.get(&u.to.key())));
+    // deleted packages are only in the old recipe
+    let deletes = diff
+        .deletes
+        .iter()
+        .map(|p| explain("-", p, from_lines.get(&p.key())));
+    adds.chain(updates).chain(deletes).collect()
+}
+
+#[test]
+fn test_explain_changes() {
+    let from_contents = r#"
+numpy                     1.24.1          py310h5d7c261_0    conda-forge
+Django                    3.2.13                   pypi_0    pypi
+"#;
+    let to_contents = r#"
+numpy                     1.24.3          py310h5d7c261_0    conda-forge
+requests                  2.31.0             pyhd8ed1ab_0    conda-forge
+"#;
+    let from_recipe = Recipe::try_from(from_contents).unwrap();
+    let diff = from_recipe.diff(to_contents.try_into().unwrap());
+    let commits = [RecipeCommit {
+        short_id: "b2c3d4e".to_string(),
+        author: "alice".to_string(),
+        title: "bump numpy".to_string(),
+        packages: HashSet::from([PackageKey::new("numpy", false)]),
+    }];
+    assert_eq!(
+        explain_changes(&diff, from_contents, to_contents, &commits),
+        [
+            "+ requests=2.31.0=pyhd8ed1ab_0  env.recipe:3",
+            "* numpy=1.24.3=py310h5d7c261_0  env.recipe:2  b2c3d4e alice: bump numpy",
+            "- Django==3.2.13  env.recipe:3",
+        ]
+    );
+}
+
+#[test]
+fn test_blame() {
+    let commit = |short_id: &str, packages: &[&str]| RecipeCommit {
+        short_id: short_id.to_string(),
+        author: "alice".to_string(),
+        title: format!("commit {}", short_id),
+        packages: packages
+            .iter()
+            .map(|name| PackageKey::new(name, false))
+            .collect(),
+    };
+    let commits = [commit("a1", &["numpy", "pandas"]), commit("b2", &["numpy"])];
+    let short_id =
+        |name: &str| blame(&commits, &PackageKey::new(name, false)).map(|c| c.short_id.as_str());
+    assert_eq!(short_id("numpy"), Some("b2"));
+    assert_eq!(short_id("pandas"), Some("a1"));
+    assert_eq!(short_id("scipy"), None);
+}
@@ -0,0 +1,539 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action::InstallPlan,
+    recipe::{ChangeGroup, Package, RecipeDiff},
+};
+
+/// bump it whenever the report schema changes incompatibly
+pub const REPORT_VERSION: u32 = 2;
+
+/// the machine-readable report of one `install` run
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub schema_version: u32,
+    pub status: Status,
+    /// `None` when any env failed
+    pub outcome: Option<Outcome>,
+    pub envs: Vec<EnvReport>,
+}
+
+impl RunReport {
+    pub fn new(mut envs: Vec<EnvReport>) -> Self {
+        for env in &mut envs {
+            env.sort();
+            env.outcome = env.classify();
+        }
+        let status = if envs.iter().any(|e| e.status == Status::Failed) {
+            Status::Failed
+        } else if envs.iter().all(|e| e.status == Status::Skipped) {
+            Status::Skipped
+        } else {
+            Status::Success
+        };
+        let outcome = envs
+            .iter()
+            .map(|e| e.outcome)
+            .collect::<Option<Vec<_>>>()
+            .map(|outcomes| outcomes.into_iter().max().unwrap_or_default());
+        Self {
+            schema_version: REPORT_VERSION,
+            status,
+            outcome,
+            envs,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct EnvReport {
+    pub env_name: String,
+    pub status: Status,
+    /// `None` when the env failed
+    pub outcome: Option<Outcome>,
+    pub error: Option<String>,
+    /// whether the env was (re)created from scratch
+    pub create_env: bool,
+    /// whether the env was restored to its previous packages after the install failed
+    pub rolled_back: bool,
+    pub diff: RecipeDiff,
+    /// the number of executed conda commands
+    pub commands: usize,
+    pub installed: Vec<Package>,
+    pub deleted: Vec<Package>,
+    pub failed: Vec<Package>,
+    /// the packages of the recipe which were already installed as is
+    pub satisfied: usize,
+    /// paths overwritten by more than one conda package
+    pub clobbered_paths: Vec<String>,
+    /// packages conda relinked although they were not going to be installed
+    pub relinked: Vec<String>,
+    /// changes of the diff which were not applied
+    pub skipped: Vec<SkippedChange>,
+    /// the recipe lines dropped since their `# [...]` selector is false on the platform or for
+    /// the virtual packages of the machine
+    pub platform_filtered: usize,
+    /// the recoverable issues met during the install
+    pub warnings: Vec<RunWarning>,
+    /// the attempts before the env was retried from scratch
+    pub failed_attempts: Vec<FailedAttempt>,
+    pub tools: ToolVersions,
+    /// the commands printed instead of run by `--dry-run`
+    pub plan: Option<InstallPlan>,
+    pub phases: Vec<PhaseReport>,
+}
+
+impl EnvReport {
+    pub fn new(env_name: &str) -> Self {
+        Self {
+            env_name: env_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn warn(&mut self, kind: WarningKind, message: impl Into<String>) {
+        self.warnings.push(RunWarning {
+            kind,
+            message: message.into(),
+        });
+    }
+
+    /// whether the install got to change the env
+    pub fn touched_env(&self) -> bool {
+        self.create_env || !self.deleted.is_empty() || !self.installed.is_empty()
+    }
+
+    /// how the env went if it didn't fail
+    pub fn classify(&self) -> Option<Outcome> {
+        if self.status == Status::Failed {
+            None
+        } else if !self.warnings.is_empty() {
+            Some(Outcome::Warned)
+        } else if self.touched_env() {
+            Some(Outcome::Changed)
+        } else {
+            Some(Outcome::Unchanged)
+        }
+    }
+
+    /// the order the packages and paths were met in isn't stable between runs
+    fn sort(&mut self) {
+        for packages in [&mut self.installed, &mut self.deleted, &mut self.failed] {
+            packages.sort_by_key(Package::sort_key);
+        }
+        self.clobbered_paths.sort();
+        self.relinked.sort();
+        self.skipped
+            .sort_by_key(|s| (s.group, s.package.sort_key()));
+    }
+}
+
+/// the reason of the changes deselected in `install --interactive`
+pub const DESELECTED_BY_OPERATOR: &str = "deselected by operator";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedChange {
+    pub group: ChangeGroup,
+    /// the target package of an update
+    pub package: Package,
+    pub reason: String,
+}
+
+impl SkippedChange {
+    /// one per change in the diff
+    pub fn from_diff(diff: &RecipeDiff, reason: &str) -> Vec<Self> {
+        let adds = diff.adds.iter().map(|p| (ChangeGroup::Adds, p));
+        let updates = diff.updates.iter().map(|u| (ChangeGroup::Updates, &u.to));
+        let deletes = diff.deletes.iter().map(|p| (ChangeGroup::Deletes, p));
+        adds.chain(updates)
+            .chain(deletes)
+            .map(|(group, package)| Self {
+                group,
+                package: package.clone(),
+                reason: reason.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// how a successful run went, ordered by severity
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// nothing was changed
+    #[default]
+    Unchanged,
+    Changed,
+    /// recoverable issues were met, whether anything was changed or not
+    Warned,
+}
+
+impl Outcome {
+    /// the exit code of `install --detailed-exit-codes`
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Unchanged => 0,
+            Self::Changed => 10,
+            Self::Warned => 11,
+        }
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Unchanged => "unchanged",
+            Self::Changed => "changed",
+            Self::Warned => "succeeded with warnings",
+        })
+    }
+}
+
+/// the versions of the tools which installed the env, `None` when they weren't run
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ToolVersions {
+    pub conda: Option<String>,
+    pub pip: Option<String>,
+}
+
+/// an install which failed and was retried from scratch with `--force`
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedAttempt {
+    pub error: String,
+    pub kind: String,
+    pub commands: usize,
+    pub phases: Vec<PhaseReport>,
+}
+
+impl FailedAttempt {
+    pub fn new(error: &anyhow::Error, kind: String, report: &EnvReport) -> Self {
+        Self {
+            error: error.to_string(),
+            kind,
+            commands: report.commands,
+            phases: report.phases.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunWarning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// the solver isn't supported by conda, the default one was used
+    UnsupportedSolver,
+    /// paths were overwritten by other packages
+    Clobbered,
+    /// a pypi package was installed after failed tries
+    PipRetried,
+    /// `pip` couldn't be installed, the pypi packages were skipped
+    PipSkipped,
+    /// the pypi `pip` of the recipe was deleted or replaced, but pip can't uninstall itself
+    PipKept,
+    /// conda or pip is a release which is known to corrupt envs
+    BlockedTool,
+    /// conda removed more packages than the deleted ones, they were reinstalled
+    Repaired,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Success,
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseReport {
+    pub name: String,
+    pub seconds: f64,
+}
+
+impl PhaseReport {
+    pub fn new(name: &str, elapsed: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            seconds: elapsed.as_secs_f64(),
+        }
+    }
+}
+
+#[test]
+fn test_serialize_run_report() {
+    use assert_json_diff::assert_json_eq;
+    use serde_json::json;
+
+    use crate::recipe::Recipe;
+
+    let old_recipe: Recipe = "django 3.2.13 pypi_0 pypi".try_into().unwrap();
+    let new_recipe: Recipe = r#"
+django                    3.2.14                   pypi_0    pypi
+ncurses                   6.3                  hca72f7f_3    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    let ncurses = diff.adds[0].clone();
+    let old_django = diff.updates[0].from.clone();
+    let django = diff.updates[0].to.clone();
+    let tzdata = Recipe::try_from("tzdata 2023c h71feb2d_0 conda-forge").unwrap()["tzdata"].clone();
+
+    let report = RunReport::new(vec![
+        EnvReport {
+            env_name: "demo".into(),
+            status: Status::Failed,
+            outcome: None,
+            error: Some("fail to install django".into()),
+            create_env: false,
+            rolled_back: false,
+            diff,
+            commands: 3,
+            installed: vec![ncurses],
+            deleted: vec![old_django],
+            failed: vec![django],
+            satisfied: 12,
+            clobbered_paths: vec!["lib/libfoo.so".into()],
+            relinked: vec!["ca-certificates-2022.07.19-hecd8cb5_0".into()],
+            skipped: SkippedChange::from_diff(
+                &RecipeDiff {
+                    adds: vec![tzdata.clone()],
+                    ..Default::default()
+                },
+                DESELECTED_BY_OPERATOR,
+            ),
+            platform_filtered: 2,
+            warnings: vec![RunWarning {
+                kind: WarningKind::Clobbered,
+                message: "1 paths are clobbered by other packages".into(),
+            }],
+            failed_attempts: vec![FailedAttempt {
+                error: "the package record 'conda-meta/numpy.json' of the env is corrupt".into(),
+                kind: "corrupt_meta".into(),
+                commands: 1,
+                phases: vec![PhaseReport::new("check", Duration::from_millis(500))],
+            }],
+            tools: ToolVersions {
+                conda: Some("22.11.1".into()),
+                pip: Some("22.1.2".into()),
+            },
+            plan: None,
+            phases: vec![
+                PhaseReport::new("check", Duration::from_millis(500)),
+                PhaseReport::new("delete", Duration::from_secs(1)),
+                PhaseReport::new("install", Duration::from_secs(2)),
+            ],
+        },
+        EnvReport {
+            status: Status::Skipped,
+            ..EnvReport::new("demo2")
+        },
+    ]);
+
+    let django = |version: &str| json!({"name": "django", "version": version, "kind": "pypi"});
+    let ncurses = json!({
+        "name": "ncurses",
+        "version": "6.3",
+        "kind": "conda",
+        "build": "hca72f7f_3",
+        "channel": "conda-forge"
+    });
+    assert_json_eq!(
+        serde_json::to_value(&report).unwrap(),
+        json!({
+            "schema_version": 2,
+            "status": "failed",
+            "outcome": null,
+            "envs": [
+                {
+                    "env_name": "demo",
+                    "status": "failed",
+                    "outcome": null,
+                    "error": "fail to install django",
+                    "create_env": false,
+                    "rolled_back": false,
+                    "diff": {
+                        "adds": [ncurses],
+                        "updates": [{
+                            "from": django("3.2.13"),
+                            "to": django("3.2.14"),
+                            "changes": ["version"]
+                        }],
+                        "deletes": [],
+                        "same": []
+                    },
+                    "commands": 3,
+                    "installed": [ncurses],
+                    "deleted": [django("3.2.13")],
+                    "failed": [django("3.2.14")],
+                    "satisfied": 12,
+                    "clobbered_paths": ["lib/libfoo.so"],
+                    "relinked": ["ca-certificates-2022.07.19-hecd8cb5_0"],
+                    "skipped": [{
+                        "group": "adds",
+                        "package": {
+                            "name": "tzdata",
+                            "version": "2023c",
+                            "kind": "conda",
+                            "build": "h71feb2d_0",
+                            "channel": "conda-forge"
+                        },
+                        "reason": "deselected by operator"
+                    }],
+                    "platform_filtered": 2,
+                    "warnings": [{
+                        "kind": "clobbered",
+                        "message": "1 paths are clobbered by other packages"
+                    }],
+                    "failed_attempts": [{
+                        "error": "the package record 'conda-meta/numpy.json' of the env is corrupt",
+                        "kind": "corrupt_meta",
+                        "commands": 1,
+                        "phases": [{"name": "check", "seconds": 0.5}]
+                    }],
+                    "tools": {"conda": "22.11.1", "pip": "22.1.2"},
+                    "plan": null,
+                    "phases": [
+                        {"name": "check", "seconds": 0.5},
+                        {"name": "delete", "seconds": 1.0},
+                        {"name": "install", "seconds": 2.0}
+                    ]
+                },
+                {
+                    "env_name": "demo2",
+                    "status": "skipped",
+                    "outcome": "unchanged",
+                    "error": null,
+                    "create_env": false,
+                    "rolled_back": false,
+                    "diff": {"adds": [], "updates": [], "deletes": [], "same": []},
+                    "commands": 0,
+                    "installed": [],
+                    "deleted": [],
+                    "failed": [],
+                    "satisfied": 0,
+                    "clobbered_paths": [],
+                    "relinked": [],
+                    "skipped": [],
+                    "platform_filtered": 0,
+                    "warnings": [],
+                    "failed_attempts": [],
+                    "tools": {"conda": null, "pip": null},
+                    "plan": null,
+                    "phases": []
+                }
+            ]
+        })
+    );
+}
+
+#[test]
+fn test_serialize_run_report_deterministically() {
+    use crate::recipe::Recipe;
+
+    let recipe: Recipe = r#"
+zlib                      1.2.13               h166bdaf_4    conda-forge
+Django                    3.2.14                   pypi_0    pypi
+numpy                     1.24.3          py310h5d7c261_0    conda-forge
+attrs                     23.1.0                   pypi_0    pypi
+"#
+    .try_into()
+    .unwrap();
+    let packages = recipe.packages.values().cloned().collect::<Vec<_>>();
+    let report = |reverse: bool| {
+        let mut packages = packages.clone();
+        let mut paths = vec!["lib/libz.so".to_string(), "bin/tool".to_string()];
+        if reverse {
+            packages.reverse();
+            paths.reverse();
+        }
+        let diff = Recipe::default().diff(recipe.clone());
+        let report = RunReport::new(vec![EnvReport {
+            installed: packages.clone(),
+            skipped: SkippedChange::from_diff(&diff, DESELECTED_BY_OPERATOR),
+            clobbered_paths: paths.clone(),
+            relinked: paths,
+            diff,
+            ..EnvReport::new("demo")
+        }]);
+        serde_json::to_string(&report).unwrap()
+    };
+    assert_eq!(report(false), report(true));
+    // conda packages first
+    let installed =
+        &serde_json::from_str::<serde_json::Value>(&report(false)).unwrap()["envs"][0]["installed"];
+    let names = installed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["numpy", "zlib", "attrs", "Django"]);
+}
+
+#[test]
+fn test_classify_outcome() {
+    use crate::recipe::Recipe;
+
+    let numpy =
+        Recipe::try_from("numpy 1.24.3 py310h5d7c261_0 conda-forge").unwrap()["numpy"].clone();
+    let unchanged = EnvReport::new("unchanged");
+    let changed = EnvReport {
+        installed: vec![numpy.clone()],
+        ..EnvReport::new("changed")
+    };
+    let created = EnvReport {
+        create_env: true,
+        ..EnvReport::new("created")
+    };
+    let mut warned = EnvReport {
+        installed: vec![numpy],
+        ..EnvReport::new("warned")
+    };
+    warned.warn(
+        WarningKind::PipRetried,
+        "django==3.2.14 is installed after 1 failed tries",
+    );
+    let mut unchanged_warned = EnvReport::new("unchanged_warned");
+    unchanged_warned.warn(
+        WarningKind::UnsupportedSolver,
+        "conda 4.10 doesn't support `--solver`",
+    );
+    let failed = EnvReport {
+        status: Status::Failed,
+        ..EnvReport::new("failed")
+    };
+
+    assert_eq!(unchanged.classify(), Some(Outcome::Unchanged));
+    assert_eq!(changed.classify(), Some(Outcome::Changed));
+    assert_eq!(created.classify(), Some(Outcome::Changed));
+    assert_eq!(warned.classify(), Some(Outcome::Warned));
+    assert_eq!(unchanged_warned.classify(), Some(Outcome::Warned));
+    assert_eq!(failed.classify(), None);
+    assert!(!unchanged.touched_env() && changed.touched_env() && created.touched_env());
+    assert_eq!(
+        [Outcome::Unchanged, Outcome::Changed, Outcome::Warned].map(Outcome::exit_code),
+        [0, 10, 11]
+    );
+
+    // the most severe outcome of the envs
+    let outcome = |envs: Vec<EnvReport>| RunReport::new(envs).outcome;
+    assert_eq!(outcome(vec![unchanged.clone()]), Some(Outcome::Unchanged));
+    assert_eq!(
+        outcome(vec![unchanged.clone(), changed.clone()]),
+        Some(Outcome::Changed)
+    );
+    assert_eq!(
+        outcome(vec![changed.clone(), warned, unchanged]),
+        Some(Outcome::Warned)
+    );
+    assert_eq!(outcome(vec![changed, failed]), None);
+}
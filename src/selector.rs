@@ -0,0 +1,384 @@
+//! the platform selectors of recipe lines, e.g. `numpy 1.24.1 py310h5d7c261_0 conda-forge  # [linux-64]`,
+//! and the virtual package selectors, e.g. `cudatoolkit 11.8.0 h37601d7_11 conda-forge  # [__cuda]`
+
+use std::{collections::HashMap, fmt::Display};
+
+use crate::query::{parse_constraint, VersionConstraint, VersionOp};
+
+/// the subdirs a selector may name
+pub const PLATFORMS: [&str; 8] = [
+    "linux-64",
+    "linux-aarch64",
+    "linux-ppc64le",
+    "osx-64",
+    "osx-arm64",
+    "win-32",
+    "win-64",
+    "win-arm64",
+];
+
+/// `<subdir>` or `not <subdir>`, a limited conda-build selector, or `[not] __<name>[<op><version>]`
+/// of a virtual package
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    Is(String),
+    Not(String),
+    Virtual {
+        /// with the leading `__`, e.g. `__glibc`
+        name: String,
+        /// any version matches without one
+        constraint: Option<VersionConstraint>,
+        negated: bool,
+    },
+}
+
+impl std::str::FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negated, platform) = match s.trim().strip_prefix("not ") {
+            Some(platform) => (true, platform.trim()),
+            None => (false, s.trim()),
+        };
+        if platform.starts_with("__") {
+            return parse_virtual(platform, negated).ok_or_else(|| {
+                format!(
+                    "invalid selector '[{}]', expected `[__<name>]` or `[__<name><op><version>]`, e.g. `[__glibc>=2.28]`",
+                    s
+                )
+            });
+        }
+        let platform = parse_platform(platform).map_err(|_| {
+            format!(
+                "invalid selector '[{}]', expected `[<subdir>]` or `[not <subdir>]` of the subdirs: {}",
+                s,
+                PLATFORMS.join(", ")
+            )
+        })?;
+        Ok(if negated {
+            Selector::Not(platform)
+        } else {
+            Selector::Is(platform)
+        })
+    }
+}
+
+fn parse_virtual(s: &str, negated: bool) -> Option<Selector> {
+    let name_end = s
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(s.len());
+    let (name, constraint) = s.split_at(name_end);
+    if name.len() <= 2 {
+        return None;
+    }
+    let constraint = match constraint.trim() {
+        "" => None,
+        constraint => Some(parse_constraint(constraint)?),
+    };
+    Some(Selector::Virtual {
+        name: name.to_string(),
+        constraint,
+        negated,
+    })
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::Is(platform) => write!(f, "[{}]", platform),
+            Selector::Not(platform) => write!(f, "[not {}]", platform),
+            Selector::Virtual {
+                name,
+                constraint,
+                negated,
+            } => {
+                let not = if *negated { "not " } else { "" };
+                let constraint = constraint
+                    .as_ref()
+                    .map(|c| format!("{}{}", op_str(c.op), c.version))
+                    .unwrap_or_default();
+                write!(f, "[{}{}{}]", not, name, constraint)
+            }
+        }
+    }
+}
+
+fn op_str(op: VersionOp) -> &'static str {
+    match op {
+        VersionOp::Eq => "==",
+        VersionOp::Ne => "!=",
+        VersionOp::Ge => ">=",
+        VersionOp::Gt => ">",
+        VersionOp::Le => "<=",
+        VersionOp::Lt => "<",
+        VersionOp::Fuzzy => "=",
+    }
+}
+
+/// what the selectors of a recipe are evaluated against
+#[derive(Debug, Clone, Default)]
+pub struct SelectorTarget {
+    /// `None` if the platform of this machine is unknown
+    pub platform: Option<String>,
+    /// `None` if they weren't detected
+    pub virtual_packages: Option<VirtualPackages>,
+}
+
+impl Selector {
+    pub fn matches(&self, target: &SelectorTarget) -> Result<bool, String> {
+        let platform = || {
+            target.platform.as_deref().ok_or_else(|| {
+                "the platform of this machine is unknown, pass `--platform` to evaluate the selectors of the recipe".to_string()
+            })
+        };
+        match self {
+            Selector::Is(p) => Ok(p == platform()?),
+            Selector::Not(p) => Ok(p != platform()?),
+            Selector::Virtual {
+                name,
+                constraint,
+                negated,
+            } => {
+                let packages = target.virtual_packages.as_ref().ok_or_else(|| {
+                    format!(
+                        "the virtual packages of this machine aren't detected to evaluate the selector {}",
+                        self
+                    )
+                })?;
+                let present = match (packages.get(name), constraint) {
+                    (Some(version), Some(constraint)) => constraint.matches(version),
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                Ok(present != *negated)
+            }
+        }
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        matches!(self, Selector::Virtual { .. })
+    }
+}
+
+/// the virtual packages of a machine by name, e.g. `__cuda` → `12.2`, as `conda info` reports them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VirtualPackages(HashMap<String, String>);
+
+impl VirtualPackages {
+    pub fn new(packages: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self(packages.into_iter().collect())
+    }
+
+    /// apply the `CONDA_OVERRIDE_<NAME>` variables like conda does, `CONDA_OVERRIDE_CUDA=11.8`
+    /// sets `__cuda` to `11.8` and an empty value removes it
+    pub fn with_overrides(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        for (key, value) in vars {
+            let name = match key.strip_prefix("CONDA_OVERRIDE_") {
+                Some(name) if !name.is_empty() => format!("__{}", name.to_ascii_lowercase()),
+                _ => continue,
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                self.0.remove(&name);
+            } else {
+                self.0.insert(name, value.to_string());
+            }
+        }
+        self
+    }
+
+    /// the version of the virtual package, `None` if the machine hasn't it
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+/// whether a line of the recipe has a virtual package selector, so they need to be detected
+pub fn uses_virtual_packages(contents: &str) -> bool {
+    contents.lines().any(
+        |line| matches!(split_selector(line), Ok((_, Some(selector))) if selector.is_virtual()),
+    )
+}
+
+/// a subdir of `PLATFORMS`, e.g. the value of `--platform`
+pub fn parse_platform(s: &str) -> Result<String, String> {
+    if PLATFORMS.contains(&s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid platform '{}', expected one of: {}",
+            s,
+            PLATFORMS.join(", ")
+        ))
+    }
+}
+
+/// the subdir of the running machine, `None` if conda has none for it
+pub fn current_platform() -> Option<&'static str> {
+    let platform = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => "linux-64",
+        ("linux", "aarch64") => "linux-aarch64",
+        ("linux", "powerpc64") => "linux-ppc64le",
+        ("macos", "x86_64") => "osx-64",
+        ("macos", "aarch64") => "osx-arm64",
+        ("windows", "x86") => "win-32",
+        ("windows", "x86_64") => "win-64",
+        ("windows", "aarch64") => "win-arm64",
+        _ => return None,
+    };
+    Some(platform)
+}
+
+/// split the trailing `# [...]` selector off a line
+pub fn split_selector(line: &str) -> Result<(&str, Option<Selector>), String> {
+    let pattern = regex::Regex::new(r"^(.*?)\s*#\s*\[([^\]]*)\]\s*$").unwrap();
+    match pattern.captures(line) {
+        // a whole comment line is kept as it is
+        Some(cap) if !cap[1].trim().is_empty() => {
+            let selector = cap[2].parse()?;
+            Ok((cap.get(1).unwrap().as_str(), Some(selector)))
+        }
+        _ => Ok((line, None)),
+    }
+}
+
+#[test]
+fn test_parse_selector() {
+    assert_eq!("linux-64".parse(), Ok(Selector::Is("linux-64".into())));
+    assert_eq!(" not  win-64 ".parse(), Ok(Selector::Not("win-64".into())));
+    for invalid in [
+        "",
+        "linux",
+        "not",
+        "not linux",
+        "linux-64 or osx-64",
+        "py>37",
+    ] {
+        assert!(invalid.parse::<Selector>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_match_selector() {
+    let target = |platform: &str| SelectorTarget {
+        platform: Some(platform.to_string()),
+        virtual_packages: None,
+    };
+    let linux = Selector::Is("linux-64".into());
+    assert_eq!(linux.matches(&target("linux-64")), Ok(true));
+    assert_eq!(linux.matches(&target("osx-arm64")), Ok(false));
+    let not_win = Selector::Not("win-64".into());
+    assert_eq!(not_win.matches(&target("osx-arm64")), Ok(true));
+    assert_eq!(not_win.matches(&target("win-64")), Ok(false));
+    assert!(linux.matches(&SelectorTarget::default()).is_err());
+}
+
+#[test]
+fn test_parse_virtual_selector() {
+    assert_eq!(
+        "__cuda".parse(),
+        Ok(Selector::Virtual {
+            name: "__cuda".into(),
+            constraint: None,
+            negated: false
+        })
+    );
+    let glibc: Selector = " not __glibc >= 2.28".parse().unwrap();
+    assert_eq!(
+        glibc,
+        Selector::Virtual {
+            name: "__glibc".into(),
+            constraint: Some(VersionConstraint {
+                op: VersionOp::Ge,
+                version: "2.28".into()
+            }),
+            negated: true
+        }
+    );
+    assert_eq!(glibc.to_string(), "[not __glibc>=2.28]");
+    for invalid in ["__", "__cuda>=", "__cuda=>11", "__cuda 11", "__cuda>=1.*"] {
+        assert!(invalid.parse::<Selector>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_match_virtual_selector() {
+    let detected = VirtualPackages::new([
+        ("__cuda".to_string(), "12.2".to_string()),
+        ("__glibc".to_string(), "2.17".to_string()),
+    ]);
+    let matches = |selector: &str, packages: &VirtualPackages| {
+        selector
+            .parse::<Selector>()
+            .unwrap()
+            .matches(&SelectorTarget {
+                platform: None,
+                virtual_packages: Some(packages.clone()),
+            })
+            .unwrap()
+    };
+
+    // present with a version
+    assert!(matches("__cuda", &detected));
+    assert!(matches("__cuda>=11.8", &detected));
+    assert!(!matches("__cuda<12", &detected));
+    assert!(!matches("__glibc>=2.28", &detected));
+    assert!(matches("not __glibc>=2.28", &detected));
+    // compared as conda versions rather than strings
+    assert!(matches("__glibc>2.9", &detected));
+
+    // absent
+    assert!(!matches("__osx", &detected));
+    assert!(!matches("__osx>=11", &detected));
+    assert!(matches("not __osx", &detected));
+
+    // overridden, an empty override hides a detected package
+    let overridden = detected.clone().with_overrides([
+        ("CONDA_OVERRIDE_CUDA".to_string(), "".to_string()),
+        ("CONDA_OVERRIDE_GLIBC".to_string(), "2.31".to_string()),
+        ("CONDA_OVERRIDE_OSX".to_string(), "13.1".to_string()),
+        ("CONDA_PREFIX".to_string(), "/opt/conda".to_string()),
+    ]);
+    assert!(!matches("__cuda", &overridden));
+    assert!(matches("not __cuda", &overridden));
+    assert!(matches("__glibc>=2.28", &overridden));
+    assert!(matches("__osx>=13", &overridden));
+    assert_eq!(overridden.get("__glibc"), Some("2.31"));
+
+    let undetected = "__cuda".parse::<Selector>().unwrap();
+    assert!(undetected.matches(&SelectorTarget::default()).is_err());
+}
+
+#[test]
+fn test_uses_virtual_packages() {
+    assert!(uses_virtual_packages(
+        "numpy 1.24.1 py310h5d7c261_0\ncudatoolkit 11.8.0 h37601d7_11  # [__cuda]"
+    ));
+    assert!(!uses_virtual_packages(
+        "numpy 1.24.1 py310h5d7c261_0  # [linux-64]\n# [__cuda]"
+    ));
+}
+
+#[test]
+fn test_split_selector() {
+    assert_eq!(
+        split_selector("numpy  1.24.1  py310h5d7c261_0  conda-forge  # [osx-arm64]"),
+        Ok((
+            "numpy  1.24.1  py310h5d7c261_0  conda-forge",
+            Some(Selector::Is("osx-arm64".into()))
+        ))
+    );
+    assert_eq!(
+        split_selector("libgcc 12.2.0 h65d4601_19 #[not osx-arm64]"),
+        Ok((
+            "libgcc 12.2.0 h65d4601_19",
+            Some(Selector::Not("osx-arm64".into()))
+        ))
+    );
+    assert_eq!(
+        split_selector("numpy 1.24.1 py310h5d7c261_0"),
+        Ok(("numpy 1.24.1 py310h5d7c261_0", None))
+    );
+    assert_eq!(split_selector("# [linux-64]"), Ok(("# [linux-64]", None)));
+    assert!(split_selector("numpy 1.24.1 py310h5d7c261_0  # [py>37]").is_err());
+}
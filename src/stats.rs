@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::Display,
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    output::human::{format_count, format_duration},
+    report::{RunReport, Status},
+};
+
+/// the file under the stats dir which runs append to
+const STATS_FILE: &str = "stats.jsonl";
+
+/// bump it whenever the line schema changes incompatibly
+pub const STATS_VERSION: u32 = 1;
+
+fn stats_version() -> u32 {
+    STATS_VERSION
+}
+
+/// one line of the local usage statistics, appended per run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatLine {
+    /// the lines written before it was added are of version 1
+    #[serde(default = "stats_version")]
+    pub schema_version: u32,
+    /// unix seconds when the run ended
+    pub finished_at: u64,
+    pub status: Status,
+    pub seconds: f64,
+    pub envs: usize,
+    /// e.g. `disk_full`, one per failed env
+    pub failures: Vec<String>,
+}
+
+impl StatLine {
+    pub fn new(report: &RunReport, duration: Duration, failures: Vec<String>) -> Self {
+        Self {
+            schema_version: STATS_VERSION,
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            status: report.status,
+            seconds: duration.as_secs_f64(),
+            envs: report.envs.len(),
+            failures,
+        }
+    }
+}
+
+/// append the line by a single `O_APPEND` write, so concurrent runs don't interleave
+pub fn append(dir: &Path, line: &StatLine) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let mut contents = serde_json::to_string(line)?;
+    contents.push('\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(STATS_FILE))?
+        .write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// read the lines of all `*.jsonl` files in the dir, returns the lines and the number of
+/// corrupt ones
+pub fn read(dir: &Path) -> anyhow::Result<(Vec<StatLine>, usize)> {
+    let mut lines = vec![];
+    let mut corrupt = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("jsonl")) {
+            continue;
+        }
+        for line in std::fs::read_to_string(&path)?.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(line) => lines.push(line),
+                Err(_) => corrupt += 1,
+            }
+        }
+    }
+    Ok((lines, corrupt))
+}
+
+/// parse `30d`, `12h`, `90m`, `2w` or seconds like `3600s`
+pub fn parse_since(s: &str) -> Result<Duration, String> {
+    let err = || {
+        format!(
+            "invalid duration '{}', expected a number with unit s, m, h, d or w, e.g. `30d`",
+            s
+        )
+    };
+    let unit_at = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+    let (number, unit) = s.split_at(unit_at);
+    let number = number.parse::<u64>().map_err(|_| err())?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(err()),
+    };
+    Ok(Duration::from_secs(number * secs))
+}
+
+#[test]
+fn test_parse_since() {
+    assert_eq!(parse_since("30d"), Ok(Duration::from_secs(30 * 86400)));
+    assert_eq!(parse_since("12h"), Ok(Duration::from_secs(12 * 3600)));
+    assert_eq!(parse_since("90m"), Ok(Duration::from_secs(90 * 60)));
+    assert_eq!(parse_since("2w"), Ok(Duration::from_secs(14 * 86400)));
+    assert_eq!(parse_since("3600s"), Ok(Duration::from_secs(3600)));
+    for invalid in ["30", "d", "", "30x", "-1d", "1.5d"] {
+        assert!(parse_since(invalid).is_err(), "{}", invalid);
+    }
+}
+
+/// the aggregated statistics
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub runs: usize,
+    pub success_rate: f64,
+    pub runs_per_day: f64,
+    pub p50: Duration,
+    pub p95: Duration,
+    /// the most frequent failures first
+    pub top_failures: Vec<(String, usize)>,
+    pub corrupt: usize,
+}
+
+impl Summary {
+    /// aggregate the lines which finished within `since` before `now`
+    pub fn new(lines: &[StatLine], corrupt: usize, since: Option<Duration>, now: u64) -> Self {
+        let after = since.map(|s| now.saturating_sub(s.as_secs())).unwrap_or(0);
+        let lines = lines
+            .iter()
+            .filter(|l| l.finished_at >= after)
+            .collect::<Vec<_>>();
+
+        let mut seconds = lines.iter().map(|l| l.seconds).collect::<Vec<_>>();
+        seconds.sort_by(|a, b| a.total_cmp(b));
+        // nearest rank
+        let percentile = |p: f64| {
+            if seconds.is_empty() {
+                return Duration::ZERO;
+            }
+            let rank = ((p * seconds.len() as f64).ceil() as usize).max(1);
+            Duration::from_secs_f64(seconds[rank - 1])
+        };
+
+        let mut failures = HashMap::<&str, usize>::new();
+        for failure in lines.iter().flat_map(|l| &l.failures) {
+            *failures.entry(failure.as_str()).or_default() += 1;
+        }
+        let mut top_failures = failures
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect::<Vec<_>>();
+        top_failures.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let successes = lines.iter().filter(|l| l.status != Status::Failed).count();
+        let days = match (since, lines.iter().map(|l| l.finished_at).min()) {
+            (Some(since), _) => since.as_secs_f64() / 86400.0,
+            (None, Some(first)) => (now.saturating_sub(first) as f64 / 86400.0).max(1.0),
+            (None, None) => 1.0,
+        };
+
+        Self {
+            runs: lines.len(),
+            success_rate: if lines.is_empty() {
+                0.0
+            } else {
+                successes as f64 / lines.len() as f64
+            },
+            runs_per_day: lines.len() as f64 / days,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            top_failures,
+            corrupt,
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, " {:<16} {}", "runs", format_count(self.runs))?;
+        writeln!(f, " {:<16} {:.1}", "runs per day", self.runs_per_day)?;
+        writeln!(
+            f,
+            " {:<16} {:.1}%",
+            "success rate",
+            self.success_rate * 100.0
+        )?;
+        writeln!(f, " {:<16} {}", "p50 duration", format_duration(self.p50))?;
+        writeln!(f, " {:<16} {}", "p95 duration", format_duration(self.p95))?;
+        if !self.top_failures.is_empty() {
+            writeln!(f, " top failures:")?;
+            for (kind, count) in self.top_failures.iter().take(5) {
+                writeln!(f, "   {:<14} {}", kind, format_count(*count))?;
+            }
+        }
+        if self.corrupt > 0 {
+            writeln!(f, " skipped {} corrupt lines", format_count(self.corrupt))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_summary() {
+    let day = 86400;
+    let now = 100 * day;
+    let line = |days_ago: u64, status: Status, seconds: f64, failures: &[&str]| StatLine {
+        schema_version: STATS_VERSION,
+        finished_at: now - days_ago * day,
+        status,
+        seconds,
+        envs: 1,
+        failures: failures.iter().map(|f| f.to_string()).collect(),
+    };
+    let lines = vec![
+        line(1, Status::Success, 10.0, &[]),
+        line(2, Status::Success, 20.0, &[]),
+        line(3, Status::Skipped, 1.0, &[]),
+        line(4, Status::Failed, 40.0, &["disk_full"]),
+        line(5, Status::Failed, 30.0, &["timeout", "disk_full"]),
+        // out of the window
+        line(40, Status::Failed, 500.0, &["timeout"]),
+    ];
+
+    let summary = Summary::new(&lines, 2, Some(Duration::from_secs(30 * day)), now);
+    assert_eq!(summary.runs, 5);
+    assert_eq!(summary.success_rate, 0.6);
+    assert_eq!(summary.runs_per_day, 5.0 / 30.0);
+    assert_eq!(summary.p50, Duration::from_secs(20));
+    assert_eq!(summary.p95, Duration::from_secs(40));
+    assert_eq!(
+        summary.top_failures,
+        [("disk_full".to_string(), 2), ("timeout".to_string(), 1)]
+    );
+    assert_eq!(summary.corrupt, 2);
+
+    let summary = Summary::new(&lines, 0, None, now);
+    assert_eq!(summary.runs, 6);
+    assert_eq!(summary.runs_per_day, 6.0 / 40.0);
+    assert_eq!(summary.p95, Duration::from_secs(500));
+
+    let summary = Summary::new(&[], 0, None, now);
+    assert_eq!(summary.runs, 0);
+    assert_eq!(summary.p50, Duration::ZERO);
+}
+
+#[test]
+fn test_append_and_read_stats() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join("conda-cage-test-stats");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let report = RunReport::new(vec![crate::report::EnvReport::new("demo")]);
+    let line = StatLine::new(&report, Duration::from_secs(3), vec![]);
+    append(&dir, &line)?;
+    append(&dir, &line)?;
+    // a line cut by a crash and files of other tools are tolerated
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join(STATS_FILE))?
+        .write_all(b"{\"finished_at\": 1, \"stat\n")?;
+    std::fs::write(dir.join("notes.txt"), "not stats")?;
+    // written before the schema version was added
+    std::fs::write(
+        dir.join("old.jsonl"),
+        "{\"finished_at\": 1, \"status\": \"success\", \"seconds\": 1.0, \"envs\": 1, \"failures\": []}\n",
+    )?;
+    std::fs::write(dir.join("other.jsonl"), "garbage\n\n")?;
+
+    let (mut lines, corrupt) = read(&dir)?;
+    lines.sort_by_key(|l| l.finished_at);
+    assert_eq!(lines[0].schema_version, 1);
+    assert_eq!(lines[1..], [line.clone(), line]);
+    assert_eq!(corrupt, 2);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
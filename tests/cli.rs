@@ -0,0 +1,474 @@
+//! the binary is only built with the `cli` feature
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[test]
+fn test_cli_help() {
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .arg("--help")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let help = String::from_utf8(output.stdout).unwrap();
+    assert!(help.contains("install"));
+    assert!(help.contains("diff"));
+}
+
+/// put a fake conda which knows no env and succeeds at everything else into the dir, returns
+/// the `PATH` which finds it first
+fn fake_conda(dir: &Path) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let conda = dir.join("conda");
+    std::fs::write(
+        &conda,
+        "#!/bin/sh\nif [ \"$1\" = list ]; then echo EnvironmentLocationNotFound >&2; exit 1; fi\nif [ \"$1 $2\" = \"env list\" ]; then echo '{\"envs\": []}'; exit 0; fi\necho \"conda $*\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&conda, std::fs::Permissions::from_mode(0o755)).unwrap();
+    format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    )
+}
+
+#[test]
+fn test_report_to_stdout_keeps_stdout_pure() {
+    let dir = std::env::temp_dir().join("conda-cage-test-report-to-stdout");
+    let path = fake_conda(&dir);
+    let recipe = dir.join("env.recipe");
+    std::fs::write(&recipe, "blas 1.0 mkl\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args([
+            "install",
+            "conda-cage-test-demo",
+            "--show-diff",
+            "--report",
+            "-",
+        ])
+        .arg("--file")
+        .arg(&recipe)
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{}", stderr);
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(report["status"], "success");
+    assert_eq!(report["envs"][0]["env_name"], "conda-cage-test-demo");
+    assert!(stderr.contains("Add 1 packages:"), "{}", stderr);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_conda_log_not_created_on_success() {
+    let dir = std::env::temp_dir().join("conda-cage-test-conda-log");
+    let path = fake_conda(&dir);
+    let recipe = dir.join("env.recipe");
+    std::fs::write(&recipe, "blas 1.0 mkl\n").unwrap();
+    let conda_log = dir.join("conda.log");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args(["install", "conda-cage-test-demo", "--file"])
+        .arg(&recipe)
+        .arg("--conda-log")
+        .arg(&conda_log)
+        .env("PATH", path)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!conda_log.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_conda_log_records_commands_on_failure() {
+    let dir = std::env::temp_dir().join("conda-cage-test-conda-log-failure");
+    let path = fake_conda(&dir);
+    let conda = dir.join("conda");
+    let script = std::fs::read_to_string(&conda).unwrap().replace(
+        "#!/bin/sh\n",
+        "#!/bin/sh\nif [ \"$1\" = create ]; then echo 'Solving environment: failed'; echo 'conda is broken' >&2; exit 1; fi\n",
+    );
+    std::fs::write(&conda, script).unwrap();
+    let recipe = dir.join("env.recipe");
+    std::fs::write(&recipe, "blas 1.0 mkl\n").unwrap();
+    let conda_log = dir.join("conda.log");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args(["install", "conda-cage-test-demo", "--file"])
+        .arg(&recipe)
+        .arg("--conda-log")
+        .arg(&conda_log)
+        .env("PATH", path)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let log = std::fs::read_to_string(&conda_log).unwrap();
+    assert!(log.starts_with("# env: conda-cage-test-demo\n"), "{}", log);
+    assert!(log.contains("conda create"), "{}", log);
+    assert!(
+        log.contains("# stdout\nSolving environment: failed\n"),
+        "{}",
+        log
+    );
+    assert!(log.contains("# stderr\nconda is broken\n"), "{}", log);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_report_of_rolled_back_failure_keeps_conda_error() {
+    let dir = std::env::temp_dir().join("conda-cage-test-rolled-back-report");
+    let path = fake_conda(&dir);
+    let prefix = dir.join("envs").join("conda-cage-test-demo");
+    std::fs::create_dir_all(prefix.join("conda-meta")).unwrap();
+    std::fs::write(prefix.join(".cage-managed"), "").unwrap();
+    std::fs::write(
+        dir.join("conda"),
+        format!(
+            "#!/bin/sh\nif [ \"$1\" = list ]; then printf 'blas 1.0 mkl\\nzlib 1.2.13 h166bdaf_4\\n'; exit 0; fi\nif [ \"$1 $2\" = \"env list\" ]; then echo '{{\"envs\": [\"{prefix}\"]}}'; exit 0; fi\nif [ \"$1\" = info ]; then echo '{{\"envs\": [\"{prefix}\"], \"envs_dirs\": [\"{envs}\"], \"pkgs_dirs\": []}}'; exit 0; fi\ncase \"$1 $*\" in install*numpy*) echo 'PackagesNotFoundError: numpy is broken' >&2; exit 1;; esac\necho \"conda $*\"\n",
+            prefix = prefix.display(),
+            envs = dir.join("envs").display(),
+        ),
+    )
+    .unwrap();
+    let recipe = dir.join("env.recipe");
+    // zlib is deleted before numpy fails to install, so the env is rolled back
+    std::fs::write(&recipe, "blas 1.0 mkl\nnumpy 1.24.1 py310_0\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args([
+            "--no-info-cache",
+            "install",
+            "conda-cage-test-demo",
+            "--rollback-on-failure",
+            "--max-log-lines",
+            "5",
+            "--report",
+            "-",
+            "--file",
+        ])
+        .arg(&recipe)
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(!output.status.success());
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let error = report["envs"][0]["error"].as_str().unwrap();
+    assert!(error.contains("is rolled back"), "{}\n{}", error, stderr);
+    assert!(error.contains("numpy is broken"), "{}\n{}", error, stderr);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_install_fresh_env_without_removing_it() {
+    let dir = std::env::temp_dir().join("conda-cage-test-fresh-env");
+    let path = fake_conda(&dir);
+    // conda fails to remove an env which doesn't exist
+    let conda = dir.join("conda");
+    let script = std::fs::read_to_string(&conda).unwrap().replacen(
+        "#!/bin/sh\n",
+        "#!/bin/sh\nif [ \"$1 $2\" = \"env remove\" ]; then echo \"env doesn't exist\" >&2; exit 1; fi\n",
+        1,
+    );
+    std::fs::write(&conda, script).unwrap();
+    let recipe = dir.join("env.recipe");
+    std::fs::write(&recipe, "blas 1.0 mkl\n").unwrap();
+
+    let install = |dry_run: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_conda-cage"));
+        command
+            .args(["install", "conda-cage-test-demo", "--file"])
+            .arg(&recipe)
+            .env("PATH", &path);
+        if dry_run {
+            command.arg("--dry-run");
+        }
+        command.output().unwrap()
+    };
+
+    let output = install(false);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = install(true);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success());
+    assert!(stdout.contains("conda create"), "{}", stdout);
+    assert!(!stdout.contains("env remove"), "{}", stdout);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_install_recipe_from_stdin() {
+    let dir = std::env::temp_dir().join("conda-cage-test-recipe-from-stdin");
+    let path = fake_conda(&dir);
+
+    let install = |recipe: &str| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+            .args([
+                "install",
+                "conda-cage-test-demo",
+                "--show-diff",
+                "--dry-run",
+                "--file",
+                "-",
+            ])
+            .env("PATH", &path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(recipe.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let output = install("blas 1.0 mkl\nzlib 1.2.13 h166bdaf_4 conda-forge\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Add 2 packages:"), "{}", stdout);
+
+    let output = install("  \n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("the recipe from stdin is empty"),
+        "{}",
+        stderr
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_diff_recipe_files_without_conda() {
+    let dir = std::env::temp_dir().join("conda-cage-test-diff-recipe-files");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let left = dir.join("left.recipe");
+    let right = dir.join("right.recipe");
+    std::fs::write(&left, "blas 1.0 mkl\nzlib 1.2.12 h166bdaf_2 conda-forge\n").unwrap();
+    // no conda on the `PATH`, any call to it would fail the diff
+    let diff = |right: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+            .arg("diff")
+            .arg(&left)
+            .arg(right)
+            .env("PATH", &dir)
+            .output()
+            .unwrap()
+    };
+
+    std::fs::write(&right, "zlib 1.2.12 h166bdaf_2 conda-forge\nblas 1.0 mkl\n").unwrap();
+    let output = diff(&right);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    std::fs::write(
+        &right,
+        "zlib 1.2.13 h166bdaf_4 conda-forge\nnumpy 1.24.1 py310h5d7c261_0\n",
+    )
+    .unwrap();
+    let output = diff(&right);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("Add 1 packages:"), "{}", stdout);
+    assert!(stdout.contains("zlib"), "{}", stdout);
+    assert!(stdout.contains("blas"), "{}", stdout);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_install_progress_without_terminal() {
+    let dir = std::env::temp_dir().join("conda-cage-test-progress-without-terminal");
+    let path = fake_conda(&dir);
+    let recipe = dir.join("env.recipe");
+    std::fs::write(&recipe, "blas 1.0 mkl\n").unwrap();
+    let install = |quiet: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_conda-cage"));
+        command
+            .args([
+                "install",
+                "conda-cage-test-demo",
+                "--show-diff",
+                "--dry-run",
+            ])
+            .arg("--file")
+            .arg(&recipe)
+            .env("PATH", &path);
+        if quiet {
+            command.arg("--quiet");
+        }
+        command.output().unwrap()
+    };
+
+    // stdout is a pipe, so the progress is printed as plain lines
+    let output = install(false);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains("[0/3] reading current environment..."),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains('\x1b'), "{}", stdout);
+
+    let output = install(true);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success());
+    assert!(!stdout.contains("[0/3]"), "{}", stdout);
+    assert!(!stdout.contains("Add 1 packages:"), "{}", stdout);
+    assert!(stdout.contains("Result:"), "{}", stdout);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_export_from_prefix_without_conda() {
+    let prefix = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/prefix");
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args(["export", "--from-prefix"])
+        .arg(&prefix)
+        .env("PATH", "")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("requests"), "{}", stdout);
+    // the dist-info of the conda numpy isn't a pypi package
+    assert_eq!(stdout.matches("numpy").count(), 1, "{}", stdout);
+}
+
+#[test]
+fn test_check_missing_env() {
+    let dir = std::env::temp_dir().join("conda-cage-test-check-missing-env");
+    let path = fake_conda(&dir);
+    let recipe = dir.join("env.recipe");
+    std::fs::write(&recipe, "blas 1.0 mkl\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args(["check", "conda-cage-test-demo", "--file"])
+        .arg(&recipe)
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("doesn't exist"), "{}", stderr);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_check_recipe_with_virtual_selector() {
+    let dir = std::env::temp_dir().join("conda-cage-test-check-virtual-selector");
+    let path = fake_conda(&dir);
+    // the env has blas alone, and this machine has cuda 12.2
+    let conda = dir.join("conda");
+    let script = std::fs::read_to_string(&conda).unwrap().replacen(
+        "#!/bin/sh\n",
+        "#!/bin/sh\nif [ \"$1\" = list ]; then echo 'blas 1.0 mkl'; exit 0; fi\nif [ \"$1\" = info ]; then echo '{\"envs\": [], \"envs_dirs\": [], \"pkgs_dirs\": [], \"virtual_pkgs\": [[\"__cuda\", \"12.2\", \"0\"]]}'; exit 0; fi\n",
+        1,
+    );
+    std::fs::write(&conda, script).unwrap();
+    let recipe = dir.join("env.recipe");
+    std::fs::write(
+        &recipe,
+        "blas 1.0 mkl\ncudatoolkit 11.8.0 h37601d7_11  # [__cuda]\n",
+    )
+    .unwrap();
+    let check = |cuda_override: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_conda-cage"));
+        command
+            .args(["--no-info-cache", "check", "conda-cage-test-demo", "--file"])
+            .arg(&recipe)
+            .env("PATH", &path)
+            .env_remove("CONDA_OVERRIDE_CUDA");
+        if let Some(version) = cuda_override {
+            command.env("CONDA_OVERRIDE_CUDA", version);
+        }
+        command.output().unwrap()
+    };
+
+    let output = check(None);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("cudatoolkit"), "{}", stdout);
+
+    // an empty override hides the detected cuda
+    let output = check(Some(""));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("matches the recipe"), "{}", stdout);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rollback_flags_conflict() {
+    let output = Command::new(env!("CARGO_BIN_EXE_conda-cage"))
+        .args(["install", "demo", "--no-rollback", "--rollback-on-failure"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--rollback-on-failure"), "{}", stderr);
+}
@@ -0,0 +1,45 @@
+//! the library surface, which must build with `--no-default-features`
+
+use std::sync::Arc;
+
+use conda_cage::{
+    action::InstallOptions,
+    output::progress::{PlainProgress, ProgressSink},
+    recipe::Recipe,
+};
+
+#[test]
+fn test_diff_recipes() {
+    let old_recipe: Recipe = r#"
+django                    3.2.13                   pypi_0    pypi
+libcxx                    12.0.0               h2f01273_0
+"#
+    .try_into()
+    .unwrap();
+    let new_recipe: Recipe = r#"
+django                    3.2.14                   pypi_0    pypi
+ncurses                   6.3                  hca72f7f_3    conda-forge
+"#
+    .try_into()
+    .unwrap();
+    let diff = old_recipe.diff(new_recipe);
+    assert_eq!(
+        (diff.adds.len(), diff.updates.len(), diff.deletes.len()),
+        (1, 1, 1)
+    );
+
+    let output = diff.to_string();
+    assert!(output.contains("Add 1 packages:"));
+    assert!(output.contains("Update 1 packages:"));
+    assert!(output.contains("Delete 1 packages:"));
+}
+
+#[test]
+fn test_custom_progress_sink() {
+    let progress: Arc<dyn ProgressSink> = Arc::new(PlainProgress::default());
+    let options = InstallOptions {
+        progress: Some(progress),
+        ..Default::default()
+    };
+    assert!(options.progress.is_some());
+}
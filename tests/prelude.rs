@@ -0,0 +1,38 @@
+//! every item of the prelude is used here, so removing one by accident breaks the build
+
+use std::sync::Arc;
+
+use conda_cage::prelude::*;
+
+#[test]
+fn test_prelude_items() {
+    let old_recipe: Recipe = "django 3.2.13 pypi_0 pypi".try_into().unwrap();
+    let new_recipe = Recipe::try_from("django 3.2.14 pypi_0 pypi").unwrap();
+    let diff: RecipeDiff = old_recipe.diff_with(new_recipe, &DiffOptions::default());
+    let update: &Update = &diff.updates[0];
+    let django: &Package = &update.to;
+    assert_eq!(django.kind, PackageKind::PyPi { extras: vec![] });
+
+    let progress: Arc<dyn ProgressSink> = Arc::new(PlainProgress::default());
+    let options = InstallOptions {
+        on_conflict: Some(OnConflict::Skip),
+        progress: Some(progress),
+        ..Default::default()
+    };
+    assert!(options.progress.is_some());
+
+    let report = RunReport::new(vec![EnvReport {
+        status: Status::Skipped,
+        diff,
+        ..EnvReport::new("demo")
+    }]);
+    assert_eq!(report.status, Status::Skipped);
+
+    let error = Error::PythonRemoval("python".into());
+    assert_eq!(error.kind(), "python_removal");
+
+    let _install = install;
+    let _install_recipe = install_recipe;
+    assert_eq!(EnvTarget::from("demo").args(), ["-n", "demo"]);
+    let _conda_version = EnvSnapshot::conda_version;
+}
@@ -0,0 +1,40 @@
+//! the sandbox of the post install hooks, which needs `bwrap`
+
+use std::{path::PathBuf, process::Command};
+
+use conda_cage::action::Sandbox;
+
+#[test]
+fn test_sandbox_only_writes_allowed_paths() {
+    if !Sandbox::is_available() {
+        eprintln!("skipped, `bwrap` isn't available");
+        return;
+    }
+    // not under the temp dir, which is a tmpfs in the sandbox
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("conda-cage-test-sandbox");
+    let _ = std::fs::remove_dir_all(&root);
+    let prefix = root.join("envs/demo");
+    let pkgs = root.join("pkgs");
+    let outside = root.join("outside");
+    for dir in [&prefix, &pkgs, &outside] {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+
+    let run = |path: &PathBuf| {
+        let script = format!("touch {}", path.join("file").display());
+        let argv = Sandbox::default().wrap(
+            &prefix,
+            &[pkgs.clone()],
+            &["sh".to_string(), "-c".to_string(), script],
+        );
+        Command::new(&argv[0])
+            .args(&argv[1..])
+            .status()
+            .unwrap()
+            .success()
+    };
+    assert!(run(&prefix));
+    assert!(run(&pkgs));
+    assert!(!run(&outside));
+    assert!(!outside.join("file").exists());
+}